@@ -0,0 +1,77 @@
+# Sv32 translation benchmark.
+# Maps PAGES 4KiB pages at 0x40000000 and the code with a megapage, then keeps
+# reading and writing one word per page from supervisor mode.
+#
+# With at most 64 pages, the size of the direct mapped dTLB, every access after
+# the first round hits the TLB. Raise PAGES above 64 to measure the page walk
+# instead, then every data access misses.
+#
+#   llvm-mc -triple=riscv32 -filetype=obj sv32.s -o sv32.o
+#   llvm-objcopy -O binary sv32.o sv32.bin
+#   riscv_em -c -b sv32.bin --bench 100000000
+
+.equ ROOT_PT,   0x80400000
+.equ LEAF_PT,   0x80401000
+.equ DATA,      0x80800000
+.equ PTE_V,     0x01
+.equ PTE_RWXAD, 0xce
+.equ PTE_RWAD,  0xc6
+.equ PAGES,     32
+
+.text
+_start:
+  # megapage identity mapping of 0x80000000 (root entry 0x200)
+  li t0, ROOT_PT + 0x200 * 4
+  li t1, (0x80000000 >> 12) << 10 | PTE_RWXAD | PTE_V
+  sw t1, 0(t0)
+
+  # root entry 0x100 (0x40000000) points to the leaf table
+  li t0, ROOT_PT + 0x100 * 4
+  li t1, (LEAF_PT >> 12) << 10 | PTE_V
+  sw t1, 0(t0)
+
+  # leaf table maps 0x40000000 + n * 4KiB to DATA + n * 4KiB
+  li t0, LEAF_PT
+  li t1, (DATA >> 12) << 10 | PTE_RWAD | PTE_V
+  li t2, PAGES
+  li t3, 1 << 10
+1:
+  sw t1, 0(t0)
+  add t1, t1, t3
+  addi t0, t0, 4
+  addi t2, t2, -1
+  bnez t2, 1b
+
+  # pmp entry 0 allows everything, NAPOT over the whole address space
+  li t0, -1
+  csrw pmpaddr0, t0
+  li t0, 0x1f
+  csrw pmpcfg0, t0
+
+  li t0, (1 << 31) | (ROOT_PT >> 12)
+  csrw satp, t0
+  sfence.vma
+
+  # mret to supervisor mode
+  li t0, 1 << 11
+  csrw mstatus, t0
+  la t0, bench
+  csrw mepc, t0
+  mret
+
+bench:
+  li a0, 0x40000000
+  li a1, PAGES
+  li a2, 4096
+  li a3, 0
+loop:
+  mv t0, a0
+  mv t1, a1
+2:
+  lw t2, 0(t0)
+  add a3, a3, t2
+  sw a3, 4(t0)
+  add t0, t0, a2
+  addi t1, t1, -1
+  bnez t1, 2b
+  j loop
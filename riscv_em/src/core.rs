@@ -1,49 +1,81 @@
+pub mod commit_log;
 pub mod csr;
 mod datapath;
 pub mod exceptions;
+mod fpu;
+pub mod gdb;
 mod instr_parse;
+pub mod loader;
+pub mod lockstep;
+pub mod monitor;
+pub mod sbi;
+#[cfg(test)]
+mod tests;
 mod virt_memory;
 
-use crate::{
-    core::csr::conuters_mirror,
-    memory::{clint::Clint, *},
-};
+use crate::{core::csr::conuters_mirror, memory::*};
 use csr::{Csr, Csr64};
 use exceptions::*;
 use instr_parse::Instruction;
-use std::{fs, u32};
+use std::u32;
 
 const TRAP_CLEAR: u32 = u32::MAX;
 
+//                       zyxvwutsrqponmlkjihgfedcba
+//                       Spent a whole week looking for a problem,
+//                       ... I missed q in alphabet.
+pub const MISA: u32 = 0b01000000000101000001000100101101;
+pub const PMP_REGIONS: usize = 16;
+pub const MAX_HARTS: usize = 8;
+
+// ABI register names of x0 to x31
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+pub const FREG_NAMES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2",
+    "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9",
+    "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum State {
     Ok,
     Sleep,
-    // Reboot,
-    // Shutdown,
+    Breakpoint,
+    Diverged, // from the lockstep reference
+    Reboot,   // requested by the guest through syscon, the test finisher or SBI
+    Shutdown,
 }
 
 pub struct Hart {
     pub core: Core,
-    pub clint: Clint,
+    pub symbols: loader::Symbols,
 }
 
 #[derive(Debug)]
 pub struct Core {
     pub pc: u32,
     reg_file: [i32; 32],
+    freg_file: [u64; 32],
     pub csr_file: [u32; 4096],
 
     trap: u32,
     pub trap_val: u32,
-    pub lr_address: u32,
-    lr_set: i32,
     pub mode: u32,
     pub wfi: bool, // wait for interrupt
 
     instr_fetch: u32,
-    pub instr_str: String,
-    pub p_start: bool,
+    instr_len: u32,
+    pub commit_log: Option<commit_log::CommitLog>, // --log-commits
+
+    tlb: virt_memory::tlb::Tlb,
+    pub svadu: bool, // hardware A/D bit updates instead of page faults
+
+    pub breakpoints: Vec<u32>, // set by the gdb stub
 }
 
 impl Default for Core {
@@ -51,30 +83,106 @@ impl Default for Core {
         Core {
             pc: 0,
             reg_file: [0; 32],
+            freg_file: [0; 32],
             csr_file: [0; 4096],
 
             trap: TRAP_CLEAR,
             trap_val: 0,
-            lr_address: 0,
-            lr_set: 0,
             mode: 0,
             wfi: false,
 
             instr_fetch: 0,
-            instr_str: String::new(),
-            p_start: false,
+            instr_len: 4,
+            commit_log: None,
+
+            tlb: virt_memory::tlb::Tlb::default(),
+            svadu: false,
+
+            breakpoints: Vec::new(),
         }
     }
 }
 
 impl Core {
+    pub fn hart_id(&self) -> usize {
+        csr::read(Csr::mhartid, self) as usize
+    }
+
+    // power on state for a reboot, the emulator configuration is kept
+    pub fn reset(&mut self) {
+        let old = std::mem::take(self);
+        self.svadu = old.svadu;
+        self.breakpoints = old.breakpoints;
+        self.commit_log = old.commit_log;
+    }
+
+    pub fn diverged(&self) -> bool {
+        match &self.commit_log {
+            Some(log) => log.diverged(),
+            None => false,
+        }
+    }
+
+    // commit log of the current instruction, None unless logging has started
+    fn log(&mut self) -> Option<&mut commit_log::CommitLog> {
+        self.commit_log.as_mut().filter(|log| log.active)
+    }
+
+    fn log_reg(&mut self, reg: u32) {
+        let val = self.reg_file[reg as usize] as u32;
+        if let Some(log) = self.log() {
+            log.reg(reg, val);
+        }
+    }
+
+    fn log_freg(&mut self, reg: u32) {
+        let val = self.freg_file[reg as usize];
+        if let Some(log) = self.log() {
+            log.freg(reg, val);
+        }
+    }
+
+    fn log_csr(&mut self, addr: u32) {
+        // spike logs the supervisor views sstatus, sie and sip as the machine csrs
+        let addr = match addr {
+            0x100 => 0x300,
+            0x104 => 0x304,
+            0x144 => 0x344,
+            x => x,
+        };
+        let val = self.csr_file[addr as usize];
+        if let Some(log) = self.log() {
+            log.csr(addr, val);
+        }
+    }
+
+    fn log_load(&mut self, addr: u32) {
+        if let Some(log) = self.log() {
+            log.load(addr);
+        }
+    }
+
+    fn log_store(&mut self, addr: u32, val: u64, bytes: u32) {
+        if let Some(log) = self.log() {
+            log.store(addr, val, bytes);
+        }
+    }
+
+    // count and log an executed instruction
+    fn retire(&mut self) {
+        if (csr::read(Csr::mcountinhibit, self) & 0b100) == 0 {
+            let minstret = csr::read_64(Csr64::minstret, self);
+            csr::write_64(Csr64::minstret, minstret + 1, self);
+        }
+        let hart_id = self.hart_id();
+        if let Some(log) = &mut self.commit_log {
+            log.commit(hart_id);
+        }
+    }
+
     fn m_mode_trap_handler(&mut self) {
         // Machine mode trap handler
         // println!("mmode trap");
-        if super::DEBUG {
-            // print!("o {:x} ", core.trap);
-            // println!("mmode trap");
-        }
         if (self.trap as i32) < 0 {
             // interrupt
             csr::write(Csr::mcause, self.trap, self);
@@ -139,10 +247,6 @@ impl Core {
     fn s_mode_trap_handler(&mut self) {
         // Supervisor mode trap handler
         // println!("smode trap");
-        if super::DEBUG {
-            // print!("o {:x} ", core.trap);
-            // println!("smode trap");
-        }
         if (self.trap as i32) < 0 {
             // interrupt
             csr::write(Csr::scause, self.trap, self);
@@ -265,72 +369,154 @@ impl Core {
 }
 
 pub fn soc_init(
-    hart: &mut Hart,
+    harts: &mut [Hart],
     bus: &mut MemoryBus,
-    kernel: &str,
-    dtb: &str,
+    bios: Option<&str>,
+    kernel: Option<&str>,
+    initrd: Option<&str>,
+    dtb: &[u8],
 ) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    // images are loaded through the first hart, all harts start at the same entry
+    let count = harts.len();
+    let hart = &mut harts[0];
     hart.core.mode = 3;
-
-    // kernel
-    let data = fs::read(kernel)?;
-    for i in 0..data.len() {
-        let _ = virt_memory::virt_write_byte(super::RAM_OFFSET + i as u32, data[i], hart, bus);
-    }
+    let (ram_base, ram_length) = bus.ram.region();
+
+    // address ranges of the bios and kernel, the initrd must not overwrite them
+    let mut loaded = Vec::new();
+    let entry = match (bios, kernel) {
+        (Some(bios), kernel) => {
+            let (entry, ranges) = loader::load_image(bios, ram_base, hart, bus)?;
+            loaded.extend(ranges);
+            if let Some(kernel) = kernel {
+                let (_, ranges) =
+                    loader::load_image(kernel, ram_base + super::KERNEL_OFFSET, hart, bus)?;
+                loaded.extend(ranges);
+            }
+            entry
+        }
+        // without a bios the built-in SBI enters the kernel in S-mode
+        (None, Some(kernel)) => {
+            let (entry, ranges) =
+                loader::load_image(kernel, ram_base + super::SBI_KERNEL_OFFSET, hart, bus)?;
+            loaded.extend(ranges);
+            entry
+        }
+        (None, None) => return Err("no bios or kernel image".into()),
+    };
+    bus.sbi = match bios {
+        Some(_) => None,
+        None => Some(sbi::Sbi::new(count)),
+    };
 
     //8 byte alligned DTB
-    let data = fs::read(dtb)?;
-    let mut dtb_addr = super::RAM_OFFSET + super::RAM_SIZE as u32 - data.len() as u32;
+    let mut dtb_addr = ram_base + ram_length - dtb.len() as u32;
     dtb_addr >>= 3;
     dtb_addr <<= 3;
     println!("dtb addr 0x{:08x}", dtb_addr);
-    for i in 0..data.len() {
-        let _ = virt_memory::virt_write_byte(dtb_addr + i as u32, data[i], hart, bus);
+    if let Some(initrd) = initrd {
+        let start = super::initrd_addr(bus.ram.region());
+        let end = loader::load_file(initrd, start, hart, bus)?;
+        if end > dtb_addr {
+            return Err(format!("{}: initrd overlaps the device tree at 0x{:08x}", initrd, dtb_addr).into());
+        }
+        let overlap = loaded.iter().find(|(x, y)| *x < end && start < *y);
+        if let Some((image_start, image_end)) = overlap {
+            return Err(format!(
+                "{}: initrd overlaps the image at 0x{:08x}-0x{:08x}",
+                initrd,
+                image_start,
+                image_end - 1
+            )
+            .into());
+        }
+    }
+    for (i, byte) in dtb.iter().enumerate() {
+        let _ = virt_memory::virt_write_byte(dtb_addr + i as u32, *byte, hart, bus);
         // self.dtb.push(data[i]);
     }
     // while self.dtb.len() % 4 != 0 {
     //     self.dtb.push(0);
     // }
 
-    hart.core.pc = 0x80000000;
-    hart.core.reg_file[5] = 0x00001000u32 as i32;
-    hart.core.reg_file[10] = 0x00; // hart ID
-    hart.core.reg_file[11] = dtb_addr as i32;
-    hart.core.reg_file[12] = 0;
-    csr::write(
-        Csr::misa,
-        0b01000000000101000001000100000001,
-        &mut hart.core,
-    );
-    //                            zyxvwutsrqponmlkjihgfedcba
-    //                            Spent a whole week looking for a problem,
-    //                            ... I missed q in alphabet.
-    csr::write(
-        Csr::menvcfgh,
-        0b00010000000000000000000000000000,
-        &mut hart.core,
-    );
-    csr::write(
-        Csr::menvcfg,
-        0b00000000000000000000000000000000,
-        &mut hart.core,
-    );
-    csr::write(Csr::marchid, 0x5, &mut hart.core);
+    // riscv-tests and riscv-arch-test talk to the host through tohost
+    let (tohost, fromhost) = (hart.symbols.find("tohost"), hart.symbols.find("fromhost"));
+    bus.htif.set_addresses(&bus.ram, tohost, fromhost);
+
+    let symbols = hart.symbols.clone();
+    for (hart_id, hart) in harts.iter_mut().enumerate() {
+        hart.core.mode = 3;
+        hart.symbols = symbols.clone();
+        hart.core.pc = entry;
+        hart.core.reg_file[5] = 0x00001000u32 as i32;
+        hart.core.reg_file[10] = hart_id as i32; // hart ID
+        hart.core.reg_file[11] = dtb_addr as i32;
+        hart.core.reg_file[12] = 0;
+        csr::write(Csr::mhartid, hart_id as u32, &mut hart.core);
+        csr::write(Csr::misa, MISA, &mut hart.core);
+        // with Svadu hardware A/D updates are enabled from reset
+        let adue = match hart.core.svadu {
+            true => csr::MENVCFGH_ADUE,
+            false => 0,
+        };
+        csr::write(
+            Csr::menvcfgh,
+            0b00010000000000000000000000000000 | adue,
+            &mut hart.core,
+        );
+        csr::write(
+            Csr::menvcfg,
+            0b00000000000000000000000000000000,
+            &mut hart.core,
+        );
+        csr::write(Csr::marchid, 0x5, &mut hart.core);
+        if bus.sbi.is_some() {
+            sbi::setup(&mut hart.core, &mut bus.clint);
+        }
+    }
     Ok(())
 }
 
+// harts and devices start over, the images are loaded again
+pub fn reboot(
+    harts: &mut [Hart],
+    bus: &mut MemoryBus,
+    bios: Option<&str>,
+    kernel: Option<&str>,
+    initrd: Option<&str>,
+    dtb: &[u8],
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    bus.reset();
+    for hart in harts.iter_mut() {
+        hart.core.reset();
+        hart.symbols = loader::Symbols::default();
+    }
+    soc_init(harts, bus, bios, kernel, initrd, dtb)
+}
+
 pub fn hart_run(hart: &mut Hart, bus: &mut MemoryBus, max_cycles: u32) -> State {
-    // TODO: devices tick
-    hart.clint.tick(&mut hart.core);
-    bus.uart.tick(&mut bus.plic);
-    bus.blk.tick(&mut bus.plic, &mut bus.ram);
-    bus.plic.tick(&mut hart.core);
+    if hart.core.diverged() {
+        return State::Diverged;
+    }
+    bus.tick(&mut hart.core);
+    if let Some(sbi) = &mut bus.sbi
+        && !sbi.hart_ready(&mut hart.core)
+    {
+        return State::Sleep;
+    }
 
     if hart.core.wfi {
         return State::Sleep;
     }
 
-    match tick(hart, bus, max_cycles) {
+    let state = tick(hart, bus, max_cycles);
+    // poweroff or reboot requested by the guest through syscon or the test finisher
+    match bus.power.take() {
+        Some(Power::Off) => return State::Shutdown,
+        Some(Power::Reboot) => return State::Reboot,
+        None => {}
+    }
+    match state {
         Ok(State::Ok) => {}
         Ok(x) => {
             return x;
@@ -346,6 +532,24 @@ pub fn hart_run(hart: &mut Hart, bus: &mut MemoryBus, max_cycles: u32) -> State
                 if hart.core.trap == 2 {
                     hart.core.trap_val = hart.core.instr_fetch;
                 }
+                if hart.core.trap == 9
+                    && let Some(state) = sbi::ecall(hart, bus)
+                {
+                    return state;
+                }
+                if matches!(hart.core.trap, 8 | 9 | 11) {
+                    let (a7, a0) = (hart.core.reg_file[17] as u32, hart.core.reg_file[10] as u32);
+                    if bus.htif.ecall(a7, a0) {
+                        return State::Ok;
+                    }
+                }
+                let hart_id = hart.core.hart_id();
+                let (trap, pc, trap_val) = (hart.core.trap, hart.core.pc, hart.core.trap_val);
+                if let Some(log) = &mut hart.core.commit_log {
+                    log.trap(hart_id, trap, pc, trap_val);
+                }
+                // a trap ends the LR/SC sequence, e.g. before a context switch
+                bus.ram.clear_reservation(hart_id);
                 if (hart.core.trap as i32) < 0 {
                     //interrupt
                     let mideleg = csr::read(Csr::mideleg, &mut hart.core);
@@ -373,8 +577,15 @@ fn tick(hart: &mut Hart, bus: &mut MemoryBus, max_cycles: u32) -> Result<State,
     let mut curr_cycle = 0;
 
     while curr_cycle < max_cycles {
+        if hart.core.breakpoints.contains(&hart.core.pc) {
+            return Ok(State::Breakpoint);
+        }
+        if hart.core.diverged() {
+            return Ok(State::Diverged);
+        }
+
         hart.core.check_interrupts();
-        csr::conuters_mirror(hart);
+        csr::conuters_mirror(hart, &bus.clint);
 
         if hart.core.trap != TRAP_CLEAR {
             return Err(());
@@ -383,21 +594,7 @@ fn tick(hart: &mut Hart, bus: &mut MemoryBus, max_cycles: u32) -> Result<State,
         hart.core.reg_file[0] = 0;
         curr_cycle += 1;
 
-        if super::DEBUG {
-            if (hart.core.pc == 0x80400094
-                || csr::read_64(Csr64::mcycle, &hart.core) > super::PRINT_START)
-                && !hart.core.p_start
-            {
-                println!("print start!");
-                hart.core.p_start = true;
-            }
-
-            // if hart.core.pc == 0x80400094 {
-            //     clint.mtime = 0xc9f4;
-            // }
-        }
-
-        if hart.core.pc & 0b11 > 0 {
+        if hart.core.pc & 0b1 > 0 {
             // check instruction address alignment
             // TODO: move this check to datapath
             hart.core.trap = 0;
@@ -411,18 +608,18 @@ fn tick(hart: &mut Hart, bus: &mut MemoryBus, max_cycles: u32) -> Result<State,
             match virt_memory::virt_fetch_word(hart.core.pc, hart, bus) {
                 Ok(fetch_result) => {
                     hart.core.instr_fetch = fetch_result;
+                    hart.core.instr_len = instr_parse::instr_len(fetch_result);
 
-                    if hart.core.p_start {
-                        hart.core.instr_str = format!(
-                            "core   0: {} 0x{:08x?} (0x{:08x?})\t",
-                            hart.core.mode, hart.core.pc, hart.core.instr_fetch
-                        );
+                    let core = &mut hart.core;
+                    if let Some(log) = &mut core.commit_log {
+                        log.begin(core.mode, core.pc, core.instr_fetch, core.instr_len);
                     }
 
                     match Instruction::from(fetch_result) {
                         Ok(instr) => {
                             let ret = match instr {
                                 Instruction::R(x) => datapath::exec_r(hart, bus, &x),
+                                Instruction::R4(x) => datapath::exec_r4(&mut hart.core, &x),
                                 Instruction::I(x) => datapath::exec_i(hart, bus, &x),
                                 Instruction::U(x) => datapath::exec_u(&mut hart.core, &x),
                                 Instruction::J(x) => datapath::exec_j(&mut hart.core, &x),
@@ -432,6 +629,8 @@ fn tick(hart: &mut Hart, bus: &mut MemoryBus, max_cycles: u32) -> Result<State,
                             match ret {
                                 Ok(State::Ok) => {}
                                 Ok(x) => {
+                                    // wfi
+                                    hart.core.retire();
                                     return Ok(x);
                                 }
                                 Err(e) => {
@@ -451,23 +650,10 @@ fn tick(hart: &mut Hart, bus: &mut MemoryBus, max_cycles: u32) -> Result<State,
                     return Err(());
                 }
             };
-            if (csr::read(Csr::mcountinhibit, &hart.core) & 0b100) == 0 {
-                let minstret = csr::read_64(Csr64::minstret, &hart.core);
-                csr::write_64(Csr64::minstret, minstret + 1, &mut hart.core);
-            }
-
-            if hart.core.p_start {
-                if hart.core.instr_fetch != 0x00000073 {
-                    if !super::SPIKE_DEBUG {
-                        print!(
-                            "hart.core   0: {} 0x{:x?} (0x{:08x?})\t",
-                            hart.core.mode, hart.core.pc, hart.core.instr_fetch
-                        );
-                        eprintln!("0x{:08x?}: 0x{:08x?}", hart.core.pc, hart.core.instr_fetch);
-                    } else {
-                        eprintln!("{}", hart.core.instr_str);
-                    }
-                }
+            hart.core.retire();
+            // the guest asked for poweroff or reboot, the other harts don't run again
+            if bus.power.is_some() {
+                break;
             }
         }
     }
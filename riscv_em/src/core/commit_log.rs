@@ -0,0 +1,226 @@
+// Commit log in the format of spike --log-commits, one line per retired instruction:
+// core   0: 3 0x80000000 (0x00000297) x5  0x80000000
+// followed by register and csr writes, loads (mem addr) and stores (mem addr value).
+// Traps are logged the way spike prints them with -l.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+
+use super::lockstep::Lockstep;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Start {
+    Instret(u64), // after given number of retired instructions
+    Pc(u32),      // from the first fetch at given address
+}
+
+// one retired instruction
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Commit {
+    pub mode: u32,
+    pub pc: u32,
+    pub instr: u32,
+    pub instr_len: u32,
+    // spike sorts writes by (register << 4 | kind), kind is 0 for x, 1 for f and 4 for csr
+    // values are kept with their width in bits
+    pub writes: BTreeMap<u32, (u64, u32)>,
+    pub loads: Vec<u32>,
+    pub stores: Vec<(u32, u64, u32)>,
+}
+
+impl Commit {
+    pub fn format(&self, hart_id: usize) -> String {
+        let mut line = format!(
+            "core {:3}: {} 0x{:08x} ({})",
+            hart_id,
+            self.mode,
+            self.pc,
+            hex(self.instr as u64, 8 * self.instr_len)
+        );
+        for (key, (val, bits)) in &self.writes {
+            line += &format!(" {} {}", write_name(*key), hex(*val, *bits));
+        }
+        for addr in &self.loads {
+            line += &format!(" mem {}", hex(*addr as u64, 32));
+        }
+        for (addr, val, bits) in &self.stores {
+            line += &format!(" mem {} {}", hex(*addr as u64, 32), hex(*val, *bits));
+        }
+        line
+    }
+}
+
+// trap taken instead of retiring the instruction at epc
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trap {
+    pub name: String,
+    pub epc: u32,
+    pub tval: Option<u32>,
+}
+
+impl Trap {
+    pub fn new(cause: u32, epc: u32, tval: u32) -> Self {
+        // ecalls and interrupts have no trap value
+        let tval = match cause {
+            8 | 9 | 11 => None,
+            x if (x as i32) < 0 => None,
+            _ => Some(tval),
+        };
+        Trap {
+            name: trap_name(cause),
+            epc,
+            tval,
+        }
+    }
+
+    pub fn format(&self, hart_id: usize) -> String {
+        let mut text = format!(
+            "core {:3}: exception {}, epc 0x{:08x}",
+            hart_id, self.name, self.epc
+        );
+        if let Some(tval) = self.tval {
+            text += &format!("\ncore {:3}:           tval 0x{:08x}", hart_id, tval);
+        }
+        text
+    }
+}
+
+#[derive(Debug)]
+pub struct CommitLog {
+    out: Option<LineWriter<File>>,
+    pub lockstep: Option<Lockstep>,
+    start: Start,
+    pub active: bool,
+    pub retired: u64,
+    current: Commit,
+}
+
+impl CommitLog {
+    pub fn new(start: Start, out: Option<File>, lockstep: Option<Lockstep>) -> Self {
+        CommitLog {
+            out: out.map(LineWriter::new),
+            lockstep,
+            start,
+            active: false,
+            retired: 0,
+            current: Commit::default(),
+        }
+    }
+
+    fn check_start(&mut self, pc: u32) {
+        if !self.active {
+            self.active = match self.start {
+                Start::Instret(n) => self.retired >= n,
+                Start::Pc(addr) => pc == addr,
+            };
+        }
+    }
+
+    // the lockstep comparison failed, the hart must not run any further
+    pub fn diverged(&self) -> bool {
+        match &self.lockstep {
+            Some(lockstep) => lockstep.divergence.is_some(),
+            None => false,
+        }
+    }
+
+    // before the execution of every fetched instruction
+    pub fn begin(&mut self, mode: u32, pc: u32, instr: u32, instr_len: u32) {
+        self.check_start(pc);
+        self.current.mode = mode;
+        self.current.pc = pc;
+        self.current.instr = instr;
+        self.current.instr_len = instr_len;
+        self.current.writes.clear();
+        self.current.loads.clear();
+        self.current.stores.clear();
+    }
+
+    pub fn reg(&mut self, reg: u32, val: u32) {
+        // writes to x0 are not logged
+        if reg != 0 {
+            self.current.writes.insert(reg << 4, (val as u64, 32));
+        }
+    }
+
+    pub fn freg(&mut self, reg: u32, val: u64) {
+        self.current.writes.insert(reg << 4 | 1, (val, 64));
+    }
+
+    pub fn csr(&mut self, addr: u32, val: u32) {
+        self.current.writes.insert(addr << 4 | 4, (val as u64, 32));
+    }
+
+    pub fn load(&mut self, addr: u32) {
+        self.current.loads.push(addr);
+    }
+
+    pub fn store(&mut self, addr: u32, val: u64, bytes: u32) {
+        self.current.stores.push((addr, val, 8 * bytes));
+    }
+
+    pub fn commit(&mut self, hart_id: usize) {
+        self.retired += 1;
+        if !self.active {
+            return;
+        }
+        let line = self.current.format(hart_id);
+        if let Some(out) = &mut self.out {
+            let _ = writeln!(out, "{}", line);
+        }
+        if let Some(lockstep) = &mut self.lockstep {
+            lockstep.commit(&self.current, line);
+        }
+    }
+
+    pub fn trap(&mut self, hart_id: usize, cause: u32, epc: u32, tval: u32) {
+        self.check_start(epc);
+        if !self.active {
+            return;
+        }
+        let trap = Trap::new(cause, epc, tval);
+        let text = trap.format(hart_id);
+        if let Some(out) = &mut self.out {
+            let _ = writeln!(out, "{}", text);
+        }
+        if let Some(lockstep) = &mut self.lockstep {
+            lockstep.trap(&trap, text);
+        }
+    }
+}
+
+pub fn hex(val: u64, bits: u32) -> String {
+    format!("0x{:0w$x}", val, w = bits as usize / 4)
+}
+
+// x5, f3 or c768_mstatus
+pub fn write_name(key: u32) -> String {
+    match key & 0xf {
+        4 => format!("c{}_{}", key >> 4, super::csr::csr_name(key >> 4)),
+        1 => format!("f{:<2}", key >> 4),
+        _ => format!("x{:<2}", key >> 4),
+    }
+}
+
+fn trap_name(cause: u32) -> String {
+    let name = match cause {
+        0 => "instruction_address_misaligned",
+        1 => "instruction_access_fault",
+        2 => "illegal_instruction",
+        3 => "breakpoint",
+        4 => "load_address_misaligned",
+        5 => "load_access_fault",
+        6 => "store_address_misaligned",
+        7 => "store_access_fault",
+        8 => "user_ecall",
+        9 => "supervisor_ecall",
+        11 => "machine_ecall",
+        12 => "instruction_page_fault",
+        13 => "load_page_fault",
+        15 => "store_page_fault",
+        _ if (cause as i32) < 0 => return format!("interrupt #{}", cause & 0xff),
+        _ => return format!("trap #{}", cause),
+    };
+    format!("trap_{}", name)
+}
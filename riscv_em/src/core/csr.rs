@@ -1,15 +1,18 @@
 use crate::core::Hart;
+use crate::memory::clint::Clint;
 
 use super::{Core, exceptions::Exception};
 
-static LEGAL_ADRESSES: [u32; 61] = [
-    0xf11, 0xf12, 0xf13, 0xf14, 0x340, 0x140, 0xC00, 0xC80, 0xC01, 0xC81, 0xC02, 0xC82, 0xB00,
+pub static LEGAL_ADRESSES: [u32; 64] = [
+    0x001, 0x002, 0x003, 0xf11, 0xf12, 0xf13, 0xf14, 0x340, 0x140, 0xC00, 0xC80, 0xC01, 0xC81, 0xC02, 0xC82, 0xB00,
     0xB80, 0xB02, 0xB82, 0x344, 0x144, 0x304, 0x104, 0x305, 0x105, 0x341, 0x141, 0x342, 0x142,
     0x343, 0x143, 0x302, 0x312, 0x303, 0x300, 0x310, 0x100, 0x180, 0x301, 0x3A0, 0x3A1, 0x3A2,
     0x3A3, 0x3B0, 0x3B1, 0x3B2, 0x3B3, 0x3B4, 0x3B5, 0x3B6, 0x3B7, 0x3B8, 0x3B9, 0x3BA, 0x3BB,
     0x3BC, 0x3BD, 0x3BE, 0x3BF, 0x306, 0x106, 0x30A, 0x31A, 0x320,
 ];
 
+pub const MENVCFGH_ADUE: u32 = 1 << 29; // menvcfg bit 61, hardware A/D updates
+
 pub fn read(csr: Csr, core: &Core) -> u32 {
     let addr = csr_addr(csr);
     core.csr_file[addr]
@@ -30,11 +33,13 @@ pub fn write(csr: Csr, data: u32, core: &mut Core) {
         0x100 => {
             // sstatus
             let sstatus = data & status_mask;
-            core.csr_file[0x100] = sstatus;
             let mut mstatus = core.csr_file[0x300];
             mstatus &= !status_mask;
             mstatus |= sstatus;
+            let mstatus = status_sd(mstatus);
+            core.csr_file[0x100] = mstatus & status_mask;
             core.csr_file[0x300] = mstatus;
+            return;
         }
         0x104 => {
             // sie
@@ -47,14 +52,20 @@ pub fn write(csr: Csr, data: u32, core: &mut Core) {
         }
         0x300 => {
             // mstatus
+            let data = status_sd(data);
             core.csr_file[0x300] = data;
             core.csr_file[0x100] = data & status_mask;
+            return;
         }
         0x304 => {
             // mie
             core.csr_file[0x304] = data;
             core.csr_file[0x104] = data & interrupt_mask & mideleg;
         }
+        0x344 => {
+            // mip
+            core.csr_file[0x144] = data & interrupt_mask & mideleg;
+        }
         _ => {
             core.csr_file[addr as usize] = data;
         }
@@ -63,6 +74,40 @@ pub fn write(csr: Csr, data: u32, core: &mut Core) {
     core.csr_file[addr] = data;
 }
 
+// SD summarizes dirty FS, VS and XS fields
+fn status_sd(mstatus: u32) -> u32 {
+    if (mstatus >> 13) & 0b11 == 0b11 {
+        mstatus | (1 << 31)
+    } else {
+        mstatus & !(1 << 31)
+    }
+}
+
+pub fn fp_enabled(core: &Core) -> bool {
+    (core.csr_file[0x300] >> 13) & 0b11 != 0
+}
+
+pub fn set_fp_dirty(core: &mut Core) {
+    if core.csr_file[0x300] & (0b11 << 13) == 0b11 << 13 {
+        return;
+    }
+    let mstatus = core.csr_file[0x300] | (0b11 << 13);
+    write(Csr::mstatus, mstatus, core);
+    core.log_csr(0x300);
+}
+
+// accrue floating point exception flags
+pub fn set_fflags(flags: u32, core: &mut Core) {
+    if flags == 0 {
+        return;
+    }
+    let fcsr = core.csr_file[0x003] | (flags & 0x1f);
+    core.csr_file[0x001] = fcsr & 0x1f;
+    core.csr_file[0x003] = fcsr;
+    core.log_csr(0x001);
+    set_fp_dirty(core);
+}
+
 #[allow(non_snake_case)]
 pub fn read_pmpXcfg(n: u32, core: &Core) -> u8 {
     let addr = match n / 4 {
@@ -102,6 +147,11 @@ pub fn read_addr(addr: u32, core: &Core) -> Result<u32, Exception> {
         return Err(Exception::Illegal_instruction);
     }
 
+    // fflags, frm, fcsr while FS is off
+    if addr <= 0x003 && !fp_enabled(core) {
+        return Err(Exception::Illegal_instruction);
+    }
+
     // trap time read from m-mode
     if (addr == 0xC01 || addr == 0xC81) && core.mode == 3 {
         return Err(Exception::Illegal_instruction);
@@ -205,18 +255,47 @@ pub fn write_addr(addr: u32, data: u32, core: &mut Core) -> Result<(), Exception
         return Err(Exception::Illegal_instruction);
     }
 
+    if addr <= 0x003 && !fp_enabled(core) {
+        return Err(Exception::Illegal_instruction);
+    }
+
     let status_mask = 0b10000001100011111110011101100010;
     let interrupt_mask = 0b1000100010;
     let mideleg = core.csr_file[csr_addr(Csr::mideleg)];
 
     match addr {
+        0x001 => {
+            // fflags
+            let fcsr = (core.csr_file[0x003] & !0x1f) | (data & 0x1f);
+            core.csr_file[0x001] = data & 0x1f;
+            core.csr_file[0x003] = fcsr;
+            set_fp_dirty(core);
+            return Ok(());
+        }
+        0x002 => {
+            // frm
+            let fcsr = (core.csr_file[0x003] & !0xe0) | ((data & 0b111) << 5);
+            core.csr_file[0x002] = data & 0b111;
+            core.csr_file[0x003] = fcsr;
+            set_fp_dirty(core);
+            return Ok(());
+        }
+        0x003 => {
+            // fcsr
+            core.csr_file[0x001] = data & 0x1f;
+            core.csr_file[0x002] = (data >> 5) & 0b111;
+            core.csr_file[0x003] = data & 0xff;
+            set_fp_dirty(core);
+            return Ok(());
+        }
         0x100 => {
             // sstatus
             let sstatus = data & status_mask;
-            core.csr_file[0x100] = sstatus;
             let mut mstatus = core.csr_file[0x300];
             mstatus &= !status_mask;
             mstatus |= sstatus;
+            let mstatus = status_sd(mstatus);
+            core.csr_file[0x100] = mstatus & status_mask;
             core.csr_file[0x300] = mstatus;
             return Ok(());
         }
@@ -232,6 +311,7 @@ pub fn write_addr(addr: u32, data: u32, core: &mut Core) -> Result<(), Exception
         }
         0x300 => {
             // mstatus
+            let data = status_sd(data);
             core.csr_file[0x300] = data;
             core.csr_file[0x100] = data & status_mask;
             return Ok(());
@@ -242,6 +322,39 @@ pub fn write_addr(addr: u32, data: u32, core: &mut Core) -> Result<(), Exception
             core.csr_file[0x104] = data & interrupt_mask & mideleg;
             return Ok(());
         }
+        0x144 => {
+            // sip; only SSIP is writable
+            let ssip = 0b10 & mideleg;
+            let mip = (core.csr_file[0x344] & !ssip) | (data & ssip);
+            core.csr_file[0x344] = mip;
+            core.csr_file[0x144] = mip & interrupt_mask & mideleg;
+            return Ok(());
+        }
+        0x344 => {
+            // mip
+            core.csr_file[0x344] = data;
+            core.csr_file[0x144] = data & interrupt_mask & mideleg;
+            return Ok(());
+        }
+        0x31A => {
+            // menvcfgh; ADUE is read only zero without Svadu
+            core.csr_file[0x31A] = match core.svadu {
+                true => data,
+                false => data & !MENVCFGH_ADUE,
+            };
+            return Ok(());
+        }
+        0x180 => {
+            // satp
+            core.csr_file[0x180] = data;
+            core.tlb.flush_all();
+            return Ok(());
+        }
+        0x341 | 0x141 => {
+            // mepc, sepc; with IALIGN=16 only bit 0 is always zero
+            core.csr_file[addr as usize] = data & !0b1;
+            return Ok(());
+        }
         _ => {
             for laddr in LEGAL_ADRESSES {
                 if laddr == addr {
@@ -325,10 +438,10 @@ pub fn write_64(csr: Csr64, data: u64, core: &mut Core) {
     };
 }
 
-pub fn conuters_mirror(hart: &mut Hart) {
+pub fn conuters_mirror(hart: &mut Hart, clint: &Clint) {
     // timers
-    let time = hart.clint.mtime;
-    let timeh = hart.clint.mtimeh;
+    let time = clint.mtime;
+    let timeh = clint.mtimeh;
     let core = &mut hart.core;
 
     let mcycle = core.csr_file[csr_addr(Csr::mcycle)];
@@ -344,9 +457,6 @@ pub fn conuters_mirror(hart: &mut Hart) {
 
     //hmpcounters?
 
-    //mcounteren
-    core.csr_file[csr_addr(Csr::mcounteren)] = 0;
-    core.csr_file[csr_addr(Csr::scounteren)] = 0;
     core.csr_file[csr_addr(Csr::mcountinhibit)] = 0;
     core.csr_file[csr_addr(Csr::scountinhibit)] = 0;
 }
@@ -382,6 +492,10 @@ pub enum Csr64 {
 #[derive(Debug)]
 #[allow(non_camel_case_types, dead_code)]
 pub enum Csr {
+    fflags,
+    frm,
+    fcsr,
+
     mvendorid,
     marchid,
     mimpid,
@@ -461,6 +575,9 @@ pub enum Csr {
 
 pub fn csr_name(addr: u32) -> String {
     match addr {
+        0x001 => "fflags".to_string(),
+        0x002 => "frm".to_string(),
+        0x003 => "fcsr".to_string(),
         0xf11 => "mvendorid".to_string(),
         0xf12 => "marchid".to_string(),
         0xf13 => "mimpid".to_string(),
@@ -529,6 +646,10 @@ pub fn csr_name(addr: u32) -> String {
 
 pub fn csr_addr(csrname: Csr) -> usize {
     match csrname {
+        Csr::fflags => 0x001,
+        Csr::frm => 0x002,
+        Csr::fcsr => 0x003,
+
         Csr::mvendorid => 0xf11,
         Csr::marchid => 0xf12,
         Csr::mimpid => 0xf13,
@@ -1,6 +1,6 @@
 use crate::core::{Core, Hart};
-use crate::core::csr::csr_name;
-use crate::core::instr_parse::{BType, IType, JType, RType, SType, UType};
+use crate::core::fpu;
+use crate::core::instr_parse::{BType, IType, JType, R4Type, RType, SType, UType};
 use crate::core::virt_memory;
 use crate::memory::MemoryBus;
 
@@ -42,12 +42,10 @@ pub fn exec_r(hart: &mut Hart, bus: &mut MemoryBus, instr: &RType) -> Result<Sta
                     }
                     //div
                     0x01 => {
-                        if instr.rs2 == 0 {
-                            hart.core.reg_file[instr.rd as usize] = 0xffffffffu32 as i32;
-                        } else {
-                            hart.core.reg_file[instr.rd as usize] = hart.core.reg_file[instr.rs1 as usize]
-                                / hart.core.reg_file[instr.rs2 as usize];
-                        }
+                        let a = hart.core.reg_file[instr.rs1 as usize];
+                        let b = hart.core.reg_file[instr.rs2 as usize];
+                        // division by zero gives -1, i32::MIN / -1 overflows to i32::MIN
+                        hart.core.reg_file[instr.rd as usize] = if b == 0 { -1 } else { a.wrapping_div(b) };
                     }
                     _ => return Err(Exception::Illegal_instruction),
                 },
@@ -59,8 +57,10 @@ pub fn exec_r(hart: &mut Hart, bus: &mut MemoryBus, instr: &RType) -> Result<Sta
                     }
                     //rem
                     0x01 => {
-                        hart.core.reg_file[instr.rd as usize] =
-                            hart.core.reg_file[instr.rs1 as usize] % hart.core.reg_file[instr.rs2 as usize];
+                        let a = hart.core.reg_file[instr.rs1 as usize];
+                        let b = hart.core.reg_file[instr.rs2 as usize];
+                        // remainder of a division by zero is the dividend, i32::MIN % -1 is 0
+                        hart.core.reg_file[instr.rd as usize] = if b == 0 { a } else { a.wrapping_rem(b) };
                     }
                     _ => return Err(Exception::Illegal_instruction),
                 },
@@ -72,10 +72,9 @@ pub fn exec_r(hart: &mut Hart, bus: &mut MemoryBus, instr: &RType) -> Result<Sta
                     }
                     //remu
                     0x01 => {
-                        hart.core.reg_file[instr.rd as usize] = (hart.core.reg_file[instr.rs1 as usize]
-                            as u32
-                            % hart.core.reg_file[instr.rs2 as usize] as u32)
-                            as i32;
+                        let a = hart.core.reg_file[instr.rs1 as usize] as u32;
+                        let b = hart.core.reg_file[instr.rs2 as usize] as u32;
+                        hart.core.reg_file[instr.rd as usize] = a.checked_rem(b).unwrap_or(a) as i32;
                     }
                     _ => return Err(Exception::Illegal_instruction),
                 },
@@ -106,15 +105,15 @@ pub fn exec_r(hart: &mut Hart, bus: &mut MemoryBus, instr: &RType) -> Result<Sta
                     }
                     //divu
                     0x01 => {
-                        hart.core.reg_file[instr.rd as usize] = (hart.core.reg_file[instr.rs1 as usize]
-                            as u32
-                            / hart.core.reg_file[instr.rs2 as usize] as u32)
-                            as i32;
+                        let a = hart.core.reg_file[instr.rs1 as usize] as u32;
+                        let b = hart.core.reg_file[instr.rs2 as usize] as u32;
+                        // division by zero gives all ones
+                        hart.core.reg_file[instr.rd as usize] = a.checked_div(b).unwrap_or(u32::MAX) as i32;
                     }
                     //sra
                     0x20 => {
                         hart.core.reg_file[instr.rd as usize] =
-                            hart.core.reg_file[instr.rs1 as usize] >> hart.core.reg_file[instr.rs2 as usize];
+                            hart.core.reg_file[instr.rs1 as usize] >> (hart.core.reg_file[instr.rs2 as usize] & 31);
                     }
                     _ => return Err(Exception::Illegal_instruction),
                 },
@@ -162,13 +161,8 @@ pub fn exec_r(hart: &mut Hart, bus: &mut MemoryBus, instr: &RType) -> Result<Sta
 
                 _ => return Err(Exception::Illegal_instruction),
             };
-            if hart.core.p_start {
-                hart.core.instr_str = format!(
-                    "{} x{} 0x{:08x}",
-                    hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                );
-            }
-            hart.core.pc += 4;
+            hart.core.log_reg(instr.rd);
+            hart.core.pc += hart.core.instr_len;
         }
         0b0101111 => {
             let mut write = true;
@@ -176,111 +170,312 @@ pub fn exec_r(hart: &mut Hart, bus: &mut MemoryBus, instr: &RType) -> Result<Sta
             let addr = hart.core.reg_file[instr.rs1 as usize] as u32;
             let rs2 = hart.core.reg_file[instr.rs2 as usize];
             let rd;
-            match virt_memory::virt_read_word(addr, hart, bus) {
-                Ok(x) => rd = x as i32,
-                Err(x) => {
-                    return Err(x);
-                }
-            };
-            hart.core.reg_file[instr.rd as usize] = rd;
             match instr.funct5 {
                 // LR.W
                 0b00010 => {
-                    hart.core.reg_file[instr.rd as usize] = rd;
-                    hart.core.lr_address = addr;
-                    hart.core.lr_set = rd;
+                    rd = virt_memory::virt_load_reserved(addr, hart, bus)? as i32;
+                    hart.core.log_load(addr);
                     write = false;
                     write_val = 0;
                 }
                 // SC.W
+                // stores only if the reservation is still valid, rd = 0 on success
                 0b00011 => {
-                    if (hart.core.lr_set == rd) && (hart.core.lr_address == addr) {
-                        write_val = rs2;
-                        hart.core.reg_file[instr.rd as usize] = 0;
-                    } else {
-                        hart.core.reg_file[instr.rd as usize] = 1;
-                        write = false;
-                        write_val = 0;
-                    }
-                    hart.core.lr_address = 0x0;
-                    // if hart.core.p_start {
-                    //     hart.core.instr_str = format!(
-                    //         "{} x{} 0x{:08x} mem 0x{:08x} 0x{:08x}",
-                    //         hart.core.instr_str,
-                    //         instr.rd,
-                    //         hart.core.reg_file[instr.rd as usize],
-                    //         addr,
-                    //         write_val
-                    //     )
-                    // }
-                }
-                // amoswap.w
-                0b00001 => {
+                    write = virt_memory::virt_store_conditional(addr, rs2 as u32, hart, bus)?;
+                    rd = !write as i32;
                     write_val = rs2;
                 }
-                // amoadd.w
-                0b00000 => {
-                    write_val = rd + rs2;
-                }
-                // amoxor.w
-                0b00100 => {
-                    write_val = rd ^ rs2;
-                }
-                // amoand.w
-                0b01100 => {
-                    write_val = rd & rs2;
-                }
-                // amoor.w
-                0b01000 => {
-                    write_val = rd | rs2;
-                }
-                //amomin.w
-                0b10000 => {
-                    write_val = rd.min(rs2);
-                }
-                // amomax.w
-                0b10100 => {
-                    write_val = rd.max(rs2);
-                }
-                // amominu.w
-                0b11000 => {
-                    write_val = (rd as u32).min(rs2 as u32) as i32;
-                }
-                // amomaxiu.w
-                0b11100 => {
-                    write_val = (rd as u32).max(rs2 as u32) as i32;
+                _ => {
+                    // AMOs raise store faults
+                    if addr & 0b11 != 0 {
+                        hart.core.trap_val = addr;
+                        return Err(Exception::StoreAMO_address_misaligned);
+                    }
+                    rd = virt_memory::virt_read_word(addr, hart, bus).map_err(|err| match err {
+                        Exception::Load_access_fault => Exception::StoreAMO_access_fault,
+                        Exception::Load_page_fault => Exception::StoreAMO_page_fault,
+                        err => err,
+                    })? as i32;
+                    hart.core.log_load(addr);
+                    write_val = match instr.funct5 {
+                        // amoswap.w
+                        0b00001 => rs2,
+                        // amoadd.w
+                        0b00000 => rd.wrapping_add(rs2),
+                        // amoxor.w
+                        0b00100 => rd ^ rs2,
+                        // amoand.w
+                        0b01100 => rd & rs2,
+                        // amoor.w
+                        0b01000 => rd | rs2,
+                        //amomin.w
+                        0b10000 => rd.min(rs2),
+                        // amomax.w
+                        0b10100 => rd.max(rs2),
+                        // amominu.w
+                        0b11000 => (rd as u32).min(rs2 as u32) as i32,
+                        // amomaxiu.w
+                        0b11100 => (rd as u32).max(rs2 as u32) as i32,
+                        _ => return Err(Exception::Illegal_instruction),
+                    };
+                    virt_memory::virt_write_word(addr, write_val as u32, hart, bus)?;
                 }
-                _ => return Err(Exception::Illegal_instruction),
             }
+            hart.core.reg_file[instr.rd as usize] = rd;
+            hart.core.log_reg(instr.rd);
             if write {
-                virt_memory::virt_write_word(addr, write_val as u32, hart, bus)?;
+                hart.core.log_store(addr, write_val as u32 as u64, 4);
             }
-            if hart.core.p_start {
-                if instr.rd != 0 {
-                    if instr.funct5 != 0b11 {
-                        hart.core.instr_str = format!(
-                            "{} x{} 0x{:08x} mem 0x{:08x} ",
-                            hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize], addr,
-                        );
-                    } else {
-                        hart.core.instr_str = format!(
-                            "{} x{} 0x{:08x} ",
-                            hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize],
-                        );
-                    }
-                } else if instr.funct5 != 0b00011 {
-                    hart.core.instr_str = format!("{} mem 0x{:08x}", hart.core.instr_str, addr,);
+            hart.core.pc += hart.core.instr_len;
+        }
+        0b1010011 => return exec_fp(&mut hart.core, instr),
+        _ => return Err(Exception::Illegal_instruction),
+    };
+
+    Ok(State::Ok)
+}
+
+// single precision values are NaN-boxed in the 64-bit f registers
+fn read_freg_s(core: &Core, reg: u32) -> u64 {
+    let val = core.freg_file[reg as usize];
+    if val >> 32 == 0xffffffff {
+        val & 0xffffffff
+    } else {
+        fpu::F32.canonical_nan()
+    }
+}
+
+fn write_freg_s(core: &mut Core, reg: u32, val: u64) {
+    core.freg_file[reg as usize] = 0xffffffff00000000 | (val & 0xffffffff);
+    core.log_freg(reg);
+    csr::set_fp_dirty(core);
+}
+
+fn write_freg_d(core: &mut Core, reg: u32, val: u64) {
+    core.freg_file[reg as usize] = val;
+    core.log_freg(reg);
+    csr::set_fp_dirty(core);
+}
+
+// static rounding mode or the dynamic one from frm
+fn rounding_mode(core: &Core, rm: u32) -> Result<fpu::Rounding, Exception> {
+    let rm = if rm == 0b111 {
+        csr::read(csr::Csr::frm, core)
+    } else {
+        rm
+    };
+    fpu::Rounding::from(rm).ok_or(Exception::Illegal_instruction)
+}
+
+// fmt field of fp instructions: 00 single, 01 double
+fn fp_format(fmt: u32) -> Result<(fpu::Format, bool), Exception> {
+    match fmt {
+        0b00 => Ok((fpu::F32, false)),
+        0b01 => Ok((fpu::F64, true)),
+        _ => Err(Exception::Illegal_instruction),
+    }
+}
+
+fn exec_fp(core: &mut Core, instr: &RType) -> Result<State, Exception> {
+    if !csr::fp_enabled(core) {
+        return Err(Exception::Illegal_instruction);
+    }
+    let (fmt, double) = fp_format(instr.funct7 & 0b11)?;
+    let (rs1, rs2) = if double {
+        (
+            core.freg_file[instr.rs1 as usize],
+            core.freg_file[instr.rs2 as usize],
+        )
+    } else {
+        (read_freg_s(core, instr.rs1), read_freg_s(core, instr.rs2))
+    };
+    let sign_bit: u64 = if double { 1 << 63 } else { 1 << 31 };
+
+    let mut fp_result = None;
+    let mut int_result = None;
+    let flags;
+    match instr.funct7 >> 2 {
+        //fadd
+        0b00000 => {
+            let (res, f) = fpu::add(rs1, rs2, fmt, rounding_mode(core, instr.funct3)?);
+            fp_result = Some(res);
+            flags = f;
+        }
+        //fsub
+        0b00001 => {
+            let (res, f) = fpu::sub(rs1, rs2, fmt, rounding_mode(core, instr.funct3)?);
+            fp_result = Some(res);
+            flags = f;
+        }
+        //fmul
+        0b00010 => {
+            let (res, f) = fpu::mul(rs1, rs2, fmt, rounding_mode(core, instr.funct3)?);
+            fp_result = Some(res);
+            flags = f;
+        }
+        //fdiv
+        0b00011 => {
+            let (res, f) = fpu::div(rs1, rs2, fmt, rounding_mode(core, instr.funct3)?);
+            fp_result = Some(res);
+            flags = f;
+        }
+        //fsqrt
+        0b01011 => {
+            if instr.rs2 != 0 {
+                return Err(Exception::Illegal_instruction);
+            }
+            let (res, f) = fpu::sqrt(rs1, fmt, rounding_mode(core, instr.funct3)?);
+            fp_result = Some(res);
+            flags = f;
+        }
+        0b00100 => {
+            let sign = match instr.funct3 {
+                //fsgnj
+                0b000 => rs2 & sign_bit,
+                //fsgnjn
+                0b001 => !rs2 & sign_bit,
+                //fsgnjx
+                0b010 => (rs1 ^ rs2) & sign_bit,
+                _ => return Err(Exception::Illegal_instruction),
+            };
+            fp_result = Some((rs1 & !sign_bit) | sign);
+            flags = 0;
+        }
+        //fmin, fmax
+        0b00101 => {
+            if instr.funct3 > 1 {
+                return Err(Exception::Illegal_instruction);
+            }
+            let (res, f) = fpu::min_max(rs1, rs2, fmt, instr.funct3 == 1);
+            fp_result = Some(res);
+            flags = f;
+        }
+        //fcvt.s.d, fcvt.d.s
+        0b01000 => {
+            let rm = rounding_mode(core, instr.funct3)?;
+            let (res, f) = match (double, instr.rs2) {
+                (false, 1) => fpu::convert(core.freg_file[instr.rs1 as usize], fpu::F64, fpu::F32, rm),
+                (true, 0) => fpu::convert(read_freg_s(core, instr.rs1), fpu::F32, fpu::F64, rm),
+                _ => return Err(Exception::Illegal_instruction),
+            };
+            fp_result = Some(res);
+            flags = f;
+        }
+        //fcvt.w, fcvt.wu
+        0b11000 => {
+            if instr.rs2 > 1 {
+                return Err(Exception::Illegal_instruction);
+            }
+            let rm = rounding_mode(core, instr.funct3)?;
+            let (res, f) = fpu::to_int(rs1, fmt, rm, instr.rs2 == 0);
+            int_result = Some(res);
+            flags = f;
+        }
+        //fcvt.s.w, fcvt.s.wu
+        0b11010 => {
+            if instr.rs2 > 1 {
+                return Err(Exception::Illegal_instruction);
+            }
+            let rm = rounding_mode(core, instr.funct3)?;
+            let source = core.reg_file[instr.rs1 as usize] as u32;
+            let (res, f) = fpu::from_int(source, instr.rs2 == 0, fmt, rm);
+            fp_result = Some(res);
+            flags = f;
+        }
+        0b11100 => {
+            if instr.rs2 != 0 {
+                return Err(Exception::Illegal_instruction);
+            }
+            match (instr.funct3, double) {
+                //fmv.x.w, moves the raw bits
+                (0b000, false) => {
+                    int_result = Some(core.freg_file[instr.rs1 as usize] as u32);
                 }
-                if write {
-                    hart.core.instr_str =
-                        format!("{} mem 0x{:08x} 0x{:08x}", hart.core.instr_str, addr, write_val);
+                //fclass
+                (0b001, _) => {
+                    int_result = Some(fpu::classify(rs1, fmt));
                 }
+                _ => return Err(Exception::Illegal_instruction),
             }
-            hart.core.pc += 4;
+            flags = 0;
+        }
+        0b10100 => {
+            let (res, f) = match instr.funct3 {
+                //feq
+                0b010 => fpu::eq(rs1, rs2, fmt),
+                //flt
+                0b001 => fpu::lt(rs1, rs2, fmt),
+                //fle
+                0b000 => fpu::le(rs1, rs2, fmt),
+                _ => return Err(Exception::Illegal_instruction),
+            };
+            int_result = Some(res as u32);
+            flags = f;
+        }
+        //fmv.w.x
+        0b11110 => {
+            if double || instr.rs2 != 0 || instr.funct3 != 0 {
+                return Err(Exception::Illegal_instruction);
+            }
+            fp_result = Some(core.reg_file[instr.rs1 as usize] as u32 as u64);
+            flags = 0;
         }
         _ => return Err(Exception::Illegal_instruction),
-    };
+    }
+
+    csr::set_fflags(flags, core);
+    if let Some(res) = fp_result {
+        if double {
+            write_freg_d(core, instr.rd, res);
+        } else {
+            write_freg_s(core, instr.rd, res);
+        }
+    }
+    if let Some(res) = int_result {
+        core.reg_file[instr.rd as usize] = res as i32;
+        core.log_reg(instr.rd);
+    }
+    core.pc += core.instr_len;
+    Ok(State::Ok)
+}
 
+pub fn exec_r4(core: &mut Core, instr: &R4Type) -> Result<State, Exception> {
+    if !csr::fp_enabled(core) {
+        return Err(Exception::Illegal_instruction);
+    }
+    let (fmt, double) = fp_format(instr.funct2)?;
+    let rm = rounding_mode(core, instr.rm)?;
+    let (rs1, rs2, rs3) = if double {
+        (
+            core.freg_file[instr.rs1 as usize],
+            core.freg_file[instr.rs2 as usize],
+            core.freg_file[instr.rs3 as usize],
+        )
+    } else {
+        (
+            read_freg_s(core, instr.rs1),
+            read_freg_s(core, instr.rs2),
+            read_freg_s(core, instr.rs3),
+        )
+    };
+    let (neg_product, neg_addend) = match instr.opcode {
+        //fmadd
+        0b1000011 => (false, false),
+        //fmsub
+        0b1000111 => (false, true),
+        //fnmsub
+        0b1001011 => (true, false),
+        //fnmadd
+        _ => (true, true),
+    };
+    let (res, flags) = fpu::fma(rs1, rs2, rs3, neg_product, neg_addend, fmt, rm);
+    csr::set_fflags(flags, core);
+    if double {
+        write_freg_d(core, instr.rd, res);
+    } else {
+        write_freg_s(core, instr.rd, res);
+    }
+    core.pc += core.instr_len;
     Ok(State::Ok)
 }
 
@@ -311,6 +506,9 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                 }
                 //slli
                 0x1 => {
+                    if instr.funct7 != 0 {
+                        return Err(Exception::Illegal_instruction);
+                    }
                     hart.core.reg_file[instr.rd as usize] =
                         hart.core.reg_file[instr.rs1 as usize] << (instr.imm & 0b11111);
                 }
@@ -348,16 +546,11 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                 }
                 _ => return Err(Exception::Illegal_instruction),
             };
-            if hart.core.p_start && instr.rd != 0 {
-                hart.core.instr_str = format!(
-                    "{} x{} 0x{:08x}",
-                    hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                );
-            }
-            hart.core.pc += 4;
+            hart.core.log_reg(instr.rd);
+            hart.core.pc += hart.core.instr_len;
         }
         0b0000011 => {
-            let addr = (hart.core.reg_file[instr.rs1 as usize] + instr.imm) as u32;
+            let addr = hart.core.reg_file[instr.rs1 as usize].wrapping_add(instr.imm) as u32;
             match instr.funct3 {
                 // lb sign-extended
                 0x0 => {
@@ -396,26 +589,40 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                 }
                 _ => return Err(Exception::Illegal_instruction),
             };
-            if hart.core.p_start {
-                hart.core.instr_str = format!(
-                    "{} x{} 0x{:08x} mem 0x{:08x}",
-                    hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize], addr
-                );
+            hart.core.log_reg(instr.rd);
+            hart.core.log_load(addr);
+            hart.core.pc += hart.core.instr_len;
+        }
+        // flw, fld
+        0b0000111 => {
+            if !csr::fp_enabled(&hart.core) {
+                return Err(Exception::Illegal_instruction);
             }
-            hart.core.pc += 4;
+            let addr = hart.core.reg_file[instr.rs1 as usize].wrapping_add(instr.imm) as u32;
+            match instr.funct3 {
+                // flw
+                0x2 => {
+                    let val = virt_memory::virt_read_word(addr, hart, bus)?;
+                    write_freg_s(&mut hart.core, instr.rd, val as u64);
+                }
+                // fld
+                0x3 => {
+                    let val = virt_memory::virt_read_dword(addr, hart, bus)?;
+                    write_freg_d(&mut hart.core, instr.rd, val);
+                }
+                _ => return Err(Exception::Illegal_instruction),
+            };
+            hart.core.log_load(addr);
+            hart.core.pc += hart.core.instr_len;
         }
         //jalr
         0b1100111 => {
             let tmp_pc = hart.core.pc;
-            hart.core.pc =
-                (i64::from(hart.core.reg_file[instr.rs1 as usize] as u32) + i64::from(instr.imm)) as u32;
-            hart.core.reg_file[instr.rd as usize] = (tmp_pc + 4) as i32;
-            if hart.core.p_start && instr.rd != 0 {
-                hart.core.instr_str = format!(
-                    "{} x{} 0x{:08x}",
-                    hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                );
-            }
+            hart.core.pc = (i64::from(hart.core.reg_file[instr.rs1 as usize] as u32)
+                + i64::from(instr.imm)) as u32
+                & !0b1;
+            hart.core.reg_file[instr.rd as usize] = (tmp_pc + hart.core.instr_len) as i32;
+            hart.core.log_reg(instr.rd);
         }
         0b1110011 => {
             let csr_addr = (instr.imm & 0xfff) as u32;
@@ -429,30 +636,9 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                         hart.core.reg_file[instr.rd as usize] = csr as i32;
                     }
                     csr::write_addr(csr_addr, source, &mut hart.core)?;
-                    if hart.core.p_start && instr.rd != 0 {
-                        hart.core.instr_str = format!(
-                            "{} x{} 0x{:08x}",
-                            hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                        )
-                    }
-                    if hart.core.p_start {
-                        if csr_addr == 0x100 {
-                            hart.core.instr_str = format!(
-                                "{} c768_mstatus 0x{:08x}",
-                                hart.core.instr_str,
-                                csr::read(csr::Csr::mstatus, &hart.core)
-                            )
-                        } else {
-                            hart.core.instr_str = format!(
-                                "{} c{}_{} 0x{:08x}",
-                                hart.core.instr_str,
-                                csr_addr,
-                                csr_name(csr_addr),
-                                source
-                            )
-                        }
-                    }
-                    hart.core.pc += 4;
+                    hart.core.log_reg(instr.rd);
+                    hart.core.log_csr(csr_addr);
+                    hart.core.pc += hart.core.instr_len;
                 }
                 // csrrs
                 0b010 => {
@@ -464,33 +650,12 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                         }
                     };
                     hart.core.reg_file[instr.rd as usize] = csr as i32;
-                    if hart.core.p_start && instr.rd != 0 {
-                        hart.core.instr_str = format!(
-                            "{} x{} 0x{:08x}",
-                            hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                        )
-                    }
+                    hart.core.log_reg(instr.rd);
                     if instr.rs1 != 0 {
                         csr::write_addr(csr_addr, csr | source, &mut hart.core)?;
-                        if hart.core.p_start {
-                            if csr_addr == 0x100 {
-                                hart.core.instr_str = format!(
-                                    "{} c768_mstatus 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr::read(csr::Csr::mstatus, &hart.core)
-                                )
-                            } else {
-                                hart.core.instr_str = format!(
-                                    "{} c{}_{} 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr_addr,
-                                    csr_name(csr_addr),
-                                    csr | source
-                                )
-                            }
-                        }
+                        hart.core.log_csr(csr_addr);
                     }
-                    hart.core.pc += 4;
+                    hart.core.pc += hart.core.instr_len;
                 }
                 // csrrc
                 0b011 => {
@@ -502,33 +667,12 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                         }
                     };
                     hart.core.reg_file[instr.rd as usize] = csr as i32;
-                    if hart.core.p_start && instr.rd != 0 {
-                        hart.core.instr_str = format!(
-                            "{} x{} 0x{:08x}",
-                            hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                        )
-                    }
+                    hart.core.log_reg(instr.rd);
                     if instr.rs1 != 0 {
                         csr::write_addr(csr_addr, csr & !source, &mut hart.core)?;
-                        if hart.core.p_start {
-                            if csr_addr == 0x100 {
-                                hart.core.instr_str = format!(
-                                    "{} c768_mstatus 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr::read(csr::Csr::mstatus, &hart.core)
-                                )
-                            } else {
-                                hart.core.instr_str = format!(
-                                    "{} c{}_{} 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr_addr,
-                                    csr_name(csr_addr),
-                                    csr & !source
-                                )
-                            }
-                        }
+                        hart.core.log_csr(csr_addr);
                     }
-                    hart.core.pc += 4;
+                    hart.core.pc += hart.core.instr_len;
                 }
                 // csrrwi
                 0b101 => {
@@ -537,31 +681,10 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                         csr = csr::read_addr(csr_addr, &hart.core)?;
                     }
                     hart.core.reg_file[instr.rd as usize] = csr as i32;
-                    if hart.core.p_start && instr.rd != 0 {
-                        hart.core.instr_str = format!(
-                            "{} x{} 0x{:08x}",
-                            hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                        )
-                    }
+                    hart.core.log_reg(instr.rd);
                     csr::write_addr(csr_addr, instr.rs1, &mut hart.core)?;
-                    if hart.core.p_start {
-                        if csr_addr == 0x100 {
-                            hart.core.instr_str = format!(
-                                "{} c768_mstatus 0x{:08x}",
-                                hart.core.instr_str,
-                                csr::read(csr::Csr::mstatus, &hart.core)
-                            )
-                        } else {
-                            hart.core.instr_str = format!(
-                                "{} c{}_{} 0x{:08x}",
-                                hart.core.instr_str,
-                                csr_addr,
-                                csr_name(csr_addr),
-                                instr.rs1
-                            )
-                        }
-                    }
-                    hart.core.pc += 4;
+                    hart.core.log_csr(csr_addr);
+                    hart.core.pc += hart.core.instr_len;
                 }
                 // csrrsi
                 0b110 => {
@@ -573,33 +696,12 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                         }
                     };
                     hart.core.reg_file[instr.rd as usize] = csr as i32;
-                    if hart.core.p_start && instr.rd != 0 {
-                        hart.core.instr_str = format!(
-                            "{} x{} 0x{:08x}",
-                            hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                        )
-                    }
+                    hart.core.log_reg(instr.rd);
                     if instr.rs1 != 0 {
                         csr::write_addr(csr_addr, csr | instr.rs1, &mut hart.core)?;
-                        if hart.core.p_start {
-                            if csr_addr == 0x100 {
-                                hart.core.instr_str = format!(
-                                    "{} c768_mstatus 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr::read(csr::Csr::mstatus, &hart.core)
-                                )
-                            } else {
-                                hart.core.instr_str = format!(
-                                    "{} c{}_{} 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr_addr,
-                                    csr_name(csr_addr),
-                                    csr | instr.rs1
-                                )
-                            }
-                        }
+                        hart.core.log_csr(csr_addr);
                     }
-                    hart.core.pc += 4;
+                    hart.core.pc += hart.core.instr_len;
                 }
                 // csrrci
                 0b111 => {
@@ -611,38 +713,31 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                         }
                     };
                     hart.core.reg_file[instr.rd as usize] = csr as i32;
-                    if hart.core.p_start && instr.rd != 0 {
-                        hart.core.instr_str = format!(
-                            "{} x{} 0x{:08x}",
-                            hart.core.instr_str, instr.rd, hart.core.reg_file[instr.rd as usize]
-                        )
-                    }
+                    hart.core.log_reg(instr.rd);
                     if instr.rs1 != 0 {
                         csr::write_addr(csr_addr, csr & !instr.rs1, &mut hart.core)?;
-                        if hart.core.p_start {
-                            if csr_addr == 0x100 {
-                                hart.core.instr_str = format!(
-                                    "{} c768_mstatus 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr::read(csr::Csr::mstatus, &hart.core)
-                                )
-                            } else {
-                                hart.core.instr_str = format!(
-                                    "{} c{}_{} 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr_addr,
-                                    csr_name(csr_addr),
-                                    csr & !instr.rs1
-                                )
-                            }
-                        }
+                        hart.core.log_csr(csr_addr);
                     }
-                    hart.core.pc += 4;
+                    hart.core.pc += hart.core.instr_len;
                 }
                 0b0 => {
-                    //sfence
+                    //sfence.vma
                     if instr.funct7 == 0b0001001 {
-                        hart.core.pc += 4;
+                        let tvm = csr::read(csr::Csr::mstatus, &hart.core) & (1 << 20) != 0;
+                        if hart.core.mode == 0 || (hart.core.mode == 1 && tvm) {
+                            return Err(Exception::Illegal_instruction);
+                        }
+                        let rs2 = (instr.imm & 0b11111) as usize;
+                        let vaddr = match instr.rs1 {
+                            0 => None,
+                            x => Some(hart.core.reg_file[x as usize] as u32),
+                        };
+                        let asid = match rs2 {
+                            0 => None,
+                            x => Some(hart.core.reg_file[x] as u32 & 0x1ff),
+                        };
+                        hart.core.tlb.flush(vaddr, asid);
+                        hart.core.pc += hart.core.instr_len;
                         return Ok(State::Ok);
                     }
                     match instr.imm {
@@ -679,14 +774,8 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                             csr::write(super::Csr::mstatus, mstatus, &mut hart.core);
                             // restore pc
                             hart.core.pc = csr::read(super::Csr::mepc, &hart.core);
-                            if hart.core.p_start {
-                                hart.core.instr_str = format!(
-                                    "{} c768_mstatus 0x{:08x} c784_mstatush 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr::read(csr::Csr::mstatus, &hart.core),
-                                    csr::read(csr::Csr::mstatush, &hart.core)
-                                )
-                            }
+                            hart.core.log_csr(0x300);
+                            hart.core.log_csr(0x310);
                         }
                         // sret
                         0b000100000010 => {
@@ -705,19 +794,13 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
                             csr::write(super::Csr::mstatus, mstatus, &mut hart.core);
                             // restore pc
                             hart.core.pc = csr::read(super::Csr::sepc, &hart.core);
-                            if hart.core.p_start {
-                                hart.core.instr_str = format!(
-                                    "{} c768_mstatus 0x{:08x}",
-                                    hart.core.instr_str,
-                                    csr::read(csr::Csr::mstatus, &hart.core),
-                                )
-                            }
+                            hart.core.log_csr(0x300);
                         }
                         // wfi
                         0b000100000101 => {
                             // *hart.core.csr(super::Csr::Mstatus) |= 1 << 3;
                             hart.core.wfi = true;
-                            hart.core.pc += 4;
+                            hart.core.pc += hart.core.instr_len;
                             return Ok(State::Sleep);
                         }
                         _ => return Err(Exception::Illegal_instruction),
@@ -728,7 +811,7 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
         }
         // fence, pause
         0b0001111 => {
-            hart.core.pc += 4;
+            hart.core.pc += hart.core.instr_len;
         }
 
         _ => return Err(Exception::Illegal_instruction),
@@ -737,80 +820,85 @@ pub fn exec_i(hart: &mut Hart, bus: &mut MemoryBus, instr: &IType) -> Result<Sta
 }
 
 pub fn exec_s(hart: &mut Hart, bus: &mut MemoryBus, instr: &SType) -> Result<State, Exception> {
-    let addr = (hart.core.reg_file[instr.rs1 as usize] + instr.imm) as u32;
-    let rs2 = hart.core.reg_file[instr.rs2 as usize];
-
-    if hart.core.p_start {
-        hart.core.instr_str = match instr.funct3 {
-            //sb
-            0x0 => format!("{} mem 0x{:08x} 0x{:02x}", hart.core.instr_str, addr, rs2 as u8),
-            //sh
-            0x1 => format!("{} mem 0x{:08x} 0x{:04x}", hart.core.instr_str, addr, rs2 as u16),
-            //sw
-            0x2 => format!("{} mem 0x{:08x} 0x{:08x}", hart.core.instr_str, addr, rs2),
-            _ => format!("{}", hart.core.instr_str),
-        };
+    if instr.opcode == 0b0100111 {
+        return exec_fp_store(hart, bus, instr);
     }
+    let addr = hart.core.reg_file[instr.rs1 as usize].wrapping_add(instr.imm) as u32;
+    let rs2 = hart.core.reg_file[instr.rs2 as usize];
 
-    match instr.funct3 {
+    let bytes = match instr.funct3 {
         //sb
-        0x0 => virt_memory::virt_write_byte(addr, rs2 as u8, hart, bus)?,
+        0x0 => {
+            virt_memory::virt_write_byte(addr, rs2 as u8, hart, bus)?;
+            1
+        }
         //sh
-        0x1 => virt_memory::virt_write_hword(addr, rs2 as u16, hart, bus)?,
+        0x1 => {
+            virt_memory::virt_write_hword(addr, rs2 as u16, hart, bus)?;
+            2
+        }
         //sw
-        0x2 => virt_memory::virt_write_word(addr, rs2 as u32, hart, bus)?,
+        0x2 => {
+            virt_memory::virt_write_word(addr, rs2 as u32, hart, bus)?;
+            4
+        }
         _ => return Err(Exception::Illegal_instruction),
     };
+    let val = rs2 as u32 as u64 & ((1 << (8 * bytes)) - 1);
+    hart.core.log_store(addr, val, bytes);
 
-    hart.core.pc += 4;
+    hart.core.pc += hart.core.instr_len;
+    Ok(State::Ok)
+}
+
+// fsw, fsd
+fn exec_fp_store(hart: &mut Hart, bus: &mut MemoryBus, instr: &SType) -> Result<State, Exception> {
+    if !csr::fp_enabled(&hart.core) {
+        return Err(Exception::Illegal_instruction);
+    }
+    let addr = hart.core.reg_file[instr.rs1 as usize].wrapping_add(instr.imm) as u32;
+    let rs2 = hart.core.freg_file[instr.rs2 as usize];
+    match instr.funct3 {
+        //fsw
+        0x2 => {
+            virt_memory::virt_write_word(addr, rs2 as u32, hart, bus)?;
+            hart.core.log_store(addr, rs2 & 0xffffffff, 4);
+        }
+        //fsd
+        0x3 => {
+            virt_memory::virt_write_dword(addr, rs2, hart, bus)?;
+            hart.core.log_store(addr, rs2, 8);
+        }
+        _ => return Err(Exception::Illegal_instruction),
+    };
+
+    hart.core.pc += hart.core.instr_len;
     Ok(State::Ok)
 }
 
 pub fn exec_b(core: &mut Core, instr: &BType) -> Result<State, Exception> {
     let rs1 = core.reg_file[instr.rs1 as usize];
     let rs2 = core.reg_file[instr.rs2 as usize];
-    let last_pc = core.pc;
-    match instr.funct3 {
+    let taken = match instr.funct3 {
         //beq
-        0x0 => {
-            if rs1 == rs2 {
-                core.pc = (core.pc as i32 + instr.imm) as u32;
-            };
-        }
+        0x0 => rs1 == rs2,
         //bne
-        0x1 => {
-            if rs1 != rs2 {
-                core.pc = (core.pc as i32 + instr.imm) as u32;
-            };
-        }
+        0x1 => rs1 != rs2,
         //blt
-        0x4 => {
-            if rs1 < rs2 {
-                core.pc = (core.pc as i32 + instr.imm) as u32;
-            };
-        }
+        0x4 => rs1 < rs2,
         //bge
-        0x5 => {
-            if rs1 >= rs2 {
-                core.pc = (core.pc as i32 + instr.imm) as u32;
-            };
-        }
+        0x5 => rs1 >= rs2,
         //bltu
-        0x6 => {
-            if (rs1 as u32) < (rs2 as u32) {
-                core.pc = (core.pc as i32 + instr.imm) as u32;
-            };
-        }
+        0x6 => (rs1 as u32) < (rs2 as u32),
         //bgeu
-        0x7 => {
-            if rs1 as u32 >= rs2 as u32 {
-                core.pc = (core.pc as i32 + instr.imm) as u32;
-            };
-        }
+        0x7 => rs1 as u32 >= rs2 as u32,
         _ => return Err(Exception::Illegal_instruction),
     };
-    if core.pc == last_pc {
-        core.pc += 4;
+    // a taken branch can target itself
+    if taken {
+        core.pc = core.pc.wrapping_add(instr.imm as u32);
+    } else {
+        core.pc += core.instr_len;
     }
     Ok(State::Ok)
 }
@@ -828,13 +916,8 @@ pub fn exec_u(core: &mut Core, instr: &UType) -> Result<State, Exception> {
         }
         _ => return Err(Exception::Illegal_instruction),
     };
-    if core.p_start {
-        core.instr_str = format!(
-            "{} x{} 0x{:08x}",
-            core.instr_str, instr.rd, core.reg_file[instr.rd as usize]
-        );
-    }
-    core.pc += 4;
+    core.log_reg(instr.rd);
+    core.pc += core.instr_len;
     Ok(State::Ok)
 }
 
@@ -842,18 +925,11 @@ pub fn exec_j(core: &mut Core, instr: &JType) -> Result<State, Exception> {
     match instr.opcode {
         //jal
         0b1101111 => {
-            core.reg_file[instr.rd as usize] = (core.pc + 4) as i32;
-            core.pc = (core.pc as i32 + instr.imm) as u32;
+            core.reg_file[instr.rd as usize] = (core.pc + core.instr_len) as i32;
+            core.pc = core.pc.wrapping_add(instr.imm as u32);
         }
         _ => return Err(Exception::Illegal_instruction),
     };
-    if core.p_start {
-        if instr.rd != 0 {
-            core.instr_str = format!(
-                "{} x{} 0x{:08x}",
-                core.instr_str, instr.rd, core.reg_file[instr.rd as usize]
-            );
-        }
-    }
+    core.log_reg(instr.rd);
     Ok(State::Ok)
 }
@@ -0,0 +1,623 @@
+// IEEE 754 binary32/binary64 arithmetic done in software, so that the rounding
+// modes and exception flags follow the RISC-V spec instead of the host FPU.
+// Values are passed around as raw bits, single precision in the low 32 bits.
+// Every operation returns (result, fflags).
+
+pub const FLAG_NV: u32 = 0b10000; // invalid operation
+pub const FLAG_DZ: u32 = 0b01000; // divide by zero
+pub const FLAG_OF: u32 = 0b00100; // overflow
+pub const FLAG_UF: u32 = 0b00010; // underflow
+pub const FLAG_NX: u32 = 0b00001; // inexact
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Rne, // round to nearest, ties to even
+    Rtz, // round towards zero
+    Rdn, // round down (towards -inf)
+    Rup, // round up (towards +inf)
+    Rmm, // round to nearest, ties to max magnitude
+}
+
+impl Rounding {
+    pub fn from(rm: u32) -> Option<Self> {
+        match rm {
+            0b000 => Some(Rounding::Rne),
+            0b001 => Some(Rounding::Rtz),
+            0b010 => Some(Rounding::Rdn),
+            0b011 => Some(Rounding::Rup),
+            0b100 => Some(Rounding::Rmm),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32,
+}
+
+pub const F32: Format = Format {
+    exp_bits: 8,
+    frac_bits: 23,
+};
+pub const F64: Format = Format {
+    exp_bits: 11,
+    frac_bits: 52,
+};
+
+impl Format {
+    fn bias(&self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+    fn emin(&self) -> i32 {
+        1 - self.bias()
+    }
+    fn exp_mask(&self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+    fn frac_mask(&self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+    fn sign_bit(&self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+    fn sign(&self, sign: bool) -> u64 {
+        if sign { self.sign_bit() } else { 0 }
+    }
+    pub fn canonical_nan(&self) -> u64 {
+        (self.exp_mask() << self.frac_bits) | (1 << (self.frac_bits - 1))
+    }
+    fn inf(&self, sign: bool) -> u64 {
+        self.sign(sign) | (self.exp_mask() << self.frac_bits)
+    }
+    fn zero(&self, sign: bool) -> u64 {
+        self.sign(sign)
+    }
+    fn max_finite(&self, sign: bool) -> u64 {
+        self.sign(sign) | ((self.exp_mask() - 1) << self.frac_bits) | self.frac_mask()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    NaN { signaling: bool },
+    Inf(bool),
+    Zero(bool),
+    // value = (-1)^sign * sig * 2^exp
+    Finite { sign: bool, sig: u128, exp: i32 },
+}
+
+fn unpack(bits: u64, fmt: Format) -> Value {
+    let sign = bits & fmt.sign_bit() != 0;
+    let exp = (bits >> fmt.frac_bits) & fmt.exp_mask();
+    let frac = bits & fmt.frac_mask();
+    if exp == fmt.exp_mask() {
+        if frac == 0 {
+            Value::Inf(sign)
+        } else {
+            Value::NaN {
+                signaling: frac >> (fmt.frac_bits - 1) == 0,
+            }
+        }
+    } else if exp == 0 {
+        if frac == 0 {
+            Value::Zero(sign)
+        } else {
+            // subnormal
+            Value::Finite {
+                sign,
+                sig: frac as u128,
+                exp: fmt.emin() - fmt.frac_bits as i32,
+            }
+        }
+    } else {
+        Value::Finite {
+            sign,
+            sig: (frac | (1 << fmt.frac_bits)) as u128,
+            exp: exp as i32 - fmt.bias() - fmt.frac_bits as i32,
+        }
+    }
+}
+
+fn is_nan(bits: u64, fmt: Format) -> bool {
+    matches!(unpack(bits, fmt), Value::NaN { .. })
+}
+
+fn is_snan(bits: u64, fmt: Format) -> bool {
+    matches!(unpack(bits, fmt), Value::NaN { signaling: true })
+}
+
+fn nan_flags(vals: &[u64], fmt: Format) -> u32 {
+    if vals.iter().any(|v| is_snan(*v, fmt)) {
+        FLAG_NV
+    } else {
+        0
+    }
+}
+
+// Rounds sig * 2^exp to a multiple of 2^lsb_exp.
+// Returns the rounded value in units of 2^lsb_exp and whether it was inexact.
+fn round_sig(sign: bool, sig: u128, exp: i32, lsb_exp: i32, rm: Rounding) -> (u128, bool) {
+    if lsb_exp <= exp {
+        return (sig << (exp - lsb_exp), false);
+    }
+    let shift = (lsb_exp - exp) as u32;
+    let (kept, rem, half) = if shift > 128 {
+        // whole value is below half of the last place
+        (0, 1, 2)
+    } else if shift == 128 {
+        (0, sig, 1 << 127)
+    } else {
+        (sig >> shift, sig & ((1 << shift) - 1), 1 << (shift - 1))
+    };
+    let inexact = rem != 0;
+    let up = match rm {
+        Rounding::Rne => rem > half || (rem == half && kept & 1 == 1),
+        Rounding::Rtz => false,
+        Rounding::Rdn => sign && inexact,
+        Rounding::Rup => !sign && inexact,
+        Rounding::Rmm => rem >= half,
+    };
+    (kept + up as u128, inexact)
+}
+
+// Rounds (-1)^sign * sig * 2^exp into the given format.
+fn round_pack(sign: bool, sig: u128, exp: i32, fmt: Format, rm: Rounding) -> (u64, u32) {
+    if sig == 0 {
+        return (fmt.zero(sign), 0);
+    }
+    let frac = fmt.frac_bits as i32;
+    let msb = 127 - sig.leading_zeros() as i32;
+    let e = exp + msb;
+
+    let mut lsb = e - frac;
+    let mut tiny = false;
+    if e < fmt.emin() {
+        // tininess is detected after rounding (with unbounded exponent)
+        let (r, _) = round_sig(sign, sig, exp, lsb, rm);
+        tiny = r >> (frac + 1) == 0 || e + 1 < fmt.emin();
+        lsb = fmt.emin() - frac;
+    }
+
+    let (mut r, inexact) = round_sig(sign, sig, exp, lsb, rm);
+    if r >> (frac + 1) != 0 {
+        // rounding carried into next binade
+        r >>= 1;
+        lsb += 1;
+    }
+
+    let mut flags = 0;
+    if inexact {
+        flags |= FLAG_NX;
+        if tiny {
+            flags |= FLAG_UF;
+        }
+    }
+
+    let bits = if r >> frac != 0 {
+        let e = lsb + frac;
+        if e > fmt.bias() {
+            flags |= FLAG_OF | FLAG_NX;
+            let to_inf = match rm {
+                Rounding::Rne | Rounding::Rmm => true,
+                Rounding::Rtz => false,
+                Rounding::Rdn => sign,
+                Rounding::Rup => !sign,
+            };
+            let res = if to_inf {
+                fmt.inf(sign)
+            } else {
+                fmt.max_finite(sign)
+            };
+            return (res, flags);
+        }
+        ((e + fmt.bias()) as u64) << frac | (r as u64 & fmt.frac_mask())
+    } else {
+        // subnormal
+        r as u64
+    };
+    (fmt.sign(sign) | bits, flags)
+}
+
+// moves the leading one to bit 125, leaves room for carries and alignment
+fn normalize(sig: u128, exp: i32) -> (u128, i32) {
+    let shift = sig.leading_zeros() as i32 - 2;
+    (sig << shift, exp - shift)
+}
+
+// shift right, any bit shifted out is or-ed into the lowest bit
+fn shift_right_jam(sig: u128, shift: u32) -> u128 {
+    if shift == 0 {
+        sig
+    } else if shift >= 128 {
+        (sig != 0) as u128
+    } else {
+        (sig >> shift) | ((sig & ((1 << shift) - 1)) != 0) as u128
+    }
+}
+
+// Sum of two finite nonzero values.
+fn add_finite(
+    a: (bool, u128, i32),
+    b: (bool, u128, i32),
+    fmt: Format,
+    rm: Rounding,
+) -> (u64, u32) {
+    let (sig_a, exp_a) = normalize(a.1, a.2);
+    let (sig_b, exp_b) = normalize(b.1, b.2);
+    let (big, small) = if exp_a >= exp_b {
+        ((a.0, sig_a, exp_a), (b.0, sig_b, exp_b))
+    } else {
+        ((b.0, sig_b, exp_b), (a.0, sig_a, exp_a))
+    };
+    let small_sig = shift_right_jam(small.1, (big.2 - small.2) as u32);
+    if big.0 == small.0 {
+        round_pack(big.0, big.1 + small_sig, big.2, fmt, rm)
+    } else if big.1 > small_sig {
+        round_pack(big.0, big.1 - small_sig, big.2, fmt, rm)
+    } else if big.1 < small_sig {
+        round_pack(small.0, small_sig - big.1, big.2, fmt, rm)
+    } else {
+        // exact zero is positive, except when rounding down
+        (fmt.zero(rm == Rounding::Rdn), 0)
+    }
+}
+
+fn add_values(a: Value, b: Value, fmt: Format, rm: Rounding) -> (u64, u32) {
+    match (a, b) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => (fmt.canonical_nan(), 0),
+        (Value::Inf(sa), Value::Inf(sb)) => {
+            if sa == sb {
+                (fmt.inf(sa), 0)
+            } else {
+                (fmt.canonical_nan(), FLAG_NV)
+            }
+        }
+        (Value::Inf(s), _) | (_, Value::Inf(s)) => (fmt.inf(s), 0),
+        (Value::Zero(sa), Value::Zero(sb)) => {
+            if sa == sb {
+                (fmt.zero(sa), 0)
+            } else {
+                (fmt.zero(rm == Rounding::Rdn), 0)
+            }
+        }
+        (Value::Zero(_), Value::Finite { sign, sig, exp })
+        | (Value::Finite { sign, sig, exp }, Value::Zero(_)) => round_pack(sign, sig, exp, fmt, rm),
+        (
+            Value::Finite {
+                sign: sa,
+                sig: siga,
+                exp: ea,
+            },
+            Value::Finite {
+                sign: sb,
+                sig: sigb,
+                exp: eb,
+            },
+        ) => add_finite((sa, siga, ea), (sb, sigb, eb), fmt, rm),
+    }
+}
+
+fn negate(v: Value) -> Value {
+    match v {
+        Value::NaN { signaling } => Value::NaN { signaling },
+        Value::Inf(s) => Value::Inf(!s),
+        Value::Zero(s) => Value::Zero(!s),
+        Value::Finite { sign, sig, exp } => Value::Finite {
+            sign: !sign,
+            sig,
+            exp,
+        },
+    }
+}
+
+pub fn add(a: u64, b: u64, fmt: Format, rm: Rounding) -> (u64, u32) {
+    let (res, flags) = add_values(unpack(a, fmt), unpack(b, fmt), fmt, rm);
+    (res, flags | nan_flags(&[a, b], fmt))
+}
+
+pub fn sub(a: u64, b: u64, fmt: Format, rm: Rounding) -> (u64, u32) {
+    let (res, flags) = add_values(unpack(a, fmt), negate(unpack(b, fmt)), fmt, rm);
+    (res, flags | nan_flags(&[a, b], fmt))
+}
+
+fn sign_of(v: Value) -> bool {
+    match v {
+        Value::NaN { .. } => false,
+        Value::Inf(s) | Value::Zero(s) => s,
+        Value::Finite { sign, .. } => sign,
+    }
+}
+
+// Exact product, None for NaN operands and inf * 0.
+fn mul_values(a: Value, b: Value) -> Option<Value> {
+    let sign = sign_of(a) ^ sign_of(b);
+    match (a, b) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => None,
+        (Value::Inf(_), Value::Zero(_)) | (Value::Zero(_), Value::Inf(_)) => None,
+        (Value::Inf(_), _) | (_, Value::Inf(_)) => Some(Value::Inf(sign)),
+        (Value::Zero(_), _) | (_, Value::Zero(_)) => Some(Value::Zero(sign)),
+        (
+            Value::Finite {
+                sig: siga, exp: ea, ..
+            },
+            Value::Finite {
+                sig: sigb, exp: eb, ..
+            },
+        ) => Some(Value::Finite {
+            sign,
+            sig: siga * sigb,
+            exp: ea + eb,
+        }),
+    }
+}
+
+pub fn mul(a: u64, b: u64, fmt: Format, rm: Rounding) -> (u64, u32) {
+    let flags = nan_flags(&[a, b], fmt);
+    let (va, vb) = (unpack(a, fmt), unpack(b, fmt));
+    match mul_values(va, vb) {
+        Some(Value::Finite { sign, sig, exp }) => {
+            let (res, f) = round_pack(sign, sig, exp, fmt, rm);
+            (res, f | flags)
+        }
+        Some(Value::Inf(s)) => (fmt.inf(s), flags),
+        Some(Value::Zero(s)) => (fmt.zero(s), flags),
+        Some(Value::NaN { .. }) => (fmt.canonical_nan(), flags),
+        None => {
+            if is_nan(a, fmt) || is_nan(b, fmt) {
+                (fmt.canonical_nan(), flags)
+            } else {
+                // inf * 0
+                (fmt.canonical_nan(), FLAG_NV)
+            }
+        }
+    }
+}
+
+// a * b + c, with the product and/or addend negated for the fmsub/fnmadd family
+pub fn fma(
+    a: u64,
+    b: u64,
+    c: u64,
+    neg_product: bool,
+    neg_addend: bool,
+    fmt: Format,
+    rm: Rounding,
+) -> (u64, u32) {
+    let flags = nan_flags(&[a, b, c], fmt);
+    if is_nan(a, fmt) || is_nan(b, fmt) {
+        return (fmt.canonical_nan(), flags);
+    }
+    let product = match mul_values(unpack(a, fmt), unpack(b, fmt)) {
+        Some(p) => p,
+        // inf * 0 is invalid even if the addend is a quiet NaN
+        None => return (fmt.canonical_nan(), FLAG_NV),
+    };
+    if is_nan(c, fmt) {
+        return (fmt.canonical_nan(), flags);
+    }
+    let product = if neg_product { negate(product) } else { product };
+    let addend = unpack(c, fmt);
+    let addend = if neg_addend { negate(addend) } else { addend };
+    let (res, f) = add_values(product, addend, fmt, rm);
+    (res, f | flags)
+}
+
+pub fn div(a: u64, b: u64, fmt: Format, rm: Rounding) -> (u64, u32) {
+    let flags = nan_flags(&[a, b], fmt);
+    let (va, vb) = (unpack(a, fmt), unpack(b, fmt));
+    let sign = sign_of(va) ^ sign_of(vb);
+    match (va, vb) {
+        (Value::NaN { .. }, _) | (_, Value::NaN { .. }) => (fmt.canonical_nan(), flags),
+        (Value::Inf(_), Value::Inf(_)) | (Value::Zero(_), Value::Zero(_)) => {
+            (fmt.canonical_nan(), FLAG_NV)
+        }
+        (Value::Inf(_), _) => (fmt.inf(sign), 0),
+        (_, Value::Inf(_)) => (fmt.zero(sign), 0),
+        (Value::Zero(_), _) => (fmt.zero(sign), 0),
+        (_, Value::Zero(_)) => (fmt.inf(sign), FLAG_DZ),
+        (
+            Value::Finite {
+                sig: siga, exp: ea, ..
+            },
+            Value::Finite {
+                sig: sigb, exp: eb, ..
+            },
+        ) => {
+            // dividend at the top, divisor at bit 63, quotient has at least 62 bits
+            let (siga, ea) = normalize(siga, ea);
+            let shift = sigb.leading_zeros() as i32 - 64;
+            let (sigb, eb) = (sigb << shift, eb - shift);
+            let mut q = siga / sigb;
+            let mut exp = ea - eb;
+            if siga % sigb != 0 {
+                q = (q << 1) | 1;
+                exp -= 1;
+            }
+            round_pack(sign, q, exp, fmt, rm)
+        }
+    }
+}
+
+// integer square root, digit by digit
+fn isqrt(n: u128) -> (u128, bool) {
+    let mut x = n;
+    let mut r: u128 = 0;
+    let mut bit: u128 = 1 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if x >= r + bit {
+            x -= r + bit;
+            r = (r >> 1) + bit;
+        } else {
+            r >>= 1;
+        }
+        bit >>= 2;
+    }
+    (r, x == 0)
+}
+
+pub fn sqrt(a: u64, fmt: Format, rm: Rounding) -> (u64, u32) {
+    match unpack(a, fmt) {
+        Value::NaN { signaling } => (fmt.canonical_nan(), if signaling { FLAG_NV } else { 0 }),
+        Value::Zero(s) => (fmt.zero(s), 0),
+        Value::Inf(false) => (fmt.inf(false), 0),
+        Value::Inf(true) | Value::Finite { sign: true, .. } => (fmt.canonical_nan(), FLAG_NV),
+        Value::Finite { sig, exp, .. } => {
+            let (sig, exp) = normalize(sig, exp);
+            // the exponent has to be even
+            let (sig, exp) = if exp.rem_euclid(2) != 0 {
+                (sig >> 1, exp + 1)
+            } else {
+                (sig, exp)
+            };
+            let (mut r, exact) = isqrt(sig);
+            let mut exp = exp / 2;
+            if !exact {
+                r = (r << 1) | 1;
+                exp -= 1;
+            }
+            round_pack(false, r, exp, fmt, rm)
+        }
+    }
+}
+
+// float -> 32-bit integer
+pub fn to_int(a: u64, fmt: Format, rm: Rounding, signed: bool) -> (u32, u32) {
+    let (max, min) = if signed {
+        (i32::MAX as u32, i32::MIN as u32)
+    } else {
+        (u32::MAX, 0)
+    };
+    match unpack(a, fmt) {
+        Value::NaN { .. } | Value::Inf(false) => (max, FLAG_NV),
+        Value::Inf(true) => (min, FLAG_NV),
+        Value::Zero(_) => (0, 0),
+        Value::Finite { sign, sig, exp } => {
+            let msb = 127 - sig.leading_zeros() as i32;
+            if exp + msb > 32 {
+                return (if sign { min } else { max }, FLAG_NV);
+            }
+            let (r, inexact) = round_sig(sign, sig, exp, 0, rm);
+            let flags = if inexact { FLAG_NX } else { 0 };
+            if signed {
+                if sign && r > 1 << 31 {
+                    (min, FLAG_NV)
+                } else if !sign && r > i32::MAX as u128 {
+                    (max, FLAG_NV)
+                } else if sign {
+                    ((r as u32).wrapping_neg(), flags)
+                } else {
+                    (r as u32, flags)
+                }
+            } else if sign && r != 0 {
+                (min, FLAG_NV)
+            } else if r > u32::MAX as u128 {
+                (max, FLAG_NV)
+            } else {
+                (r as u32, flags)
+            }
+        }
+    }
+}
+
+// 32-bit integer -> float
+pub fn from_int(x: u32, signed: bool, fmt: Format, rm: Rounding) -> (u64, u32) {
+    if signed {
+        let x = x as i32;
+        round_pack(x < 0, x.unsigned_abs() as u128, 0, fmt, rm)
+    } else {
+        round_pack(false, x as u128, 0, fmt, rm)
+    }
+}
+
+// float -> float of different precision
+pub fn convert(a: u64, from: Format, to: Format, rm: Rounding) -> (u64, u32) {
+    match unpack(a, from) {
+        Value::NaN { signaling } => (to.canonical_nan(), if signaling { FLAG_NV } else { 0 }),
+        Value::Inf(s) => (to.inf(s), 0),
+        Value::Zero(s) => (to.zero(s), 0),
+        Value::Finite { sign, sig, exp } => round_pack(sign, sig, exp, to, rm),
+    }
+}
+
+// a < b for non NaN values
+fn less(a: u64, b: u64, fmt: Format) -> bool {
+    let sa = a & fmt.sign_bit() != 0;
+    let sb = b & fmt.sign_bit() != 0;
+    let ma = a & !fmt.sign_bit();
+    let mb = b & !fmt.sign_bit();
+    if ma == 0 && mb == 0 {
+        return false;
+    }
+    match (sa, sb) {
+        (true, false) => true,
+        (false, true) => false,
+        (false, false) => ma < mb,
+        (true, true) => ma > mb,
+    }
+}
+
+fn equal(a: u64, b: u64, fmt: Format) -> bool {
+    a == b || (a | b) & !fmt.sign_bit() == 0
+}
+
+// quiet comparison
+pub fn eq(a: u64, b: u64, fmt: Format) -> (bool, u32) {
+    if is_nan(a, fmt) || is_nan(b, fmt) {
+        return (false, nan_flags(&[a, b], fmt));
+    }
+    (equal(a, b, fmt), 0)
+}
+
+// signaling comparisons
+pub fn lt(a: u64, b: u64, fmt: Format) -> (bool, u32) {
+    if is_nan(a, fmt) || is_nan(b, fmt) {
+        return (false, FLAG_NV);
+    }
+    (less(a, b, fmt), 0)
+}
+
+pub fn le(a: u64, b: u64, fmt: Format) -> (bool, u32) {
+    if is_nan(a, fmt) || is_nan(b, fmt) {
+        return (false, FLAG_NV);
+    }
+    (less(a, b, fmt) || equal(a, b, fmt), 0)
+}
+
+// IEEE 754-2019 minimumNumber/maximumNumber, -0 is smaller than +0
+pub fn min_max(a: u64, b: u64, fmt: Format, max: bool) -> (u64, u32) {
+    let flags = nan_flags(&[a, b], fmt);
+    match (is_nan(a, fmt), is_nan(b, fmt)) {
+        (true, true) => (fmt.canonical_nan(), flags),
+        (true, false) => (b, flags),
+        (false, true) => (a, flags),
+        (false, false) => {
+            let a_smaller = less(a, b, fmt) || (equal(a, b, fmt) && a & fmt.sign_bit() != 0);
+            if a_smaller != max {
+                (a, flags)
+            } else {
+                (b, flags)
+            }
+        }
+    }
+}
+
+pub fn classify(a: u64, fmt: Format) -> u32 {
+    let subnormal = (a >> fmt.frac_bits) & fmt.exp_mask() == 0;
+    match unpack(a, fmt) {
+        Value::Inf(true) => 1 << 0,
+        Value::Finite { sign: true, .. } if !subnormal => 1 << 1,
+        Value::Finite { sign: true, .. } => 1 << 2,
+        Value::Zero(true) => 1 << 3,
+        Value::Zero(false) => 1 << 4,
+        Value::Finite { sign: false, .. } if subnormal => 1 << 5,
+        Value::Finite { sign: false, .. } => 1 << 6,
+        Value::Inf(false) => 1 << 7,
+        Value::NaN { signaling: true } => 1 << 8,
+        Value::NaN { signaling: false } => 1 << 9,
+    }
+}
@@ -0,0 +1,539 @@
+// GDB remote serial protocol stub
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// Register numbers follow gdb's riscv numbering:
+// x0-x31 = 0-31, pc = 32, f0-f31 = 33-64, csr N = 65 + N
+//
+// Every hart is a thread, thread id is hart id + 1.
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::core::virt_memory::{self, sv32};
+use crate::core::{FREG_NAMES, Hart, REG_NAMES, State, csr, hart_run, lockstep};
+use crate::memory::{self, MemoryBus};
+
+const REG_PC: usize = 32;
+const REG_F0: usize = 33;
+const REG_CSR0: usize = 65;
+
+pub struct GdbStub {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    // memory packets use physical addresses (Qqemu.PhyMemMode:1)
+    phys_mem: bool,
+    // hart selected for register and memory access
+    hart_id: usize,
+}
+
+impl GdbStub {
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for gdb connection on port {}", port);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            phys_mem: false,
+            hart_id: 0,
+        })
+    }
+
+    // Serves gdb until it detaches or the guest powers off or reboots (Ok(true)),
+    // or gdb kills the target (Ok(false)).
+    // `run` executes one batch of instructions the same way the main loop does.
+    pub fn serve(
+        &mut self,
+        harts: &mut [Hart],
+        bus: &mut MemoryBus,
+        run: &mut dyn FnMut(&mut [Hart], &mut MemoryBus) -> State,
+    ) -> io::Result<bool> {
+        // None when the connection is closed
+        while let Some(packet) = self.read_packet()? {
+            if packet.is_empty() || !packet.is_char_boundary(1) {
+                continue;
+            }
+
+            let (cmd, args) = packet.split_at(1);
+            let hart = &mut harts[self.hart_id];
+            let reply = match cmd {
+                "?" => self.stop_reply(),
+                "g" => (0..=REG_PC).map(|i| read_reg(hart, i).unwrap()).collect(),
+                "G" => {
+                    for i in 0..=REG_PC {
+                        match args.get(8 * i..8 * i + 8) {
+                            Some(val) => write_reg(hart, i, val),
+                            None => break,
+                        };
+                    }
+                    "OK".to_string()
+                }
+                "p" => match usize::from_str_radix(args, 16).ok().and_then(|i| read_reg(hart, i)) {
+                    Some(val) => val,
+                    None => "E01".to_string(),
+                },
+                "P" => match args.split_once('=') {
+                    Some((reg, val)) => match usize::from_str_radix(reg, 16) {
+                        Ok(reg) if write_reg(hart, reg, val) => "OK".to_string(),
+                        _ => "E01".to_string(),
+                    },
+                    None => "E01".to_string(),
+                },
+                "m" => match parse_addr_len(args) {
+                    Some((addr, len)) => read_memory(hart, bus, addr, len, self.phys_mem),
+                    None => "E01".to_string(),
+                },
+                "M" => match args.split_once(':') {
+                    Some((addr_len, data)) => match parse_addr_len(addr_len) {
+                        Some((addr, len)) => self.write_memory(hart, bus, addr, len, data),
+                        None => "E01".to_string(),
+                    },
+                    None => "E01".to_string(),
+                },
+                "c" => {
+                    if let Ok(addr) = u32::from_str_radix(args, 16) {
+                        hart.core.pc = addr;
+                    }
+                    self.resume(harts, bus, run)?
+                }
+                "s" => {
+                    if let Ok(addr) = u32::from_str_radix(args, 16) {
+                        hart.core.pc = addr;
+                    }
+                    step(hart, bus);
+                    self.stop_reply()
+                }
+                // software and hardware breakpoints are handled the same way, on all harts
+                "Z" | "z" => match parse_breakpoint(args) {
+                    Some(addr) => {
+                        for hart in harts.iter_mut() {
+                            let bps = &mut hart.core.breakpoints;
+                            if cmd == "Z" {
+                                if !bps.contains(&addr) {
+                                    bps.push(addr);
+                                }
+                            } else {
+                                bps.retain(|x| *x != addr);
+                            }
+                        }
+                        "OK".to_string()
+                    }
+                    None => String::new(),
+                },
+                // Hg selects the hart, Hc is ignored since continue always runs all harts
+                "H" => match args.strip_prefix('g').map(parse_thread) {
+                    Some(Some(0)) | None => "OK".to_string(),
+                    Some(Some(thread)) if thread <= harts.len() => {
+                        self.hart_id = thread - 1;
+                        "OK".to_string()
+                    }
+                    Some(_) => "E01".to_string(),
+                },
+                "T" => match parse_thread(args) {
+                    Some(thread) if (1..=harts.len()).contains(&thread) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                },
+                "D" => {
+                    self.write_packet("OK")?;
+                    clear_breakpoints(harts);
+                    return Ok(true);
+                }
+                "k" => return Ok(false),
+                "q" | "Q" => self.query(&packet, harts.len()),
+                _ => String::new(),
+            };
+            self.write_packet(&reply)?;
+            // the main loop handles the poweroff or reboot request
+            if reply.starts_with('W') {
+                break;
+            }
+        }
+        clear_breakpoints(harts);
+        Ok(true)
+    }
+
+    fn stop_reply(&self) -> String {
+        format!("T05thread:{:x};", self.hart_id + 1)
+    }
+
+    fn query(&mut self, packet: &str, harts: usize) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            format!("QC{:x}", self.hart_id + 1)
+        } else if packet == "qfThreadInfo" {
+            let threads: Vec<String> = (1..=harts).map(|x| format!("{:x}", x)).collect();
+            format!("m{}", threads.join(","))
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_addr_len(args) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let offset = (offset as usize).min(xml.len());
+                    let end = (offset + len).min(xml.len());
+                    let prefix = if end == xml.len() { "l" } else { "m" };
+                    format!("{}{}", prefix, &xml[offset..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else if let Some(mode) = packet.strip_prefix("Qqemu.PhyMemMode:") {
+            self.phys_mem = mode == "1";
+            "OK".to_string()
+        } else if packet == "qqemu.PhyMemMode" {
+            (self.phys_mem as u32).to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn resume(
+        &mut self,
+        harts: &mut [Hart],
+        bus: &mut MemoryBus,
+        run: &mut dyn FnMut(&mut [Hart], &mut MemoryBus) -> State,
+    ) -> io::Result<String> {
+        // step off the breakpoints harts are stopped at
+        for hart in harts.iter_mut() {
+            if hart.core.breakpoints.contains(&hart.core.pc) {
+                step(hart, bus);
+            }
+        }
+        loop {
+            match run(harts, bus) {
+                State::Breakpoint => {
+                    // report the hart that hit the breakpoint
+                    if let Some(hart_id) =
+                        harts.iter().position(|hart| hart.core.breakpoints.contains(&hart.core.pc))
+                    {
+                        self.hart_id = hart_id;
+                    }
+                    break;
+                }
+                State::Diverged => {
+                    // the hart stays stopped where it left the lockstep reference
+                    if let Some(hart_id) = harts.iter().position(|hart| hart.core.diverged()) {
+                        self.hart_id = hart_id;
+                    }
+                    if let Some(report) = lockstep::report(harts) {
+                        bus.console_write(report.replace('\n', "\r\n").as_bytes());
+                    }
+                    break;
+                }
+                // the target exited
+                State::Reboot | State::Shutdown => return Ok("W00".to_string()),
+                _ => {}
+            }
+            if self.interrupted()? {
+                break;
+            }
+        }
+        Ok(self.stop_reply())
+    }
+
+    // checks for ctrl-c (0x03) without blocking
+    fn interrupted(&mut self) -> io::Result<bool> {
+        if !self.reader.buffer().is_empty() {
+            let byte = self.reader.buffer()[0];
+            self.reader.consume(1);
+            return Ok(byte == 0x03);
+        }
+        self.reader.get_ref().set_nonblocking(true)?;
+        let ret = self.reader.fill_buf().map(|buf| buf.first().copied());
+        self.reader.get_ref().set_nonblocking(false)?;
+        match ret {
+            Ok(Some(byte)) => {
+                self.reader.consume(1);
+                Ok(byte == 0x03)
+            }
+            // connection closed, stop so that serve can notice it
+            Ok(None) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_memory(
+        &self,
+        hart: &mut Hart,
+        bus: &mut MemoryBus,
+        addr: u32,
+        len: usize,
+        data: &str,
+    ) -> String {
+        let bytes = match decode_hex(data) {
+            Some(x) if x.len() == len => x,
+            _ => return "E01".to_string(),
+        };
+        let trap_val = hart.core.trap_val;
+        let mut ret = Ok(());
+        for (i, byte) in bytes.into_iter().enumerate() {
+            let addr = addr.wrapping_add(i as u32);
+            ret = match self.phys_mem {
+                true => memory::store_byte(bus, addr, byte),
+                false => virt_memory::virt_write_byte(addr, byte, hart, bus),
+            };
+            if ret.is_err() {
+                break;
+            }
+        }
+        hart.core.trap_val = trap_val;
+        match ret {
+            Ok(_) => "OK".to_string(),
+            Err(_) => "E14".to_string(),
+        }
+    }
+
+    // returns None when the connection is closed
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0u8];
+        loop {
+            // skip acks and stray ctrl-c until start of the packet
+            loop {
+                if self.reader.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = Vec::new();
+            if self.reader.read_until(b'#', &mut data)? == 0 {
+                return Ok(None);
+            }
+            data.pop();
+            let mut checksum = [0u8; 2];
+            self.reader.read_exact(&mut checksum)?;
+
+            let sum = data.iter().fold(0u8, |acc, x| acc.wrapping_add(*x));
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|x| u8::from_str_radix(x, 16).ok());
+            if expected != Some(sum) {
+                self.writer.write_all(b"-")?;
+                continue;
+            }
+            self.writer.write_all(b"+")?;
+            return Ok(Some(unescape(&data)));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, |acc, x| acc.wrapping_add(x));
+        let packet = format!("${}#{:02x}", data, sum);
+        loop {
+            self.writer.write_all(packet.as_bytes())?;
+            let mut ack = [0u8];
+            if self.reader.read(&mut ack)? == 0 || ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn clear_breakpoints(harts: &mut [Hart]) {
+    for hart in harts.iter_mut() {
+        hart.core.breakpoints.clear();
+    }
+}
+
+// Only RAM is read: a device load can have side effects, reading the claim register of
+// the PLIC claims an interrupt. The translation does not touch the tlb or the A bits.
+pub(super) fn read_memory(
+    hart: &Hart,
+    bus: &mut MemoryBus,
+    addr: u32,
+    len: usize,
+    phys_mem: bool,
+) -> String {
+    let mut reply = String::new();
+    for i in 0..len.min(0x800) {
+        let addr = addr.wrapping_add(i as u32);
+        let phys = match phys_mem {
+            true => Some(addr),
+            false => sv32::debug_translate(addr, &hart.core, bus),
+        };
+        match phys.filter(|x| bus.ram.claim(*x)) {
+            Some(x) => reply.push_str(&format!("{:02x}", bus.ram.load_byte(x))),
+            None => break,
+        }
+    }
+    if reply.is_empty() && len > 0 {
+        return "E14".to_string();
+    }
+    reply
+}
+
+// executes exactly one instruction, ignoring breakpoints
+fn step(hart: &mut Hart, bus: &mut MemoryBus) {
+    let breakpoints = std::mem::take(&mut hart.core.breakpoints);
+    hart_run(hart, bus, 1);
+    hart.core.breakpoints = breakpoints;
+}
+
+pub(super) fn read_reg(hart: &Hart, reg: usize) -> Option<String> {
+    let core = &hart.core;
+    match reg {
+        // x0 is only cleared before the next instruction
+        0 => Some(hex_u32(0)),
+        1..REG_PC => Some(hex_u32(core.reg_file[reg] as u32)),
+        REG_PC => Some(hex_u32(core.pc)),
+        REG_F0..REG_CSR0 => Some(hex_u64(core.freg_file[reg - REG_F0])),
+        _ => {
+            let addr = reg.checked_sub(REG_CSR0)?;
+            if csr::LEGAL_ADRESSES.contains(&(addr as u32)) {
+                Some(hex_u32(core.csr_file[addr]))
+            } else {
+                None
+            }
+        }
+    }
+}
+
+pub(super) fn write_reg(hart: &mut Hart, reg: usize, val: &str) -> bool {
+    let core = &mut hart.core;
+    match reg {
+        0..REG_PC => match parse_u32(val) {
+            Some(x) if reg != 0 => core.reg_file[reg] = x as i32,
+            Some(_) => {}
+            None => return false,
+        },
+        REG_PC => match parse_u32(val) {
+            Some(x) => core.pc = x,
+            None => return false,
+        },
+        REG_F0..REG_CSR0 => match decode_hex(val) {
+            Some(x) if x.len() == 8 => {
+                core.freg_file[reg - REG_F0] = u64::from_le_bytes(x.try_into().unwrap())
+            }
+            _ => return false,
+        },
+        _ => {
+            let addr = (reg - REG_CSR0) as u32;
+            match parse_u32(val) {
+                Some(x) if csr::LEGAL_ADRESSES.contains(&addr) => {
+                    // like a csrw in M-mode: aliases, WARL fields and the tlb flush of satp
+                    let mode = core.mode;
+                    core.mode = 3;
+                    let ret = csr::write_addr(addr, x, core);
+                    core.mode = mode;
+                    return ret.is_ok();
+                }
+                _ => return false,
+            }
+        }
+    }
+    true
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>riscv:rv32</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (i, name) in REG_NAMES.iter().enumerate() {
+        let ty = match i {
+            1 => "code_ptr",
+            2 => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>", name, ty, i);
+    }
+    xml += &format!("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>", REG_PC);
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.fpu\">";
+    for (i, name) in FREG_NAMES.iter().enumerate() {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"ieee_double\" regnum=\"{}\"/>",
+            name,
+            REG_F0 + i
+        );
+    }
+    for addr in [0x001, 0x002, 0x003] {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>",
+            csr::csr_name(addr),
+            REG_CSR0 + addr as usize
+        );
+    }
+    xml += "</feature><feature name=\"org.gnu.gdb.riscv.csr\">";
+    for addr in csr::LEGAL_ADRESSES.iter().filter(|x| **x > 0x003) {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>",
+            csr::csr_name(*addr),
+            REG_CSR0 + *addr as usize
+        );
+    }
+    xml += "</feature></target>";
+    xml
+}
+
+// registers are sent in target (little endian) byte order
+fn hex_u32(val: u32) -> String {
+    val.to_le_bytes().iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn hex_u64(val: u64) -> String {
+    val.to_le_bytes().iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn parse_u32(val: &str) -> Option<u32> {
+    let bytes = decode_hex(val)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+pub(super) fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// "addr,length"
+pub(super) fn parse_addr_len(args: &str) -> Option<(u32, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u32::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+// thread id in hex, -1 means all threads
+pub(super) fn parse_thread(args: &str) -> Option<usize> {
+    match args {
+        "-1" => Some(0),
+        _ => usize::from_str_radix(args, 16).ok(),
+    }
+}
+
+// "type,addr,kind", only types 0 (software) and 1 (hardware) are supported
+pub(super) fn parse_breakpoint(args: &str) -> Option<u32> {
+    let mut fields = args.split(',');
+    match fields.next()? {
+        "0" | "1" => u32::from_str_radix(fields.next()?, 16).ok(),
+        _ => None,
+    }
+}
+
+// '}' escapes the next byte xored with 0x20
+pub(super) fn unescape(data: &[u8]) -> String {
+    let mut out = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'}' => {
+                if let Some(next) = iter.next() {
+                    out.push(next ^ 0x20);
+                }
+            }
+            _ => out.push(*byte),
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
@@ -1,3 +1,5 @@
+pub mod compressed;
+
 use super::exceptions;
 
 //register
@@ -27,6 +29,33 @@ impl RType {
     }
 }
 
+//fused multiply-add
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct R4Type {
+    pub opcode: u32,
+    pub rd: u32,
+    pub rs1: u32,
+    pub rs2: u32,
+    pub rs3: u32,
+    pub funct2: u32,
+    pub rm: u32,
+}
+
+impl R4Type {
+    fn from(byte_code: u32) -> Self {
+        Self {
+            opcode: byte_code & 127,
+            rd: (byte_code & 3968) >> 7,
+            rs1: (byte_code & 1015808) >> 15,
+            rs2: (byte_code & 32505856) >> 20,
+            rs3: byte_code >> 27,
+            funct2: (byte_code >> 25) & 0b11,
+            rm: (byte_code & 28672) >> 12,
+        }
+    }
+}
+
 //immediate
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -231,10 +260,15 @@ impl JType {
     }
 }
 
+/// Length in bytes of the instruction starting with `parcel`.
+pub fn instr_len(parcel: u32) -> u32 {
+    if parcel & 0b11 == 0b11 { 4 } else { 2 }
+}
 
 #[derive(Debug)]
 pub enum Instruction {
     R(RType),
+    R4(R4Type),
     I(IType),
     S(SType),
     B(BType),
@@ -244,22 +278,26 @@ pub enum Instruction {
 
 impl Instruction {
     pub fn from(byte_code: u32) -> Result<Self, exceptions::Exception> {
+        // 16-bit instructions are expanded into their 32-bit equivalents
+        let byte_code = if byte_code & 0b11 != 0b11 {
+            compressed::expand(byte_code as u16)?
+        } else {
+            byte_code
+        };
         let opcode = byte_code & 127;
         match opcode {
-            0b0110011 | 0b0101111 => Ok(Instruction::R(RType::from(byte_code))),
-            0b0010011 | 0b0000011 | 0b1100111 | 0b1110011 | 0b0001111 => {
+            0b0110011 | 0b0101111 | 0b1010011 => Ok(Instruction::R(RType::from(byte_code))),
+            0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 => {
+                Ok(Instruction::R4(R4Type::from(byte_code)))
+            }
+            0b0010011 | 0b0000011 | 0b1100111 | 0b1110011 | 0b0001111 | 0b0000111 => {
                 Ok(Instruction::I(IType::from(byte_code)))
             }
-            0b0100011 => Ok(Instruction::S(SType::from(byte_code))),
+            0b0100011 | 0b0100111 => Ok(Instruction::S(SType::from(byte_code))),
             0b1100011 => Ok(Instruction::B(BType::from(byte_code))),
             0b1101111 => Ok(Instruction::J(JType::from(byte_code))),
             0b0110111 | 0b0010111 => Ok(Instruction::U(UType::from(byte_code))),
-            _ => {
-                println!("\ropcode: 0b{opcode:b}");
-                println!("\r0x{byte_code:x}");
-                panic!();
-                Err(exceptions::Exception::Illegal_instruction)
-            }
+            _ => Err(exceptions::Exception::Illegal_instruction),
         }
     }
 }
@@ -0,0 +1,270 @@
+// RV32C: every 16-bit instruction is expanded into the 32-bit instruction it is
+// an alias of, so the datapath only has to know the base encodings.
+// Quadrant 0b11 is never seen here, those are the 32-bit instructions.
+
+use crate::core::exceptions::Exception;
+
+const OP_LOAD: u32 = 0b0000011;
+const OP_LOAD_FP: u32 = 0b0000111;
+const OP_IMM: u32 = 0b0010011;
+const OP_STORE: u32 = 0b0100011;
+const OP_STORE_FP: u32 = 0b0100111;
+const OP: u32 = 0b0110011;
+const OP_LUI: u32 = 0b0110111;
+const OP_BRANCH: u32 = 0b1100011;
+const OP_JALR: u32 = 0b1100111;
+const OP_JAL: u32 = 0b1101111;
+
+const EBREAK: u32 = 0x00100073;
+
+fn bits(parcel: u32, hi: u32, lo: u32) -> u32 {
+    (parcel >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+fn bit(parcel: u32, n: u32) -> u32 {
+    (parcel >> n) & 0b1
+}
+
+// sign extend value that is `width` bits wide
+fn sext(val: u32, width: u32) -> i32 {
+    ((val << (32 - width)) as i32) >> (32 - width)
+}
+
+// rd', rs1', rs2' address only registers x8-x15
+fn creg(val: u32) -> u32 {
+    val + 8
+}
+
+fn r_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: i32) -> u32 {
+    ((imm as u32) & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    bits(imm, 11, 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | bits(imm, 4, 0) << 7 | opcode
+}
+
+fn b_type(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    bit(imm, 12) << 31
+        | bits(imm, 10, 5) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | bits(imm, 4, 1) << 8
+        | bit(imm, 11) << 7
+        | OP_BRANCH
+}
+
+fn u_type(opcode: u32, rd: u32, imm: i32) -> u32 {
+    ((imm as u32) & 0xfffff) << 12 | rd << 7 | opcode
+}
+
+fn j_type(rd: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    bit(imm, 20) << 31
+        | bits(imm, 10, 1) << 21
+        | bit(imm, 11) << 20
+        | bits(imm, 19, 12) << 12
+        | rd << 7
+        | OP_JAL
+}
+
+// offset[11|4|9:8|10|6|7|3:1|5] of c.j and c.jal
+fn cj_imm(p: u32) -> i32 {
+    let imm = bit(p, 12) << 11
+        | bit(p, 11) << 4
+        | bits(p, 10, 9) << 8
+        | bit(p, 8) << 10
+        | bit(p, 7) << 6
+        | bit(p, 6) << 7
+        | bits(p, 5, 3) << 1
+        | bit(p, 2) << 5;
+    sext(imm, 12)
+}
+
+// offset[8|4:3] offset[7:6|2:1|5] of c.beqz and c.bnez
+fn cb_imm(p: u32) -> i32 {
+    let imm = bit(p, 12) << 8
+        | bits(p, 11, 10) << 3
+        | bits(p, 6, 5) << 6
+        | bits(p, 4, 3) << 1
+        | bit(p, 2) << 5;
+    sext(imm, 9)
+}
+
+// imm[5] imm[4:0] of c.addi, c.li, c.andi
+fn ci_imm(p: u32) -> i32 {
+    sext(bit(p, 12) << 5 | bits(p, 6, 2), 6)
+}
+
+// uimm[5:3] uimm[2|6] of c.lw, c.sw, c.flw, c.fsw
+fn cl_w_imm(p: u32) -> i32 {
+    (bits(p, 12, 10) << 3 | bit(p, 6) << 2 | bit(p, 5) << 6) as i32
+}
+
+// uimm[5:3] uimm[7:6] of c.fld, c.fsd
+fn cl_d_imm(p: u32) -> i32 {
+    (bits(p, 12, 10) << 3 | bits(p, 6, 5) << 6) as i32
+}
+
+pub fn expand(parcel: u16) -> Result<u32, Exception> {
+    let p = parcel as u32;
+    let funct3 = bits(p, 15, 13);
+    let rd = bits(p, 11, 7);
+    let rs2 = bits(p, 6, 2);
+    let rd_c = creg(bits(p, 4, 2));
+    let rs1_c = creg(bits(p, 9, 7));
+
+    let instr = match (p & 0b11, funct3) {
+        // c.addi4spn
+        (0b00, 0b000) => {
+            let nzuimm = bits(p, 12, 11) << 4 | bits(p, 10, 7) << 6 | bit(p, 6) << 2 | bit(p, 5) << 3;
+            if nzuimm == 0 {
+                // also covers the all zero illegal instruction
+                return Err(Exception::Illegal_instruction);
+            }
+            i_type(OP_IMM, rd_c, 0b000, 2, nzuimm as i32)
+        }
+        // c.fld
+        (0b00, 0b001) => i_type(OP_LOAD_FP, rd_c, 0b011, rs1_c, cl_d_imm(p)),
+        // c.lw
+        (0b00, 0b010) => i_type(OP_LOAD, rd_c, 0b010, rs1_c, cl_w_imm(p)),
+        // c.flw
+        (0b00, 0b011) => i_type(OP_LOAD_FP, rd_c, 0b010, rs1_c, cl_w_imm(p)),
+        // c.fsd
+        (0b00, 0b101) => s_type(OP_STORE_FP, 0b011, rs1_c, rd_c, cl_d_imm(p)),
+        // c.sw
+        (0b00, 0b110) => s_type(OP_STORE, 0b010, rs1_c, rd_c, cl_w_imm(p)),
+        // c.fsw
+        (0b00, 0b111) => s_type(OP_STORE_FP, 0b010, rs1_c, rd_c, cl_w_imm(p)),
+
+        // c.addi, c.nop
+        (0b01, 0b000) => i_type(OP_IMM, rd, 0b000, rd, ci_imm(p)),
+        // c.jal
+        (0b01, 0b001) => j_type(1, cj_imm(p)),
+        // c.li
+        (0b01, 0b010) => i_type(OP_IMM, rd, 0b000, 0, ci_imm(p)),
+        (0b01, 0b011) => {
+            if rd == 2 {
+                // c.addi16sp
+                let nzimm = bit(p, 12) << 9
+                    | bit(p, 6) << 4
+                    | bit(p, 5) << 6
+                    | bits(p, 4, 3) << 7
+                    | bit(p, 2) << 5;
+                if nzimm == 0 {
+                    return Err(Exception::Illegal_instruction);
+                }
+                i_type(OP_IMM, 2, 0b000, 2, sext(nzimm, 10))
+            } else {
+                // c.lui
+                if bit(p, 12) == 0 && rs2 == 0 {
+                    return Err(Exception::Illegal_instruction);
+                }
+                u_type(OP_LUI, rd, ci_imm(p))
+            }
+        }
+        (0b01, 0b100) => match bits(p, 11, 10) {
+            // c.srli
+            0b00 => {
+                if bit(p, 12) != 0 {
+                    // shamt[5] must be zero on rv32
+                    return Err(Exception::Illegal_instruction);
+                }
+                i_type(OP_IMM, rs1_c, 0b101, rs1_c, rs2 as i32)
+            }
+            // c.srai
+            0b01 => {
+                if bit(p, 12) != 0 {
+                    return Err(Exception::Illegal_instruction);
+                }
+                i_type(OP_IMM, rs1_c, 0b101, rs1_c, (0b0100000 << 5 | rs2) as i32)
+            }
+            // c.andi
+            0b10 => i_type(OP_IMM, rs1_c, 0b111, rs1_c, ci_imm(p)),
+            _ => {
+                if bit(p, 12) != 0 {
+                    // c.subw, c.addw are rv64 only
+                    return Err(Exception::Illegal_instruction);
+                }
+                match bits(p, 6, 5) {
+                    // c.sub
+                    0b00 => r_type(OP, rs1_c, 0b000, rs1_c, rd_c, 0b0100000),
+                    // c.xor
+                    0b01 => r_type(OP, rs1_c, 0b100, rs1_c, rd_c, 0),
+                    // c.or
+                    0b10 => r_type(OP, rs1_c, 0b110, rs1_c, rd_c, 0),
+                    // c.and
+                    _ => r_type(OP, rs1_c, 0b111, rs1_c, rd_c, 0),
+                }
+            }
+        },
+        // c.j
+        (0b01, 0b101) => j_type(0, cj_imm(p)),
+        // c.beqz
+        (0b01, 0b110) => b_type(0b000, rs1_c, 0, cb_imm(p)),
+        // c.bnez
+        (0b01, 0b111) => b_type(0b001, rs1_c, 0, cb_imm(p)),
+
+        // c.slli
+        (0b10, 0b000) => {
+            if bit(p, 12) != 0 {
+                return Err(Exception::Illegal_instruction);
+            }
+            i_type(OP_IMM, rd, 0b001, rd, rs2 as i32)
+        }
+        // c.fldsp
+        (0b10, 0b001) => {
+            let uimm = bit(p, 12) << 5 | bits(p, 6, 5) << 3 | bits(p, 4, 2) << 6;
+            i_type(OP_LOAD_FP, rd, 0b011, 2, uimm as i32)
+        }
+        // c.lwsp
+        (0b10, 0b010) => {
+            if rd == 0 {
+                return Err(Exception::Illegal_instruction);
+            }
+            let uimm = bit(p, 12) << 5 | bits(p, 6, 4) << 2 | bits(p, 3, 2) << 6;
+            i_type(OP_LOAD, rd, 0b010, 2, uimm as i32)
+        }
+        // c.flwsp
+        (0b10, 0b011) => {
+            let uimm = bit(p, 12) << 5 | bits(p, 6, 4) << 2 | bits(p, 3, 2) << 6;
+            i_type(OP_LOAD_FP, rd, 0b010, 2, uimm as i32)
+        }
+        (0b10, 0b100) => match (bit(p, 12), rd, rs2) {
+            // c.jr
+            (0, 0, 0) => return Err(Exception::Illegal_instruction),
+            (0, _, 0) => i_type(OP_JALR, 0, 0b000, rd, 0),
+            // c.mv
+            (0, _, _) => r_type(OP, rd, 0b000, 0, rs2, 0),
+            // c.ebreak
+            (_, 0, 0) => EBREAK,
+            // c.jalr
+            (_, _, 0) => i_type(OP_JALR, 1, 0b000, rd, 0),
+            // c.add
+            (_, _, _) => r_type(OP, rd, 0b000, rd, rs2, 0),
+        },
+        // c.fsdsp
+        (0b10, 0b101) => {
+            let uimm = bits(p, 12, 10) << 3 | bits(p, 9, 7) << 6;
+            s_type(OP_STORE_FP, 0b011, 2, rs2, uimm as i32)
+        }
+        // c.swsp
+        (0b10, 0b110) => {
+            let uimm = bits(p, 12, 9) << 2 | bits(p, 8, 7) << 6;
+            s_type(OP_STORE, 0b010, 2, rs2, uimm as i32)
+        }
+        // c.fswsp
+        (0b10, 0b111) => {
+            let uimm = bits(p, 12, 9) << 2 | bits(p, 8, 7) << 6;
+            s_type(OP_STORE_FP, 0b010, 2, rs2, uimm as i32)
+        }
+        _ => return Err(Exception::Illegal_instruction),
+    };
+    Ok(instr)
+}
@@ -0,0 +1,128 @@
+// Loading of bios/kernel images, either ELF files or flat binaries.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use object::elf::{EM_RISCV, PT_LOAD};
+use object::read::elf::{ElfFile32, FileHeader, ProgramHeader};
+use object::{Endianness, Object, ObjectSymbol, SymbolKind};
+
+use crate::core::{Hart, virt_memory};
+use crate::memory::MemoryBus;
+
+// function symbols of all loaded ELF images, sorted by address
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    funcs: Vec<(u32, u32, String)>, // address, size, name
+    globals: HashMap<String, u32>,  // all named symbols, e.g. tohost
+}
+
+impl Symbols {
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let idx = self.funcs.partition_point(|(start, _, _)| *start <= addr);
+        let (start, size, name) = self.funcs.get(idx.checked_sub(1)?)?;
+        // symbols without size cover everything up to the next one
+        if *size != 0 && addr - start >= *size {
+            return None;
+        }
+        Some((name, addr - start))
+    }
+
+    // "function+0x1c" or just the address when there is no symbol
+    pub fn format(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+            None => format!("0x{:08x}", addr),
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.globals.get(name).copied()
+    }
+
+    fn add(&mut self, addr: u32, size: u32, name: &str) {
+        let idx = self.funcs.partition_point(|(start, _, _)| *start <= addr);
+        self.funcs.insert(idx, (addr, size, name.to_string()));
+    }
+}
+
+// start and end address of each part of a loaded image
+pub type Ranges = Vec<(u32, u32)>;
+
+// Loads an image into memory and returns its entry point and the address ranges it occupies.
+// Flat binaries are placed at `flat_addr`.
+pub fn load_image(
+    path: &str,
+    flat_addr: u32,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<(u32, Ranges), Box<dyn Error>> {
+    let data = fs::read(path)?;
+    if !data.starts_with(b"\x7fELF") {
+        write_bytes(flat_addr, &data, hart, bus)?;
+        return Ok((flat_addr, vec![(flat_addr, flat_addr + data.len() as u32)]));
+    }
+
+    let elf = ElfFile32::<Endianness>::parse(&*data)?;
+    let endian = elf.endian();
+    if elf.elf_header().e_machine(endian) != EM_RISCV {
+        return Err(format!("{}: not a RISC-V ELF file", path).into());
+    }
+
+    let mut ranges = Vec::new();
+    for segment in elf.elf_program_headers() {
+        if segment.p_type(endian) != PT_LOAD {
+            continue;
+        }
+        let addr = segment.p_paddr(endian);
+        let mut bytes = segment.data(endian, &*data).map_err(|_| "invalid ELF segment")?.to_vec();
+        // bss
+        bytes.resize(segment.p_memsz(endian) as usize, 0);
+        write_bytes(addr, &bytes, hart, bus)?;
+        if !bytes.is_empty() {
+            ranges.push((addr, addr + bytes.len() as u32));
+        }
+    }
+
+    for symbol in elf.symbols() {
+        let Ok(name) = symbol.name() else {
+            continue;
+        };
+        if !name.is_empty() && !symbol.is_undefined() {
+            hart.symbols.globals.insert(name.to_string(), symbol.address() as u32);
+        }
+        if symbol.kind() == SymbolKind::Text && symbol.address() != 0 {
+            hart.symbols.add(symbol.address() as u32, symbol.size() as u32, name);
+        }
+    }
+
+    Ok((elf.entry() as u32, ranges))
+}
+
+// Loads a file as is, e.g. an initramfs, and returns the address after its end.
+pub fn load_file(path: &str, addr: u32, hart: &mut Hart, bus: &mut MemoryBus) -> Result<u32, Box<dyn Error>> {
+    let data = fs::read(path)?;
+    write_bytes(addr, &data, hart, bus)?;
+    Ok(addr + data.len() as u32)
+}
+
+fn write_bytes(
+    addr: u32,
+    data: &[u8],
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<(), Box<dyn Error>> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let end = addr.wrapping_add(data.len() as u32 - 1);
+    if !bus.ram.claim(addr) || !bus.ram.claim(end) || end < addr {
+        return Err(format!("image segment 0x{:08x}-0x{:08x} is outside of RAM", addr, end).into());
+    }
+    for (i, byte) in data.iter().enumerate() {
+        let _ = virt_memory::virt_write_byte(addr + i as u32, *byte, hart, bus);
+    }
+    Ok(())
+}
@@ -0,0 +1,331 @@
+// Lockstep comparison with a reference commit log (spike --log-commits, optionally with -l for traps).
+// Every hart reads the lines of its own core from the reference and compares them with the
+// instructions it retires. The comparison starts at the first reference instruction with the pc
+// the commit log starts at, which skips the boot rom of spike.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Lines};
+use std::iter::Peekable;
+
+use super::commit_log::{Commit, Trap, hex, write_name};
+use super::{FREG_NAMES, Hart, monitor};
+
+// lines of context in the report
+const HISTORY: usize = 8;
+const LOOKAHEAD: usize = 4;
+const END: &str = "<end of the reference>";
+
+#[derive(Debug)]
+enum Event {
+    Commit(Commit),
+    Trap(Trap),
+}
+
+#[derive(Debug)]
+pub struct Lockstep {
+    hart_id: usize,
+    reference: Peekable<Lines<BufReader<File>>>,
+    // next reference event, read ahead when a trap is missing from the reference
+    pending: Option<(Event, String)>,
+    synced: bool,
+    pub compared: u64,
+
+    history: VecDeque<String>,
+    reference_history: VecDeque<String>,
+    // description of the first difference, our line and the reference line
+    pub divergence: Option<(String, String, String)>,
+}
+
+impl Lockstep {
+    pub fn open(path: &str, hart_id: usize) -> io::Result<Self> {
+        Ok(Lockstep {
+            hart_id,
+            reference: BufReader::new(File::open(path)?).lines().peekable(),
+            pending: None,
+            synced: false,
+            compared: 0,
+            history: VecDeque::new(),
+            reference_history: VecDeque::new(),
+            divergence: None,
+        })
+    }
+
+    // next event of this hart, other lines (e.g. disassembly of spike -l) are skipped
+    fn next(&mut self) -> Option<(Event, String)> {
+        if let Some(event) = self.pending.take() {
+            return Some(event);
+        }
+        while let Some(Ok(line)) = self.reference.next() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if parse_hart(&tokens) != Some(self.hart_id) {
+                continue;
+            }
+            if let Some(commit) = parse_commit(&tokens) {
+                return Some((Event::Commit(commit), line));
+            }
+            if let Some(mut trap) = parse_trap(&tokens) {
+                let mut text = line;
+                // trap value is printed on the next line
+                if let Some(Ok(next)) = self.reference.peek() {
+                    let tokens: Vec<&str> = next.split_whitespace().collect();
+                    if parse_hart(&tokens) == Some(self.hart_id)
+                        && let ["core", _, "tval", tval] = tokens.as_slice()
+                    {
+                        trap.tval = parse_hex(tval).map(|x| x as u32);
+                        text = format!("{}\n{}", text, next);
+                        self.reference.next();
+                    }
+                }
+                return Some((Event::Trap(trap), text));
+            }
+        }
+        None
+    }
+
+    fn remember(&mut self, line: String, reference: String) {
+        self.history.push_back(line);
+        self.reference_history.push_back(reference);
+        if self.history.len() > HISTORY {
+            self.history.pop_front();
+            self.reference_history.pop_front();
+        }
+    }
+
+    fn diverge(&mut self, reason: String, line: String, reference: String) {
+        self.divergence = Some((reason, line, reference));
+    }
+
+    pub fn commit(&mut self, commit: &Commit, line: String) {
+        if self.divergence.is_some() {
+            return;
+        }
+        if !self.synced {
+            loop {
+                match self.next() {
+                    Some((Event::Commit(x), reference)) if x.pc == commit.pc => {
+                        self.pending = Some((Event::Commit(x), reference));
+                        break;
+                    }
+                    Some(_) => {}
+                    None => {
+                        let reason = format!("pc 0x{:08x} is not in the reference", commit.pc);
+                        return self.diverge(reason, line, END.to_string());
+                    }
+                }
+            }
+            self.synced = true;
+        }
+        match self.next() {
+            Some((Event::Commit(expected), reference)) => match compare(commit, &expected) {
+                Some(reason) => self.diverge(reason, line, reference),
+                None => {
+                    self.compared += 1;
+                    self.remember(line, reference);
+                }
+            },
+            Some((Event::Trap(expected), reference)) => {
+                let reason = format!("reference takes {} instead", expected.name);
+                self.diverge(reason, line, reference);
+            }
+            None => self.diverge("end of the reference".to_string(), line, END.to_string()),
+        }
+    }
+
+    pub fn trap(&mut self, trap: &Trap, text: String) {
+        if self.divergence.is_some() || !self.synced {
+            return;
+        }
+        match self.next() {
+            Some((Event::Trap(expected), reference)) => {
+                if expected.name != trap.name || expected.epc != trap.epc {
+                    let reason = format!(
+                        "{} at 0x{:08x}, reference {} at 0x{:08x}",
+                        trap.name, trap.epc, expected.name, expected.epc
+                    );
+                    self.diverge(reason, text, reference);
+                } else if expected.tval.is_some() && expected.tval != trap.tval {
+                    let reason = format!("{} trap value differs", trap.name);
+                    self.diverge(reason, text, reference);
+                } else {
+                    self.remember(text, reference);
+                }
+            }
+            Some((Event::Commit(expected), reference)) => {
+                if expected.pc == trap.epc {
+                    let reason = format!("{}, reference retires the instruction", trap.name);
+                    self.diverge(reason, text, reference);
+                } else {
+                    // the reference doesn't log traps (no -l), the handler comes next
+                    self.pending = Some((Event::Commit(expected), reference));
+                }
+            }
+            None => self.diverge("end of the reference".to_string(), text, END.to_string()),
+        }
+    }
+
+    fn report(&mut self, hart: &Hart) -> String {
+        let (reason, line, reference) = match &self.divergence {
+            Some(x) => x.clone(),
+            None => return String::new(),
+        };
+        let mut report = format!(
+            "\nhart {} diverged from the reference after {} instructions: {}\n\nriscv_em:\n",
+            self.hart_id, self.compared, reason
+        );
+        for x in &self.history {
+            report += &format!("  {}\n", x.replace('\n', "\n  "));
+        }
+        report += &format!("> {}\n\nreference:\n", line.replace('\n', "\n> "));
+        for x in &self.reference_history {
+            report += &format!("  {}\n", x.replace('\n', "\n  "));
+        }
+        report += &format!("> {}\n", reference.replace('\n', "\n> "));
+        for _ in 0..LOOKAHEAD {
+            match self.next() {
+                Some((_, x)) => report += &format!("  {}\n", x.replace('\n', "\n  ")),
+                None => break,
+            }
+        }
+
+        report += "\n";
+        report += &monitor::registers(hart);
+        for (i, name) in FREG_NAMES.iter().enumerate() {
+            report += &format!("{:>4} 0x{:016x}", name, hart.core.freg_file[i]);
+            report += if i % 4 == 3 { "\n" } else { "   " };
+        }
+        report += &monitor::csrs(hart, None);
+        report
+    }
+}
+
+// What went wrong on the first hart that diverged, with the instructions around it and the
+// state of the hart. None when all harts follow the reference.
+pub fn report(harts: &mut [Hart]) -> Option<String> {
+    let hart = harts.iter_mut().find(|hart| hart.core.diverged())?;
+    let log = hart.core.commit_log.as_mut()?;
+    let mut lockstep = log.lockstep.take()?;
+    let report = lockstep.report(hart);
+    hart.core.commit_log.as_mut()?.lockstep = Some(lockstep);
+    Some(report)
+}
+
+// first difference between our instruction and the reference
+pub(super) fn compare(commit: &Commit, expected: &Commit) -> Option<String> {
+    if commit.pc != expected.pc {
+        return Some(format!(
+            "pc 0x{:08x}, reference 0x{:08x}",
+            commit.pc, expected.pc
+        ));
+    }
+    if commit.instr != expected.instr {
+        return Some(format!(
+            "instruction 0x{:08x}, reference 0x{:08x}",
+            commit.instr, expected.instr
+        ));
+    }
+    if commit.mode != expected.mode {
+        return Some(format!("mode {}, reference {}", commit.mode, expected.mode));
+    }
+    let keys: BTreeSet<&u32> = commit.writes.keys().chain(expected.writes.keys()).collect();
+    for key in keys {
+        let name = write_name(*key).trim_end().to_string();
+        match (commit.writes.get(key), expected.writes.get(key)) {
+            (Some((val, _)), Some((expected, _))) if val == expected => {}
+            (Some((val, bits)), Some((expected, _))) => {
+                return Some(format!(
+                    "{} {}, reference {}",
+                    name,
+                    hex(*val, *bits),
+                    hex(*expected, *bits)
+                ));
+            }
+            (Some((val, bits)), None) => {
+                return Some(format!(
+                    "{} {}, not written in the reference",
+                    name,
+                    hex(*val, *bits)
+                ));
+            }
+            (None, Some((expected, bits))) => {
+                return Some(format!(
+                    "{} not written, reference {}",
+                    name,
+                    hex(*expected, *bits)
+                ));
+            }
+            (None, None) => {}
+        }
+    }
+    if commit.loads != expected.loads || commit.stores != expected.stores {
+        return Some("memory accesses differ".to_string());
+    }
+    None
+}
+
+fn parse_hex(token: &str) -> Option<u64> {
+    u64::from_str_radix(token.strip_prefix("0x")?, 16).ok()
+}
+
+// "core   0: ..."
+fn parse_hart(tokens: &[&str]) -> Option<usize> {
+    match tokens {
+        ["core", id, ..] => id.strip_suffix(':')?.parse().ok(),
+        _ => None,
+    }
+}
+
+// core   0: 3 0x80000000 (0x00000297) x5  0x80000000 mem 0x80001000
+pub(super) fn parse_commit(tokens: &[&str]) -> Option<Commit> {
+    let [_, _, mode, pc, instr, rest @ ..] = tokens else {
+        return None;
+    };
+    let instr = instr.strip_prefix('(')?.strip_suffix(')')?;
+    let mut commit = Commit {
+        mode: mode.parse().ok()?,
+        pc: parse_hex(pc)? as u32,
+        instr: parse_hex(instr)? as u32,
+        instr_len: (instr.len() as u32 - 2) / 2,
+        ..Default::default()
+    };
+    let mut rest = rest.iter().peekable();
+    while let Some(token) = rest.next() {
+        if *token == "mem" {
+            let addr = parse_hex(rest.next()?)? as u32;
+            match rest
+                .peek()
+                .and_then(|x| parse_hex(x).map(|val| (val, x.len() as u32 - 2)))
+            {
+                Some((val, digits)) => {
+                    rest.next();
+                    commit.stores.push((addr, val, 4 * digits));
+                }
+                None => commit.loads.push(addr),
+            }
+            continue;
+        }
+        let val = rest.next()?;
+        let bits = 4 * (val.len() as u32 - 2);
+        let val = parse_hex(val)?;
+        let key = match token.split_at(1) {
+            ("x", reg) => reg.parse::<u32>().ok()? << 4,
+            ("f", reg) => reg.parse::<u32>().ok()? << 4 | 1,
+            ("c", csr) => csr.split('_').next()?.parse::<u32>().ok()? << 4 | 4,
+            _ => return None,
+        };
+        commit.writes.insert(key, (val, bits));
+    }
+    Some(commit)
+}
+
+// core   0: exception trap_illegal_instruction, epc 0x80000004
+pub(super) fn parse_trap(tokens: &[&str]) -> Option<Trap> {
+    let [_, _, "exception", name @ .., "epc", epc] = tokens else {
+        return None;
+    };
+    Some(Trap {
+        name: name.join(" ").strip_suffix(',')?.to_string(),
+        epc: parse_hex(epc)? as u32,
+        tval: None,
+    })
+}
@@ -0,0 +1,301 @@
+// Emulator monitor, opened with ctrl-a c on the console.
+// The machine is paused while the monitor is open.
+
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::core::virt_memory::sv32;
+use crate::core::{Hart, REG_NAMES, State, csr, hart_run};
+use crate::memory::{MemoryBus, MmioDevice, virtio::VirtioDevice};
+
+const HELP: &str = "\
+c | cont                resume the machine
+q | quit                exit the emulator
+hart [n]                select hart n
+s | step [n]            execute n instructions on the selected hart
+info registers          general purpose registers and pc
+info csr [name|addr]    all csrs or a single one
+info plic|clint|virtio  device state
+xp addr [n]             n words of physical memory, RAM only
+x addr [n]              n words of virtual memory, current translation of the hart
+                        n is 1 by default and at most 1024
+translate addr          walk the page table of the hart for a virtual address
+an empty line repeats the last command
+";
+
+// words of one xp or x command
+const MAX_DUMP: u32 = 1024;
+
+#[derive(Default)]
+pub struct Monitor {
+    hart_id: usize,
+    last_line: String,
+}
+
+impl Monitor {
+    // Runs until the machine is resumed (true) or the user quits (false).
+    pub fn run(&mut self, harts: &mut [Hart], bus: &mut MemoryBus) -> bool {
+        print(bus, "\nriscv_em monitor, type help for commands\n");
+        loop {
+            print(bus, &format!("(hart {}) ", self.hart_id));
+            let mut line = read_line(bus);
+            if line.trim().is_empty() {
+                line = self.last_line.clone();
+            } else {
+                self.last_line = line.clone();
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let reply = match words.as_slice() {
+                [] => String::new(),
+                ["c" | "cont"] => return true,
+                ["q" | "quit"] => return false,
+                ["help" | "h"] => HELP.to_string(),
+                ["hart"] => format!("hart {} of {}\n", self.hart_id, harts.len()),
+                ["hart", n] => match n.parse::<usize>() {
+                    Ok(n) if n < harts.len() => {
+                        self.hart_id = n;
+                        String::new()
+                    }
+                    _ => format!("no hart {}\n", n),
+                },
+                ["s" | "step"] => step(&mut harts[self.hart_id], bus, 1),
+                ["s" | "step", n] => match n.parse() {
+                    Ok(n) => step(&mut harts[self.hart_id], bus, n),
+                    Err(_) => "invalid count\n".to_string(),
+                },
+                ["info", "registers" | "r"] => registers(&harts[self.hart_id]),
+                ["info", "csr"] => csrs(&harts[self.hart_id], None),
+                ["info", "csr", csr] => csrs(&harts[self.hart_id], Some(csr)),
+                ["info", "plic"] => bus.plic.info(),
+                ["info", "clint"] => bus.clint.info(harts.len()),
+                ["info", "virtio"] => virtio(bus),
+                ["xp", args @ ..] => match parse_dump(args) {
+                    Some((addr, n)) => {
+                        dump(addr, n, |addr| load_word(bus, addr, |addr, _| Some(addr)))
+                    }
+                    None => "usage: xp addr [n]\n".to_string(),
+                },
+                ["x", args @ ..] => match parse_dump(args) {
+                    Some((addr, n)) => {
+                        let core = &harts[self.hart_id].core;
+                        dump(addr, n, |addr| {
+                            load_word(bus, addr, |addr, bus| {
+                                sv32::debug_translate(addr, core, bus)
+                            })
+                        })
+                    }
+                    None => "usage: x addr [n]\n".to_string(),
+                },
+                ["translate", addr] => match parse_u32(addr) {
+                    Some(addr) => translate(&harts[self.hart_id], bus, addr),
+                    None => "usage: translate addr\n".to_string(),
+                },
+                _ => "unknown command, type help for commands\n".to_string(),
+            };
+            print(bus, &reply);
+        }
+    }
+}
+
+// the console is in raw mode
+fn print(bus: &mut MemoryBus, text: &str) {
+    bus.console_write(text.replace('\n', "\r\n").as_bytes());
+}
+
+fn read_line(bus: &mut MemoryBus) -> String {
+    let mut line = String::new();
+    loop {
+        let byte = match bus.console_read() {
+            Some(x) => x,
+            None => {
+                sleep(Duration::from_millis(10));
+                continue;
+            }
+        };
+        match byte {
+            b'\r' | b'\n' => {
+                print(bus, "\n");
+                return line;
+            }
+            // backspace, delete
+            0x08 | 0x7f if line.pop().is_some() => print(bus, "\x08 \x08"),
+            0x20..0x7f => {
+                line.push(byte as char);
+                print(bus, &(byte as char).to_string());
+            }
+            _ => {}
+        }
+    }
+}
+
+// executes n instructions, ignoring breakpoints
+fn step(hart: &mut Hart, bus: &mut MemoryBus, n: u32) -> String {
+    let breakpoints = std::mem::take(&mut hart.core.breakpoints);
+    for _ in 0..n {
+        if matches!(
+            hart_run(hart, bus, 1),
+            State::Sleep | State::Diverged | State::Reboot | State::Shutdown
+        ) {
+            break;
+        }
+    }
+    hart.core.breakpoints = breakpoints;
+
+    let mut reply = format!("pc {}", location(hart, hart.core.pc));
+    if hart.core.wfi {
+        reply += " waiting for interrupt";
+    }
+    reply + "\n"
+}
+
+pub fn registers(hart: &Hart) -> String {
+    let core = &hart.core;
+    let mut reply = String::new();
+    for (i, name) in REG_NAMES.iter().enumerate() {
+        // x0 is only cleared before the next instruction
+        let val = if i == 0 { 0 } else { core.reg_file[i] as u32 };
+        reply += &format!("{:>4} 0x{:08x}", name, val);
+        reply += if i % 4 == 3 { "\n" } else { "   " };
+    }
+    reply += &format!("  pc {}\n", location(hart, core.pc));
+    let mode = match core.mode {
+        0 => "user",
+        1 => "supervisor",
+        _ => "machine",
+    };
+    reply += &format!("mode {}\n", mode);
+    reply
+}
+
+// "0x80000010 <main+0x10>"
+fn location(hart: &Hart, addr: u32) -> String {
+    match hart.symbols.lookup(addr) {
+        Some(_) => format!("0x{:08x} <{}>", addr, hart.symbols.format(addr)),
+        None => format!("0x{:08x}", addr),
+    }
+}
+
+// by name or address
+pub fn csrs(hart: &Hart, csr: Option<&str>) -> String {
+    let addrs: Vec<u32> = match csr {
+        None => csr::LEGAL_ADRESSES.to_vec(),
+        Some(csr) => {
+            let addr = csr::LEGAL_ADRESSES
+                .iter()
+                .find(|addr| csr::csr_name(**addr) == csr)
+                .copied()
+                .or_else(|| parse_u32(csr).filter(|x| csr::LEGAL_ADRESSES.contains(x)));
+            match addr {
+                Some(x) => vec![x],
+                None => return format!("unknown csr {}\n", csr),
+            }
+        }
+    };
+    addrs
+        .iter()
+        .map(|addr| {
+            format!(
+                "{:>14} (0x{:03x}) 0x{:08x}\n",
+                csr::csr_name(*addr),
+                addr,
+                hart.core.csr_file[*addr as usize]
+            )
+        })
+        .collect()
+}
+
+// Only RAM is read: a device load can have side effects, reading the claim register of the
+// PLIC claims an interrupt. Every byte is translated on its own, a word can cross a page.
+pub(super) fn load_word(
+    bus: &mut MemoryBus,
+    addr: u32,
+    translate: impl Fn(u32, &mut MemoryBus) -> Option<u32>,
+) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let phys = translate(addr.wrapping_add(i as u32), bus).filter(|x| bus.ram.claim(*x))?;
+        *byte = bus.ram.load_byte(phys);
+    }
+    Some(u32::from_le_bytes(bytes))
+}
+
+pub(super) fn dump(addr: u32, n: u32, mut load: impl FnMut(u32) -> Option<u32>) -> String {
+    let mut reply = String::new();
+    for i in 0..n {
+        let addr = addr.wrapping_add(i.wrapping_mul(4));
+        if i % 4 == 0 {
+            reply += &format!("{:08x}:", addr);
+        }
+        match load(addr) {
+            Some(x) => reply += &format!(" 0x{:08x}", x),
+            None => {
+                reply += " cannot access memory\n";
+                return reply;
+            }
+        }
+        if i % 4 == 3 || i == n - 1 {
+            reply += "\n";
+        }
+    }
+    reply
+}
+
+// every virtio transport, by address
+fn virtio(bus: &MemoryBus) -> String {
+    let mut reply = String::new();
+    for device in bus.devices_of::<VirtioDevice>() {
+        reply += &format!("virtio@{:x}: {}", device.region().0, device.info(&bus.ram));
+    }
+    match reply.is_empty() {
+        true => "not attached\n".to_string(),
+        false => reply,
+    }
+}
+
+fn translate(hart: &Hart, bus: &mut MemoryBus, addr: u32) -> String {
+    let satp = csr::read(csr::Csr::satp, &hart.core);
+    if satp >> 31 == 0 {
+        return format!("satp 0x{:08x}, translation is off\n", satp);
+    }
+    let mut reply = format!("satp 0x{:08x}\n", satp);
+    let (ptes, phys) = sv32::walk_info(addr, satp, bus);
+    for (level, (pte_addr, pte)) in ptes.iter().enumerate() {
+        let flags: String = "vrwxugad"
+            .chars()
+            .enumerate()
+            .map(|(bit, flag)| match pte >> bit & 1 {
+                1 => flag,
+                _ => '-',
+            })
+            .collect();
+        reply += &format!(
+            "level {} pte @ 0x{:08x}: 0x{:08x} ppn 0x{:05x} {}\n",
+            1 - level,
+            pte_addr,
+            pte,
+            pte >> 10,
+            flags
+        );
+    }
+    match phys {
+        Some(x) => reply + &format!("0x{:08x} -> 0x{:08x}\n", addr, x),
+        None => reply + &format!("0x{:08x} is not mapped\n", addr),
+    }
+}
+
+fn parse_u32(arg: &str) -> Option<u32> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+// "addr [n]", n defaults to 1 word and is capped at MAX_DUMP
+pub(super) fn parse_dump(args: &[&str]) -> Option<(u32, u32)> {
+    match args {
+        [addr] => Some((parse_u32(addr)?, 1)),
+        [addr, n] => Some((parse_u32(addr)?, parse_u32(n)?.min(MAX_DUMP))),
+        _ => None,
+    }
+}
@@ -0,0 +1,318 @@
+use super::{Core, Hart, MAX_HARTS, State, TRAP_CLEAR, csr, csr::Csr};
+use crate::memory::{MemoryBus, clint::Clint};
+
+// Built-in SBI (--sbi) in place of an M-mode firmware like OpenSBI.
+// Harts run the kernel in S-mode and their ecalls are handled by the emulator:
+// BASE, TIME, IPI, RFENCE, HSM, SRST and DBCN of SBI 2.0, and the legacy console.
+
+pub const EXT_BASE: u32 = 0x10;
+pub const EXT_TIME: u32 = 0x54494d45;
+pub const EXT_IPI: u32 = 0x735049;
+pub const EXT_RFENCE: u32 = 0x52464e43;
+pub const EXT_HSM: u32 = 0x48534d;
+pub const EXT_SRST: u32 = 0x53525354;
+pub const EXT_DBCN: u32 = 0x4442434e;
+pub const LEGACY_CONSOLE_PUTCHAR: u32 = 0x1;
+pub const LEGACY_CONSOLE_GETCHAR: u32 = 0x2;
+
+const SPEC_VERSION: u32 = 2 << 24;
+// implementation ids are assigned by the spec, this one is not taken
+const IMPL_ID: u32 = 0x726d;
+const IMPL_VERSION: u32 = 1;
+
+pub const ERR_NOT_SUPPORTED: i32 = -2;
+pub const ERR_INVALID_PARAM: i32 = -3;
+pub const ERR_ALREADY_AVAILABLE: i32 = -6;
+
+const SSIP: u32 = 1 << 1;
+const STIP: u32 = 1 << 5;
+const MTIP: u32 = 1 << 7;
+const SEIP: u32 = 1 << 9;
+// everything but ecalls from S and M-mode is handled by the kernel
+const MEDELEG: u32 = 0xb1ff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Hsm {
+    Started,
+    Stopped,
+    StartPending { addr: u32, opaque: u32 },
+}
+
+impl Hsm {
+    fn status(&self) -> u32 {
+        match self {
+            Hsm::Started => 0,
+            Hsm::Stopped => 1,
+            Hsm::StartPending { .. } => 2,
+        }
+    }
+}
+
+// state shared by the harts, requests for other harts are picked up before they run
+pub struct Sbi {
+    count: usize,
+    harts: [Hsm; MAX_HARTS],
+    ipi: [bool; MAX_HARTS],
+    fence: [bool; MAX_HARTS],
+}
+
+impl Sbi {
+    // only hart 0 runs, the kernel starts the others through HSM
+    pub fn new(count: usize) -> Self {
+        let mut harts = [Hsm::Stopped; MAX_HARTS];
+        harts[0] = Hsm::Started;
+        Sbi {
+            count,
+            harts,
+            ipi: [false; MAX_HARTS],
+            fence: [false; MAX_HARTS],
+        }
+    }
+
+    // called before the hart runs, false while it is stopped
+    pub fn hart_ready(&mut self, core: &mut Core) -> bool {
+        let hart_id = core.hart_id();
+        match self.harts[hart_id] {
+            Hsm::Stopped => return false,
+            Hsm::StartPending { addr, opaque } => {
+                // same entry state as the boot hart, a1 is the opaque value
+                core.pc = addr;
+                core.mode = 1;
+                core.wfi = false;
+                core.reg_file[10] = hart_id as i32;
+                core.reg_file[11] = opaque as i32;
+                csr::write(Csr::satp, 0, core);
+                let mstatus = csr::read(Csr::mstatus, core);
+                csr::write(Csr::mstatus, mstatus & !0b10, core);
+                core.tlb.flush_all();
+                self.harts[hart_id] = Hsm::Started;
+            }
+            Hsm::Started => {}
+        }
+
+        // the supervisor timer is the machine timer set through the TIME extension
+        let mip = csr::read(Csr::mip, core);
+        let stip = match mip & MTIP {
+            0 => 0,
+            _ => STIP,
+        };
+        csr::write(Csr::mip, (mip & !STIP) | stip, core);
+
+        self.deliver(core);
+        true
+    }
+
+    // pending IPIs and remote fences of the hart
+    fn deliver(&mut self, core: &mut Core) {
+        let hart_id = core.hart_id();
+        if std::mem::take(&mut self.ipi[hart_id]) {
+            let mip = csr::read(Csr::mip, core);
+            csr::write(Csr::mip, mip | SSIP, core);
+            core.wfi = false;
+        }
+        if std::mem::take(&mut self.fence[hart_id]) {
+            core.tlb.flush_all();
+        }
+    }
+
+    // harts selected by hart_mask and hart_mask_base, None if any of them does not exist
+    fn hart_mask(&self, mask: u32, base: u32) -> Option<Vec<usize>> {
+        if base == u32::MAX {
+            return Some((0..self.count).collect());
+        }
+        let harts: Vec<usize> = (0..32)
+            .filter(|bit| mask >> bit & 1 != 0)
+            .map(|bit| base as usize + bit)
+            .collect();
+        match harts.iter().all(|hart_id| *hart_id < self.count) {
+            true => Some(harts),
+            false => None,
+        }
+    }
+}
+
+// machine state an M-mode firmware leaves behind before it enters the kernel
+pub fn setup(core: &mut Core, clint: &mut Clint) {
+    csr::write(Csr::mideleg, SSIP | STIP | SEIP, core);
+    csr::write(Csr::medeleg, MEDELEG, core);
+    csr::write(Csr::mcounteren, 0b111, core);
+    // one NAPOT region over the whole address space
+    csr::write(Csr::pmpaddr0, u32::MAX, core);
+    csr::write(Csr::pmpcfg0, 0x1f, core);
+    // no timer interrupt until the kernel sets one
+    clint.set_mtimecmp(core.hart_id(), u64::MAX);
+    core.mode = 1;
+}
+
+// ecall from S-mode, None without the built-in SBI.
+// Arguments are in a0-a5, the extension in a7 and the function in a6,
+// the error code is returned in a0 and the value in a1.
+pub fn ecall(hart: &mut Hart, bus: &mut MemoryBus) -> Option<State> {
+    // out of the bus while the call uses the console
+    let mut sbi = bus.sbi.take()?;
+    let core = &mut hart.core;
+    let hart_id = core.hart_id();
+    let eid = core.reg_file[17] as u32;
+    let fid = core.reg_file[16] as u32;
+    let a: [u32; 6] = std::array::from_fn(|i| core.reg_file[10 + i] as u32);
+    let mut state = State::Ok;
+
+    let ret = match eid {
+        // legacy extensions only return a value in a0
+        LEGACY_CONSOLE_PUTCHAR => {
+            bus.console_write(&[a[0] as u8]);
+            None
+        }
+        LEGACY_CONSOLE_GETCHAR => {
+            core.reg_file[10] = bus.console_read().map_or(-1, i32::from);
+            None
+        }
+        EXT_BASE => Some(match fid {
+            0 => Ok(SPEC_VERSION),
+            1 => Ok(IMPL_ID),
+            2 => Ok(IMPL_VERSION),
+            3 => Ok(matches!(
+                a[0],
+                EXT_BASE
+                    | EXT_TIME
+                    | EXT_IPI
+                    | EXT_RFENCE
+                    | EXT_HSM
+                    | EXT_SRST
+                    | EXT_DBCN
+                    | LEGACY_CONSOLE_PUTCHAR
+                    | LEGACY_CONSOLE_GETCHAR
+            ) as u32),
+            4 => Ok(csr::read(Csr::mvendorid, core)),
+            5 => Ok(csr::read(Csr::marchid, core)),
+            6 => Ok(csr::read(Csr::mimpid, core)),
+            _ => Err(ERR_NOT_SUPPORTED),
+        }),
+        EXT_TIME => Some(match fid {
+            // set_timer, the stime value is split into a0 and a1
+            0 => {
+                bus.clint
+                    .set_mtimecmp(hart_id, (a[1] as u64) << 32 | a[0] as u64);
+                let mip = csr::read(Csr::mip, core);
+                csr::write(Csr::mip, mip & !(STIP | MTIP), core);
+                Ok(0)
+            }
+            _ => Err(ERR_NOT_SUPPORTED),
+        }),
+        EXT_IPI => Some(match (fid, sbi.hart_mask(a[0], a[1])) {
+            (0, Some(harts)) => {
+                for target in harts {
+                    sbi.ipi[target] = true;
+                }
+                Ok(0)
+            }
+            (0, None) => Err(ERR_INVALID_PARAM),
+            _ => Err(ERR_NOT_SUPPORTED),
+        }),
+        EXT_RFENCE => Some(match (fid, sbi.hart_mask(a[0], a[1])) {
+            // remote_fence_i, instructions are not cached
+            (0, Some(_)) => Ok(0),
+            // remote_sfence_vma and remote_sfence_vma_asid flush the whole TLB
+            (1 | 2, Some(harts)) => {
+                for target in harts {
+                    sbi.fence[target] = true;
+                }
+                Ok(0)
+            }
+            (0..=2, None) => Err(ERR_INVALID_PARAM),
+            // hypervisor fences
+            _ => Err(ERR_NOT_SUPPORTED),
+        }),
+        EXT_HSM => Some(match fid {
+            // hart_start
+            0 | 2 if a[0] as usize >= sbi.count => Err(ERR_INVALID_PARAM),
+            0 => match sbi.harts[a[0] as usize] {
+                Hsm::Stopped => {
+                    sbi.harts[a[0] as usize] = Hsm::StartPending {
+                        addr: a[1],
+                        opaque: a[2],
+                    };
+                    Ok(0)
+                }
+                _ => Err(ERR_ALREADY_AVAILABLE),
+            },
+            // hart_stop, does not return
+            1 => {
+                sbi.harts[hart_id] = Hsm::Stopped;
+                Ok(0)
+            }
+            // hart_get_status
+            2 => Ok(sbi.harts[a[0] as usize].status()),
+            // hart_suspend, only the default retentive suspend which is wfi
+            3 => match a[0] {
+                0 => {
+                    core.wfi = true;
+                    Ok(0)
+                }
+                _ => Err(ERR_NOT_SUPPORTED),
+            },
+            _ => Err(ERR_NOT_SUPPORTED),
+        }),
+        EXT_SRST => Some(match (fid, a[0]) {
+            // system_reset: shutdown, cold and warm reboot
+            (0, 0) => {
+                state = State::Shutdown;
+                Ok(0)
+            }
+            (0, 1 | 2) => {
+                state = State::Reboot;
+                Ok(0)
+            }
+            (0, _) => Err(ERR_INVALID_PARAM),
+            _ => Err(ERR_NOT_SUPPORTED),
+        }),
+        EXT_DBCN => Some(match fid {
+            // console_write and console_read, the buffer is in physical memory given by
+            // its size in a0 and the address in a1 (low) and a2 (high)
+            0 | 1 => {
+                let (len, addr) = (a[0], a[1]);
+                let end = addr.wrapping_add(len.saturating_sub(1));
+                // every byte, the buffer may span a gap between RAM banks
+                if a[2] != 0 || end < addr || !(addr..=end).all(|x| bus.ram.claim(x)) {
+                    Err(ERR_INVALID_PARAM)
+                } else if fid == 0 {
+                    let bytes: Vec<u8> = (addr..addr + len).map(|x| bus.ram.load_byte(x)).collect();
+                    bus.console_write(&bytes);
+                    Ok(len)
+                } else {
+                    let mut read = 0;
+                    while read < len {
+                        let Some(byte) = bus.console_read() else {
+                            break;
+                        };
+                        bus.ram.store_byte(addr + read, byte);
+                        read += 1;
+                    }
+                    Ok(read)
+                }
+            }
+            // console_write_byte
+            2 => {
+                bus.console_write(&[a[0] as u8]);
+                Ok(0)
+            }
+            _ => Err(ERR_NOT_SUPPORTED),
+        }),
+        _ => Some(Err(ERR_NOT_SUPPORTED)),
+    };
+    if let Some(ret) = ret {
+        let (error, value) = match ret {
+            Ok(value) => (0, value),
+            Err(error) => (error, 0),
+        };
+        core.reg_file[10] = error;
+        core.reg_file[11] = value as i32;
+    }
+
+    // requests to the hart itself take effect before it continues
+    sbi.deliver(core);
+    bus.sbi = Some(sbi);
+    core.pc += 4;
+    core.trap = TRAP_CLEAR;
+    Some(state)
+}
@@ -0,0 +1,246 @@
+// Guest programs run on a bare machine: harts, RAM and idle devices.
+// Instructions are encoded by hand, there is no assembler in the build.
+
+mod bus;
+mod commit_log;
+mod datapath;
+mod fdt;
+mod fpu;
+mod gdb;
+mod htif;
+mod images;
+mod lockstep;
+mod lrsc;
+mod memory_map;
+mod monitor;
+mod plic;
+mod power;
+mod sbi;
+mod smp;
+mod svadu;
+mod tlb;
+mod virtio;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::RAM_OFFSET;
+use crate::core::{Core, Hart, State, csr, hart_run, loader};
+use crate::memory::{MemoryBus, MmioDevice, clint, ns16550, ram};
+
+// every hart gets its own program at PROGRAM + n * PROGRAM_STRIDE
+const PROGRAM: u32 = RAM_OFFSET;
+const PROGRAM_STRIDE: u32 = 0x1000;
+// scratch memory for the programs
+const DATA: u32 = RAM_OFFSET + 0x10000;
+// Sv32 root page table, the second level tables follow it
+const PAGE_TABLES: u32 = RAM_OFFSET + 0x100000;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_G: u32 = 1 << 5;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+// everything the guest writes to the console
+#[derive(Clone, Default)]
+struct Console(Rc<RefCell<Vec<u8>>>);
+
+impl Console {
+    fn take(&self) -> Vec<u8> {
+        self.0.take()
+    }
+}
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Machine {
+    harts: Vec<Hart>,
+    bus: MemoryBus,
+    console: Console,
+    next_table: u32, // next free second level page table
+}
+
+impl Machine {
+    fn new(harts: usize) -> Self {
+        let harts = (0..harts)
+            .map(|hart_id| {
+                let mut hart = Hart {
+                    core: Core::default(),
+                    symbols: loader::Symbols::default(),
+                };
+                hart.core.mode = 3;
+                hart.core.pc = PROGRAM + hart_id as u32 * PROGRAM_STRIDE;
+                csr::write(csr::Csr::mhartid, hart_id as u32, &mut hart.core);
+                hart
+            })
+            .collect();
+        let mut bus = MemoryBus::new(ram::RAM::default());
+        let console = Console::default();
+        let uart = ns16550::Uart::new(Box::new(console.clone()));
+        bus.attach(Box::new(uart)).unwrap();
+        Machine {
+            harts,
+            bus,
+            console,
+            next_table: PAGE_TABLES + 0x1000,
+        }
+    }
+
+    // the hart runs in S-mode with Sv32 translation through PAGE_TABLES, PMP allows everything
+    fn supervisor(&mut self, hart_id: usize, asid: u32) {
+        let core = &mut self.harts[hart_id].core;
+        csr::write(csr::Csr::pmpaddr0, u32::MAX, core);
+        // NAPOT, rwx
+        csr::write(csr::Csr::pmpcfg0, 0x1f, core);
+        let satp = 1 << 31 | asid << 22 | PAGE_TABLES >> 12;
+        csr::write(csr::Csr::satp, satp, core);
+        core.mode = 1;
+    }
+
+    // address of the leaf pte of a 4 KiB page, the second level table is allocated if needed
+    fn pte_addr(&mut self, va: u32) -> u32 {
+        let root_pte = PAGE_TABLES + 4 * (va >> 22);
+        let mut pte = self.bus.ram.load_word(root_pte);
+        if pte & PTE_V == 0 {
+            pte = (self.next_table >> 12) << 10 | PTE_V;
+            self.bus.ram.store_word(root_pte, pte);
+            self.next_table += 0x1000;
+        }
+        ((pte >> 10) << 12) + 4 * (va >> 12 & 0x3ff)
+    }
+
+    fn map(&mut self, va: u32, pa: u32, flags: u32) {
+        let pte_addr = self.pte_addr(va);
+        self.bus
+            .ram
+            .store_word(pte_addr, (pa >> 12) << 10 | flags | PTE_V);
+    }
+
+    fn map_mega(&mut self, va: u32, pa: u32, flags: u32) {
+        let pte = (pa >> 12) << 10 | flags | PTE_V;
+        self.bus.ram.store_word(PAGE_TABLES + 4 * (va >> 22), pte);
+    }
+
+    fn load(&mut self, hart_id: usize, program: &[u32]) {
+        let base = PROGRAM + hart_id as u32 * PROGRAM_STRIDE;
+        for (i, instr) in program.iter().enumerate() {
+            self.bus.ram.store_word(base + 4 * i as u32, *instr);
+        }
+    }
+
+    // executes given number of instructions on one hart, a trap counts as one
+    fn run(&mut self, hart_id: usize, instructions: u32) {
+        for _ in 0..instructions {
+            assert_eq!(hart_run(&mut self.harts[hart_id], &mut self.bus, 1), State::Ok);
+        }
+    }
+
+    fn reg(&self, hart_id: usize, reg: usize) -> u32 {
+        self.harts[hart_id].core.reg_file[reg] as u32
+    }
+
+    fn set_reg(&mut self, hart_id: usize, reg: usize, val: u32) {
+        self.harts[hart_id].core.reg_file[reg] = val as i32;
+    }
+
+    fn pc(&self, hart_id: usize) -> u32 {
+        self.harts[hart_id].core.pc - PROGRAM - hart_id as u32 * PROGRAM_STRIDE
+    }
+}
+
+// instruction encoders, registers are numbers
+
+fn r_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, rs2: u32, funct7: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn i_type(opcode: u32, rd: u32, funct3: u32, rs1: u32, imm: i32) -> u32 {
+    ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn s_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 5 & 0x7f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | ((imm & 0x1f) << 7)
+        | opcode
+}
+
+fn b_type(funct3: u32, rs1: u32, rs2: u32, offset: i32) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 12 & 1) << 31)
+        | ((imm >> 5 & 0x3f) << 25)
+        | (rs2 << 20)
+        | (rs1 << 15)
+        | (funct3 << 12)
+        | ((imm >> 1 & 0xf) << 8)
+        | ((imm >> 11 & 1) << 7)
+        | 0b1100011
+}
+
+fn u_type(opcode: u32, rd: u32, imm: u32) -> u32 {
+    (imm << 12) | (rd << 7) | opcode
+}
+
+fn j_type(rd: u32, offset: i32) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 20 & 1) << 31)
+        | ((imm >> 1 & 0x3ff) << 21)
+        | ((imm >> 11 & 1) << 20)
+        | ((imm >> 12 & 0xff) << 12)
+        | (rd << 7)
+        | 0b1101111
+}
+
+fn amo(funct5: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    (funct5 << 27) | (rs2 << 20) | (rs1 << 15) | (0b010 << 12) | (rd << 7) | 0b0101111
+}
+
+fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0b0010011, rd, 0b000, rs1, imm)
+}
+
+fn sw(rs2: u32, rs1: u32, offset: i32) -> u32 {
+    s_type(0b0100011, 0b010, rs1, rs2, offset)
+}
+
+fn bne(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b_type(0b001, rs1, rs2, offset)
+}
+
+fn lr_w(rd: u32, rs1: u32) -> u32 {
+    amo(0b00010, rd, rs1, 0)
+}
+
+fn sc_w(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    amo(0b00011, rd, rs1, rs2)
+}
+
+fn csrrw(rd: u32, csr: u32, rs1: u32) -> u32 {
+    i_type(0b1110011, rd, 0b001, rs1, csr as i32)
+}
+
+fn csrrs(rd: u32, csr: u32, rs1: u32) -> u32 {
+    i_type(0b1110011, rd, 0b010, rs1, csr as i32)
+}
+
+const NOP: u32 = 0x00000013;
+const ECALL: u32 = 0x00000073;
+const MRET: u32 = 0x30200073;
+// jal x0, 0
+const HALT: u32 = 0x0000006f;
@@ -0,0 +1,232 @@
+// Devices on the memory bus: attaching, the device an address reaches and accesses of widths a
+// device doesn't implement.
+
+use super::*;
+use crate::core::exceptions::Exception;
+use crate::fdt::FdtWriter;
+use crate::memory::{self, Access, Unsupported};
+
+// little endian registers that log every access the bus makes
+struct Registers {
+    name: &'static str,
+    base: u32,
+    bytes: Vec<u8>,
+    access: Access,
+    log: Vec<(u32, u32, Option<u32>)>, // address, size, data of a write
+}
+
+impl Registers {
+    fn new(name: &'static str, base: u32, length: u32, access: Access) -> Self {
+        Registers {
+            name,
+            base,
+            bytes: vec![0; length as usize],
+            access,
+            log: Vec::new(),
+        }
+    }
+}
+
+impl MmioDevice for Registers {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn region(&self) -> (u32, u32) {
+        (self.base, self.bytes.len() as u32)
+    }
+
+    fn access(&self) -> Access {
+        self.access
+    }
+
+    fn read(&mut self, addr: u32, size: u32) -> u32 {
+        self.log.push((addr, size, None));
+        let offset = (addr - self.base) as usize;
+        let bytes = &self.bytes[offset..offset + size as usize];
+        bytes
+            .iter()
+            .rev()
+            .fold(0, |val, byte| val << 8 | *byte as u32)
+    }
+
+    fn write(&mut self, addr: u32, size: u32, data: u32) {
+        self.log.push((addr, size, Some(data)));
+        let offset = (addr - self.base) as usize;
+        for i in 0..size as usize {
+            self.bytes[offset + i] = (data >> (8 * i)) as u8;
+        }
+    }
+
+    fn fdt_node(&self, _fdt: &mut FdtWriter, _harts: usize) {}
+}
+
+const WORDS: Access = Access {
+    widths: &[4],
+    unsupported: Unsupported::Split,
+};
+
+const BASE: u32 = 0x30000000;
+
+fn registers(m: &Machine, base: u32) -> &Registers {
+    m.bus
+        .devices_of::<Registers>()
+        .find(|x| x.base == base)
+        .unwrap()
+}
+
+#[test]
+fn attach() {
+    let mut m = Machine::new(1);
+    m.bus
+        .attach(Box::new(Registers::new("first", BASE, 0x1000, WORDS)))
+        .unwrap();
+    for (name, base, length, other) in [
+        ("end", BASE + 0xffc, 8, "first"),
+        ("start", BASE - 4, 8, "first"),
+        ("inside", BASE + 0x100, 4, "first"),
+        ("around", BASE - 0x1000, 0x3000, "first"),
+        ("uart", 0x100000fc, 8, "uart"),
+        ("clint", 0x2000000, 4, "clint"),
+    ] {
+        let device = Registers::new(name, base, length, WORDS);
+        let err = m.bus.attach(Box::new(device)).unwrap_err();
+        let expected = format!("{} at 0x{:08x} overlaps the {}", name, base, other);
+        assert_eq!(err, expected);
+    }
+    // right after the first one and up to the end of the address space
+    for base in [BASE + 0x1000, 0xfffff000] {
+        let device = Registers::new("next", base, 0x1000, WORDS);
+        m.bus.attach(Box::new(device)).unwrap();
+    }
+    let bases: Vec<u32> = m.bus.devices_of::<Registers>().map(|x| x.base).collect();
+    assert_eq!(bases, [BASE, BASE + 0x1000, 0xfffff000]);
+}
+
+#[test]
+fn device_at() {
+    let mut m = Machine::new(1);
+    // attached out of order, with a gap between them
+    for base in [BASE + 0x1000, BASE] {
+        let mut device = Registers::new("registers", base, 0x100, WORDS);
+        device.bytes[0xfc] = (base >> 12) as u8 | 0x80;
+        device.bytes[0] = (base >> 12) as u8;
+        m.bus.attach(Box::new(device)).unwrap();
+    }
+    for (addr, val) in [
+        (BASE, Some(0x00)),
+        (BASE + 0xfc, Some(0x80)),
+        (BASE + 0x100, None),
+        (BASE + 0xffc, None),
+        (BASE + 0x1000, Some(0x01)),
+        (BASE + 0x10fc, Some(0x81)),
+        (BASE + 0x1100, None),
+        (BASE - 4, None),
+    ] {
+        let result = memory::load_word(&mut m.bus, addr);
+        match val {
+            Some(val) => assert_eq!(result.unwrap(), val, "0x{:08x}", addr),
+            None => assert!(
+                matches!(result, Err(Exception::Load_access_fault)),
+                "0x{:08x}",
+                addr
+            ),
+        }
+        assert_eq!(m.bus.accepts(addr, 4), val.is_some(), "0x{:08x}", addr);
+        let store = memory::store_word(&mut m.bus, addr, 0);
+        assert_eq!(store.is_ok(), val.is_some(), "0x{:08x}", addr);
+    }
+}
+
+#[test]
+fn split_narrow() {
+    let mut m = Machine::new(1);
+    let mut device = Registers::new("registers", BASE, 0x10, WORDS);
+    device.bytes[4..8].copy_from_slice(&0x11223344u32.to_le_bytes());
+    m.bus.attach(Box::new(device)).unwrap();
+
+    assert!(m.bus.accepts(BASE + 5, 1));
+    assert_eq!(memory::load_hword(&mut m.bus, BASE + 6).unwrap(), 0x1122);
+    assert_eq!(memory::load_byte(&mut m.bus, BASE + 5).unwrap(), 0x33);
+    // read-modify-write of the containing register
+    memory::store_byte(&mut m.bus, BASE + 5, 0xab).unwrap();
+    memory::store_hword(&mut m.bus, BASE + 6, 0xcdef).unwrap();
+    let device = registers(&m, BASE);
+    assert_eq!(device.bytes[4..8], 0xcdefab44u32.to_le_bytes());
+    assert_eq!(
+        device.log,
+        [
+            (BASE + 4, 4, None),
+            (BASE + 4, 4, None),
+            (BASE + 4, 4, None),
+            (BASE + 4, 4, Some(0x1122ab44)),
+            (BASE + 4, 4, None),
+            (BASE + 4, 4, Some(0xcdefab44)),
+        ]
+    );
+}
+
+#[test]
+fn split_wide() {
+    let mut m = Machine::new(1);
+    let bytes = Access {
+        widths: &[1],
+        unsupported: Unsupported::Split,
+    };
+    m.bus
+        .attach(Box::new(Registers::new("registers", BASE, 0x10, bytes)))
+        .unwrap();
+
+    // low address first
+    memory::store_word(&mut m.bus, BASE + 4, 0x11223344).unwrap();
+    assert_eq!(memory::load_hword(&mut m.bus, BASE + 6).unwrap(), 0x1122);
+    let device = registers(&m, BASE);
+    assert_eq!(
+        device.log,
+        [
+            (BASE + 4, 1, Some(0x11223344)),
+            (BASE + 5, 1, Some(0x112233)),
+            (BASE + 6, 1, Some(0x1122)),
+            (BASE + 7, 1, Some(0x11)),
+            (BASE + 6, 1, None),
+            (BASE + 7, 1, None),
+        ]
+    );
+    assert_eq!(device.bytes[4..8], 0x11223344u32.to_le_bytes());
+}
+
+#[test]
+fn fault() {
+    let mut m = Machine::new(1);
+    let words = Access {
+        widths: &[4],
+        unsupported: Unsupported::Fault,
+    };
+    m.bus
+        .attach(Box::new(Registers::new("registers", BASE, 0x10, words)))
+        .unwrap();
+
+    assert!(m.bus.accepts(BASE + 4, 4));
+    assert!(!m.bus.accepts(BASE + 4, 1));
+    assert!(!m.bus.accepts(BASE + 4, 2));
+    assert!(matches!(
+        memory::load_byte(&mut m.bus, BASE + 4),
+        Err(Exception::Load_access_fault)
+    ));
+    assert!(matches!(
+        memory::load_hword(&mut m.bus, BASE + 4),
+        Err(Exception::Load_access_fault)
+    ));
+    assert!(matches!(
+        memory::store_byte(&mut m.bus, BASE + 4, 1),
+        Err(Exception::StoreAMO_access_fault)
+    ));
+    assert!(matches!(
+        memory::store_hword(&mut m.bus, BASE + 4, 1),
+        Err(Exception::StoreAMO_access_fault)
+    ));
+    // the device never sees the accesses
+    assert!(registers(&m, BASE).log.is_empty());
+    memory::store_word(&mut m.bus, BASE + 4, 1).unwrap();
+    assert_eq!(registers(&m, BASE).log, [(BASE + 4, 4, Some(1))]);
+}
@@ -0,0 +1,80 @@
+// Commit log lines as spike --log-commits prints them, parsed the way the lockstep comparison
+// reads the reference and formatted again.
+
+use std::fs;
+
+use super::*;
+use crate::core::commit_log::{CommitLog, Start, Trap};
+use crate::core::lockstep::{parse_commit, parse_trap};
+
+fn round_trip(line: &str) {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let commit = parse_commit(&tokens).unwrap_or_else(|| panic!("{} not parsed", line));
+    assert_eq!(commit.format(0), line);
+}
+
+#[test]
+fn spike_lines_round_trip() {
+    for line in [
+        // auipc t0, 0
+        "core   0: 3 0x80000000 (0x00000297) x5  0x80000000",
+        // lw a0, 0(t0)
+        "core   0: 3 0x80000004 (0x0002a503) x10 0x00000013 mem 0x80000000",
+        // sw a1, 0(a0)
+        "core   0: 1 0x80000008 (0x00b52023) mem 0x80001000 0x00000001",
+        // sb a1, 0(a0)
+        "core   0: 0 0x8000000c (0x00b50023) mem 0x80001000 0x01",
+        // c.li a0, 0
+        "core   0: 3 0x80000010 (0x4501) x10 0x00000000",
+        // fld ft0, 0(a0)
+        "core   0: 3 0x80000012 (0x00053007) f0  0x3ff0000000000000 mem 0x00000000",
+        // fsd ft1, 8(a0)
+        "core   0: 3 0x80000016 (0x00153427) mem 0x00000008 0x4000000000000000",
+        // csrw mtvec, t0
+        "core   0: 3 0x8000001a (0x30529073) c773_mtvec 0x80000000",
+        // fadd.s ft0, ft1, ft2 with fflags
+        "core   0: 3 0x8000001e (0x00208053) f0  0xffffffff3f800001 c1_fflags 0x00000001",
+    ] {
+        round_trip(line);
+    }
+}
+
+#[test]
+fn spike_traps_round_trip() {
+    let text = "core   0: exception trap_illegal_instruction, epc 0x80000004\n\
+                core   0:           tval 0x00000000";
+    assert_eq!(Trap::new(2, 0x80000004, 0).format(0), text);
+    let first: Vec<&str> = text.lines().next().unwrap().split_whitespace().collect();
+    let trap = parse_trap(&first).unwrap();
+    assert_eq!(
+        (trap.name.as_str(), trap.epc),
+        ("trap_illegal_instruction", 0x80000004)
+    );
+
+    // ecalls have no trap value
+    let text = "core   0: exception trap_machine_ecall, epc 0x80000008";
+    assert_eq!(Trap::new(11, 0x80000008, 0x1234).format(0), text);
+    let tokens: Vec<&str> = text.split_whitespace().collect();
+    let trap = parse_trap(&tokens).unwrap();
+    assert_eq!(trap, Trap::new(11, 0x80000008, 0));
+}
+
+#[test]
+fn supervisor_csrs_logged_as_machine_csrs() {
+    let path = std::env::temp_dir().join(format!("riscv_em_log_{}", std::process::id()));
+    let mut m = Machine::new(1);
+    // csrw sie, ra; csrw sip, ra
+    m.load(0, &[csrrw(0, 0x104, 1), csrrw(0, 0x144, 1)]);
+    m.set_reg(0, 1, 0x222);
+    let out = fs::File::create(&path).unwrap();
+    m.harts[0].core.commit_log = Some(CommitLog::new(Start::Instret(0), Some(out), None));
+    m.run(0, 2);
+    m.harts[0].core.commit_log = None;
+    let log = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        log,
+        "core   0: 3 0x80000000 (0x10409073) c772_mie 0x00000222\n\
+         core   0: 3 0x80000004 (0x14409073) c836_mip 0x00000000\n"
+    );
+}
@@ -0,0 +1,529 @@
+// RV32IMA instruction semantics, including the corner cases of the spec.
+// Operands are passed in x1 and x2, the result is read from x3.
+
+use super::*;
+use crate::core::instr_parse::compressed;
+
+const HANDLER: u32 = PROGRAM + 0x100;
+
+fn op(funct7: u32, funct3: u32) -> u32 {
+    r_type(0b0110011, 3, funct3, 1, 2, funct7)
+}
+
+fn op_imm(funct3: u32, imm: i32) -> u32 {
+    i_type(0b0010011, 3, funct3, 1, imm)
+}
+
+fn load(funct3: u32, offset: i32) -> u32 {
+    i_type(0b0000011, 3, funct3, 1, offset)
+}
+
+fn store(funct3: u32, offset: i32) -> u32 {
+    s_type(0b0100011, funct3, 1, 2, offset)
+}
+
+fn machine(x1: u32, x2: u32, program: &[u32]) -> Machine {
+    let mut m = Machine::new(1);
+    m.set_reg(0, 1, x1);
+    m.set_reg(0, 2, x2);
+    m.load(0, program);
+    csr::write(csr::Csr::mtvec, HANDLER, &mut m.harts[0].core);
+    m
+}
+
+// (instruction, x1, x2, x3 afterwards)
+fn check(cases: &[(u32, u32, u32, u32)]) {
+    for (instr, x1, x2, x3) in cases {
+        let mut m = machine(*x1, *x2, &[*instr]);
+        m.run(0, 1);
+        assert_eq!(m.pc(0), 4, "0x{:08x} trapped", instr);
+        assert_eq!(
+            m.reg(0, 3),
+            *x3,
+            "0x{:08x} with x1 = 0x{:08x}, x2 = 0x{:08x}",
+            instr,
+            x1,
+            x2
+        );
+    }
+}
+
+// the second instruction of the program traps, returns mcause and mtval
+fn trap(instr: u32, x1: u32, x2: u32) -> (u32, u32) {
+    let mut m = machine(x1, x2, &[NOP, instr]);
+    m.run(0, 2);
+    let core = &m.harts[0].core;
+    assert_eq!(core.pc, HANDLER, "0x{:08x} didn't trap", instr);
+    assert_eq!(csr::read(csr::Csr::mepc, core), PROGRAM + 4);
+    (csr::read(csr::Csr::mcause, core), csr::read(csr::Csr::mtval, core))
+}
+
+#[test]
+fn add_sub() {
+    let (add, sub) = (op(0x00, 0), op(0x20, 0));
+    check(&[
+        (add, 1, 2, 3),
+        (add, 0x7fffffff, 1, 0x80000000),
+        (add, 0xffffffff, 1, 0),
+        (sub, 1, 2, 0xffffffff),
+        (sub, 0x80000000, 1, 0x7fffffff),
+        (op_imm(0, -1), 0, 0, 0xffffffff),
+        (op_imm(0, 2047), 1, 0, 0x800),
+        (op_imm(0, -2048), 0x80000000, 0, 0x7ffff800),
+    ]);
+}
+
+#[test]
+fn logic() {
+    check(&[
+        (op(0, 4), 0xff00ff00, 0x0ff00ff0, 0xf0f0f0f0),
+        (op(0, 6), 0xff00ff00, 0x0ff00ff0, 0xfff0fff0),
+        (op(0, 7), 0xff00ff00, 0x0ff00ff0, 0x0f000f00),
+        // immediates are sign extended
+        (op_imm(4, -1), 0x12345678, 0, 0xedcba987),
+        (op_imm(6, -2048), 0x00000001, 0, 0xfffff801),
+        (op_imm(7, 0x7ff), 0xffffffff, 0, 0x000007ff),
+    ]);
+}
+
+#[test]
+fn set_less_than() {
+    let (slt, sltu) = (op(0, 2), op(0, 3));
+    check(&[
+        (slt, 0xffffffff, 1, 1),
+        (slt, 1, 0xffffffff, 0),
+        (slt, 5, 5, 0),
+        (sltu, 0xffffffff, 1, 0),
+        (sltu, 1, 0xffffffff, 1),
+        (op_imm(2, 0), 0x80000000, 0, 1),
+        // seqz
+        (op_imm(3, 1), 0, 0, 1),
+        (op_imm(3, 1), 7, 0, 0),
+        // the immediate is sign extended, then compared unsigned
+        (op_imm(3, -1), 0xfffffffe, 0, 1),
+    ]);
+}
+
+#[test]
+fn shifts() {
+    let (sll, srl, sra) = (op(0x00, 1), op(0x00, 5), op(0x20, 5));
+    check(&[
+        (sll, 1, 31, 0x80000000),
+        (srl, 0x80000000, 31, 1),
+        (sra, 0x80000000, 31, 0xffffffff),
+        (sra, 0x40000000, 30, 1),
+        // only the low 5 bits of the shift amount count
+        (sll, 1, 32, 1),
+        (sll, 1, 33, 2),
+        (srl, 0x80000000, 0xffffffe1, 0x40000000),
+        (sra, 0x80000000, 33, 0xc0000000),
+        (sra, 0x80000000, 0xffffffff, 0xffffffff),
+        (op_imm(1, 4), 0x0f00000f, 0, 0xf00000f0),
+        (op_imm(5, 4), 0xf000000f, 0, 0x0f000000),
+        (op_imm(5, 0x400 | 4), 0x8000000f, 0, 0xf8000000),
+    ]);
+}
+
+#[test]
+fn reserved_shift_encodings() {
+    for instr in [op_imm(1, 0x400 | 1), op_imm(1, 0x20), op_imm(5, 0x20 | 1)] {
+        assert_eq!(trap(instr, 1, 0), (2, instr));
+    }
+}
+
+#[test]
+fn upper_immediates() {
+    check(&[
+        (u_type(0b0110111, 3, 0xfffff), 0, 0, 0xfffff000),
+        (u_type(0b0110111, 3, 0x00001), 0, 0, 0x00001000),
+        (u_type(0b0010111, 3, 0x00001), 0, 0, PROGRAM + 0x1000),
+        // wraps around
+        (u_type(0b0010111, 3, 0x80000), 0, 0, PROGRAM.wrapping_add(0x80000000)),
+    ]);
+}
+
+#[test]
+fn jumps() {
+    // jal links the next instruction
+    let mut m = machine(0, 0, &[j_type(3, 8)]);
+    m.run(0, 1);
+    assert_eq!((m.pc(0), m.reg(0, 3)), (8, PROGRAM + 4));
+
+    let mut m = machine(0, 0, &[NOP, j_type(3, -4)]);
+    m.run(0, 2);
+    assert_eq!(m.pc(0), 0);
+
+    // jalr clears the lowest bit of the target
+    let mut m = machine(PROGRAM + 0x11, 0, &[i_type(0b1100111, 3, 0, 1, 0)]);
+    m.run(0, 1);
+    assert_eq!((m.pc(0), m.reg(0, 3)), (0x10, PROGRAM + 4));
+
+    // target is computed before rd is written
+    let mut m = machine(PROGRAM + 0x20, 0, &[i_type(0b1100111, 1, 0, 1, -8)]);
+    m.run(0, 1);
+    assert_eq!((m.pc(0), m.reg(0, 1)), (0x18, PROGRAM + 4));
+}
+
+#[test]
+fn branches() {
+    // (funct3, x1, x2, taken)
+    let cases = [
+        (0b000, 5, 5, true),
+        (0b000, 5, 6, false),
+        (0b001, 5, 6, true),
+        (0b001, 5, 5, false),
+        (0b100, 0xffffffff, 1, true),
+        (0b100, 1, 0xffffffff, false),
+        (0b100, 5, 5, false),
+        (0b101, 5, 5, true),
+        (0b101, 0xffffffff, 1, false),
+        (0b110, 0xffffffff, 1, false),
+        (0b110, 1, 0xffffffff, true),
+        (0b111, 0xffffffff, 1, true),
+        (0b111, 5, 5, true),
+        (0b111, 1, 0xffffffff, false),
+    ];
+    for (funct3, x1, x2, taken) in cases {
+        let mut m = machine(x1, x2, &[b_type(funct3, 1, 2, 12)]);
+        m.run(0, 1);
+        let expected = if taken { 12 } else { 4 };
+        assert_eq!(m.pc(0), expected, "funct3 {} x1 0x{:08x} x2 0x{:08x}", funct3, x1, x2);
+    }
+
+    let mut m = machine(0, 0, &[NOP, NOP, b_type(0, 0, 0, -8)]);
+    m.run(0, 3);
+    assert_eq!(m.pc(0), 0);
+
+    // a branch to itself stays there
+    let mut m = machine(0, 0, &[b_type(0, 0, 0, 0)]);
+    m.run(0, 3);
+    assert_eq!(m.pc(0), 0);
+}
+
+#[test]
+fn loads() {
+    let word = 0x80ff7f01;
+    let cases = [
+        (load(0, 0), 0x00000001),
+        (load(0, 1), 0x0000007f),
+        (load(0, 2), 0xffffffff),
+        (load(0, 3), 0xffffff80),
+        (load(4, 2), 0x000000ff),
+        (load(4, 3), 0x00000080),
+        (load(1, 0), 0x00007f01),
+        (load(1, 2), 0xffff80ff),
+        (load(5, 2), 0x000080ff),
+        (load(2, 0), word),
+    ];
+    for (instr, x3) in cases {
+        let mut m = machine(DATA, 0, &[instr]);
+        m.bus.ram.store_word(DATA, word);
+        m.run(0, 1);
+        assert_eq!(m.reg(0, 3), x3, "0x{:08x}", instr);
+    }
+
+    let mut m = machine(DATA + 8, 0, &[load(2, -8)]);
+    m.bus.ram.store_word(DATA, word);
+    m.run(0, 1);
+    assert_eq!(m.reg(0, 3), word);
+}
+
+#[test]
+fn stores() {
+    // (instruction, word afterwards)
+    let cases = [
+        (store(0, 1), 0x1122dd44),
+        (store(1, 2), 0xccdd3344),
+        (store(2, 0), 0xaabbccdd),
+    ];
+    for (instr, word) in cases {
+        let mut m = machine(DATA, 0xaabbccdd, &[instr]);
+        m.bus.ram.store_word(DATA, 0x11223344);
+        m.bus.ram.store_word(DATA + 4, 0x55667788);
+        m.run(0, 1);
+        assert_eq!(m.bus.ram.load_word(DATA), word, "0x{:08x}", instr);
+        assert_eq!(m.bus.ram.load_word(DATA + 4), 0x55667788);
+    }
+
+    let mut m = machine(DATA + 8, 0xaabbccdd, &[store(2, -4)]);
+    m.run(0, 1);
+    assert_eq!(m.bus.ram.load_word(DATA + 4), 0xaabbccdd);
+}
+
+#[test]
+fn misaligned_accesses_trap() {
+    assert_eq!(trap(load(2, 2), DATA, 0), (4, DATA + 2));
+    assert_eq!(trap(load(1, 1), DATA, 0), (4, DATA + 1));
+    assert_eq!(trap(load(5, 3), DATA, 0), (4, DATA + 3));
+    assert_eq!(trap(store(2, 1), DATA, 0), (6, DATA + 1));
+    assert_eq!(trap(store(1, 3), DATA, 0), (6, DATA + 3));
+    assert_eq!(trap(lr_w(3, 1), DATA + 2, 0), (4, DATA + 2));
+    assert_eq!(trap(sc_w(3, 1, 2), DATA + 2, 0), (6, DATA + 2));
+    // AMOs raise store faults
+    assert_eq!(trap(amo(0b00000, 3, 1, 2), DATA + 2, 0), (6, DATA + 2));
+}
+
+#[test]
+fn access_faults() {
+    // nothing is mapped there, mtval is the address
+    let unmapped = 0x4000_0000;
+    assert_eq!(trap(load(2, 4), unmapped, 0), (5, unmapped + 4));
+    assert_eq!(trap(load(4, 0), unmapped, 0), (5, unmapped));
+    assert_eq!(trap(store(0, 8), unmapped, 0), (7, unmapped + 8));
+    assert_eq!(trap(lr_w(3, 1), unmapped, 0), (5, unmapped));
+    assert_eq!(trap(amo(0b00000, 3, 1, 2), unmapped, 0), (7, unmapped));
+
+    // the CLINT only implements word accesses
+    let (clint, _) = clint::Clint::default().region();
+    assert_eq!(trap(load(0, 0), clint, 0), (5, clint));
+    assert_eq!(trap(store(1, 0), clint, 0), (7, clint));
+    let mut m = machine(clint, 0, &[load(2, 0)]);
+    m.run(0, 1);
+    assert_eq!(m.pc(0), 4);
+}
+
+#[test]
+fn multiply() {
+    let (mul, mulh, mulhsu, mulhu) = (op(1, 0), op(1, 1), op(1, 2), op(1, 3));
+    check(&[
+        (mul, 3, 0xfffffffe, 0xfffffffa),
+        (mul, 0x80000000, 0xffffffff, 0x80000000),
+        (mul, 0x10000, 0x10000, 0),
+        (mulh, 0x80000000, 0x80000000, 0x40000000),
+        (mulh, 0xffffffff, 0xffffffff, 0),
+        (mulh, 0x80000000, 1, 0xffffffff),
+        (mulhsu, 0xffffffff, 0xffffffff, 0xffffffff),
+        (mulhsu, 0x80000000, 0xffffffff, 0x80000000),
+        (mulhsu, 1, 0x80000000, 0),
+        (mulhu, 0xffffffff, 0xffffffff, 0xfffffffe),
+        (mulhu, 0x80000000, 2, 1),
+    ]);
+}
+
+#[test]
+fn divide() {
+    let (div, divu, rem, remu) = (op(1, 4), op(1, 5), op(1, 6), op(1, 7));
+    check(&[
+        (div, 7, 2, 3),
+        // rounds towards zero
+        (div, 0xfffffff9, 2, 0xfffffffd),
+        (divu, 0xffffffff, 2, 0x7fffffff),
+        (rem, 0xfffffff9, 2, 0xffffffff),
+        (rem, 7, 0xfffffffe, 1),
+        (remu, 0xffffffff, 10, 5),
+        // division by zero
+        (div, 7, 0, 0xffffffff),
+        (div, 0x80000000, 0, 0xffffffff),
+        (divu, 7, 0, 0xffffffff),
+        (rem, 0xfffffff9, 0, 0xfffffff9),
+        (remu, 7, 0, 7),
+        // overflow
+        (div, 0x80000000, 0xffffffff, 0x80000000),
+        (rem, 0x80000000, 0xffffffff, 0),
+    ]);
+}
+
+#[test]
+fn atomics() {
+    // (funct5, value in memory, x2, value stored)
+    let cases = [
+        (0b00001, 5, 9, 9),
+        (0b00000, 0xffffffff, 1, 0),
+        (0b00100, 0xff00ff00, 0x0ff00ff0, 0xf0f0f0f0),
+        (0b01100, 0xff00ff00, 0x0ff00ff0, 0x0f000f00),
+        (0b01000, 0xff00ff00, 0x0ff00ff0, 0xfff0fff0),
+        (0b10000, 0xffffffff, 1, 0xffffffff),
+        (0b10100, 0xffffffff, 1, 1),
+        (0b11000, 0xffffffff, 1, 1),
+        (0b11100, 0xffffffff, 1, 0xffffffff),
+    ];
+    for (funct5, old, x2, new) in cases {
+        let mut m = machine(DATA, x2, &[amo(funct5, 3, 1, 2)]);
+        m.bus.ram.store_word(DATA, old);
+        m.run(0, 1);
+        assert_eq!(m.reg(0, 3), old, "funct5 {:05b}", funct5);
+        assert_eq!(m.bus.ram.load_word(DATA), new, "funct5 {:05b}", funct5);
+    }
+
+    // rd = rs2, the old value of rs2 is stored
+    let mut m = machine(DATA, 3, &[amo(0b00000, 2, 1, 2)]);
+    m.bus.ram.store_word(DATA, 4);
+    m.run(0, 1);
+    assert_eq!((m.reg(0, 2), m.bus.ram.load_word(DATA)), (4, 7));
+
+    // store/AMO page faults on pages that are missing, not readable or not writable
+    for flags in [0, PTE_X | PTE_A, PTE_R | PTE_A] {
+        let mut m = machine(0x20000, 1, &[amo(0b00000, 3, 1, 2)]);
+        m.supervisor(0, 0);
+        m.map(0x10000, PROGRAM, PTE_R | PTE_X | PTE_A);
+        if flags != 0 {
+            m.map(0x20000, DATA, flags);
+        }
+        m.harts[0].core.pc = 0x10000;
+        m.run(0, 1);
+        let core = &m.harts[0].core;
+        assert_eq!(core.pc, HANDLER);
+        assert_eq!(csr::read(csr::Csr::mcause, core), 15, "flags {:02x}", flags);
+        assert_eq!(csr::read(csr::Csr::mtval, core), 0x20000);
+    }
+}
+
+#[test]
+fn x0_is_zero() {
+    // writes to x0 are discarded, add x3, x0, x0
+    let add = r_type(0b0110011, 3, 0, 0, 0, 0);
+    let mut m = machine(DATA, 0, &[addi(0, 0, 5), add, i_type(0b0000011, 0, 2, 1, 0), add]);
+    m.bus.ram.store_word(DATA, 1);
+    m.run(0, 2);
+    assert_eq!(m.reg(0, 3), 0);
+    m.run(0, 2);
+    assert_eq!(m.reg(0, 3), 0);
+}
+
+#[test]
+fn system() {
+    assert_eq!(trap(ECALL, 0, 0).0, 11);
+    assert_eq!(trap(0x00100073, 0, 0).0, 3);
+    // unknown funct7 of an OP instruction
+    let illegal = op(0x02, 0);
+    assert_eq!(trap(illegal, 0, 0), (2, illegal));
+
+    // fence
+    let mut m = machine(0, 0, &[0x0ff0000f]);
+    m.run(0, 1);
+    assert_eq!(m.pc(0), 4);
+}
+
+#[test]
+fn compressed_expansion() {
+    // (parcel, instruction it expands to), both encoded by llvm-mc
+    let cases = [
+        (0x1fe8, 0x3fc10513), // c.addi4spn a0, sp, 1020
+        (0x3fe8, 0x0f87b507), // c.fld fa0, 248(a5)
+        (0x5d6c, 0x07c52583), // c.lw a1, 124(a0)
+        (0x60ac, 0x0404a587), // c.flw fa1, 64(s1)
+        (0xa600, 0x00863427), // c.fsd fs0, 8(a2)
+        (0xc290, 0x00c6a023), // c.sw a2, 0(a3)
+        (0xfc7c, 0x06f42e27), // c.fsw fa5, 124(s0)
+        (0x0001, 0x00000013), // c.nop
+        (0x1501, 0xfe050513), // c.addi a0, -32
+        (0x3001, 0x801ff0ef), // c.jal -2048
+        (0x42fd, 0x01f00293), // c.li t0, 31
+        (0x7101, 0xe0010113), // c.addi16sp sp, -512
+        (0x7501, 0xfffe0537), // c.lui a0, 0xfffe0
+        (0x807d, 0x01f45413), // c.srli s0, 31
+        (0x8785, 0x4017d793), // c.srai a5, 1
+        (0x9b7d, 0xfff77713), // c.andi a4, -1
+        (0x8c89, 0x40a484b3), // c.sub s1, a0
+        (0x8db1, 0x00c5c5b3), // c.xor a1, a2
+        (0x8ed9, 0x00e6e6b3), // c.or a3, a4
+        (0x8fe1, 0x0087f7b3), // c.and a5, s0
+        (0xaffd, 0x7fe0006f), // c.j 2046
+        (0xd081, 0xf00480e3), // c.beqz s1, -256
+        (0xed7d, 0x0e051f63), // c.bnez a0, 254
+        (0x00fe, 0x01f09093), // c.slli ra, 31
+        (0x357e, 0x1f813507), // c.fldsp fa0, 504(sp)
+        (0x50fe, 0x0fc12083), // c.lwsp ra, 252(sp)
+        (0x6002, 0x00012007), // c.flwsp ft0, 0(sp)
+        (0x8082, 0x00008067), // c.jr ra
+        (0x852e, 0x00b00533), // c.mv a0, a1
+        (0x9002, 0x00100073), // c.ebreak
+        (0x9282, 0x000280e7), // c.jalr t0
+        (0x952e, 0x00b50533), // c.add a0, a1
+        (0xa226, 0x10913027), // c.fsdsp fs1, 256(sp)
+        (0xdfa2, 0x0e812e23), // c.swsp s0, 252(sp)
+        (0xe22a, 0x00a12227), // c.fswsp fa0, 4(sp)
+    ];
+    for (parcel, instr) in cases {
+        let expanded = compressed::expand(parcel).ok();
+        assert_eq!(expanded, Some(instr), "0x{:04x}", parcel);
+    }
+}
+
+#[test]
+fn compressed_reserved_encodings() {
+    let cases = [
+        (0x0000, "all zero"),
+        (0x0004, "c.addi4spn with nzuimm 0"),
+        (0x8000, "quadrant 0, funct3 100"),
+        (0x6101, "c.addi16sp with nzimm 0"),
+        (0x6501, "c.lui with nzimm 0"),
+        (0x9005, "c.srli with shamt[5]"),
+        (0x9405, "c.srai with shamt[5]"),
+        (0x1086, "c.slli with shamt[5]"),
+        (0x9c01, "c.subw, rv64 only"),
+        (0x4002, "c.lwsp with rd 0"),
+        (0x8002, "c.jr with rs1 0"),
+    ];
+    for (parcel, name) in cases {
+        assert!(compressed::expand(parcel).is_err(), "{}", name);
+        // the parcel is in mtval
+        assert_eq!(trap(parcel as u32, 0, 0), (2, parcel as u32), "{}", name);
+    }
+}
+
+#[test]
+fn compressed_instructions_execute() {
+    // c.li x3, 5; addi x3, x3, 10 at a halfword boundary; c.addi x3, 1
+    let addi = addi(3, 3, 10);
+    let program = [0x4195 | addi << 16, addi >> 16 | 0x0185 << 16];
+    let mut m = machine(0, 0, &program);
+    m.run(0, 3);
+    assert_eq!((m.pc(0), m.reg(0, 3)), (8, 16));
+}
+
+// the halves of a 32-bit instruction on two pages, mapped to unrelated frames
+#[test]
+fn fetch_across_page_boundary() {
+    let addi = addi(3, 0, 42);
+    let mut m = machine(0, 0, &[]);
+    m.supervisor(0, 0);
+    m.map(0x10000, DATA, PTE_R | PTE_X | PTE_A);
+    m.map(0x11000, DATA + 0x3000, PTE_R | PTE_X | PTE_A);
+    m.bus.ram.store_hword(DATA + 0xffe, addi as u16);
+    m.bus.ram.store_hword(DATA + 0x3000, (addi >> 16) as u16);
+    m.harts[0].core.pc = 0x10ffe;
+    m.run(0, 1);
+    assert_eq!((m.harts[0].core.pc, m.reg(0, 3)), (0x11002, 42));
+
+    // the second page is not mapped, mtval is the address of the second half
+    let mut m = machine(0, 0, &[]);
+    m.supervisor(0, 0);
+    m.map(0x10000, DATA, PTE_R | PTE_X | PTE_A);
+    m.bus.ram.store_hword(DATA + 0xffe, addi as u16);
+    m.harts[0].core.pc = 0x10ffe;
+    m.run(0, 1);
+    let core = &m.harts[0].core;
+    assert_eq!(core.pc, HANDLER);
+    assert_eq!(csr::read(csr::Csr::mcause, core), 12);
+    assert_eq!(csr::read(csr::Csr::mtval, core), 0x11000);
+    assert_eq!(csr::read(csr::Csr::mepc, core), 0x10ffe);
+}
+
+#[test]
+fn epc_bit_0_is_zero() {
+    for epc in [0x341, 0x141] {
+        let mut m = machine(PROGRAM + 0x23, 0, &[csrrw(0, epc, 1), csrrs(3, epc, 0)]);
+        m.run(0, 2);
+        assert_eq!(m.reg(0, 3), PROGRAM + 0x22, "csr 0x{:03x}", epc);
+    }
+
+    // mret returns to the masked address
+    let mut m = machine(PROGRAM + 0x11, 0, &[csrrw(0, 0x341, 1), MRET]);
+    m.run(0, 2);
+    assert_eq!(m.pc(0), 0x10);
+}
+
+#[test]
+fn last_pmp_entry_is_checked() {
+    let mut m = machine(DATA, 0x1234, &[store(0b010, 0)]);
+    let core = &mut m.harts[0].core;
+    // entry 15, the top byte of pmpcfg3: locked, NA4, read only
+    csr::write(csr::Csr::pmpaddr15, DATA >> 2, core);
+    csr::write(csr::Csr::pmpcfg3, (0x80 | 0b10 << 3 | 0b001) << 24, core);
+    m.run(0, 1);
+    let core = &m.harts[0].core;
+    assert_eq!(core.pc, HANDLER);
+    assert_eq!(csr::read(csr::Csr::mcause, core), 7);
+    assert_eq!(m.bus.ram.load_word(DATA), 0);
+}
@@ -0,0 +1,142 @@
+// The generated device tree, read back the way the kernel walks the structure block.
+
+use super::*;
+use crate::memory::{syscon::Syscon, virtio::VirtioDevice, virtio_blk::VirtioBlk};
+use crate::{BOOTARGS, device_tree, fdt, initrd_addr};
+
+fn be_u32(dtb: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(dtb[offset..offset + 4].try_into().unwrap())
+}
+
+type Props = Vec<(String, Vec<u8>)>;
+
+fn string(dtb: &[u8], offset: usize) -> String {
+    let len = dtb[offset..].iter().position(|x| *x == 0).unwrap();
+    String::from_utf8(dtb[offset..offset + len].to_vec()).unwrap()
+}
+
+// path of every node, "/" for the root, with its properties
+fn nodes(dtb: &[u8]) -> Vec<(String, Props)> {
+    let strings = be_u32(dtb, 12) as usize;
+    let mut offset = be_u32(dtb, 8) as usize;
+    let mut path: Vec<String> = Vec::new();
+    let mut nodes: Vec<(String, Props)> = Vec::new();
+    loop {
+        let token = be_u32(dtb, offset);
+        offset += 4;
+        match token {
+            // FDT_BEGIN_NODE
+            0x1 => {
+                let name = string(dtb, offset);
+                offset = (offset + name.len() + 1).next_multiple_of(4);
+                path.push(name);
+                nodes.push((format!("/{}", path[1..].join("/")), Vec::new()));
+            }
+            // FDT_END_NODE
+            0x2 => {
+                path.pop();
+            }
+            // FDT_PROP
+            0x3 => {
+                let len = be_u32(dtb, offset) as usize;
+                let name = string(dtb, strings + be_u32(dtb, offset + 4) as usize);
+                let value = dtb[offset + 8..offset + 8 + len].to_vec();
+                nodes.last_mut().unwrap().1.push((name, value));
+                offset = (offset + 8 + len).next_multiple_of(4);
+            }
+            // FDT_END
+            0x9 => return nodes,
+            token => panic!("token 0x{:x} at 0x{:x}", token, offset - 4),
+        }
+    }
+}
+
+fn prop<'a>(nodes: &'a [(String, Props)], node: &str, name: &str) -> Option<&'a [u8]> {
+    let (_, props) = nodes.iter().find(|(path, _)| path == node)?;
+    let (_, value) = props.iter().find(|(x, _)| x == name)?;
+    Some(value)
+}
+
+#[test]
+fn generated_tree() {
+    let mut m = Machine::new(2);
+    let blk = VirtioBlk::default();
+    m.bus
+        .attach(Box::new(VirtioDevice::new(0, Box::new(blk))))
+        .unwrap();
+    let dtb = fdt::generate(&m.harts, &m.bus, "console=hvc0", None);
+
+    assert_eq!(fdt::count_cpus(&dtb), Some(2));
+    let nodes = nodes(&dtb);
+    for node in [
+        "/chosen",
+        "/cpus/cpu@0",
+        "/cpus/cpu@1/interrupt-controller",
+        &format!("/memory@{:x}", RAM_OFFSET),
+        "/soc/plic@c000000",
+        "/soc/virtio@4200000",
+    ] {
+        assert!(nodes.iter().any(|(x, _)| x == node), "{} missing", node);
+    }
+}
+
+#[test]
+fn syscon_drivers() {
+    let mut m = Machine::new(1);
+    let dtb = fdt::generate(&m.harts, &m.bus, "", None);
+    assert!(prop(&nodes(&dtb), "/poweroff", "compatible").is_none());
+
+    m.bus.attach(Box::new(Syscon::default())).unwrap();
+    let dtb = fdt::generate(&m.harts, &m.bus, "", None);
+    let nodes = nodes(&dtb);
+    let phandle = prop(&nodes, "/soc/syscon@1c00000", "phandle").unwrap();
+    for (name, value) in [("poweroff", 1u32), ("reboot", 2)] {
+        let node = format!("/{}", name);
+        let compatible = format!("syscon-{}\0", name);
+        assert_eq!(
+            prop(&nodes, &node, "compatible"),
+            Some(compatible.as_bytes())
+        );
+        assert_eq!(prop(&nodes, &node, "regmap"), Some(phandle));
+        assert_eq!(prop(&nodes, &node, "offset"), Some(&[0u8; 4][..]));
+        assert_eq!(prop(&nodes, &node, "value"), Some(&value.to_be_bytes()[..]));
+    }
+}
+
+#[test]
+fn chosen_initrd() {
+    let m = Machine::new(1);
+    let initrd = std::env::temp_dir().join(format!("riscv_em_initrd_{}", std::process::id()));
+    std::fs::write(&initrd, [0; 100]).unwrap();
+    let dtb = device_tree(&m.harts, &m.bus, None, initrd.to_str());
+    std::fs::remove_file(&initrd).unwrap();
+    let nodes = nodes(&dtb.unwrap());
+    let start = initrd_addr(m.bus.ram.region());
+    let bootargs = format!("{}\0", BOOTARGS);
+    assert_eq!(
+        prop(&nodes, "/chosen", "bootargs"),
+        Some(bootargs.as_bytes())
+    );
+    assert_eq!(
+        prop(&nodes, "/chosen", "linux,initrd-start"),
+        Some(&start.to_be_bytes()[..])
+    );
+    assert_eq!(
+        prop(&nodes, "/chosen", "linux,initrd-end"),
+        Some(&(start + 100).to_be_bytes()[..])
+    );
+}
+
+#[test]
+fn append_replaces_bootargs() {
+    let m = Machine::new(1);
+    // the default command line is replaced, no initrd properties without --initrd
+    let dtb = device_tree(&m.harts, &m.bus, Some("console=hvc0"), None).unwrap();
+    let nodes = nodes(&dtb);
+    assert_eq!(
+        prop(&nodes, "/chosen", "bootargs"),
+        Some(&b"console=hvc0\0"[..])
+    );
+    assert!(prop(&nodes, "/chosen", "linux,initrd-start").is_none());
+    assert!(prop(&nodes, "/chosen", "linux,initrd-end").is_none());
+}
@@ -0,0 +1,229 @@
+// Floating point: rounding, exception flags and NaN-boxing. The arithmetic is checked on
+// the fpu functions directly, the register file and fcsr through executed instructions.
+
+use super::*;
+use crate::core::fpu::{self, F32, FLAG_NV, FLAG_NX, FLAG_OF, FLAG_UF, Rounding};
+
+const ONE: u64 = 0x3f800000;
+const TWO: u64 = 0x40000000;
+const QNAN: u64 = 0x7fc00000;
+const SNAN: u64 = 0x7f800001;
+const INF: u64 = 0x7f800000;
+const NEG_INF: u64 = 0xff800000;
+const MAX: u64 = 0x7f7fffff;
+const NEG_MAX: u64 = 0xff7fffff;
+const MIN_NORMAL: u64 = 0x00800000;
+const NEG_ZERO: u64 = 0x80000000;
+
+const MODES: [Rounding; 5] = [
+    Rounding::Rne,
+    Rounding::Rtz,
+    Rounding::Rdn,
+    Rounding::Rup,
+    Rounding::Rmm,
+];
+
+// FS initial, mtvec set
+fn machine(program: &[u32]) -> Machine {
+    let mut m = Machine::new(1);
+    let core = &mut m.harts[0].core;
+    csr::write(csr::Csr::mstatus, 0b01 << 13, core);
+    csr::write(csr::Csr::mtvec, PROGRAM + 0x100, core);
+    m.load(0, program);
+    m
+}
+
+fn fadd_s(rd: u32, rs1: u32, rs2: u32, rm: u32) -> u32 {
+    r_type(0b1010011, rd, rm, rs1, rs2, 0b0000000)
+}
+
+#[test]
+fn rounding_modes() {
+    // 1 + 2^-24 is halfway between 1 and the next float
+    let tie = 0x33800000;
+    // 1 + 3 * 2^-24 is halfway between two floats, the upper one is even
+    let tie_odd = 0x34400000;
+    let expected = [
+        (ONE, tie, [ONE, ONE, ONE, 0x3f800001, 0x3f800001]),
+        (
+            ONE | NEG_ZERO,
+            tie | NEG_ZERO,
+            [0xbf800000, 0xbf800000, 0xbf800001, 0xbf800000, 0xbf800001],
+        ),
+        (
+            ONE,
+            tie_odd,
+            [0x3f800002, 0x3f800001, 0x3f800001, 0x3f800002, 0x3f800002],
+        ),
+    ];
+    for (a, b, results) in expected {
+        for (rm, result) in MODES.into_iter().zip(results) {
+            assert_eq!(fpu::add(a, b, F32, rm), (result, FLAG_NX), "{:?}", rm);
+        }
+    }
+    // exact results raise nothing
+    for rm in MODES {
+        assert_eq!(fpu::add(ONE, ONE, F32, rm), (TWO, 0));
+    }
+}
+
+#[test]
+fn overflow() {
+    let flags = FLAG_OF | FLAG_NX;
+    let positive = [INF, MAX, MAX, INF, INF];
+    let negative = [NEG_INF, NEG_MAX, NEG_INF, NEG_MAX, NEG_INF];
+    for (rm, (pos, neg)) in MODES.into_iter().zip(positive.into_iter().zip(negative)) {
+        assert_eq!(fpu::mul(MAX, TWO, F32, rm), (pos, flags), "{:?}", rm);
+        assert_eq!(fpu::mul(NEG_MAX, TWO, F32, rm), (neg, flags), "{:?}", rm);
+    }
+}
+
+#[test]
+fn subnormal_results() {
+    let half = 0x3f000000;
+    let rm = Rounding::Rne;
+    // exact subnormal, no underflow
+    assert_eq!(fpu::mul(MIN_NORMAL, half, F32, rm), (0x00400000, 0));
+    // inexact subnormal
+    let flags = FLAG_UF | FLAG_NX;
+    assert_eq!(fpu::mul(0x00800001, half, F32, rm), (0x00400000, flags));
+    // 2^-126 * (1 - 2^-24) is tiny even with an unbounded exponent, rounds up to 2^-126
+    assert_eq!(
+        fpu::mul(0x3f7fffff, MIN_NORMAL, F32, rm),
+        (MIN_NORMAL, flags)
+    );
+    // 2^-126 * (1 - 2^-26) rounds to 2^-126 first, so it is not tiny after rounding
+    let product = fpu::mul(0x3f800400, 0x007ffc00, F32, rm);
+    assert_eq!(product, (MIN_NORMAL, FLAG_NX));
+    // towards zero it stays below 2^-126
+    let product = fpu::mul(0x3f800400, 0x007ffc00, F32, Rounding::Rtz);
+    assert_eq!(product, (0x007fffff, flags));
+}
+
+#[test]
+fn fcvt_w_saturates() {
+    let rtz = Rounding::Rtz;
+    // 2^31
+    let big = 0x4f000000;
+    let minus_one = ONE | NEG_ZERO;
+    let signed = [
+        (QNAN, 0x7fffffff, FLAG_NV),
+        (INF, 0x7fffffff, FLAG_NV),
+        (NEG_INF, 0x80000000, FLAG_NV),
+        (big, 0x7fffffff, FLAG_NV),
+        (big | NEG_ZERO, 0x80000000, 0),
+        (0x3fc00000, 1, FLAG_NX),
+    ];
+    for (a, result, flags) in signed {
+        assert_eq!(fpu::to_int(a, F32, rtz, true), (result, flags));
+    }
+    let unsigned = [
+        (QNAN, 0xffffffff, FLAG_NV),
+        (INF, 0xffffffff, FLAG_NV),
+        (NEG_INF, 0, FLAG_NV),
+        (big, 0x80000000, 0),
+        (minus_one, 0, FLAG_NV),
+        // rounds to zero, which fits
+        (0xbf000000, 0, FLAG_NX),
+    ];
+    for (a, result, flags) in unsigned {
+        assert_eq!(fpu::to_int(a, F32, rtz, false), (result, flags));
+    }
+    // 1.5 to nearest even
+    assert_eq!(
+        fpu::to_int(0x3fc00000, F32, Rounding::Rne, true),
+        (2, FLAG_NX)
+    );
+}
+
+#[test]
+fn min_max() {
+    let canonical = F32.canonical_nan();
+    let cases = [
+        // a signaling NaN is invalid but the other operand is still returned
+        (SNAN, ONE, ONE, ONE, FLAG_NV),
+        (ONE, SNAN, ONE, ONE, FLAG_NV),
+        (QNAN, ONE, ONE, ONE, 0),
+        (QNAN, SNAN, canonical, canonical, FLAG_NV),
+        (QNAN, QNAN, canonical, canonical, 0),
+        // -0 is less than +0
+        (NEG_ZERO, 0, NEG_ZERO, 0, 0),
+        (0, NEG_ZERO, NEG_ZERO, 0, 0),
+    ];
+    for (a, b, min, max, flags) in cases {
+        assert_eq!(fpu::min_max(a, b, F32, false), (min, flags));
+        assert_eq!(fpu::min_max(a, b, F32, true), (max, flags));
+    }
+}
+
+#[test]
+fn fma_inf_times_zero() {
+    let canonical = F32.canonical_nan();
+    let rm = Rounding::Rne;
+    // invalid even though the addend is a quiet NaN
+    let fma = fpu::fma(INF, 0, QNAN, false, false, F32, rm);
+    assert_eq!(fma, (canonical, FLAG_NV));
+    let fma = fpu::fma(0, NEG_INF, QNAN, true, true, F32, rm);
+    assert_eq!(fma, (canonical, FLAG_NV));
+    // a quiet NaN addend alone is not
+    assert_eq!(
+        fpu::fma(ONE, 0, QNAN, false, false, F32, rm),
+        (canonical, 0)
+    );
+}
+
+#[test]
+fn dynamic_rounding_mode() {
+    let mut m = machine(&[fadd_s(3, 1, 2, 0b111), fadd_s(3, 1, 2, 0b111)]);
+    let boxed = 0xffffffff00000000;
+    let core = &mut m.harts[0].core;
+    core.freg_file[1] = boxed | ONE;
+    core.freg_file[2] = boxed | 0x33800000;
+    csr::write(csr::Csr::frm, Rounding::Rup as u32, core);
+    m.run(0, 1);
+    let core = &mut m.harts[0].core;
+    assert_eq!(core.freg_file[3], boxed | 0x3f800001);
+    assert_eq!(csr::read(csr::Csr::fflags, core), FLAG_NX);
+
+    // 0b101 is reserved
+    csr::write(csr::Csr::frm, 0b101, core);
+    m.run(0, 1);
+    let core = &m.harts[0].core;
+    assert_eq!(core.pc, PROGRAM + 0x100);
+    assert_eq!(csr::read(csr::Csr::mcause, core), 2);
+}
+
+#[test]
+fn nan_boxing() {
+    let mut m = machine(&[fadd_s(3, 1, 2, 0), fadd_s(4, 2, 2, 0)]);
+    let core = &mut m.harts[0].core;
+    // the upper half is not all ones, the value reads as the canonical NaN
+    core.freg_file[1] = ONE;
+    core.freg_file[2] = 0xffffffff00000000 | ONE;
+    m.run(0, 2);
+    let core = &m.harts[0].core;
+    assert_eq!(core.freg_file[3], 0xffffffff7fc00000);
+    assert_eq!(core.freg_file[4], 0xffffffff00000000 | TWO);
+    assert_eq!(csr::read(csr::Csr::fflags, core), 0);
+}
+
+#[test]
+fn fsd_faults_before_writing() {
+    let fsd = s_type(0b0100111, 0b011, 1, 1, 0);
+    let mut m = machine(&[fsd]);
+    m.set_reg(0, 1, DATA);
+    m.bus.ram.store_word(DATA, 0x11111111);
+    m.bus.ram.store_word(DATA + 4, 0x22222222);
+    let core = &mut m.harts[0].core;
+    core.freg_file[1] = 0x3333333344444444;
+    // the upper word is locked and read only, even for M-mode
+    csr::write(csr::Csr::pmpaddr0, (DATA + 4) >> 2, core);
+    csr::write(csr::Csr::pmpcfg0, 0x80 | 0b10 << 3 | 0b001, core);
+    m.run(0, 1);
+    let core = &m.harts[0].core;
+    assert_eq!(core.pc, PROGRAM + 0x100);
+    assert_eq!(csr::read(csr::Csr::mcause, core), 7);
+    assert_eq!(csr::read(csr::Csr::mtval, core), DATA + 4);
+    assert_eq!(m.bus.ram.load_word(DATA), 0x11111111);
+    assert_eq!(m.bus.ram.load_word(DATA + 4), 0x22222222);
+}
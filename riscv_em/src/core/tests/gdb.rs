@@ -0,0 +1,114 @@
+// Packet parsing, registers and memory of the GDB stub, without a connection.
+
+use super::*;
+use crate::core::gdb::{
+    decode_hex, parse_addr_len, parse_breakpoint, parse_thread, read_memory, read_reg, unescape,
+    write_reg,
+};
+
+const REG_PC: usize = 32;
+const REG_F0: usize = 33;
+const REG_CSR0: usize = 65;
+
+#[test]
+fn packet_fields() {
+    assert_eq!(decode_hex("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+    assert_eq!(decode_hex(""), Some(vec![]));
+    assert_eq!(decode_hex("abc"), None);
+    assert_eq!(decode_hex("zz"), None);
+
+    assert_eq!(parse_addr_len("80000000,40"), Some((0x80000000, 0x40)));
+    assert_eq!(parse_addr_len("80000000"), None);
+    assert_eq!(parse_addr_len("100000000,4"), None);
+
+    // -1 is every thread, the stub picks the first
+    assert_eq!(parse_thread("-1"), Some(0));
+    assert_eq!(parse_thread("0"), Some(0));
+    assert_eq!(parse_thread("a"), Some(10));
+    assert_eq!(parse_thread("x"), None);
+
+    assert_eq!(parse_breakpoint("0,80000010,4"), Some(0x80000010));
+    assert_eq!(parse_breakpoint("1,80000010,2"), Some(0x80000010));
+    // watchpoints are not supported
+    assert_eq!(parse_breakpoint("2,80000010,4"), None);
+
+    assert_eq!(unescape(b"X}\x03}]"), "X#}");
+}
+
+#[test]
+fn registers() {
+    let mut m = Machine::new(1);
+    let hart = &mut m.harts[0];
+    // numbered x0..x31, pc, f0..f31 and the CSRs from 65, little endian
+    assert!(write_reg(hart, 5, "78563412"));
+    assert_eq!(read_reg(hart, 5).unwrap(), "78563412");
+    assert_eq!(hart.core.reg_file[5], 0x12345678);
+    assert!(write_reg(hart, REG_PC, "00100080"));
+    assert_eq!(hart.core.pc, 0x80001000);
+    assert!(write_reg(hart, REG_F0 + 1, "0000803f00000000"));
+    assert_eq!(hart.core.freg_file[1], 0x3f800000);
+    assert_eq!(read_reg(hart, REG_F0 + 1).unwrap(), "0000803f00000000");
+
+    // x0 writes are ignored
+    assert!(write_reg(hart, 0, "01000000"));
+    assert_eq!(read_reg(hart, 0).unwrap(), "00000000");
+    assert_eq!(hart.core.reg_file[0], 0);
+
+    // malformed values and unknown registers
+    assert!(!write_reg(hart, 5, "1234"));
+    assert!(!write_reg(hart, REG_F0, "00000000"));
+    assert!(read_reg(hart, REG_CSR0 + 0x7ff).is_none());
+    assert!(!write_reg(hart, REG_CSR0 + 0x7ff, "00000000"));
+}
+
+#[test]
+fn csr_writes_go_through_the_csr_file() {
+    let mut m = Machine::new(1);
+    let hart = &mut m.harts[0];
+    // S-mode can't write mstatus, the debugger can
+    hart.core.mode = 1;
+    // sstatus.SIE is the SIE bit of mstatus
+    assert!(write_reg(hart, REG_CSR0 + 0x100, "02000000"));
+    assert_eq!(csr::read(csr::Csr::mstatus, &hart.core) & 0b10, 0b10);
+    assert!(write_reg(hart, REG_CSR0 + 0x300, "08000000"));
+    assert_eq!(read_reg(hart, REG_CSR0 + 0x300).unwrap(), "08000000");
+    assert_eq!(hart.core.mode, 1);
+}
+
+#[test]
+fn memory_reads() {
+    let mut m = Machine::new(1);
+    m.bus.ram.store_word(DATA, 0x44332211);
+    let hart = &m.harts[0];
+    assert_eq!(read_memory(hart, &mut m.bus, DATA, 4, true), "11223344");
+    assert_eq!(read_memory(hart, &mut m.bus, DATA, 0, true), "");
+
+    // stops at the end of RAM, nothing at all is an error
+    let (base, length) = m.bus.ram.region();
+    let end = base + length;
+    assert_eq!(read_memory(hart, &mut m.bus, end - 2, 4, true), "0000");
+    assert_eq!(read_memory(hart, &mut m.bus, end, 4, true), "E14");
+
+    // devices are not read, the pending interrupt stays unclaimed
+    let claim = 0xc200004;
+    m.bus.plic.write(0xc000004, 4, 1);
+    m.bus.plic.write(0xc002000, 4, 1 << 1);
+    m.bus.plic.set_active(1, true);
+    m.bus.plic.tick(&mut m.harts[0].core);
+    let hart = &m.harts[0];
+    assert_eq!(read_memory(hart, &mut m.bus, claim, 4, true), "E14");
+    assert_eq!(m.bus.plic.read(claim, 4), 1);
+}
+
+#[test]
+fn virtual_memory_reads() {
+    let mut m = Machine::new(1);
+    m.supervisor(0, 0);
+    m.map(0x10000, DATA, PTE_R | PTE_A);
+    m.bus.ram.store_word(DATA + 8, 0xddccbbaa);
+    let hart = &m.harts[0];
+    assert_eq!(read_memory(hart, &mut m.bus, 0x10008, 4, false), "aabbccdd");
+    // the next page is not mapped
+    assert_eq!(read_memory(hart, &mut m.bus, 0x10ffe, 4, false), "0000");
+    assert_eq!(read_memory(hart, &mut m.bus, 0x11000, 4, false), "E14");
+}
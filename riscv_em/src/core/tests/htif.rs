@@ -0,0 +1,187 @@
+// The host-target interface of spike: commands written to tohost, answers read from fromhost.
+
+use super::*;
+use crate::write_signature;
+
+const TOHOST: u32 = DATA;
+const FROMHOST: u32 = DATA + 0x8;
+const MAGIC_MEM: u32 = DATA + 0x40;
+const BUF: u32 = DATA + 0x80;
+
+// sw x2, 0(x1) and sw x3, 4(x1)
+const STORE_LOW: u32 = 0x0020a023;
+const STORE_HIGH: u32 = 0x0030a223;
+
+// x1 points to tohost
+fn machine(program: &[u32]) -> Machine {
+    let mut m = Machine::new(1);
+    m.bus
+        .htif
+        .set_addresses(&m.bus.ram, Some(TOHOST), Some(FROMHOST));
+    m.load(0, program);
+    m.set_reg(0, 1, TOHOST);
+    m
+}
+
+fn load_dword(m: &Machine, addr: u32) -> u64 {
+    m.bus.ram.load_word(addr) as u64 | (m.bus.ram.load_word(addr + 4) as u64) << 32
+}
+
+fn store_dword(m: &mut Machine, addr: u32, data: u64) {
+    m.bus.ram.store_word(addr, data as u32);
+    m.bus.ram.store_word(addr + 4, (data >> 32) as u32);
+}
+
+#[test]
+fn exit() {
+    let mut m = machine(&[STORE_LOW, STORE_LOW]);
+    m.set_reg(0, 2, 1);
+    m.run(0, 1);
+    assert_eq!(m.bus.htif.exit_code, Some(0));
+
+    m.bus.htif.exit_code = None;
+    m.set_reg(0, 2, 42 << 1 | 1);
+    m.run(0, 1);
+    assert_eq!(m.bus.htif.exit_code, Some(42));
+    assert_eq!(load_dword(&m, TOHOST), 0);
+    // nothing to answer
+    assert_eq!(load_dword(&m, FROMHOST), 0);
+}
+
+#[test]
+fn sys_write() {
+    let mut m = machine(&[STORE_LOW]);
+    // bytes above 0x7f reach the console unchanged
+    let text = [b'o', b'k', 0xc3, 0xa9, b'\n'];
+    for (i, byte) in text.iter().enumerate() {
+        m.bus.ram.store_byte(BUF + i as u32, *byte);
+    }
+    for (i, arg) in [64, 1, BUF as u64, text.len() as u64]
+        .into_iter()
+        .enumerate()
+    {
+        store_dword(&mut m, MAGIC_MEM + 8 * i as u32, arg);
+    }
+    m.set_reg(0, 2, MAGIC_MEM);
+    m.run(0, 1);
+    assert_eq!(m.console.take(), text);
+    // the return value replaces the syscall number
+    assert_eq!(load_dword(&m, MAGIC_MEM), text.len() as u64);
+    assert_eq!(load_dword(&m, TOHOST), 0);
+    assert_eq!(load_dword(&m, FROMHOST), 1);
+    assert_eq!(m.bus.htif.exit_code, None);
+}
+
+#[test]
+fn unknown_syscall() {
+    let mut m = machine(&[STORE_LOW]);
+    store_dword(&mut m, MAGIC_MEM, 1234);
+    m.set_reg(0, 2, MAGIC_MEM);
+    m.run(0, 1);
+    assert_eq!(load_dword(&m, MAGIC_MEM), (-38i64) as u64);
+    assert_eq!(load_dword(&m, FROMHOST), 1);
+}
+
+#[test]
+fn putchar() {
+    // high word first, the store to the low word hands the command over
+    let mut m = machine(&[STORE_HIGH, STORE_LOW]);
+    m.set_reg(0, 2, 0xe9);
+    m.set_reg(0, 3, 0x01010000);
+    m.run(0, 1);
+    assert!(m.console.take().is_empty());
+    m.run(0, 1);
+    assert_eq!(m.console.take(), [0xe9]);
+    assert_eq!(load_dword(&m, TOHOST), 0);
+    assert_eq!(load_dword(&m, FROMHOST), 1 << 56 | 1 << 48);
+}
+
+#[test]
+fn fsd_hands_over() {
+    // fsd f1, 0(x1)
+    let fsd = s_type(0b0100111, 0b011, 1, 1, 0);
+    let mut m = machine(&[fsd]);
+    let core = &mut m.harts[0].core;
+    csr::write(csr::Csr::mstatus, 0b01 << 13, core);
+    core.freg_file[1] = 0x0101_0000_0000_0021;
+    m.run(0, 1);
+    assert_eq!(m.console.take(), b"!");
+    assert_eq!(load_dword(&m, TOHOST), 0);
+}
+
+#[test]
+fn byte_stores_stay_in_ram() {
+    // sb x2, 0(x1)
+    let sb = s_type(0b0100011, 0b000, 1, 2, 0);
+    let mut m = machine(&[sb]);
+    m.set_reg(0, 2, 1);
+    m.run(0, 1);
+    assert_eq!(m.bus.htif.exit_code, None);
+    assert_eq!(load_dword(&m, TOHOST), 1);
+}
+
+#[test]
+fn exit_on_ecall() {
+    let mut m = machine(&[ECALL, ECALL]);
+    csr::write(csr::Csr::mtvec, PROGRAM + 0x100, &mut m.harts[0].core);
+    m.set_reg(0, 17, 93);
+    m.set_reg(0, 10, 5);
+    // without the option the ecall traps
+    m.run(0, 1);
+    assert_eq!(m.bus.htif.exit_code, None);
+    assert_eq!(m.harts[0].core.pc, PROGRAM + 0x100);
+
+    m.harts[0].core.pc = PROGRAM + 4;
+    m.bus.htif.exit_on_ecall = true;
+    m.run(0, 1);
+    assert_eq!(m.bus.htif.exit_code, Some(5));
+    assert_eq!(m.harts[0].core.pc, PROGRAM + 4);
+}
+
+#[test]
+fn tohost_outside_ram() {
+    let (base, length) = ram::RAM::default().region();
+    for tohost in [0x40000000, base + length - 4] {
+        let mut m = machine(&[STORE_LOW]);
+        csr::write(csr::Csr::mtvec, PROGRAM + 0x100, &mut m.harts[0].core);
+        m.bus.htif.set_addresses(&m.bus.ram, Some(tohost), None);
+        assert!(!m.bus.htif.claim(tohost));
+        // a plain store to RAM or an access fault, never a command
+        m.set_reg(0, 1, tohost);
+        m.set_reg(0, 2, 1);
+        m.run(0, 1);
+        assert_eq!(m.bus.htif.exit_code, None);
+    }
+}
+
+#[test]
+fn signature() {
+    // the test writes its results, then exits through tohost
+    let mut m = machine(&[
+        addi(4, 0, 0x123),
+        sw(4, 1, 0x100),
+        addi(4, 0, -1),
+        sw(4, 1, 0x104),
+        STORE_LOW,
+    ]);
+    m.set_reg(0, 2, 1);
+    m.run(0, 5);
+    assert_eq!(m.bus.htif.exit_code, Some(0));
+
+    let path = std::env::temp_dir().join(format!("riscv_em_signature_{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    // the end is not part of the signature
+    let result = write_signature(path, &m.bus, TOHOST + 0x100, TOHOST + 0x10c);
+    let text = std::fs::read_to_string(path);
+    let _ = std::fs::remove_file(path);
+    result.unwrap();
+    assert_eq!(text.unwrap(), "00000123\nffffffff\n00000000\n");
+
+    let (base, length) = m.bus.ram.region();
+    let err = write_signature(path, &m.bus, base + length - 4, base + length + 4).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!("signature at 0x{:08x} is outside of RAM", base + length)
+    );
+    assert!(!std::path::Path::new(path).exists());
+}
@@ -0,0 +1,285 @@
+// ELF and flat images: segments at their physical addresses, BSS, the entry point and the
+// symbols of the monitor and the lockstep reports.
+
+use object::Endianness;
+use object::elf::{
+    EM_RISCV, EM_X86_64, ET_EXEC, PF_R, PF_X, PT_LOAD, SHN_ABS, STB_GLOBAL, STT_FUNC, STT_OBJECT,
+};
+use object::write::elf::{FileHeader, ProgramHeader, Sym, Writer};
+
+use super::*;
+use crate::core::soc_init;
+use crate::{fdt, initrd_addr};
+
+// (physical address, virtual address, contents, size in memory)
+type Segment<'a> = (u32, u32, &'a [u8], u32);
+
+// (name, value, size, type)
+type Symbol<'a> = (&'a str, u32, u32, u8);
+
+fn elf(machine: u16, entry: u32, segments: &[Segment], symbols: &[Symbol]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    let mut writer = Writer::new(Endianness::Little, false, &mut buffer);
+    writer.reserve_file_header();
+    writer.reserve_program_headers(segments.len() as u32);
+    let offsets: Vec<usize> = segments
+        .iter()
+        .map(|(_, _, data, _)| writer.reserve(data.len(), 4))
+        .collect();
+
+    writer.reserve_null_section_index();
+    writer.reserve_null_symbol_index();
+    let names: Vec<_> = symbols
+        .iter()
+        .map(|(name, ..)| {
+            writer.reserve_symbol_index(None);
+            writer.add_string(name.as_bytes())
+        })
+        .collect();
+    writer.reserve_symtab_section_index();
+    writer.reserve_strtab_section_index();
+    writer.reserve_shstrtab_section_index();
+    writer.reserve_symtab();
+    writer.reserve_strtab();
+    writer.reserve_shstrtab();
+    writer.reserve_section_headers();
+
+    writer
+        .write_file_header(&FileHeader {
+            os_abi: 0,
+            abi_version: 0,
+            e_type: ET_EXEC,
+            e_machine: machine,
+            e_entry: entry as u64,
+            e_flags: 0,
+        })
+        .unwrap();
+    writer.write_align_program_headers();
+    for ((paddr, vaddr, data, memsz), offset) in segments.iter().zip(&offsets) {
+        writer.write_program_header(&ProgramHeader {
+            p_type: PT_LOAD,
+            p_flags: PF_R | PF_X,
+            p_offset: *offset as u64,
+            p_vaddr: *vaddr as u64,
+            p_paddr: *paddr as u64,
+            p_filesz: data.len() as u64,
+            p_memsz: *memsz as u64,
+            p_align: 4,
+        });
+    }
+    for (_, _, data, _) in segments {
+        writer.write_align(4);
+        writer.write(data);
+    }
+
+    writer.write_null_symbol();
+    for ((_, value, size, kind), name) in symbols.iter().zip(names) {
+        writer.write_symbol(&Sym {
+            name: Some(name),
+            section: None,
+            st_info: STB_GLOBAL << 4 | kind,
+            st_other: 0,
+            st_shndx: SHN_ABS,
+            st_value: *value as u64,
+            st_size: *size as u64,
+        });
+    }
+    writer.write_strtab();
+    writer.write_shstrtab();
+    writer.write_null_section_header();
+    writer.write_symtab_section_header(1);
+    writer.write_strtab_section_header();
+    writer.write_shstrtab_section_header();
+    buffer
+}
+
+// image file removed when dropped
+struct Image(String);
+
+impl Image {
+    fn new(name: &str, bytes: &[u8]) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("riscv_em_{}_{}.elf", name, std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        Image(path.to_str().unwrap().to_string())
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn load(m: &mut Machine, image: &Image) -> Result<u32, String> {
+    loader::load_image(&image.0, PROGRAM, &mut m.harts[0], &mut m.bus)
+        .map(|(entry, _)| entry)
+        .map_err(|x| x.to_string())
+}
+
+#[test]
+fn segments_at_physical_addresses() {
+    let text = [0x13, 0x00, 0x00, 0x00, 0x6f, 0x00, 0x00, 0x00];
+    let data = [1, 2, 3, 4];
+    let image = Image::new(
+        "segments",
+        &elf(
+            EM_RISCV,
+            0xc0000004,
+            &[
+                // linked at a virtual address, loaded at the physical one
+                (PROGRAM, 0xc0000000, &text, 8),
+                // BSS follows the data
+                (DATA, 0xc0010000, &data, 16),
+            ],
+            &[("tohost", DATA + 0x100, 8, STT_OBJECT)],
+        ),
+    );
+    let mut m = Machine::new(1);
+    for i in 0..5 {
+        m.bus.ram.store_word(DATA + 4 * i, 0xffffffff);
+    }
+    assert_eq!(load(&mut m, &image), Ok(0xc0000004));
+    assert_eq!(m.bus.ram.load_word(PROGRAM + 4), 0x0000006f);
+    assert_eq!(m.bus.ram.load_word(DATA), 0x04030201);
+    for i in 1..4 {
+        assert_eq!(m.bus.ram.load_word(DATA + 4 * i), 0);
+    }
+    // past p_memsz
+    assert_eq!(m.bus.ram.load_word(DATA + 16), 0xffffffff);
+    assert_eq!(m.harts[0].symbols.find("tohost"), Some(DATA + 0x100));
+}
+
+#[test]
+fn flat_binary() {
+    let image = Image::new("flat", &[0x6f, 0, 0, 0]);
+    let mut m = Machine::new(1);
+    assert_eq!(load(&mut m, &image), Ok(PROGRAM));
+    assert_eq!(m.bus.ram.load_word(PROGRAM), 0x0000006f);
+}
+
+#[test]
+fn outside_of_ram() {
+    let (base, length) = ram::RAM::default().region();
+    let end = base + length;
+    for addr in [0x40000000, end - 4, base - 4] {
+        let image = Image::new(
+            "outside",
+            &elf(EM_RISCV, base, &[(addr, addr, &[0; 8], 8)], &[]),
+        );
+        let mut m = Machine::new(1);
+        let err = load(&mut m, &image).unwrap_err();
+        assert!(err.contains("outside of RAM"), "{}", err);
+    }
+    // BSS past the end of RAM
+    let image = Image::new(
+        "bss",
+        &elf(EM_RISCV, base, &[(end - 4, end - 4, &[0; 4], 8)], &[]),
+    );
+    assert!(load(&mut Machine::new(1), &image).is_err());
+
+    let image = Image::new("x86", &elf(EM_X86_64, base, &[], &[]));
+    let err = load(&mut Machine::new(1), &image).unwrap_err();
+    assert!(err.contains("not a RISC-V ELF file"), "{}", err);
+}
+
+#[test]
+fn symbols() {
+    let image = Image::new(
+        "symbols",
+        &elf(
+            EM_RISCV,
+            PROGRAM,
+            &[],
+            &[
+                ("start", PROGRAM, 8, STT_FUNC),
+                // no size, it runs up to the next symbol
+                ("spin", PROGRAM + 0x10, 0, STT_FUNC),
+                ("trap", PROGRAM + 0x40, 4, STT_FUNC),
+                // not a function
+                ("buffer", PROGRAM + 0x100, 4, STT_OBJECT),
+            ],
+        ),
+    );
+    let mut m = Machine::new(1);
+    load(&mut m, &image).unwrap();
+    let symbols = &m.harts[0].symbols;
+    assert_eq!(symbols.lookup(PROGRAM + 4), Some(("start", 4)));
+    assert_eq!(symbols.lookup(PROGRAM + 8), None);
+    assert_eq!(symbols.lookup(PROGRAM + 0x3c), Some(("spin", 0x2c)));
+    assert_eq!(symbols.lookup(PROGRAM - 4), None);
+    assert_eq!(symbols.lookup(PROGRAM + 0x104), None);
+
+    assert_eq!(symbols.format(PROGRAM), "start");
+    assert_eq!(symbols.format(PROGRAM + 0x14), "spin+0x4");
+    assert_eq!(
+        symbols.format(PROGRAM + 0x44),
+        format!("0x{:08x}", PROGRAM + 0x44)
+    );
+    assert_eq!(symbols.find("buffer"), Some(PROGRAM + 0x100));
+}
+
+#[test]
+fn loaded_ranges() {
+    let image = Image::new(
+        "ranges",
+        &elf(
+            EM_RISCV,
+            PROGRAM,
+            &[(PROGRAM, PROGRAM, &[0; 8], 8), (DATA, DATA, &[0; 4], 16)],
+            &[],
+        ),
+    );
+    let mut m = Machine::new(1);
+    let (_, ranges) = loader::load_image(&image.0, PROGRAM, &mut m.harts[0], &mut m.bus).unwrap();
+    assert_eq!(ranges, [(PROGRAM, PROGRAM + 8), (DATA, DATA + 16)]);
+
+    let image = Image::new("ranges_flat", &[0; 12]);
+    let (_, ranges) = loader::load_image(&image.0, DATA, &mut m.harts[0], &mut m.bus).unwrap();
+    assert_eq!(ranges, [(DATA, DATA + 12)]);
+}
+
+#[test]
+fn initrd_overlapping_the_kernel() {
+    let start = initrd_addr(ram::RAM::default().region());
+    let bios = Image::new("initrd_bios", &HALT.to_le_bytes());
+    let initrd = Image::new("initrd", &[0xff; 0x100]);
+    let m = Machine::new(1);
+    let dtb = fdt::generate(&m.harts, &m.bus, "", None);
+    // the BSS of the kernel ends right before the initrd, then inside it
+    for (memsz, overlaps) in [(0x1000, false), (0x1004, true)] {
+        let kernel = Image::new(
+            "initrd_kernel",
+            &elf(
+                EM_RISCV,
+                start - 0x1000,
+                &[(start - 0x1000, start - 0x1000, &[0; 4], memsz)],
+                &[],
+            ),
+        );
+        let mut m = Machine::new(1);
+        let result = soc_init(
+            &mut m.harts,
+            &mut m.bus,
+            Some(&bios.0),
+            Some(&kernel.0),
+            Some(&initrd.0),
+            &dtb,
+        );
+        match overlaps {
+            false => {
+                result.unwrap();
+                assert_eq!(m.bus.ram.load_word(start), 0xffffffff);
+            }
+            true => {
+                let err = result.unwrap_err().to_string();
+                let range = format!("0x{:08x}-0x{:08x}", start - 0x1000, start + 3);
+                assert!(
+                    err.contains("initrd overlaps the image at") && err.contains(&range),
+                    "{}",
+                    err
+                );
+            }
+        }
+    }
+}
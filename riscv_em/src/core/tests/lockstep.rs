@@ -0,0 +1,184 @@
+// Lockstep comparison against reference logs written the way spike prints them, with and
+// without -l, for one and for several harts.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::core::commit_log::{Commit, Trap};
+use crate::core::lockstep::{Lockstep, compare, parse_commit};
+
+// removed when dropped
+struct Reference(PathBuf);
+
+impl Drop for Reference {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn open(name: &str, lines: &[&str], hart_id: usize) -> (Reference, Lockstep) {
+    let path = std::env::temp_dir().join(format!("riscv_em_{}_{}.log", name, std::process::id()));
+    fs::write(&path, lines.join("\n") + "\n").unwrap();
+    let lockstep = Lockstep::open(path.to_str().unwrap(), hart_id).unwrap();
+    (Reference(path), lockstep)
+}
+
+fn parse(line: &str) -> Commit {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    parse_commit(&tokens).unwrap()
+}
+
+fn commit(lockstep: &mut Lockstep, line: &str) {
+    lockstep.commit(&parse(line), line.to_string());
+}
+
+fn trap(lockstep: &mut Lockstep, trap: Trap) {
+    let text = trap.format(0);
+    lockstep.trap(&trap, text);
+}
+
+fn reason(lockstep: &Lockstep) -> Option<&str> {
+    lockstep
+        .divergence
+        .as_ref()
+        .map(|(reason, _, _)| reason.as_str())
+}
+
+const AUIPC: &str = "core   0: 3 0x80000000 (0x00000297) x5  0x80000000";
+const STORE: &str = "core   0: 3 0x80000004 (0x00b52023) mem 0x80001000 0x00000001";
+// ecall at 0x80000008, the handler is at 0x80000100
+const ECALL: &str = "core   0: exception trap_machine_ecall, epc 0x80000008";
+const HANDLER: &str = "core   0: 3 0x80000100 (0x00000013)";
+
+#[test]
+fn compare_commits() {
+    assert_eq!(compare(&parse(AUIPC), &parse(AUIPC)), None);
+    let other = "core   0: 3 0x80000000 (0x00000297) x5  0x80000004";
+    assert_eq!(
+        compare(&parse(other), &parse(AUIPC)).unwrap(),
+        "x5 0x80000004, reference 0x80000000"
+    );
+    let none = "core   0: 3 0x80000000 (0x00000297)";
+    assert_eq!(
+        compare(&parse(none), &parse(AUIPC)).unwrap(),
+        "x5 not written, reference 0x80000000"
+    );
+    let mode = "core   0: 1 0x80000000 (0x00000297) x5  0x80000000";
+    assert_eq!(
+        compare(&parse(mode), &parse(AUIPC)).unwrap(),
+        "mode 1, reference 3"
+    );
+}
+
+#[test]
+fn sync_past_boot_rom() {
+    let (_file, mut lockstep) = open(
+        "boot_rom",
+        &[
+            // disassembly of -l is skipped
+            "core   0: 0x00001000 (0x00000297) auipc   t0, 0x0",
+            "core   0: 3 0x00001000 (0x00000297) x5  0x00001000",
+            "core   0: 3 0x00001004 (0x02028593) x11 0x00001020",
+            "core   0: 3 0x00001010 (0x00028067)",
+            AUIPC,
+            STORE,
+        ],
+        0,
+    );
+    commit(&mut lockstep, AUIPC);
+    commit(&mut lockstep, STORE);
+    assert_eq!(reason(&lockstep), None);
+    assert_eq!(lockstep.compared, 2);
+}
+
+#[test]
+fn differing_store_value() {
+    let (_file, mut lockstep) = open("store", &[AUIPC, STORE], 0);
+    commit(&mut lockstep, AUIPC);
+    commit(
+        &mut lockstep,
+        "core   0: 3 0x80000004 (0x00b52023) mem 0x80001000 0x00000002",
+    );
+    assert_eq!(reason(&lockstep), Some("memory accesses differ"));
+    assert_eq!(lockstep.compared, 1);
+}
+
+#[test]
+fn trap_only_in_our_log() {
+    // reference without -l: the handler follows the instruction before the ecall
+    let (_file, mut lockstep) = open("no_traps", &[AUIPC, HANDLER], 0);
+    commit(&mut lockstep, AUIPC);
+    trap(&mut lockstep, Trap::new(11, 0x80000008, 0));
+    commit(&mut lockstep, HANDLER);
+    assert_eq!(reason(&lockstep), None);
+    assert_eq!(lockstep.compared, 2);
+
+    // the reference retires the instruction we trapped on
+    let ecall = "core   0: 3 0x80000008 (0x00000073)";
+    let (_file, mut lockstep) = open("retired", &[AUIPC, ecall], 0);
+    commit(&mut lockstep, AUIPC);
+    trap(&mut lockstep, Trap::new(11, 0x80000008, 0));
+    assert_eq!(
+        reason(&lockstep),
+        Some("trap_machine_ecall, reference retires the instruction")
+    );
+}
+
+#[test]
+fn trap_in_both_logs() {
+    let (_file, mut lockstep) = open("traps", &[AUIPC, ECALL, HANDLER], 0);
+    commit(&mut lockstep, AUIPC);
+    trap(&mut lockstep, Trap::new(11, 0x80000008, 0));
+    commit(&mut lockstep, HANDLER);
+    assert_eq!(reason(&lockstep), None);
+
+    // a different trap
+    let (_file, mut lockstep) = open("other_trap", &[AUIPC, ECALL], 0);
+    commit(&mut lockstep, AUIPC);
+    trap(&mut lockstep, Trap::new(2, 0x80000008, 0));
+    assert_eq!(
+        reason(&lockstep),
+        Some("trap_illegal_instruction at 0x80000008, reference trap_machine_ecall at 0x80000008")
+    );
+}
+
+#[test]
+fn trap_value() {
+    let lines = [
+        AUIPC,
+        "core   0: exception trap_load_page_fault, epc 0x80000008",
+        "core   0:           tval 0x00001000",
+        HANDLER,
+    ];
+    let (_file, mut lockstep) = open("tval", &lines, 0);
+    commit(&mut lockstep, AUIPC);
+    trap(&mut lockstep, Trap::new(13, 0x80000008, 0x1000));
+    commit(&mut lockstep, HANDLER);
+    assert_eq!(reason(&lockstep), None);
+
+    let (_file, mut lockstep) = open("other_tval", &lines, 0);
+    commit(&mut lockstep, AUIPC);
+    trap(&mut lockstep, Trap::new(13, 0x80000008, 0x2000));
+    assert_eq!(
+        reason(&lockstep),
+        Some("trap_load_page_fault trap value differs")
+    );
+}
+
+#[test]
+fn lines_of_other_harts_are_skipped() {
+    let hart_1 = [
+        "core   1: 3 0x80000000 (0x00000297) x5  0x80000000",
+        "core   1: 3 0x80000004 (0x00b52023) mem 0x80001000 0x00000001",
+    ];
+    let lines = [AUIPC, hart_1[0], STORE, hart_1[1]];
+    let (_file, mut lockstep) = open("harts", &lines, 1);
+    commit(&mut lockstep, hart_1[0]);
+    commit(&mut lockstep, hart_1[1]);
+    assert_eq!(reason(&lockstep), None);
+    assert_eq!(lockstep.compared, 2);
+
+    // and the end of the reference of this hart is reached
+    commit(&mut lockstep, hart_1[0]);
+    assert_eq!(reason(&lockstep), Some("end of the reference"));
+}
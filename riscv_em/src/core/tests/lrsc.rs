@@ -0,0 +1,162 @@
+// LR/SC reservation regression programs.
+// x10 holds the address of the reserved word in all of them.
+
+use super::*;
+
+const MEPC: u32 = 0x341;
+const MTVEC: u32 = 0x305;
+
+fn machine(harts: usize) -> Machine {
+    let mut m = Machine::new(harts);
+    for hart_id in 0..harts {
+        m.set_reg(hart_id, 10, DATA);
+    }
+    m
+}
+
+#[test]
+fn sc_after_lr_stores() {
+    let mut m = machine(1);
+    m.bus.ram.store_word(DATA, 7);
+    m.load(0, &[lr_w(11, 10), addi(12, 11, 1), sc_w(13, 10, 12)]);
+    m.run(0, 3);
+    assert_eq!(m.reg(0, 11), 7);
+    assert_eq!(m.reg(0, 13), 0);
+    assert_eq!(m.bus.ram.load_word(DATA), 8);
+}
+
+#[test]
+fn sc_without_lr_fails() {
+    let mut m = machine(1);
+    m.bus.ram.store_word(DATA, 7);
+    m.load(0, &[addi(12, 0, 5), sc_w(13, 10, 12)]);
+    m.run(0, 2);
+    assert_eq!(m.reg(0, 13), 1);
+    assert_eq!(m.bus.ram.load_word(DATA), 7);
+}
+
+#[test]
+fn sc_consumes_reservation() {
+    let mut m = machine(1);
+    m.load(0, &[lr_w(11, 10), addi(12, 0, 1), sc_w(13, 10, 12), addi(12, 0, 2), sc_w(14, 10, 12)]);
+    m.run(0, 5);
+    assert_eq!(m.reg(0, 13), 0);
+    assert_eq!(m.reg(0, 14), 1);
+    assert_eq!(m.bus.ram.load_word(DATA), 1);
+}
+
+#[test]
+fn sc_to_other_granule_fails() {
+    let mut m = machine(1);
+    m.set_reg(0, 15, DATA + 0x100);
+    m.load(0, &[lr_w(11, 10), addi(12, 0, 1), sc_w(13, 15, 12)]);
+    m.run(0, 3);
+    assert_eq!(m.reg(0, 13), 1);
+    assert_eq!(m.bus.ram.load_word(DATA + 0x100), 0);
+}
+
+// the value is the same again when hart 0 executes SC, but it was written in between
+#[test]
+fn aba_store_fails_sc() {
+    let mut m = machine(2);
+    m.load(0, &[lr_w(11, 10), addi(12, 11, 1), sc_w(13, 10, 12)]);
+    m.load(1, &[addi(5, 0, 1), sw(5, 10, 0), sw(0, 10, 0)]);
+    m.run(0, 1);
+    m.run(1, 3);
+    m.run(0, 2);
+    assert_eq!(m.reg(0, 13), 1);
+    assert_eq!(m.bus.ram.load_word(DATA), 0);
+}
+
+// store of the other hart to a different word of the reservation set
+fn neighbour_store(granule: u32) -> u32 {
+    let mut m = machine(2);
+    m.bus.ram.set_reservation_granule(granule);
+    m.load(0, &[lr_w(11, 10), addi(12, 11, 1), sc_w(13, 10, 12)]);
+    m.load(1, &[addi(5, 0, 1), sw(5, 10, 8)]);
+    m.run(0, 1);
+    m.run(1, 2);
+    m.run(0, 2);
+    m.reg(0, 13)
+}
+
+#[test]
+fn store_to_granule_fails_sc() {
+    assert_eq!(neighbour_store(64), 1);
+    assert_eq!(neighbour_store(8), 0);
+    assert_eq!(neighbour_store(4), 0);
+}
+
+#[test]
+fn device_store_fails_sc() {
+    let mut m = machine(1);
+    m.load(0, &[lr_w(11, 10), addi(12, 11, 1), sc_w(13, 10, 12)]);
+    m.run(0, 1);
+    // DMA writes go straight to RAM
+    m.bus.ram.store_byte(DATA + 3, 0);
+    m.run(0, 2);
+    assert_eq!(m.reg(0, 13), 1);
+}
+
+// LR of the other hart doesn't take the reservation away
+#[test]
+fn lr_of_other_hart_keeps_reservation() {
+    let mut m = machine(2);
+    m.load(0, &[lr_w(11, 10), addi(12, 11, 1), sc_w(13, 10, 12)]);
+    m.load(1, &[lr_w(11, 10)]);
+    m.run(0, 1);
+    m.run(1, 1);
+    m.run(0, 2);
+    assert_eq!(m.reg(0, 13), 0);
+}
+
+fn trap_between(instr: u32) -> u32 {
+    let mut m = machine(1);
+    m.set_reg(0, 6, PROGRAM + 0x100);
+    m.load(0, &[csrrw(0, MTVEC, 6), lr_w(11, 10), instr, sc_w(13, 10, 12), HALT]);
+    // handler returns to the next instruction
+    for (i, instr) in [csrrs(5, MEPC, 0), addi(5, 5, 4), csrrw(0, MEPC, 5), MRET]
+        .iter()
+        .enumerate()
+    {
+        m.bus.ram.store_word(PROGRAM + 0x100 + 4 * i as u32, *instr);
+    }
+    m.run(0, 20);
+    assert_eq!(m.pc(0), 16);
+    m.reg(0, 13)
+}
+
+#[test]
+fn trap_clears_reservation() {
+    assert_eq!(trap_between(NOP), 0);
+    assert_eq!(trap_between(ECALL), 1);
+}
+
+// two harts increment the same counter in LR/SC loops, interleaved with different quanta
+#[test]
+fn interleaved_increments() {
+    const ITERATIONS: u32 = 100;
+    let program = [
+        lr_w(11, 10),
+        addi(11, 11, 1),
+        sc_w(13, 10, 11),
+        bne(13, 0, -12),
+        addi(14, 14, -1),
+        bne(14, 0, -20),
+        HALT,
+    ];
+    for quantum in 1..8 {
+        let mut m = machine(2);
+        for hart_id in 0..2 {
+            m.load(hart_id, &program);
+            m.set_reg(hart_id, 14, ITERATIONS);
+        }
+        for _ in 0..10000 {
+            m.run(0, quantum);
+            m.run(1, quantum + 1);
+        }
+        assert_eq!(m.pc(0), 24);
+        assert_eq!(m.pc(1), 24);
+        assert_eq!(m.bus.ram.load_word(DATA), 2 * ITERATIONS, "quantum {}", quantum);
+    }
+}
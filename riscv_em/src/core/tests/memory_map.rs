@@ -0,0 +1,64 @@
+// -m and --memory-map: sizes, banks and their place among the devices.
+
+use super::*;
+use crate::{check_memory_map, parse_bank, parse_size};
+
+#[test]
+fn sizes() {
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("0x2000"), Ok(0x2000));
+    assert_eq!(parse_size("64K"), Ok(64 << 10));
+    assert_eq!(parse_size("16m"), Ok(16 << 20));
+    assert_eq!(parse_size("1G"), Ok(1 << 30));
+    assert_eq!(parse_size("3g"), Ok(3 << 30));
+    // not a multiple of 4 KiB, zero, 4 GiB and above, no number
+    for arg in ["100", "1K", "0", "4G", "5G", "M", "16T", "-1M"] {
+        assert!(parse_size(arg).is_err(), "{}", arg);
+    }
+}
+
+#[test]
+fn banks() {
+    assert_eq!(parse_bank("0x80000000:64M"), Ok((0x80000000, 64 << 20)));
+    assert_eq!(parse_bank("4096:4K"), Ok((4096, 4096)));
+    // ends 64 KiB below 4 GiB
+    assert_eq!(parse_bank("0xbfff0000:1G"), Ok((0xbfff0000, 1 << 30)));
+    for arg in [
+        "0x80000000",
+        "0x80000800:64M",
+        "0xc0000000:1G",
+        "0xf0000000:512M",
+        "0x80000000:100",
+        "x:64M",
+    ] {
+        assert!(parse_bank(arg).is_err(), "{}", arg);
+    }
+}
+
+fn bus(banks: &[(u32, u32)]) -> MemoryBus {
+    let mut bus = MemoryBus::new(ram::RAM::new(banks));
+    bus.attach(Box::new(ns16550::Uart::new(Box::new(io::sink()))))
+        .unwrap();
+    bus
+}
+
+#[test]
+fn memory_map() {
+    let main = (RAM_OFFSET, 64 << 20);
+    assert_eq!(check_memory_map(&bus(&[main])), Ok(()));
+    // banks next to each other and next to the UART at 0x10000000
+    let below_uart = (0x0fff0000, 0x10000);
+    let after_main = (RAM_OFFSET + (64 << 20), 0x1000);
+    assert_eq!(
+        check_memory_map(&bus(&[main, below_uart, after_main])),
+        Ok(())
+    );
+
+    let err = check_memory_map(&bus(&[main, (RAM_OFFSET + 0x1000, 0x1000)])).unwrap_err();
+    assert!(err.contains("overlaps another bank"), "{}", err);
+    let err = check_memory_map(&bus(&[main, (0x0fff0000, 0x20000)])).unwrap_err();
+    assert!(err.contains("overlaps the uart"), "{}", err);
+    // like -m 2G from the default base, the bank runs up to 4 GiB
+    let err = check_memory_map(&bus(&[(0xfff00000, 0x100000)])).unwrap_err();
+    assert!(err.contains("4 GiB"), "{}", err);
+}
@@ -0,0 +1,98 @@
+// Commands of the monitor that read the machine: memory dumps and csrs.
+
+use super::*;
+use crate::core::monitor::{csrs, dump, load_word, parse_dump};
+
+fn xp(m: &mut Machine, addr: u32, n: u32) -> String {
+    dump(addr, n, |addr| {
+        load_word(&mut m.bus, addr, |addr, _| Some(addr))
+    })
+}
+
+#[test]
+fn dumps() {
+    let mut m = Machine::new(1);
+    for i in 0..6 {
+        m.bus.ram.store_word(DATA + 4 * i, 0x11111111 * (i + 1));
+    }
+    assert_eq!(
+        xp(&mut m, DATA, 5),
+        format!(
+            "{:08x}: 0x11111111 0x22222222 0x33333333 0x44444444\n{:08x}: 0x55555555\n",
+            DATA,
+            DATA + 0x10
+        )
+    );
+    assert_eq!(xp(&mut m, DATA, 0), "");
+
+    // stops at the end of RAM
+    let (base, length) = m.bus.ram.region();
+    m.bus.ram.store_word(base + length - 4, 0xdeadbeef);
+    assert_eq!(
+        xp(&mut m, base + length - 4, 3),
+        format!(
+            "{:08x}: 0xdeadbeef cannot access memory\n",
+            base + length - 4
+        )
+    );
+
+    // the address wraps around
+    let mut addrs = Vec::new();
+    let reply = dump(0xfffffff8, 4, |addr| {
+        addrs.push(addr);
+        Some(addr)
+    });
+    assert_eq!(addrs, [0xfffffff8, 0xfffffffc, 0, 4]);
+    assert_eq!(
+        reply,
+        "fffffff8: 0xfffffff8 0xfffffffc 0x00000000 0x00000004\n"
+    );
+}
+
+#[test]
+fn dump_count() {
+    assert_eq!(parse_dump(&["0x80000000"]), Some((0x80000000, 1)));
+    assert_eq!(parse_dump(&["0x80000000", "16"]), Some((0x80000000, 16)));
+    assert_eq!(
+        parse_dump(&["0x80000000", "0xffffffff"]),
+        Some((0x80000000, 1024))
+    );
+    assert_eq!(parse_dump(&["0x80000000", "1", "2"]), None);
+    assert_eq!(parse_dump(&["main"]), None);
+}
+
+#[test]
+fn devices_are_not_read() {
+    let mut m = Machine::new(1);
+    // a pending interrupt of source 1 for hart 0
+    let claim = 0xc200004;
+    m.bus.plic.write(0xc000004, 4, 1);
+    m.bus.plic.write(0xc002000, 4, 1 << 1);
+    m.bus.plic.set_active(1, true);
+    m.bus.plic.tick(&mut m.harts[0].core);
+
+    assert_eq!(
+        xp(&mut m, claim, 1),
+        format!("{:08x}: cannot access memory\n", claim)
+    );
+    assert_eq!(load_word(&mut m.bus, claim, |addr, _| Some(addr)), None);
+    // still pending
+    assert_eq!(m.bus.plic.read(claim, 4), 1);
+}
+
+#[test]
+fn csr_lookup() {
+    let mut m = Machine::new(1);
+    csr::write(csr::Csr::mscratch, 0x1234, &mut m.harts[0].core);
+    let hart = &m.harts[0];
+    let line = "      mscratch (0x340) 0x00001234\n";
+    assert_eq!(csrs(hart, Some("mscratch")), line);
+    assert_eq!(csrs(hart, Some("0x340")), line);
+    assert_eq!(csrs(hart, Some("832")), line);
+    assert_eq!(csrs(hart, Some("nocsr")), "unknown csr nocsr\n");
+    // not an implemented csr
+    assert_eq!(csrs(hart, Some("0x7ff")), "unknown csr 0x7ff\n");
+    let all = csrs(hart, None);
+    assert_eq!(all.lines().count(), csr::LEGAL_ADRESSES.len());
+    assert!(all.contains(line));
+}
@@ -0,0 +1,113 @@
+// PLIC arbitration through its registers: claim order, thresholds, one context per privilege
+// mode and hart, and completion.
+
+use super::*;
+use crate::memory::plic::Plic;
+
+const BASE: u32 = 0xc000000;
+const MEIP: u32 = 1 << 11;
+const SEIP: u32 = 1 << 9;
+
+fn enable(context: u32) -> u32 {
+    BASE + 0x2000 + 0x80 * context
+}
+
+fn threshold(context: u32) -> u32 {
+    BASE + 0x200000 + 0x1000 * context
+}
+
+fn claim(context: u32) -> u32 {
+    threshold(context) + 4
+}
+
+// the given sources with their priorities, raised and enabled in the context
+fn plic(context: u32, sources: &[(u32, u32)]) -> Plic {
+    let mut plic = Plic::default();
+    let mut enabled = 0;
+    for (id, priority) in sources {
+        plic.write(BASE + 4 * id, 4, *priority);
+        plic.set_active(*id, true);
+        enabled |= 1 << id;
+    }
+    plic.write(enable(context), 4, enabled);
+    plic
+}
+
+fn mip(m: &mut Machine, plic: &mut Plic, hart_id: usize) -> u32 {
+    let core = &mut m.harts[hart_id].core;
+    plic.tick(core);
+    csr::read(csr::Csr::mip, core) & (MEIP | SEIP)
+}
+
+// source 6 for M-mode and source 8 for S-mode of hart 1
+fn plic_with_m_and_s() -> Plic {
+    let mut plic = plic(2, &[(6, 1), (8, 1)]);
+    plic.write(enable(2), 4, 1 << 6);
+    plic.write(enable(3), 4, 1 << 8);
+    plic
+}
+
+#[test]
+fn claim_highest_priority_then_lowest_id() {
+    let mut m = Machine::new(1);
+    let mut plic = plic(0, &[(3, 2), (7, 5), (5, 5), (9, 1)]);
+    assert_eq!(mip(&mut m, &mut plic, 0), MEIP);
+    let claims: Vec<u32> = (0..5).map(|_| plic.read(claim(0), 4)).collect();
+    assert_eq!(claims, [5, 7, 3, 9, 0]);
+    assert_eq!(mip(&mut m, &mut plic, 0), 0);
+}
+
+#[test]
+fn threshold_masks_lower_priorities() {
+    let mut m = Machine::new(1);
+    let mut plic = plic(0, &[(3, 2)]);
+    plic.write(threshold(0), 4, 2);
+    assert_eq!(mip(&mut m, &mut plic, 0), 0);
+    assert_eq!(plic.read(claim(0), 4), 0);
+    // priority 0 never interrupts
+    plic.write(threshold(0), 4, 0);
+    plic.write(BASE + 4 * 3, 4, 0);
+    assert_eq!(mip(&mut m, &mut plic, 0), 0);
+    plic.write(BASE + 4 * 3, 4, 2);
+    plic.write(threshold(0), 4, 1);
+    assert_eq!(mip(&mut m, &mut plic, 0), MEIP);
+    assert_eq!(plic.read(claim(0), 4), 3);
+}
+
+#[test]
+fn separate_contexts_per_mode_and_hart() {
+    let mut m = Machine::new(2);
+    // context 3 is S-mode of hart 1
+    let mut plic = plic(3, &[(4, 1)]);
+    assert_eq!(mip(&mut m, &mut plic, 0), 0);
+    assert_eq!(mip(&mut m, &mut plic, 1), SEIP);
+    for context in 0..3 {
+        assert_eq!(plic.read(claim(context), 4), 0);
+    }
+    assert_eq!(plic.read(claim(3), 4), 4);
+
+    // M-mode of hart 1 as well
+    let mut plic = plic_with_m_and_s();
+    assert_eq!(mip(&mut m, &mut plic, 1), MEIP | SEIP);
+    assert_eq!(plic.read(claim(2), 4), 6);
+    assert_eq!(mip(&mut m, &mut plic, 1), SEIP);
+    assert_eq!(plic.read(claim(3), 4), 8);
+    assert_eq!(mip(&mut m, &mut plic, 1), 0);
+}
+
+#[test]
+fn complete_rearms_the_source() {
+    let mut m = Machine::new(1);
+    let mut plic = plic(0, &[(2, 1)]);
+    mip(&mut m, &mut plic, 0);
+    assert_eq!(plic.read(claim(0), 4), 2);
+    // the line is still high, but the source is in service until completed
+    assert_eq!(mip(&mut m, &mut plic, 0), 0);
+    assert_eq!(plic.read(claim(0), 4), 0);
+    // completion in a context the source is not enabled in is ignored
+    plic.write(claim(1), 4, 2);
+    assert_eq!(mip(&mut m, &mut plic, 0), 0);
+    plic.write(claim(0), 4, 2);
+    assert_eq!(mip(&mut m, &mut plic, 0), MEIP);
+    assert_eq!(plic.read(claim(0), 4), 2);
+}
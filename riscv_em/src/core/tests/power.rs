@@ -0,0 +1,108 @@
+// Poweroff and reboot requested by the guest through syscon or the test finisher.
+
+use super::*;
+use crate::core::reboot;
+use crate::fdt;
+use crate::memory::{sifive_test::SifiveTest, syscon::Syscon};
+
+const SYSCON: u32 = 0x1c00000;
+const FINISHER: u32 = 0x100000;
+
+fn machine(program: &[u32]) -> Machine {
+    let mut m = Machine::new(1);
+    m.bus.attach(Box::new(Syscon::default())).unwrap();
+    m.bus.attach(Box::new(SifiveTest::default())).unwrap();
+    m.load(0, program);
+    m
+}
+
+// x1 points to the device, the store of val is the fourth instruction
+fn request(base: u32, val: u32) -> [u32; 5] {
+    let upper = val.wrapping_add(0x800) >> 12;
+    [
+        u_type(0b0110111, 1, base >> 12),
+        u_type(0b0110111, 2, upper),
+        addi(2, 2, val.wrapping_sub(upper << 12) as i32),
+        sw(2, 1, 0),
+        addi(3, 0, 1),
+    ]
+}
+
+#[test]
+fn poweroff_ends_the_quantum() {
+    let mut m = machine(&request(SYSCON, 1));
+    assert_eq!(hart_run(&mut m.harts[0], &mut m.bus, 100), State::Shutdown);
+    // nothing runs after the store
+    assert_eq!(m.pc(0), 16);
+    assert_eq!(m.reg(0, 3), 0);
+    assert_eq!(m.bus.power, None);
+}
+
+#[test]
+fn other_values_are_ignored() {
+    let mut m = machine(&request(SYSCON, 3));
+    m.run(0, 5);
+    assert_eq!(m.reg(0, 3), 1);
+    assert_eq!(m.bus.power, None);
+}
+
+#[test]
+fn reboot_reloads_the_images() {
+    let program = request(SYSCON, 2);
+    let mut m = machine(&program);
+    assert_eq!(hart_run(&mut m.harts[0], &mut m.bus, 100), State::Reboot);
+
+    let bios = std::env::temp_dir().join(format!("riscv_em_bios_{}", std::process::id()));
+    let image: Vec<u8> = program.iter().flat_map(|x| x.to_le_bytes()).collect();
+    std::fs::write(&bios, image).unwrap();
+    // the guest changed RAM and registers before the reboot
+    m.bus.ram.store_word(DATA, 0x12345678);
+    m.set_reg(0, 4, 0x1234);
+    m.harts[0].core.mode = 1;
+    let dtb = fdt::generate(&m.harts, &m.bus, "", None);
+    let result = reboot(&mut m.harts, &mut m.bus, bios.to_str(), None, None, &dtb);
+    std::fs::remove_file(&bios).unwrap();
+    result.unwrap();
+
+    assert_eq!(m.bus.ram.load_word(DATA), 0);
+    assert_eq!(m.bus.ram.load_word(PROGRAM), program[0]);
+    let core = &m.harts[0].core;
+    assert_eq!((core.pc, core.mode), (PROGRAM, 3));
+    assert_eq!(m.reg(0, 4), 0);
+    assert!(m.bus.sbi.is_none());
+    // syscon is back at its power on value, the program runs again
+    assert_eq!(m.bus.device::<Syscon>().unwrap().val, 0);
+    assert_eq!(hart_run(&mut m.harts[0], &mut m.bus, 100), State::Reboot);
+    assert_eq!(m.bus.power, None);
+}
+
+fn finisher(m: &Machine) -> &SifiveTest {
+    m.bus.device::<SifiveTest>().unwrap()
+}
+
+#[test]
+fn finisher_pass() {
+    let mut m = machine(&request(FINISHER, 0x5555));
+    assert_eq!(hart_run(&mut m.harts[0], &mut m.bus, 100), State::Shutdown);
+    assert_eq!(m.reg(0, 3), 0);
+    assert_eq!(finisher(&m).exit_code(), 0);
+}
+
+#[test]
+fn finisher_fail() {
+    // the code is in the high half
+    let mut m = machine(&request(FINISHER, 3 << 16 | 0x3333));
+    assert_eq!(hart_run(&mut m.harts[0], &mut m.bus, 100), State::Shutdown);
+    assert_eq!(m.reg(0, 3), 0);
+    assert_eq!(finisher(&m).exit_code(), 3);
+}
+
+#[test]
+fn finisher_reset() {
+    let mut m = machine(&request(FINISHER, 0x7777));
+    assert_eq!(hart_run(&mut m.harts[0], &mut m.bus, 100), State::Reboot);
+    assert_eq!(m.reg(0, 3), 0);
+    assert_eq!(finisher(&m).exit_code(), 0);
+    m.bus.reset();
+    assert_eq!(finisher(&m).command(), 0);
+}
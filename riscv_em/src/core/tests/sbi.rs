@@ -0,0 +1,139 @@
+// Built-in SBI calls, every program starts with the ecall under test.
+
+use super::*;
+use crate::core::sbi::{
+    self, ERR_INVALID_PARAM, EXT_BASE, EXT_DBCN, EXT_HSM, EXT_IPI, EXT_SRST, EXT_TIME,
+    LEGACY_CONSOLE_PUTCHAR,
+};
+
+const SIP: u32 = 0x144;
+const MIP: u32 = 0x344;
+
+fn machine(harts: usize) -> Machine {
+    let mut m = Machine::new(harts);
+    m.bus.sbi = Some(sbi::Sbi::new(harts));
+    for hart in m.harts.iter_mut() {
+        sbi::setup(&mut hart.core, &mut m.bus.clint);
+    }
+    m
+}
+
+// loads the arguments of the next ecall of the hart
+fn args(m: &mut Machine, hart_id: usize, eid: u32, fid: u32, args: &[u32]) {
+    m.set_reg(hart_id, 17, eid);
+    m.set_reg(hart_id, 16, fid);
+    for (i, arg) in args.iter().enumerate() {
+        m.set_reg(hart_id, 10 + i, *arg);
+    }
+}
+
+fn mip(m: &Machine, hart_id: usize) -> u32 {
+    m.harts[hart_id].core.csr_file[MIP as usize]
+}
+
+#[test]
+fn probe_extension() {
+    let mut m = machine(1);
+    m.load(0, &[ECALL, ECALL]);
+    args(&mut m, 0, EXT_BASE, 3, &[EXT_HSM]);
+    m.run(0, 1);
+    assert_eq!((m.reg(0, 10), m.reg(0, 11)), (0, 1));
+    assert_eq!(m.pc(0), 4);
+    args(&mut m, 0, EXT_BASE, 3, &[0x12345]);
+    m.run(0, 1);
+    assert_eq!((m.reg(0, 10), m.reg(0, 11)), (0, 0));
+}
+
+#[test]
+fn hart_start() {
+    let mut m = machine(2);
+    assert_eq!(hart_run(&mut m.harts[1], &mut m.bus, 1), State::Sleep);
+
+    m.load(0, &[ECALL, ECALL]);
+    m.load(1, &[NOP]);
+    let entry = PROGRAM + PROGRAM_STRIDE;
+    args(&mut m, 0, EXT_HSM, 0, &[1, entry, 0x1234]);
+    m.run(0, 1);
+    assert_eq!(m.reg(0, 10), 0);
+    m.run(1, 1);
+    assert_eq!(m.pc(1), 4);
+    assert_eq!((m.reg(1, 10), m.reg(1, 11)), (1, 0x1234));
+    assert_eq!(m.harts[1].core.mode, 1);
+
+    // hart_get_status, started
+    args(&mut m, 0, EXT_HSM, 2, &[1]);
+    m.run(0, 1);
+    assert_eq!((m.reg(0, 10), m.reg(0, 11)), (0, 0));
+}
+
+#[test]
+fn ipi_sets_ssip_until_cleared_through_sip() {
+    let mut m = machine(1);
+    let csrrc = i_type(0b1110011, 0, 0b011, 5, SIP as i32);
+    m.load(0, &[ECALL, csrrc]);
+    args(&mut m, 0, EXT_IPI, 0, &[0b1, 0]);
+    m.set_reg(0, 5, 0b10);
+    m.run(0, 1);
+    assert_eq!(m.reg(0, 10), 0);
+    assert_ne!(mip(&m, 0) & 0b10, 0);
+    m.run(0, 1);
+    assert_eq!(mip(&m, 0) & 0b10, 0);
+}
+
+#[test]
+fn set_timer() {
+    let mut m = machine(1);
+    m.load(0, &[ECALL, NOP, NOP]);
+    args(&mut m, 0, EXT_TIME, 0, &[100, 0]);
+    m.run(0, 1);
+    m.run(0, 1);
+    assert_eq!(mip(&m, 0) & (1 << 5), 0);
+    m.bus.clint.mtime = 200;
+    m.run(0, 1);
+    assert_ne!(mip(&m, 0) & (1 << 5), 0);
+}
+
+#[test]
+fn system_reset() {
+    let mut m = machine(1);
+    m.load(0, &[ECALL]);
+    args(&mut m, 0, EXT_SRST, 0, &[0, 0]);
+    assert_eq!(hart_run(&mut m.harts[0], &mut m.bus, 1), State::Shutdown);
+}
+
+#[test]
+fn console_bytes_pass_through() {
+    let mut m = machine(1);
+    m.load(0, &[ECALL, ECALL, ECALL]);
+    // "é" in UTF-8, split over two calls
+    args(&mut m, 0, LEGACY_CONSOLE_PUTCHAR, 0, &[0xc3]);
+    m.run(0, 1);
+    args(&mut m, 0, EXT_DBCN, 2, &[0xa9]);
+    m.run(0, 1);
+    assert_eq!(m.reg(0, 10), 0);
+    // a buffer ending in the middle of a sequence
+    m.bus.ram.store_byte(DATA, b'x');
+    m.bus.ram.store_byte(DATA + 1, 0xe2);
+    args(&mut m, 0, EXT_DBCN, 0, &[2, DATA, 0]);
+    m.run(0, 1);
+    assert_eq!((m.reg(0, 10), m.reg(0, 11)), (0, 2));
+    assert_eq!(m.console.take(), [0xc3, 0xa9, b'x', 0xe2]);
+}
+
+#[test]
+fn console_buffer_across_banks() {
+    let mut m = machine(1);
+    let second = RAM_OFFSET + 0x30000;
+    m.bus.ram = ram::RAM::new(&[(RAM_OFFSET, 0x20000), (second, 0x1000)]);
+    m.load(0, &[ECALL, ECALL]);
+    // first and last byte are in RAM, the middle is not
+    let addr = RAM_OFFSET + 0x1fff0;
+    args(&mut m, 0, EXT_DBCN, 0, &[second + 0x10 - addr, addr, 0]);
+    m.run(0, 1);
+    assert_eq!((m.reg(0, 10) as i32, m.reg(0, 11)), (ERR_INVALID_PARAM, 0));
+    // console_read checks the same way
+    args(&mut m, 0, EXT_DBCN, 1, &[second + 0x10 - addr, addr, 0]);
+    m.run(0, 1);
+    assert_eq!(m.reg(0, 10) as i32, ERR_INVALID_PARAM);
+    assert!(m.console.take().is_empty());
+}
@@ -0,0 +1,97 @@
+// Several harts on one bus: software interrupts between harts, a timer per hart and the
+// registers every hart starts with.
+
+use super::*;
+use crate::core::soc_init;
+use crate::memory;
+use crate::{check_cpus, fdt};
+
+const CLINT: u32 = 0x2000000;
+const MSIP: u32 = 1 << 3;
+const MTIP: u32 = 1 << 7;
+
+fn mip(m: &Machine, hart_id: usize) -> u32 {
+    csr::read(csr::Csr::mip, &m.harts[hart_id].core) & (MSIP | MTIP)
+}
+
+#[test]
+fn software_interrupt_to_another_hart() {
+    let mut m = Machine::new(2);
+    // hart 0 sets and clears the msip of hart 1
+    let program = [
+        u_type(0b0110111, 1, CLINT >> 12),
+        addi(2, 0, 1),
+        sw(2, 1, 4),
+        sw(0, 1, 4),
+    ];
+    m.load(0, &program);
+    m.load(1, &[NOP, NOP]);
+    m.bus.clint.set_mtimecmp(0, u64::MAX);
+    m.bus.clint.set_mtimecmp(1, u64::MAX);
+    m.run(0, 3);
+    m.run(1, 1);
+    assert_eq!(mip(&m, 1), MSIP);
+    // the next quantum of hart 0 starts with a tick
+    m.run(0, 1);
+    assert_eq!(mip(&m, 0), 0);
+    m.run(1, 1);
+    assert_eq!(mip(&m, 1), 0);
+}
+
+#[test]
+fn timer_per_hart() {
+    let mut m = Machine::new(3);
+    for hart_id in 0..3 {
+        m.load(hart_id, &[NOP]);
+    }
+    // mtimecmp of hart 1 through the registers, the others never fire
+    for (reg, val) in [
+        (0x4000, u32::MAX),
+        (0x4004, u32::MAX),
+        (0x4008, 100),
+        (0x400c, 0),
+    ] {
+        memory::store_word(&mut m.bus, CLINT + reg, val).unwrap();
+    }
+    m.bus.clint.set_mtimecmp(2, u64::MAX);
+    m.bus.clint.mtime = 200;
+    for hart_id in 0..3 {
+        m.run(hart_id, 1);
+    }
+    assert_eq!((mip(&m, 0), mip(&m, 1), mip(&m, 2)), (0, MTIP, 0));
+}
+
+#[test]
+fn harts_start_with_their_id() {
+    let mut m = Machine::new(4);
+    let bios = std::env::temp_dir().join(format!("riscv_em_smp_{}", std::process::id()));
+    std::fs::write(&bios, HALT.to_le_bytes()).unwrap();
+    let dtb = fdt::generate(&m.harts, &m.bus, "", None);
+    let result = soc_init(&mut m.harts, &mut m.bus, bios.to_str(), None, None, &dtb);
+    std::fs::remove_file(&bios).unwrap();
+    result.unwrap();
+
+    let dtb_addr = m.reg(0, 11);
+    assert_eq!(m.bus.ram.load_word(dtb_addr), 0xd00dfeed_u32.to_be());
+    for hart_id in 0..4 {
+        let core = &m.harts[hart_id].core;
+        assert_eq!(csr::read(csr::Csr::mhartid, core), hart_id as u32);
+        // a0 is the hart id, a1 the device tree, every hart starts at the entry
+        assert_eq!(
+            (m.reg(hart_id, 10), m.reg(hart_id, 11)),
+            (hart_id as u32, dtb_addr)
+        );
+        assert_eq!((core.pc, core.mode), (PROGRAM, 3));
+    }
+}
+
+#[test]
+fn device_tree_lists_every_hart() {
+    let m = Machine::new(2);
+    let dtb = fdt::generate(&m.harts, &m.bus, "", None);
+    assert_eq!(check_cpus(&dtb, 2), Ok(()));
+    let err = check_cpus(&dtb, 4).unwrap_err();
+    assert_eq!(err, "device tree lists 2 cpus, but there are 4 harts");
+    assert!(check_cpus(&dtb[..dtb.len() / 2], 2).is_err());
+    assert!(check_cpus(b"not a device tree", 2).is_err());
+}
@@ -0,0 +1,81 @@
+// A and D bits: without menvcfg.ADUE a pte that needs an update raises a page fault (Svade),
+// with it the page walker writes the pte (Svadu).
+
+use super::*;
+
+const VA: u32 = 0x10000;
+const HANDLER: u32 = PROGRAM + 0x100;
+
+// lw x3, 0(x1)
+const LW: u32 = 0x0000a183;
+// sw x3, 0(x1)
+const SW: u32 = 0x0030a023;
+
+// S-mode, VA is mapped read/write to DATA with the given A and D bits, x1 = VA
+fn machine(adue: bool, flags: u32, program: &[u32]) -> Machine {
+    let mut m = Machine::new(1);
+    m.load(0, program);
+    m.supervisor(0, 0);
+    m.map_mega(PROGRAM, PROGRAM, PTE_R | PTE_X | PTE_A);
+    m.map(VA, DATA, PTE_R | PTE_W | flags);
+    m.set_reg(0, 1, VA);
+    let core = &mut m.harts[0].core;
+    core.svadu = true;
+    let menvcfgh = if adue { csr::MENVCFGH_ADUE } else { 0 };
+    csr::write(csr::Csr::menvcfgh, menvcfgh, core);
+    csr::write(csr::Csr::mtvec, HANDLER, core);
+    m
+}
+
+fn pte(m: &mut Machine) -> u32 {
+    let pte_addr = m.pte_addr(VA);
+    m.bus.ram.load_word(pte_addr)
+}
+
+// (mcause, mtval) of the trap taken by the first instruction
+fn trap(m: &mut Machine) -> (u32, u32) {
+    m.run(0, 1);
+    let core = &m.harts[0].core;
+    assert_eq!(core.pc, HANDLER);
+    (
+        csr::read(csr::Csr::mcause, core),
+        csr::read(csr::Csr::mtval, core),
+    )
+}
+
+#[test]
+fn page_fault_without_adue() {
+    let mut m = machine(false, 0, &[LW]);
+    assert_eq!(trap(&mut m), (13, VA));
+    assert_eq!(pte(&mut m) & (PTE_A | PTE_D), 0);
+    // a store to a page that is accessed but not dirty
+    let mut m = machine(false, PTE_A, &[SW]);
+    assert_eq!(trap(&mut m), (15, VA));
+    assert_eq!(pte(&mut m) & PTE_D, 0);
+}
+
+#[test]
+fn walker_sets_a_and_d_with_adue() {
+    let mut m = machine(true, 0, &[LW, SW]);
+    m.run(0, 1);
+    assert_eq!(m.harts[0].core.pc, PROGRAM + 4);
+    assert_eq!(pte(&mut m) & (PTE_A | PTE_D), PTE_A);
+    m.run(0, 1);
+    assert_eq!(m.harts[0].core.pc, PROGRAM + 8);
+    assert_eq!(pte(&mut m) & (PTE_A | PTE_D), PTE_A | PTE_D);
+}
+
+#[test]
+fn pmp_denied_pte_write_is_access_fault() {
+    for (instr, cause) in [(LW, 5), (SW, 7)] {
+        let mut m = machine(true, 0, &[instr]);
+        let pte_addr = m.pte_addr(VA);
+        let core = &mut m.harts[0].core;
+        // entry 0: the leaf pte is read only, entry 1: everything else
+        csr::write(csr::Csr::pmpaddr0, pte_addr >> 2, core);
+        csr::write(csr::Csr::pmpaddr1, u32::MAX, core);
+        csr::write(csr::Csr::pmpcfg0, 0x1f << 8 | 0b10 << 3 | 0b001, core);
+        assert_eq!(trap(&mut m), (cause, VA));
+        assert_eq!(pte(&mut m) & (PTE_A | PTE_D), 0);
+    }
+}
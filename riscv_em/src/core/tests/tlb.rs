@@ -0,0 +1,110 @@
+// The TLB keeps a translation until sfence.vma or a satp write removes it. Every test
+// translates VA once, points its pte somewhere else and checks whether the next load
+// still sees the cached translation.
+
+use super::*;
+
+const VA: u32 = 0x10000;
+// VA is first backed by OLD, then by NEW
+const OLD: u32 = DATA;
+const NEW: u32 = DATA + 0x1000;
+const SATP: u32 = 0x180;
+
+// lw x3, 0(x1)
+const LW: u32 = 0x0000a183;
+
+fn sfence_vma(rs1: u32, rs2: u32) -> u32 {
+    r_type(0b1110011, 0, 0b000, rs1, rs2, 0b0001001)
+}
+
+// S-mode with the given ASID, x1 = VA, x2 = another page, x5 = asid, x6 = another asid
+fn machine(asid: u32, flags: u32, program: &[u32]) -> Machine {
+    let mut m = Machine::new(1);
+    m.load(0, program);
+    m.supervisor(0, asid);
+    m.map_mega(PROGRAM, PROGRAM, PTE_R | PTE_X | PTE_A);
+    m.map(VA, OLD, PTE_R | PTE_A | flags);
+    m.bus.ram.store_word(OLD, 1);
+    m.bus.ram.store_word(NEW, 2);
+    m.set_reg(0, 1, VA);
+    m.set_reg(0, 2, VA + 0x1000);
+    m.set_reg(0, 5, asid);
+    m.set_reg(0, 6, asid + 1);
+    m
+}
+
+// runs the first load, remaps VA and runs the rest, returns what the last load read
+fn remapped(m: &mut Machine, flags: u32, instructions: u32) -> u32 {
+    m.run(0, 1);
+    assert_eq!(m.reg(0, 3), 1);
+    m.map(VA, NEW, PTE_R | PTE_A | flags);
+    m.run(0, instructions);
+    m.reg(0, 3)
+}
+
+#[test]
+fn translation_is_cached() {
+    let mut m = machine(0, 0, &[LW, LW]);
+    assert_eq!(remapped(&mut m, 0, 1), 1);
+}
+
+#[test]
+fn sfence_vma_all() {
+    let mut m = machine(0, 0, &[LW, sfence_vma(0, 0), LW]);
+    assert_eq!(remapped(&mut m, 0, 2), 2);
+}
+
+#[test]
+fn sfence_vma_address() {
+    // another page keeps the entry
+    let mut m = machine(0, 0, &[LW, sfence_vma(2, 0), LW]);
+    assert_eq!(remapped(&mut m, 0, 2), 1);
+    let mut m = machine(0, 0, &[LW, sfence_vma(1, 0), LW]);
+    assert_eq!(remapped(&mut m, 0, 2), 2);
+}
+
+#[test]
+fn sfence_vma_asid() {
+    let mut m = machine(3, 0, &[LW, sfence_vma(0, 6), LW]);
+    assert_eq!(remapped(&mut m, 0, 2), 1);
+    let mut m = machine(3, 0, &[LW, sfence_vma(0, 5), LW]);
+    assert_eq!(remapped(&mut m, 0, 2), 2);
+    // address and asid
+    let mut m = machine(3, 0, &[LW, sfence_vma(1, 6), LW]);
+    assert_eq!(remapped(&mut m, 0, 2), 1);
+    let mut m = machine(3, 0, &[LW, sfence_vma(1, 5), LW]);
+    assert_eq!(remapped(&mut m, 0, 2), 2);
+}
+
+#[test]
+fn global_pages_survive_asid_flush() {
+    let mut m = machine(3, PTE_G, &[LW, sfence_vma(0, 5), LW]);
+    assert_eq!(remapped(&mut m, PTE_G, 2), 1);
+    let mut m = machine(3, PTE_G, &[LW, sfence_vma(1, 5), LW]);
+    assert_eq!(remapped(&mut m, PTE_G, 2), 1);
+    let mut m = machine(3, PTE_G, &[LW, sfence_vma(1, 0), LW]);
+    assert_eq!(remapped(&mut m, PTE_G, 2), 2);
+}
+
+#[test]
+fn satp_write_flushes() {
+    let mut m = machine(0, 0, &[LW, csrrw(0, SATP, 7), LW]);
+    m.set_reg(0, 7, 1 << 31 | PAGE_TABLES >> 12);
+    assert_eq!(remapped(&mut m, 0, 2), 2);
+}
+
+#[test]
+fn megapage_hit_on_every_page() {
+    let mega = 0x400000;
+    let (old, new) = (RAM_OFFSET + 0x400000, RAM_OFFSET + 0x800000);
+    let mut m = machine(0, 0, &[LW, LW]);
+    m.map_mega(mega, old, PTE_R | PTE_A);
+    m.set_reg(0, 1, mega);
+    m.run(0, 1);
+    // another 4 KiB page of the megapage, still through the cached entry
+    m.map_mega(mega, new, PTE_R | PTE_A);
+    m.bus.ram.store_word(old + 0x3004, 3);
+    m.set_reg(0, 1, mega + 0x3004);
+    m.run(0, 1);
+    assert_eq!(m.reg(0, 3), 3);
+}
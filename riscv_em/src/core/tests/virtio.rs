@@ -0,0 +1,382 @@
+// virtio-blk requests and virtio-net frames through the virtio-mmio registers, the test is the
+// driver.
+
+use std::fs;
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use super::*;
+use crate::memory::{
+    self, virtio::VirtioDevice, virtio::registers::*, virtio_blk::VirtioBlk, virtio_net::NetConfig,
+};
+use crate::parse_drive;
+
+// slot 1, the second transport
+const BASE: u32 = 0x4201000;
+const QUEUE_SIZE: u32 = 8;
+const DESC: u32 = DATA;
+const AVAIL: u32 = DATA + 0x100;
+const USED: u32 = DATA + 0x200;
+const HEADER: u32 = DATA + 0x400;
+const STATUS: u32 = DATA + 0x410;
+const BUFFER: u32 = DATA + 0x800;
+// rings of the second queue
+const QUEUE_1: u32 = 0x1000;
+
+// disk image with byte i = i % 251, removed when dropped
+struct Image(String);
+
+impl Image {
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("riscv_em_{}_{}.img", name, std::process::id()));
+        let bytes: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, bytes).unwrap();
+        Image(path.to_str().unwrap().to_string())
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn reg_write(m: &mut Machine, reg: u32, val: u32) {
+    memory::store_word(&mut m.bus, BASE + reg, val).unwrap();
+}
+
+fn reg_read(m: &mut Machine, reg: u32) -> u32 {
+    memory::load_word(&mut m.bus, BASE + reg).unwrap()
+}
+
+// a machine with a disk in slot 1 and its queue set up by the driver
+fn machine(image: &Image, read_only: bool) -> Machine {
+    let mut m = Machine::new(1);
+    let mut blk = VirtioBlk::default();
+    blk.init(&image.0, read_only).unwrap();
+    m.bus
+        .attach(Box::new(VirtioDevice::new(1, Box::new(blk))))
+        .unwrap();
+
+    assert_eq!(reg_read(&mut m, _MagicValue), 0x74726976);
+    assert_eq!(reg_read(&mut m, _DeviceID), 2);
+    reg_write(&mut m, _Status, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    reg_write(&mut m, _DriverFeaturesSel, 1);
+    reg_write(&mut m, _DriverFeatures, 1);
+    reg_write(&mut m, _Status, STATUS_FEATURES_OK);
+    setup_queue(&mut m, 0);
+    reg_write(&mut m, _Status, STATUS_DRIVER_OK);
+    m
+}
+
+fn setup_queue(m: &mut Machine, queue: u32) {
+    let offset = queue * QUEUE_1;
+    reg_write(m, _QueueSel, queue);
+    reg_write(m, _QueueSize, QUEUE_SIZE);
+    reg_write(m, _QueueDescLow, DESC + offset);
+    reg_write(m, _QueueDriverLow, AVAIL + offset);
+    reg_write(m, _QueueDeviceLow, USED + offset);
+    reg_write(m, _QueueReady, 1);
+}
+
+// makes descriptor i of a queue the head of the next available chain
+fn make_available(m: &mut Machine, queue: u32, i: u16) {
+    let avail = AVAIL + queue * QUEUE_1;
+    let idx = m.bus.ram.load_hword(avail + 2);
+    m.bus
+        .ram
+        .store_hword(avail + 4 + 2 * (idx as u32 % QUEUE_SIZE), i);
+    m.bus.ram.store_hword(avail + 2, idx + 1);
+}
+
+fn descriptor(m: &mut Machine, i: u32, addr: u32, len: u32, flags: u16, next: u16) {
+    descriptor_in(m, 0, i, addr, len, flags, next);
+}
+
+fn descriptor_in(m: &mut Machine, queue: u32, i: u32, addr: u32, len: u32, flags: u16, next: u16) {
+    let desc = DESC + queue * QUEUE_1 + 16 * i;
+    m.bus.ram.store_word(desc, addr);
+    m.bus.ram.store_word(desc + 4, 0);
+    m.bus.ram.store_word(desc + 8, len);
+    m.bus.ram.store_hword(desc + 12, flags);
+    m.bus.ram.store_hword(desc + 14, next);
+}
+
+// one request of 512 bytes at BUFFER, returns the status byte
+fn request(m: &mut Machine, op: u32, sector: u32) -> u8 {
+    m.bus.ram.store_word(HEADER, op);
+    m.bus.ram.store_word(HEADER + 4, 0);
+    m.bus.ram.store_word(HEADER + 8, sector);
+    m.bus.ram.store_word(HEADER + 12, 0);
+    m.bus.ram.store_byte(STATUS, 0xff);
+    let data_flags = match op {
+        0 => VIRTQ_DESC_F_WRITE,
+        _ => 0,
+    };
+    descriptor(m, 0, HEADER, 16, VIRTQ_DESC_F_NEXT, 1);
+    descriptor(m, 1, BUFFER, 512, data_flags | VIRTQ_DESC_F_NEXT, 2);
+    descriptor(m, 2, STATUS, 1, VIRTQ_DESC_F_WRITE, 0);
+
+    let idx = m.bus.ram.load_hword(AVAIL + 2);
+    make_available(m, 0, 0);
+    reg_write(m, _QueueNotify, 0);
+    m.bus.tick(&mut m.harts[0].core);
+
+    assert_eq!(m.bus.ram.load_hword(USED + 2), idx + 1);
+    assert_eq!(
+        reg_read(m, _InterruptStatus) & INT_UsedBufferNotification,
+        1
+    );
+    reg_write(m, _InterruptACK, INT_UsedBufferNotification);
+    m.bus.ram.load_byte(STATUS)
+}
+
+#[test]
+fn read_and_write_in_second_slot() {
+    let image = Image::new("rw");
+    let mut m = machine(&image, false);
+    // capacity in sectors is the first field of the configuration space
+    assert_eq!(reg_read(&mut m, _Config), 8);
+
+    assert_eq!(request(&mut m, 0, 1), 0);
+    assert_eq!(m.bus.ram.load_byte(BUFFER), (512 % 251) as u8);
+
+    m.bus.ram.store_byte(BUFFER, 0xaa);
+    assert_eq!(request(&mut m, 1, 2), 0);
+    assert_eq!(fs::read(&image.0).unwrap()[1024], 0xaa);
+}
+
+#[test]
+fn read_only_drive() {
+    let image = Image::new("ro");
+    let mut m = machine(&image, true);
+    // VIRTIO_BLK_F_RO
+    assert_eq!(reg_read(&mut m, _DeviceFeatures) & (1 << 5), 1 << 5);
+
+    assert_eq!(request(&mut m, 0, 0), 0);
+    m.bus.ram.store_byte(BUFFER, 0xaa);
+    // VIRTIO_BLK_S_IOERR
+    assert_eq!(request(&mut m, 1, 0), 1);
+    assert_eq!(fs::read(&image.0).unwrap()[0], 0);
+}
+
+// guest socket and the peer socket of the test, removed when dropped
+struct Sockets(String, String);
+
+impl Drop for Sockets {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+        let _ = fs::remove_file(&self.1);
+    }
+}
+
+// a machine with a network device in slot 1 and both queues set up by the driver
+fn net_machine(config: &str) -> Machine {
+    let net = NetConfig::parse(config).unwrap().open(0).unwrap();
+    let mut m = Machine::new(1);
+    m.bus
+        .attach(Box::new(VirtioDevice::new(1, Box::new(net))))
+        .unwrap();
+    assert_eq!(reg_read(&mut m, _DeviceID), 1);
+    reg_write(&mut m, _Status, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+    reg_write(&mut m, _Status, STATUS_FEATURES_OK);
+    // receive and transmit queue
+    setup_queue(&mut m, 0);
+    setup_queue(&mut m, 1);
+    reg_write(&mut m, _Status, STATUS_DRIVER_OK);
+    m
+}
+
+// 12 byte header and the frame in one buffer, sent with the next tick
+fn transmit(m: &mut Machine, frame: &[u8]) {
+    for (i, byte) in frame.iter().enumerate() {
+        m.bus.ram.store_byte(BUFFER + 12 + i as u32, *byte);
+    }
+    descriptor_in(m, 1, 0, BUFFER, 12 + frame.len() as u32, 0, 0);
+    make_available(m, 1, 0);
+    reg_write(m, _QueueNotify, 1);
+    m.bus.tick(&mut m.harts[0].core);
+}
+
+// a receive buffer for the next frame
+fn offer_buffer(m: &mut Machine, addr: u32) {
+    descriptor_in(m, 0, 0, addr, 1526, VIRTQ_DESC_F_WRITE, 0);
+    make_available(m, 0, 0);
+    reg_write(m, _QueueNotify, 0);
+    reg_write(m, _InterruptACK, INT_UsedBufferNotification);
+}
+
+#[test]
+fn net_frames_through_unix_socket() {
+    let dir = std::env::temp_dir();
+    let name = |side| format!("riscv_em_net_{}_{}.sock", side, std::process::id());
+    let sockets = Sockets(
+        dir.join(name("guest")).to_str().unwrap().to_string(),
+        dir.join(name("peer")).to_str().unwrap().to_string(),
+    );
+    let peer = UnixDatagram::bind(&sockets.1).unwrap();
+    peer.set_nonblocking(true).unwrap();
+    let mut m = net_machine(&format!(
+        "unix,bind={},peer={},mac=52:54:00:00:00:07",
+        sockets.0, sockets.1
+    ));
+    // the driver reads the MAC address a byte at a time
+    let mac: Vec<u8> = (0..6)
+        .map(|i| memory::load_byte(&mut m.bus, BASE + _Config + i).unwrap())
+        .collect();
+    assert_eq!(mac, [0x52, 0x54, 0x00, 0x00, 0x00, 0x07]);
+
+    let frame: Vec<u8> = (0..64).collect();
+
+    transmit(&mut m, &frame);
+    let mut received = [0; 128];
+    assert_eq!(peer.recv(&mut received).unwrap(), 64);
+    assert_eq!(received[..64], frame[..]);
+    assert_eq!(m.bus.ram.load_hword(USED + QUEUE_1 + 2), 1);
+
+    // receive: the buffer waits for a frame
+    offer_buffer(&mut m, BUFFER);
+    m.bus.tick(&mut m.harts[0].core);
+    assert_eq!(m.bus.ram.load_hword(USED + 2), 0);
+
+    peer.send_to(&frame, &sockets.0).unwrap();
+    m.bus.tick(&mut m.harts[0].core);
+    assert_eq!(m.bus.ram.load_hword(USED + 2), 1);
+    // length of the header and the frame
+    assert_eq!(m.bus.ram.load_word(USED + 8), 12 + 64);
+    // num_buffers
+    assert_eq!(m.bus.ram.load_hword(BUFFER + 10), 1);
+    for (i, byte) in frame.iter().enumerate() {
+        assert_eq!(m.bus.ram.load_byte(BUFFER + 12 + i as u32), *byte);
+    }
+    assert_eq!(
+        reg_read(&mut m, _InterruptStatus) & INT_UsedBufferNotification,
+        1
+    );
+}
+
+// the pcap file of a test, removed when dropped
+struct Capture(String);
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn net_frames_through_udp_with_capture() {
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    // a free port for the guest
+    let guest = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("riscv_em_{}.pcap", std::process::id()));
+    let capture = Capture(path.to_str().unwrap().to_string());
+    let mut m = net_machine(&format!(
+        "udp,bind={},peer={},pcap={}",
+        guest,
+        peer.local_addr().unwrap(),
+        capture.0
+    ));
+
+    let sent: Vec<u8> = (0..60).collect();
+    transmit(&mut m, &sent);
+    let mut received = [0; 128];
+    let (len, from) = peer.recv_from(&mut received).unwrap();
+    assert_eq!((&received[..len], from), (&sent[..], guest));
+
+    let reply: Vec<u8> = (100..180).collect();
+    offer_buffer(&mut m, BUFFER);
+    peer.send_to(&reply, guest).unwrap();
+    // the datagram may take a moment on loopback
+    for _ in 0..100 {
+        m.bus.tick(&mut m.harts[0].core);
+        if m.bus.ram.load_hword(USED + 2) == 1 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(m.bus.ram.load_word(USED + 8), 12 + 80);
+    for (i, byte) in reply.iter().enumerate() {
+        assert_eq!(m.bus.ram.load_byte(BUFFER + 12 + i as u32), *byte);
+    }
+
+    // global header, then both frames in the order they passed the device
+    let pcap = fs::read(&capture.0).unwrap();
+    let u32_at = |offset: usize| u32::from_le_bytes(pcap[offset..offset + 4].try_into().unwrap());
+    assert_eq!(pcap.len(), 24 + 16 + 60 + 16 + 80);
+    assert_eq!(u32_at(0), 0xa1b2c3d4);
+    assert_eq!(u32_at(4), 2 | 4 << 16);
+    // snaplen and LINKTYPE_ETHERNET
+    assert_eq!((u32_at(16), u32_at(20)), (65536, 1));
+    let mut offset = 24;
+    for frame in [&sent, &reply] {
+        let len = frame.len();
+        assert_eq!(
+            (u32_at(offset + 8), u32_at(offset + 12)),
+            (len as u32, len as u32)
+        );
+        assert_eq!(&pcap[offset + 16..offset + 16 + len], &frame[..]);
+        offset += 16 + len;
+    }
+}
+
+#[test]
+fn net_buffers_outside_ram() {
+    let mut m = net_machine("none");
+    // the transmit buffer ends past RAM
+    let (base, length) = m.bus.ram.region();
+    descriptor_in(&mut m, 1, 0, base + length - 8, 12 + 64, 0, 0);
+    make_available(&mut m, 1, 0);
+    reg_write(&mut m, _QueueNotify, 1);
+    m.bus.tick(&mut m.harts[0].core);
+    assert_ne!(reg_read(&mut m, _Status) & STATUS_NEEDS_RESET, 0);
+}
+
+#[test]
+fn net_transmit_chain_too_large() {
+    let mut m = net_machine("none");
+    // two descriptors of 64 KiB each, larger than header and datagram
+    descriptor_in(&mut m, 1, 0, DATA, 0x10000, VIRTQ_DESC_F_NEXT, 1);
+    descriptor_in(&mut m, 1, 1, DATA + 0x10000, 0x10000, 0, 0);
+    make_available(&mut m, 1, 0);
+    reg_write(&mut m, _QueueNotify, 1);
+    m.bus.tick(&mut m.harts[0].core);
+    assert_ne!(reg_read(&mut m, _Status) & STATUS_NEEDS_RESET, 0);
+}
+
+#[test]
+fn missing_queues_have_no_size() {
+    let mut m = net_machine("none");
+    reg_write(&mut m, _QueueSel, 1);
+    assert_ne!(reg_read(&mut m, _QueueSizeMax), 0);
+    // the driver probes one past the last queue
+    reg_write(&mut m, _QueueSel, 2);
+    assert_eq!(reg_read(&mut m, _QueueSizeMax), 0);
+    assert_eq!(reg_read(&mut m, _QueueReady), 0);
+    // writes to its registers are ignored
+    reg_write(&mut m, _QueueSize, QUEUE_SIZE);
+    reg_write(&mut m, _QueueReady, 1);
+    assert_eq!(reg_read(&mut m, _QueueReady), 0);
+    assert_eq!(reg_read(&mut m, _Status) & STATUS_NEEDS_RESET, 0);
+}
+
+#[test]
+fn drive_argument() {
+    // only a trailing ro is an option, other commas are part of the path
+    for (arg, path, read_only) in [
+        ("disk.img", "disk.img", false),
+        ("disk.img,ro", "disk.img", true),
+        ("a,b.img", "a,b.img", false),
+        ("a,b.img,ro", "a,b.img", true),
+        ("disk.img,rw", "disk.img,rw", false),
+        ("disk.img,ro,ro", "disk.img,ro", true),
+    ] {
+        assert_eq!(parse_drive(arg), Ok((path.to_string(), read_only)));
+    }
+}
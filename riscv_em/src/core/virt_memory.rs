@@ -1,5 +1,6 @@
 mod pmp;
-mod sv32;
+pub mod sv32;
+pub mod tlb;
 use crate::{
     core::{Core, Hart, exceptions, virt_memory::sv32::AccessType},
     memory::*,
@@ -19,7 +20,8 @@ pub fn virt_read_word(
     match sv32::translate(addr, hart, bus, AccessType::R) {
         Ok((phys_addr, perm)) => {
             if perm.r {
-                return phys_read_word(phys_addr, hart, bus);
+                return phys_read_word(phys_addr, hart, bus)
+                    .inspect_err(|_| hart.core.trap_val = addr);
             }
             hart.core.trap_val = addr;
             return Err(exceptions::Exception::Load_page_fault);
@@ -36,21 +38,52 @@ pub fn virt_read_word(
         }
     };
 }
+pub fn virt_read_dword(
+    addr: u32,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<u64, exceptions::Exception> {
+    // aligned doubleword never crosses a page
+    if addr & 0b111 > 0 {
+        hart.core.trap_val = addr;
+        return Err(exceptions::Exception::Load_address_misaligned);
+    }
+    let low = virt_read_word(addr, hart, bus)? as u64;
+    let high = virt_read_word(addr + 4, hart, bus)? as u64;
+    Ok((high << 32) | low)
+}
 pub fn virt_fetch_word(
     addr: u32,
     hart: &mut Hart,
     bus: &mut MemoryBus,
 ) -> Result<u32, exceptions::Exception> {
-    let perm = pmp::pmp_check(addr, 4, &hart.core);
-    if !perm.x {
-        // println!("3 Error! read:0x{:x}", addr);
-        hart.core.trap_val = addr;
-        return Err(exceptions::Exception::Instruction_access_fault);
+    // Instructions are fetched in 16-bit parcels. A 32-bit instruction
+    // can straddle a page boundary, then both halves are translated separately.
+    let phys_addr = fetch_translate(addr, hart, bus)?;
+    let low =
+        phys_fetch_hword(phys_addr, hart, bus).inspect_err(|_| hart.core.trap_val = addr)? as u32;
+    if low & 0b11 != 0b11 {
+        // compressed instruction
+        return Ok(low);
     }
+    let phys_addr = if (addr & 0xfff) == 0xffe {
+        fetch_translate(addr + 2, hart, bus)?
+    } else {
+        phys_addr + 2
+    };
+    let high = phys_fetch_hword(phys_addr, hart, bus)
+        .inspect_err(|_| hart.core.trap_val = addr + 2)? as u32;
+    Ok((high << 16) | low)
+}
+fn fetch_translate(
+    addr: u32,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<u32, exceptions::Exception> {
     match sv32::translate(addr, hart, bus, AccessType::X) {
         Ok((phys_addr, perm)) => {
             if perm.x {
-                return phys_fetch_word(phys_addr, hart, bus);
+                return Ok(phys_addr);
             }
             hart.core.trap_val = addr;
             return Err(exceptions::Exception::Instruction_page_fault);
@@ -79,7 +112,8 @@ pub fn virt_read_hword(
     match sv32::translate(addr, hart, bus, AccessType::R) {
         Ok((phys_addr, perm)) => {
             if perm.r {
-                return phys_read_hword(phys_addr, hart, bus);
+                return phys_read_hword(phys_addr, hart, bus)
+                    .inspect_err(|_| hart.core.trap_val = addr);
             }
             hart.core.trap_val = addr;
             return Err(exceptions::Exception::Load_page_fault);
@@ -104,7 +138,8 @@ pub fn virt_read_byte(
     match sv32::translate(addr, hart, bus, AccessType::R) {
         Ok((phys_addr, perm)) => {
             if perm.r {
-                return phys_read_byte(phys_addr, hart, bus);
+                return phys_read_byte(phys_addr, hart, bus)
+                    .inspect_err(|_| hart.core.trap_val = addr);
             }
             hart.core.trap_val = addr;
             return Err(exceptions::Exception::Load_page_fault);
@@ -134,7 +169,8 @@ pub fn virt_write_word(
     match sv32::translate(addr, hart, bus, AccessType::W) {
         Ok((phys_addr, perm)) => {
             if perm.w {
-                return phys_write_word(phys_addr, data, hart, bus);
+                return phys_write_word(phys_addr, data, hart, bus)
+                    .inspect_err(|_| hart.core.trap_val = addr);
             }
             // println!("mmu error 51");
             hart.core.trap_val = addr;
@@ -152,6 +188,116 @@ pub fn virt_write_word(
         }
     };
 }
+// LR.W, reserves the granule of the physical address
+pub fn virt_load_reserved(
+    addr: u32,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<u32, exceptions::Exception> {
+    if addr & 0b11 > 0 {
+        hart.core.trap_val = addr;
+        return Err(exceptions::Exception::Load_address_misaligned);
+    }
+    let phys_addr = data_translate(addr, false, hart, bus)?;
+    // only main memory supports reservations
+    if !bus.ram.claim(phys_addr) {
+        hart.core.trap_val = addr;
+        return Err(exceptions::Exception::Load_access_fault);
+    }
+    let val = phys_read_word(phys_addr, hart, bus).inspect_err(|_| hart.core.trap_val = addr)?;
+    bus.ram.reserve(hart.core.hart_id(), phys_addr);
+    Ok(val)
+}
+// SC.W, stores only while the reservation is valid and returns whether it did
+pub fn virt_store_conditional(
+    addr: u32,
+    data: u32,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<bool, exceptions::Exception> {
+    if addr & 0b11 > 0 {
+        hart.core.trap_val = addr;
+        return Err(exceptions::Exception::StoreAMO_address_misaligned);
+    }
+    let phys_addr = data_translate(addr, true, hart, bus)?;
+    if !bus.ram.claim(phys_addr) {
+        hart.core.trap_val = addr;
+        return Err(exceptions::Exception::StoreAMO_access_fault);
+    }
+    if !bus.ram.take_reservation(hart.core.hart_id(), phys_addr) {
+        return Ok(false);
+    }
+    phys_write_word(phys_addr, data, hart, bus).inspect_err(|_| hart.core.trap_val = addr)?;
+    Ok(true)
+}
+fn data_translate(
+    addr: u32,
+    write: bool,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<u32, exceptions::Exception> {
+    let (access, page_fault) = match write {
+        true => (AccessType::W, exceptions::Exception::StoreAMO_page_fault),
+        false => (AccessType::R, exceptions::Exception::Load_page_fault),
+    };
+    match sv32::translate(addr, hart, bus, access) {
+        Ok((phys_addr, perm)) => {
+            if (write && perm.w) || (!write && perm.r) {
+                return Ok(phys_addr);
+            }
+            hart.core.trap_val = addr;
+            Err(page_fault)
+        }
+        Err(Some(x)) => Err(x),
+        Err(None) => {
+            hart.core.trap_val = addr;
+            Err(page_fault)
+        }
+    }
+}
+pub fn virt_write_dword(
+    addr: u32,
+    data: u64,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<(), exceptions::Exception> {
+    if addr & 0b111 > 0 {
+        hart.core.trap_val = addr;
+        return Err(exceptions::Exception::StoreAMO_address_misaligned);
+    }
+    // An aligned doubleword never crosses a page, but PMP or the bus can still refuse the
+    // second word. Both words are checked before the first one is written.
+    let phys_addr = store_translate(addr, hart, bus)?;
+    let high = phys_addr + 4;
+    if !pmp::pmp_check(high, 4, &hart.core).w || !bus.accepts(high, 4) {
+        hart.core.trap_val = addr + 4;
+        return Err(exceptions::Exception::StoreAMO_access_fault);
+    }
+    // high word first, the store to the low word of tohost hands the whole command over
+    phys_write_word(high, (data >> 32) as u32, hart, bus)
+        .inspect_err(|_| hart.core.trap_val = addr + 4)?;
+    phys_write_word(phys_addr, data as u32, hart, bus).inspect_err(|_| hart.core.trap_val = addr)
+}
+fn store_translate(
+    addr: u32,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<u32, exceptions::Exception> {
+    match sv32::translate(addr, hart, bus, AccessType::W) {
+        Ok((phys_addr, perm)) => {
+            if perm.w {
+                return Ok(phys_addr);
+            }
+            hart.core.trap_val = addr;
+            Err(exceptions::Exception::StoreAMO_page_fault)
+        }
+        Err(Some(x)) => Err(x),
+        Err(None) => {
+            hart.core.trap_val = addr;
+            Err(exceptions::Exception::StoreAMO_page_fault)
+        }
+    }
+}
 pub fn virt_write_hword(
     addr: u32,
     data: u16,
@@ -165,7 +311,8 @@ pub fn virt_write_hword(
     match sv32::translate(addr, hart, bus, AccessType::W) {
         Ok((phys_addr, perm)) => {
             if perm.w {
-                return phys_write_hword(phys_addr, data, hart, bus);
+                return phys_write_hword(phys_addr, data, hart, bus)
+                    .inspect_err(|_| hart.core.trap_val = addr);
             }
             // println!("mmu error 61");
             hart.core.trap_val = addr;
@@ -192,7 +339,8 @@ pub fn virt_write_byte(
     match sv32::translate(addr, hart, bus, AccessType::W) {
         Ok((phys_addr, perm)) => {
             if perm.w {
-                return phys_write_byte(phys_addr, data, hart, bus);
+                return phys_write_byte(phys_addr, data, hart, bus)
+                    .inspect_err(|_| hart.core.trap_val = addr);
             }
             // println!("mmu error 71");
             hart.core.trap_val = addr;
@@ -222,26 +370,30 @@ pub fn phys_read_word(
         hart.core.trap_val = addr;
         return Err(exceptions::Exception::Load_access_fault);
     }
-    if hart.clint.claim(addr) {
-        let val = hart.clint.read(addr);
-        return Ok(val);
-    }
 
-    load_word(bus, addr)
+    let val = load_word(bus, addr).inspect_err(|_| hart.core.trap_val = addr)?;
+    if !bus.ram.claim(addr) {
+        // e.g. a PLIC claim takes the interrupt away from the hart immediately
+        bus.update_mip(&mut hart.core);
+    }
+    Ok(val)
 }
-pub fn phys_fetch_word(
+pub fn phys_fetch_hword(
     addr: u32,
     hart: &mut Hart,
     bus: &mut MemoryBus,
-) -> Result<u32, exceptions::Exception> {
-    let perm = pmp::pmp_check(addr, 4, &hart.core);
+) -> Result<u16, exceptions::Exception> {
+    let perm = pmp::pmp_check(addr, 2, &hart.core);
     if !perm.x {
         // println!("3 Error! read:0x{:x}", addr);
         hart.core.trap_val = addr;
         return Err(exceptions::Exception::Instruction_access_fault);
     }
 
-    load_word(bus, addr)
+    load_hword(bus, addr).map_err(|_| {
+        hart.core.trap_val = addr;
+        exceptions::Exception::Instruction_access_fault
+    })
 }
 
 pub fn phys_read_hword(
@@ -256,7 +408,7 @@ pub fn phys_read_hword(
         return Err(exceptions::Exception::Load_access_fault);
     }
 
-    load_hword(bus, addr)
+    load_hword(bus, addr).inspect_err(|_| hart.core.trap_val = addr)
 }
 pub fn phys_read_byte(
     addr: u32,
@@ -270,7 +422,7 @@ pub fn phys_read_byte(
         return Err(exceptions::Exception::Load_access_fault);
     }
 
-    load_byte(bus, addr)
+    load_byte(bus, addr).inspect_err(|_| hart.core.trap_val = addr)
 }
 
 pub fn phys_write_word(
@@ -283,12 +435,14 @@ pub fn phys_write_word(
     if !perm.w {
         // println!("9 Error! write:0x{:x}", addr);
         hart.core.trap_val = addr;
-        return Err(exceptions::Exception::Load_access_fault);
+        return Err(exceptions::Exception::StoreAMO_access_fault);
     }
-    if hart.clint.claim(addr) {
-        hart.clint.write(addr, data);
+    store_word(bus, addr, data).inspect_err(|_| hart.core.trap_val = addr)?;
+    if !bus.ram.claim(addr) {
+        // e.g. clearing own msip or moving mtimecmp takes effect immediately
+        bus.update_mip(&mut hart.core);
     }
-    store_word(bus, addr, data)
+    Ok(())
 }
 pub fn phys_write_hword(
     addr: u32,
@@ -300,10 +454,10 @@ pub fn phys_write_hword(
     if !perm.w {
         // println!("11 Error! write:0x{:x}", addr);
         hart.core.trap_val = addr;
-        return Err(exceptions::Exception::Load_access_fault);
+        return Err(exceptions::Exception::StoreAMO_access_fault);
     }
 
-    store_hword(bus, addr, data)
+    store_hword(bus, addr, data).inspect_err(|_| hart.core.trap_val = addr)
 }
 pub fn phys_write_byte(
     addr: u32,
@@ -315,8 +469,8 @@ pub fn phys_write_byte(
     if !perm.w {
         // println!("13 Error! write:0x{:x}", addr);
         hart.core.trap_val = addr;
-        return Err(exceptions::Exception::Load_access_fault);
+        return Err(exceptions::Exception::StoreAMO_access_fault);
     }
 
-    store_byte(bus, addr, data)
+    store_byte(bus, addr, data).inspect_err(|_| hart.core.trap_val = addr)
 }
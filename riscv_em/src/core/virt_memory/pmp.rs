@@ -1,4 +1,4 @@
-use crate::core::{Core, csr};
+use crate::core::{Core, PMP_REGIONS, csr};
 
 #[derive(Debug)]
 struct PmpCfg {
@@ -24,7 +24,7 @@ impl PmpCfg {
 pub fn pmp_check(addr: u32, len: u32, core: &Core) -> super::MemoryPermissions {
     // return super::MemoryPermissions { r: true, w: true, x: true, };
     let pmpaddr0_addr = csr::csr_addr(csr::Csr::pmpaddr0);
-    for i in 0..15usize {
+    for i in 0..PMP_REGIONS {
         let pmpcfg = csr::read_pmpXcfg(i as u32, core);
         let pmpcfg = PmpCfg::from(pmpcfg);
         let pmpaddr = core.csr_file[pmpaddr0_addr + i] as u64;
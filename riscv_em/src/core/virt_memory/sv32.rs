@@ -3,6 +3,8 @@ use crate::{
     memory::{MemoryBus, MemoryPermissions},
 };
 
+use super::tlb::TlbEntry;
+
 const PAGESIZE: u32 = 1 << 12;
 const LEVELS: u32 = 2;
 const PTESIZE: u32 = 4;
@@ -59,7 +61,6 @@ impl Into<u32> for PTE {
     }
 }
 
-#[allow(dead_code)]
 impl PTE {
     fn set_a(self) -> Self {
         Self { a: true, ..self }
@@ -162,13 +163,119 @@ pub fn translate(
         ));
     }
 
-    // check tlb
-    // if let Some(v) = memory.tlb.get(&(mode, virt_a)) {
-    //     return Ok(v.clone());
-    // }
-
     let va = VA::from(virt_a);
+    let vpn = virt_a >> 12;
+    let fetch = a_type == AccessType::X;
+
+    let check = |entry: &TlbEntry| -> Result<MemoryPermissions, Option<exceptions::Exception>> {
+        let pte = PTE::from(entry.pte);
+        if pte.u {
+            //user page, never executable from supervisor mode
+            if mode == 1 && (mstatus_sum == 0 || a_type == AccessType::X) {
+                return Err(None);
+            }
+        } else {
+            //supervisor page
+            if mode != 1 {
+                return Err(None);
+            }
+        }
+
+        if mstatus_mxr > 0 {
+            // make eXecutable Readable
+            Ok(MemoryPermissions {
+                r: pte.r || pte.x,
+                w: pte.w,
+                x: pte.x,
+            })
+        } else {
+            Ok(MemoryPermissions {
+                r: pte.r,
+                w: pte.w,
+                x: pte.x,
+            })
+        }
+    };
+    let allowed = |perm: &MemoryPermissions| match a_type {
+        AccessType::R => perm.r,
+        AccessType::W => perm.w,
+        AccessType::X => perm.x,
+    };
+    let ad_set = |entry: &TlbEntry| {
+        let pte = PTE::from(entry.pte);
+        pte.a && (a_type != AccessType::W || pte.d)
+    };
+
+    // a cached entry that would fault might be stale, walk the page table again in that case
+    if let Some(entry) = hart.core.tlb.lookup(vpn, satp.asid, fetch)
+        && let Ok(perm) = check(&entry)
+        && allowed(&perm)
+        && ad_set(&entry)
+    {
+        return Ok((entry.phys_addr(virt_a), perm));
+    }
+
+    // pte reads that fault are reported as access fault of the original access
+    let mut entry = walk(&va, &satp, hart, bus).map_err(|err| {
+        err.map(|_| {
+            hart.core.trap_val = virt_a;
+            access_fault(&a_type)
+        })
+    })?;
+    let perm = check(&entry)?;
+    if allowed(&perm) && !ad_set(&entry) {
+        let adue = csr::read(csr::Csr::menvcfgh, &hart.core) & csr::MENVCFGH_ADUE != 0;
+        if !adue {
+            // Svade extension
+            return Err(None);
+        }
+        update_ad(&mut entry, virt_a, &a_type, hart, bus)?;
+    }
+    // pte with A bit clear are never cached
+    hart.core.tlb.insert(entry, fetch);
+    Ok((entry.phys_addr(virt_a), perm))
+}
+
+// Svadu extension; the page walker sets A, and D on writes, itself.
+// Harts take turns between instructions and devices only run between quanta, so
+// nothing can change the pte between the walk and the update.
+fn update_ad(
+    entry: &mut TlbEntry,
+    virt_a: u32,
+    a_type: &AccessType,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<(), Option<exceptions::Exception>> {
+    let mut pte = PTE::from(entry.pte).set_a();
+    if *a_type == AccessType::W {
+        pte = pte.set_d();
+    }
+    let pte: u32 = pte.into();
 
+    // the pte write is checked by pmp, failure is reported as access fault of the original access
+    if phys_write_word(entry.pte_addr, pte, hart, bus).is_err() {
+        hart.core.trap_val = virt_a;
+        return Err(Some(access_fault(a_type)));
+    }
+    entry.pte = pte;
+    entry.valid = true;
+    Ok(())
+}
+
+fn access_fault(a_type: &AccessType) -> exceptions::Exception {
+    match a_type {
+        AccessType::R => exceptions::Exception::Load_access_fault,
+        AccessType::W => exceptions::Exception::StoreAMO_access_fault,
+        AccessType::X => exceptions::Exception::Instruction_access_fault,
+    }
+}
+
+fn walk(
+    va: &VA,
+    satp: &SATP,
+    hart: &mut Hart,
+    bus: &mut MemoryBus,
+) -> Result<TlbEntry, Option<exceptions::Exception>> {
     let mut a = satp.ppn * PAGESIZE;
     let mut i = LEVELS - 1;
 
@@ -177,7 +284,9 @@ pub fn translate(
     let mut pte_addr = a + index;
     let pte_m1 = phys_read_word(pte_addr, hart, bus)?;
 
+    let mut pte_raw = pte_m1;
     let mut pte = PTE::from(pte_m1);
+    let global = pte.g;
 
     if !pte.v || (!pte.r && pte.w) {
         // print!(" mmu6 ");
@@ -194,6 +303,7 @@ pub fn translate(
         let index = va.vpn0 * PTESIZE;
         pte_addr = a + index;
         let pte_m0 = phys_read_word(pte_addr, hart, bus)?;
+        pte_raw = pte_m0;
         pte = PTE::from(pte_m0);
         if !pte.v || (!pte.r && pte.w) {
             // page fault
@@ -207,20 +317,6 @@ pub fn translate(
             // print!(" mmu4 ");
             return Err(None);
         }
-
-        if pte.u {
-            //user page
-            if (mode == 1 && mstatus_sum == 0) || mode > 1 {
-                // print!(" mmu3 ");
-                return Err(None);
-            }
-        } else {
-            //supervisor page
-            if mode != 1 {
-                // print!(" mmu2 ");
-                return Err(None);
-            }
-        }
     }
 
     // leaf pte has been reached
@@ -233,46 +329,64 @@ pub fn translate(
     let pa = PA {
         ppn1: pte.ppn1,
         ppn0: if i > 0 { va.vpn0 } else { pte.ppn0 },
-        offset: va.offset,
+        offset: 0,
     };
-
     let phys_a: u32 = pa.into();
 
-    let res: (u32, MemoryPermissions);
-    if mstatus_mxr > 0 {
-        // make eXecutable Readable
-        res = (
-            phys_a,
-            MemoryPermissions {
-                r: pte.x,
-                w: pte.w,
-                x: pte.x,
-            },
-        );
-    } else {
-        res = (
-            phys_a,
-            MemoryPermissions {
-                r: pte.r,
-                w: pte.w,
-                x: pte.x,
-            },
-        );
-    }
+    Ok(TlbEntry {
+        valid: pte.a,
+        asid: satp.asid,
+        vpn: (va.vpn1 << 10) | va.vpn0,
+        ppn: phys_a >> 12,
+        superpage: i > 0,
+        global: global || pte.g,
+        pte: pte_raw,
+        pte_addr,
+    })
+}
 
-    // Svade extension
-    if !pte.a || (a_type == AccessType::W && !pte.d) {
-        return Err(None);
-    }
+// Page table walk for the monitor; no permission checks, no A/D updates, no tlb.
+// Returns the visited (pte address, pte) pairs and the physical address if a leaf was reached.
+pub fn walk_info(virt_a: u32, satp: u32, bus: &mut MemoryBus) -> (Vec<(u32, u32)>, Option<u32>) {
+    let satp = SATP::from(satp);
+    let va = VA::from(virt_a);
+    let mut ptes = Vec::new();
 
-    // let mut pte = pte.set_a();
-    // match a_type {
-    //     AccessType::W => pte = pte.set_d(),
-    //     _ => {}
-    // }
-    // let pte_u32: u32 = pte.into();
-    //
-    // phys_write_word(pte_addr, pte_u32, core)?;
+    let mut pte_addr = satp.ppn * PAGESIZE + va.vpn1 * PTESIZE;
+    for level in (0..LEVELS).rev() {
+        // a pte in a device would be a device load
+        if !bus.ram.claim(pte_addr) {
+            break;
+        }
+        let pte_raw = bus.ram.load_word(pte_addr);
+        ptes.push((pte_addr, pte_raw));
+        let pte = PTE::from(pte_raw);
+        if !pte.v || (!pte.r && pte.w) {
+            break;
+        }
+        if pte.r || pte.x {
+            let phys_a = match level {
+                0 => (pte.ppn << 12) | va.offset,
+                _ => (pte.ppn1 << 22) | (va.vpn0 << 12) | va.offset,
+            };
+            return (ptes, Some(phys_a));
+        }
+        pte_addr = pte.ppn * PAGESIZE + va.vpn0 * PTESIZE;
+    }
+    (ptes, None)
+}
 
-    return Ok(res);
+// Physical address a load of the hart would use, for debuggers. Like walk_info it leaves the
+// tlb and the A/D bits alone and checks no permissions. None if the page is not mapped.
+pub fn debug_translate(virt_a: u32, core: &Core, bus: &mut MemoryBus) -> Option<u32> {
+    let satp = csr::read(csr::Csr::satp, core);
+    let mstatus = csr::read(csr::Csr::mstatus, core);
+    let mode = match (mstatus >> 17) & 0b1 {
+        1 => (mstatus >> 11) & 0b11,
+        _ => core.mode,
+    };
+    if satp >> 31 == 0 || mode > 1 {
+        return Some(virt_a);
+    }
+    walk_info(virt_a, satp, bus).1
 }
@@ -0,0 +1,105 @@
+// Software TLB for Sv32 translations.
+//
+// Separate, direct mapped instruction and data TLBs tagged with ASID.
+// A 4 KiB page goes to the slot of its vpn, a megapage to the slot of its
+// vpn1, so a lookup probes both.
+// Entries only hold the translation and the leaf PTE flags. Permissions are
+// checked on every access, so changes of the privilege mode or of
+// MPRV/SUM/MXR don't need a flush.
+
+const TLB_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TlbEntry {
+    pub valid: bool,
+    pub asid: u32,
+    pub vpn: u32, // virtual address >> 12, of the access that filled a megapage
+    pub ppn: u32, // physical address >> 12, of the access that filled a megapage
+    pub superpage: bool,
+    pub global: bool,
+    pub pte: u32, // leaf pte
+    pub pte_addr: u32,
+}
+
+impl TlbEntry {
+    fn covers(&self, vpn: u32) -> bool {
+        if self.superpage {
+            self.vpn >> 10 == vpn >> 10
+        } else {
+            self.vpn == vpn
+        }
+    }
+
+    fn slot(&self) -> usize {
+        match self.superpage {
+            true => (self.vpn >> 10) as usize % TLB_SIZE,
+            false => self.vpn as usize % TLB_SIZE,
+        }
+    }
+
+    // physical address of a virtual address the entry covers
+    pub fn phys_addr(&self, virt_a: u32) -> u32 {
+        match self.superpage {
+            true => (self.ppn >> 10) << 22 | (virt_a & 0x3fffff),
+            false => self.ppn << 12 | (virt_a & 0xfff),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Tlb {
+    itlb: [TlbEntry; TLB_SIZE],
+    dtlb: [TlbEntry; TLB_SIZE],
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Tlb {
+            itlb: [TlbEntry::default(); TLB_SIZE],
+            dtlb: [TlbEntry::default(); TLB_SIZE],
+        }
+    }
+}
+
+impl Tlb {
+    fn entries(&mut self, fetch: bool) -> &mut [TlbEntry; TLB_SIZE] {
+        match fetch {
+            true => &mut self.itlb,
+            false => &mut self.dtlb,
+        }
+    }
+
+    pub fn lookup(&mut self, vpn: u32, asid: u32, fetch: bool) -> Option<TlbEntry> {
+        let entries = self.entries(fetch);
+        [vpn, vpn >> 10]
+            .into_iter()
+            .map(|x| entries[x as usize % TLB_SIZE])
+            .find(|entry| entry.valid && entry.covers(vpn) && (entry.global || entry.asid == asid))
+    }
+
+    pub fn insert(&mut self, entry: TlbEntry, fetch: bool) {
+        self.entries(fetch)[entry.slot()] = entry;
+    }
+
+    // sfence.vma; vaddr from rs1 and asid from rs2 when they are not x0
+    pub fn flush(&mut self, vaddr: Option<u32>, asid: Option<u32>) {
+        for entry in self.itlb.iter_mut().chain(self.dtlb.iter_mut()) {
+            let addr_match = match vaddr {
+                Some(vaddr) => entry.covers(vaddr >> 12),
+                None => true,
+            };
+            // global mappings are only flushed when asid is not given
+            let asid_match = match asid {
+                Some(asid) => !entry.global && entry.asid == asid,
+                None => true,
+            };
+            if addr_match && asid_match {
+                entry.valid = false;
+            }
+        }
+    }
+
+    pub fn flush_all(&mut self) {
+        self.flush(None, None);
+    }
+}
@@ -0,0 +1,287 @@
+// Flattened device tree generated from the machine configuration.
+// https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+
+use std::collections::HashMap;
+
+use crate::core::{self, Hart};
+use crate::memory::{MemoryBus, MmioDevice, ns16550::Uart, syscon};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+const TIMEBASE_FREQ: u32 = 10000000;
+const CPU_FREQ: u32 = 1000000000;
+
+pub const PLIC_PHANDLE: u32 = 1;
+// interrupt controller of hart n has phandle CPU_INTC_PHANDLE + n
+const CPU_INTC_PHANDLE: u32 = 2;
+pub const SYSCON_PHANDLE: u32 = CPU_INTC_PHANDLE + core::MAX_HARTS as u32;
+
+// interrupt numbers of the hart local interrupt controller
+pub const IRQ_M_SOFT: u32 = 3;
+pub const IRQ_M_TIMER: u32 = 7;
+pub const IRQ_S_EXT: u32 = 9;
+pub const IRQ_M_EXT: u32 = 11;
+
+// device nodes are written by the devices themselves, see MmioDevice::fdt_node
+#[derive(Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl FdtWriter {
+    fn push_u32(&mut self, val: u32) {
+        self.structure.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8]) {
+        let offset = match self.string_offsets.get(name) {
+            Some(x) => *x,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.string_offsets.insert(name.to_string(), offset);
+                offset
+            }
+        };
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    pub fn prop_u32(&mut self, name: &str, value: u32) {
+        self.prop(name, &value.to_be_bytes());
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|x| x.to_be_bytes()).collect();
+        self.prop(name, &value);
+    }
+
+    pub fn prop_str(&mut self, name: &str, value: &str) {
+        self.prop_strs(name, &[value]);
+    }
+
+    pub fn prop_strs(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for x in values {
+            value.extend_from_slice(x.as_bytes());
+            value.push(0);
+        }
+        self.prop(name, &value);
+    }
+
+    // "reg" with #address-cells = #size-cells = 2
+    pub fn prop_reg(&mut self, (base, length): (u32, u32)) {
+        self.prop_cells("reg", &[0, base, 0, length]);
+    }
+
+    fn finish(mut self, boot_cpuid: u32) -> Vec<u8> {
+        self.push_u32(FDT_END);
+
+        let header_size = 40;
+        // empty memory reservation block, one terminating entry
+        let rsvmap_size = 16;
+        let off_struct = header_size + rsvmap_size;
+        let off_strings = off_struct + self.structure.len() as u32;
+        let total_size = off_strings + self.strings.len() as u32;
+
+        let mut blob = Vec::with_capacity(total_size as usize);
+        for val in [
+            FDT_MAGIC,
+            total_size,
+            off_struct,
+            off_strings,
+            header_size,
+            17, // version
+            16, // last compatible version
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ] {
+            blob.extend_from_slice(&val.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+// one <phandle irq> pair per hart and interrupt of a hart local interrupt controller
+pub fn interrupts_extended(harts: usize, irqs: &[u32]) -> Vec<u32> {
+    (0..harts as u32)
+        .flat_map(|hart_id| {
+            irqs.iter()
+                .flat_map(move |irq| [CPU_INTC_PHANDLE + hart_id, *irq])
+        })
+        .collect()
+}
+
+// "rv32imafdc" from misa extension bits, followed by multi-letter extensions
+fn isa_string(misa: u32, hart: &Hart) -> String {
+    let mut isa = String::from("rv32");
+    for ext in "imafdqcbvh".chars() {
+        if misa & (1 << (ext as u32 - 'a' as u32)) != 0 {
+            isa.push(ext);
+        }
+    }
+    if hart.core.svadu {
+        isa.push_str("_svadu");
+    }
+    isa
+}
+
+// initrd is the address range of the initramfs in RAM
+pub fn generate(harts: &[Hart], bus: &MemoryBus, bootargs: &str, initrd: Option<(u32, u32)>) -> Vec<u8> {
+    let mut fdt = FdtWriter::default();
+
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_str("compatible", "ucbbar,spike-bare-dev");
+    fdt.prop_str("model", "ucbbar,spike-bare");
+
+    fdt.begin_node("chosen");
+    if let Some(uart) = bus.device::<Uart>() {
+        fdt.prop_str("stdout-path", &format!("/soc/serial@{:x}", uart.region().0));
+    }
+    fdt.prop_str("bootargs", bootargs);
+    if let Some((start, end)) = initrd {
+        fdt.prop_u32("linux,initrd-start", start);
+        fdt.prop_u32("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.prop_u32("timebase-frequency", TIMEBASE_FREQ);
+    for (hart_id, hart) in harts.iter().enumerate() {
+        let hart_id = hart_id as u32;
+        fdt.begin_node(&format!("cpu@{:x}", hart_id));
+        fdt.prop_str("device_type", "cpu");
+        fdt.prop_u32("reg", hart_id);
+        fdt.prop_str("status", "okay");
+        fdt.prop_str("compatible", "riscv");
+        fdt.prop_str("riscv,isa", &isa_string(core::MISA, hart));
+        fdt.prop_str("mmu-type", "riscv,sv32");
+        fdt.prop_u32("riscv,pmpregions", core::PMP_REGIONS as u32);
+        fdt.prop_u32("riscv,pmpgranularity", 4);
+        fdt.prop_u32("clock-frequency", CPU_FREQ);
+        fdt.begin_node("interrupt-controller");
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_str("compatible", "riscv,cpu-intc");
+        fdt.prop_u32("phandle", CPU_INTC_PHANDLE + hart_id);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    for (ram_base, ram_length) in bus.ram.regions() {
+        fdt.begin_node(&format!("memory@{:x}", ram_base));
+        fdt.prop_str("device_type", "memory");
+        fdt.prop_reg((ram_base, ram_length));
+        fdt.end_node();
+    }
+
+    fdt.begin_node("soc");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_strs("compatible", &["ucbbar,spike-bare-soc", "simple-bus"]);
+    fdt.prop_empty("ranges");
+
+    for device in bus.devices() {
+        device.fdt_node(&mut fdt, harts.len());
+    }
+    fdt.end_node();
+
+    // the syscon drivers are not children of the syscon node
+    if bus.device::<syscon::Syscon>().is_some() {
+        for (name, value) in [
+            ("poweroff", syscon::SYSCON_POWEROFF),
+            ("reboot", syscon::SYSCON_REBOOT),
+        ] {
+            fdt.begin_node(name);
+            fdt.prop_str("compatible", &format!("syscon-{}", name));
+            fdt.prop_u32("regmap", SYSCON_PHANDLE);
+            fdt.prop_u32("offset", 0);
+            fdt.prop_u32("value", value);
+            fdt.end_node();
+        }
+    }
+
+    fdt.end_node();
+
+    fdt.finish(0)
+}
+
+// Number of cpu nodes in /cpus of a device tree blob, None if the blob is malformed.
+pub fn count_cpus(dtb: &[u8]) -> Option<usize> {
+    let be_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(dtb.get(offset..offset + 4)?.try_into().ok()?))
+    };
+    if be_u32(0)? != FDT_MAGIC {
+        return None;
+    }
+    let mut offset = be_u32(8)? as usize;
+
+    let mut path: Vec<&[u8]> = Vec::new();
+    let mut cpus = 0;
+    loop {
+        let token = be_u32(offset)?;
+        offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let len = dtb.get(offset..)?.iter().position(|x| *x == 0)?;
+                let name = &dtb[offset..offset + len];
+                if path.len() == 2 && path[1] == b"cpus" && name.starts_with(b"cpu@") {
+                    cpus += 1;
+                }
+                path.push(name);
+                offset = (offset + len + 1).next_multiple_of(4);
+            }
+            FDT_END_NODE => {
+                path.pop()?;
+            }
+            FDT_PROP => {
+                let len = be_u32(offset)? as usize;
+                offset = (offset + 8 + len).next_multiple_of(4);
+            }
+            FDT_END => return Some(cpus),
+            // FDT_NOP
+            0x4 => {}
+            _ => return None,
+        }
+    }
+}
@@ -1,85 +1,287 @@
 mod core;
 mod device;
+mod fdt;
 mod memory;
 use clap::Parser;
 use core::Core;
 use std::process;
-use std::{error::Error, io::stdout};
+use std::{error::Error, fs, io::stdout};
 use termion::raw::IntoRawMode;
 
 use std::time::SystemTime;
 
 use crate::memory::*;
 
+// default memory map, one bank of main memory
 const RAM_SIZE: u32 = 64 * 1024 * 1024;
 const RAM_OFFSET: u32 = 0x80000000;
-const DEBUG: bool = false;
-const SPIKE_DEBUG: bool = true;
-const PRINT_START: u64 = 0 as u64;
+// images are placed relative to the start of main memory
+const KERNEL_OFFSET: u32 = 0x200000;
+// with the built-in SBI, RV32 Linux must start at a 4 MiB aligned address
+const SBI_KERNEL_OFFSET: u32 = 0x400000;
 const REAL_TIME: bool = false;
+const BOOTARGS: &str = "console=ttyS0 earlycon root=/dev/vda rootwait";
+// instructions every hart executes before the next one is scheduled
+const QUANTUM: u32 = 5000;
 
-/// RISCV (rv32ima) emulator
+/// RISCV (rv32imafdc) emulator
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(group(clap::ArgGroup::new("trace").args(["log_commits", "lockstep"]).multiple(true)))]
 struct Args {
-    /// bios loaded at 0x8000000
-    #[arg(short, long)]
-    bios: String,
+    /// bios, ELF file or flat binary loaded at the start of main memory (0x80000000)
+    #[arg(short, long, required_unless_present = "sbi")]
+    bios: Option<String>,
 
-    /// kernel loaded at 0x80200000
+    /// kernel, ELF file or flat binary loaded 2 MiB into main memory (4 MiB with --sbi)
     #[arg(short, long)]
     kernel: Option<String>,
 
-    #[arg(short, long)]
-    drive: Option<String>,
+    /// boot the kernel in S-mode with the built-in SBI instead of a bios
+    #[arg(long, requires = "kernel", conflicts_with = "bios")]
+    sbi: bool,
+
+    /// initramfs, loaded in the middle of main memory (at most 128 MiB in) and passed to the
+    /// kernel through /chosen
+    #[arg(long, conflicts_with = "dtb")]
+    initrd: Option<String>,
+
+    /// kernel command line, replaces the default bootargs
+    #[arg(long, conflicts_with = "dtb")]
+    append: Option<String>,
+
+    /// disk image for a virtio-blk device, path[,ro]; repeat for more disks, the first is /dev/vda
+    #[arg(short, long, value_parser = parse_drive)]
+    drive: Vec<(String, bool)>,
+
+    /// virtio-net device, udp,bind=addr:port,peer=addr:port | unix,bind=path,peer=path | none,
+    /// options ,mac=52:54:00:12:34:56 and ,pcap=file; repeat for more interfaces
+    #[arg(long, value_parser = virtio_net::NetConfig::parse)]
+    net: Vec<virtio_net::NetConfig>,
+
+    /// size of main memory, with an optional K, M or G suffix
+    #[arg(short, long, default_value = "64M", value_parser = parse_size)]
+    memory: u32,
+
+    /// RAM banks as base:size, comma separated; the first one is main memory and replaces -m
+    #[arg(long, value_delimiter = ',', value_parser = parse_bank, conflicts_with = "memory")]
+    memory_map: Vec<(u32, u32)>,
 
     #[arg(short, long)]
     cooked: bool,
+
+    /// number of harts
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=core::MAX_HARTS as i64))]
+    harts: u8,
+
+    /// size of the LR/SC reservation set in bytes, power of two
+    #[arg(long, default_value_t = ram::DEFAULT_RESERVATION_GRANULE, value_parser = parse_granule)]
+    lr_granule: u32,
+
+    /// page walker updates A/D bits (Svadu) instead of raising page faults
+    #[arg(long)]
+    svadu: bool,
+
+    /// use this device tree blob instead of the generated one
+    #[arg(long)]
+    dtb: Option<String>,
+
+    /// write the generated device tree blob to a file and exit
+    #[arg(long)]
+    dump_dtb: Option<String>,
+
+    /// run given number of instructions, print the execution speed and exit
+    #[arg(long)]
+    bench: Option<u64>,
+
+    /// wait for gdb connection on given tcp port
+    #[arg(short, long)]
+    gdb: Option<u16>,
+
+    /// write a commit log in the format of spike --log-commits to a file
+    #[arg(long)]
+    log_commits: Option<String>,
+
+    /// compare every instruction with a reference commit log (spike --log-commits) and stop at
+    /// the first difference
+    #[arg(long)]
+    lockstep: Option<String>,
+
+    /// start the commit log and the lockstep comparison after given number of instructions
+    #[arg(long, requires = "trace", conflicts_with = "log_start_pc")]
+    log_start: Option<u64>,
+
+    /// start the commit log and the lockstep comparison at the first fetch from given address
+    #[arg(long, requires = "trace", value_parser = parse_addr)]
+    log_start_pc: Option<u32>,
+
+    /// when the test exits (tohost or an exit ecall) write the memory between begin_signature
+    /// and end_signature to a file, one hex word per line (riscv-arch-test, RISCOF)
+    #[arg(long, requires = "bios")]
+    signature: Option<String>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
-    let mut hart = core::Hart {
-        core: Core::default(),
-        clint: clint::Clint::default(),
-    };
-
-    let mut vblk = virtio_blk::VirtioBlk::default();
+    let mut harts: Vec<core::Hart> = (0..args.harts)
+        .map(|_| core::Hart {
+            core: Core::default(),
+            symbols: core::loader::Symbols::default(),
+        })
+        .collect();
 
-    // if args.bios.is_none() {
-    //     println!("Wrong arguments!");
-    //     process::exit(1);
-    // }
+    for hart in harts.iter_mut() {
+        hart.core.svadu = args.svadu;
+    }
 
-    if let Some(drive) = args.drive {
-        vblk.init(&drive);
+    if args.log_commits.is_some() || args.lockstep.is_some() {
+        let start = match args.log_start_pc {
+            Some(pc) => core::commit_log::Start::Pc(pc),
+            None => core::commit_log::Start::Instret(args.log_start.unwrap_or(0)),
+        };
+        // every hart writes whole lines to the same file
+        let file = match &args.log_commits {
+            Some(path) => Some(fs::File::create(path)?),
+            None => None,
+        };
+        for (hart_id, hart) in harts.iter_mut().enumerate() {
+            let out = match &file {
+                Some(file) => Some(file.try_clone()?),
+                None => None,
+            };
+            let lockstep = match &args.lockstep {
+                Some(path) => Some(core::lockstep::Lockstep::open(path, hart_id)?),
+                None => None,
+            };
+            hart.core.commit_log = Some(core::commit_log::CommitLog::new(start, out, lockstep));
+        }
     }
 
+    let banks = match args.memory_map.is_empty() {
+        true => vec![(RAM_OFFSET, args.memory)],
+        false => args.memory_map.clone(),
+    };
+
     let uart = match args.cooked {
         false => ns16550::Uart::new(Box::new(stdout().into_raw_mode().unwrap())),
         true => ns16550::Uart::new(Box::new(stdout())),
     };
 
-    let mut bus = memory::MemoryBus {
-        ram: ram::RAM::default(),
-        uart: uart,
-        blk: virtio::VirtioDevice::new(Box::new(vblk)),
-        plic: plic::Plic::default(),
+    let mut bus = memory::MemoryBus::new(ram::RAM::new(&banks));
+    bus.attach(Box::new(uart))?;
+    bus.attach(Box::new(syscon::Syscon::default()))?;
+    bus.attach(Box::new(sifive_test::SifiveTest::default()))?;
+    if args.drive.len() + args.net.len() > virtio::VIRTIO_SLOTS as usize {
+        return Err(format!("at most {} drives and network interfaces", virtio::VIRTIO_SLOTS).into());
+    }
+    for (slot, (path, read_only)) in args.drive.iter().enumerate() {
+        let mut vblk = virtio_blk::VirtioBlk::default();
+        vblk.init(path, *read_only)
+            .map_err(|err| format!("{}: {}", path, err))?;
+        bus.attach(Box::new(virtio::VirtioDevice::new(slot as u32, Box::new(vblk))))?;
+    }
+    // network interfaces in the slots after the disks
+    for (n, config) in args.net.iter().enumerate() {
+        let vnet = config.open(n as u32).map_err(|err| format!("net: {}", err))?;
+        let slot = (args.drive.len() + n) as u32;
+        bus.attach(Box::new(virtio::VirtioDevice::new(slot, Box::new(vnet))))?;
+    }
+
+    bus.ram.set_reservation_granule(args.lr_granule);
+    check_memory_map(&bus)?;
+
+    let dtb = match args.dtb {
+        Some(path) => fs::read(path)?,
+        None => device_tree(&harts, &bus, args.append.as_deref(), args.initrd.as_deref())?,
     };
+    check_cpus(&dtb, harts.len())?;
+    if let Some(path) = args.dump_dtb {
+        fs::write(path, &dtb)?;
+        return Ok(());
+    }
 
-    core::soc_init(
-        &mut hart,
-        &mut bus,
-        &args.bios, //kernel Image
-        "/home/msjtw/Documents/digital_design/riscv_em/device_tree/spike.dtb",
-    )?;
+    let (bios, kernel, initrd) = (args.bios.as_deref(), args.kernel.as_deref(), args.initrd.as_deref());
+    core::soc_init(&mut harts, &mut bus, bios, kernel, initrd, &dtb)?;
+
+    let signature = match &args.signature {
+        Some(path) => {
+            let symbols = &harts[0].symbols;
+            match (symbols.find("begin_signature"), symbols.find("end_signature")) {
+                (Some(begin), Some(end)) if begin <= end => {
+                    bus.htif.exit_on_ecall = true;
+                    Some((path.clone(), begin, end))
+                }
+                _ => {
+                    let bios = bios.unwrap_or_default();
+                    return Err(format!("{}: no begin_signature/end_signature symbols", bios).into());
+                }
+            }
+        }
+        None => None,
+    };
 
     let mut last_time = SystemTime::now();
 
+    if let Some(instructions) = args.bench {
+        let start = SystemTime::now();
+        let retired = |harts: &[core::Hart]| -> u64 {
+            harts.iter().map(|hart| core::csr::read_64(core::csr::Csr64::minstret, &hart.core)).sum()
+        };
+        while retired(&harts) < instructions {
+            if run_batch(&mut harts, &mut bus, &mut last_time) == core::State::Shutdown {
+                break;
+            }
+        }
+        let secs = start.elapsed()?.as_secs_f64();
+        println!(
+            "{} instructions in {:.2}s, {:.1} MIPS",
+            instructions,
+            secs,
+            instructions as f64 / secs / 1e6
+        );
+        return Ok(());
+    }
+
+    if let Some(port) = args.gdb {
+        let mut stub = core::gdb::GdbStub::listen(port)?;
+        let mut run = |harts: &mut [core::Hart], bus: &mut MemoryBus| {
+            run_batch(harts, bus, &mut last_time)
+        };
+        if !stub.serve(&mut harts, &mut bus, &mut run)? {
+            // killed from gdb
+            return Ok(());
+        }
+    }
+
+    let mut monitor = core::monitor::Monitor::default();
     loop {
-        match core::hart_run(&mut hart, &mut bus, 5000) {
-            core::State::Ok => {}
+        let uart = bus.device_mut::<ns16550::Uart>();
+        if uart.is_some_and(|uart| std::mem::take(&mut uart.monitor_requested))
+            && !monitor.run(&mut harts, &mut bus)
+        {
+            return Ok(());
+        }
+        if let Some(code) = bus.htif.exit_code {
+            if let Some((path, begin, end)) = &signature {
+                write_signature(path, &bus, *begin, *end)?;
+            }
+            // restore the terminal first
+            drop(bus);
+            if code != 0 {
+                eprintln!("*** FAILED *** (tohost = {})", code);
+            }
+            // exit status is a byte, a failure must not wrap around to 0
+            process::exit(code.min(255) as i32);
+        }
+        match run_batch(&mut harts, &mut bus, &mut last_time) {
+            core::State::Ok | core::State::Breakpoint => {}
+            core::State::Diverged => {
+                if let Some(report) = core::lockstep::report(&mut harts) {
+                    bus.console_write(report.replace('\n', "\r\n").as_bytes());
+                }
+                return Err("diverged from the lockstep reference".into());
+            }
             core::State::Sleep => {
                 // println!("Sleep... 0x{:08x} < 0x{:08x}; {}", proc.mtime, proc.mtimecmp, i128::from(proc.mtimecmp) - i128::from(proc.mtime));
                 // println!("mie: 0b{:b}", proc.csr_file[0x304]);
@@ -93,30 +295,166 @@ fn main() -> Result<(), Box<dyn Error>> {
                 //     memory::Time::Mtime,
                 //     proc.memory.csr_read(memory::Time::Mtimecmp),
                 // );
-            } // core::State::Reboot => {
-              //     println!("Shutting down...");
-              //     break;
-              // }
-              // core::State::Shutdown => {
-              //     println!("Shutting down...");
-              //     break;
-              // }
+            }
+            core::State::Reboot => {
+                let (bios, kernel, initrd) = (args.bios.as_deref(), args.kernel.as_deref(), args.initrd.as_deref());
+                core::reboot(&mut harts, &mut bus, bios, kernel, initrd, &dtb)?;
+            }
+            core::State::Shutdown => {
+                // poweroff is a clean exit, the test finisher can report a failure
+                let finisher = bus.device::<sifive_test::SifiveTest>();
+                let code = finisher.map_or(0, |finisher| finisher.exit_code());
+                // restore the terminal first
+                drop(bus);
+                if code != 0 {
+                    eprintln!("*** FAILED *** (finisher code = {})", code);
+                }
+                process::exit(code.min(255) as i32);
+            }
         }
+    }
+}
 
-        if REAL_TIME {
-            let time_diff = SystemTime::now()
-                .duration_since(last_time)
-                .unwrap()
-                .as_millis() as u32;
-            last_time = SystemTime::now();
-            hart.clint.mtime += time_diff;
-        } else {
-            hart.clint.mtime += 50;
+// RISCOF format, 32-bit words in hex, one per line
+fn write_signature(path: &str, bus: &MemoryBus, begin: u32, end: u32) -> Result<(), Box<dyn Error>> {
+    let mut text = String::new();
+    for addr in (begin..end).step_by(4) {
+        if !bus.ram.claim(addr) {
+            return Err(format!("signature at 0x{:08x} is outside of RAM", addr).into());
         }
+        text += &format!("{:08x}\n", bus.ram.load_word(addr));
+    }
+    fs::write(path, text)?;
+    Ok(())
+}
 
-        hart.core.lr_address = 0x0;
-        if hart.core.p_start {
-            eprintln!("mtime change 0x{:x}", hart.clint.mtime);
+// far enough above the kernel for it to decompress and set up its memory, as on QEMU
+fn initrd_addr((ram_base, ram_length): (u32, u32)) -> u32 {
+    ram_base + (ram_length / 2).min(128 * 1024 * 1024)
+}
+
+// the generated device tree, --append replaces the default kernel command line
+fn device_tree(
+    harts: &[core::Hart],
+    bus: &MemoryBus,
+    append: Option<&str>,
+    initrd: Option<&str>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let initrd = match initrd {
+        Some(path) => {
+            let start = initrd_addr(bus.ram.region());
+            Some((start, start + fs::metadata(path)?.len() as u32))
         }
+        None => None,
+    };
+    let bootargs = append.unwrap_or(BOOTARGS);
+    Ok(fdt::generate(harts, bus, bootargs, initrd))
+}
+
+// RAM banks must end below 4 GiB and not overlap each other or a device
+fn check_memory_map(bus: &MemoryBus) -> Result<(), String> {
+    let banks = bus.ram.regions();
+    let overlap = |(a, a_len): (u32, u32), (b, b_len): (u32, u32)| {
+        (a as u64) < b as u64 + b_len as u64 && (b as u64) < a as u64 + a_len as u64
+    };
+    for (i, bank) in banks.iter().enumerate() {
+        if bank.0.checked_add(bank.1).is_none() {
+            return Err(format!("RAM bank at 0x{:08x} does not end below 4 GiB", bank.0));
+        }
+        if banks[..i].iter().any(|other| overlap(*bank, *other)) {
+            return Err(format!("RAM bank at 0x{:08x} overlaps another bank", bank.0));
+        }
+        if let Some(device) = bus.devices().find(|device| overlap(*bank, device.region())) {
+            return Err(format!("RAM bank at 0x{:08x} overlaps the {}", bank.0, device.name()));
+        }
+    }
+    Ok(())
+}
+
+// a device tree from --dtb must describe every hart
+fn check_cpus(dtb: &[u8], harts: usize) -> Result<(), String> {
+    match fdt::count_cpus(dtb) {
+        Some(cpus) if cpus == harts => Ok(()),
+        Some(cpus) => Err(format!(
+            "device tree lists {} cpus, but there are {} harts",
+            cpus, harts
+        )),
+        None => Err("malformed device tree blob".to_string()),
+    }
+}
+
+fn parse_size(arg: &str) -> Result<u32, String> {
+    let (number, unit) = match arg.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&arg[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&arg[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&arg[..i], 1 << 30),
+        _ => (arg, 1),
+    };
+    match parse_addr(number).ok().and_then(|x| x.checked_mul(unit)) {
+        Some(size) if size > 0 && size % 4096 == 0 => Ok(size),
+        _ => Err("expected a multiple of 4 KiB below 4 GiB, e.g. 16M".to_string()),
+    }
+}
+
+fn parse_bank(arg: &str) -> Result<(u32, u32), String> {
+    let Some((base, size)) = arg.split_once(':') else {
+        return Err("expected base:size, e.g. 0x80000000:64M".to_string());
+    };
+    let base = parse_addr(base)?;
+    let size = parse_size(size)?;
+    if base % 4096 != 0 || base.checked_add(size).is_none() {
+        return Err("bank must be 4 KiB aligned and end below 4 GiB".to_string());
+    }
+    Ok((base, size))
+}
+
+fn parse_addr(arg: &str) -> Result<u32, String> {
+    let parsed = match arg.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.map_err(|_| "expected a decimal or 0x prefixed hex address".to_string())
+}
+
+// "path[,ro]", everything before a trailing ",ro" is the path, commas included
+fn parse_drive(arg: &str) -> Result<(String, bool), String> {
+    match arg.strip_suffix(",ro") {
+        Some(path) => Ok((path.to_string(), true)),
+        None => Ok((arg.to_string(), false)),
+    }
+}
+
+fn parse_granule(arg: &str) -> Result<u32, String> {
+    match arg.parse::<u32>() {
+        Ok(x) if x.is_power_of_two() && (4..=4096).contains(&x) => Ok(x),
+        _ => Err("expected a power of two between 4 and 4096".to_string()),
+    }
+}
+
+// Runs every hart for one quantum, round robin.
+// Returns Breakpoint as soon as any hart stops at one and Sleep when all harts wait for interrupts.
+fn run_batch(harts: &mut [core::Hart], bus: &mut MemoryBus, last_time: &mut SystemTime) -> core::State {
+    let mut state = core::State::Sleep;
+    for hart in harts.iter_mut() {
+        match core::hart_run(hart, bus, QUANTUM) {
+            x @ (core::State::Breakpoint
+            | core::State::Diverged
+            | core::State::Reboot
+            | core::State::Shutdown) => return x,
+            core::State::Sleep => {}
+            _ => state = core::State::Ok,
+        }
+    }
+
+    if REAL_TIME {
+        let time_diff = SystemTime::now()
+            .duration_since(*last_time)
+            .unwrap()
+            .as_millis() as u32;
+        *last_time = SystemTime::now();
+        bus.clint.mtime += time_diff;
+    } else {
+        bus.clint.mtime += 50;
     }
+    state
 }
@@ -1,13 +1,20 @@
 pub mod clint;
+pub mod htif;
 pub mod ns16550;
 pub mod plic;
 pub mod ram;
+pub mod sifive_test;
+pub mod syscon;
 pub mod virtio;
 pub mod virtio_blk;
+pub mod virtio_net;
+
+use std::any::Any;
 
 use crate::{
-    core::exceptions,
-    memory::{ns16550::Uart, plic::Plic, ram::RAM, virtio::VirtioDevice},
+    core::{Core, exceptions, sbi::Sbi},
+    fdt::FdtWriter,
+    memory::{clint::Clint, htif::Htif, ns16550::Uart, plic::Plic, ram::RAM},
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -17,63 +24,327 @@ pub struct MemoryPermissions {
     pub x: bool,
 }
 
+// The CLINT and the PLIC are wired to the harts and stay fields, other peripherals are attached
+// to the bus at run time.
 pub struct MemoryBus {
     pub ram: RAM,
-    pub uart: Uart,
-    pub blk: VirtioDevice,
     pub plic: Plic,
+    pub clint: Clint,
+    pub htif: Htif,
+    devices: Vec<Box<dyn MmioDevice>>, // sorted by base address
+    pub sbi: Option<Sbi>,              // built-in SBI firmware, set up with the images
+    pub power: Option<Power>,          // requested by the last device write, ends the quantum
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Power {
+    Off,
+    Reboot,
+}
+
+// A memory mapped peripheral. read and write get the absolute address of an access inside
+// region() and one of the widths in bytes the device implements.
+pub trait MmioDevice: Any {
+    // for error messages
+    fn name(&self) -> &'static str;
+    // base address and length
+    fn region(&self) -> (u32, u32);
+    fn access(&self) -> Access;
+    fn read(&mut self, addr: u32, size: u32) -> u32;
+    fn write(&mut self, addr: u32, size: u32, data: u32);
+    // called before every quantum of a hart
+    fn tick(&mut self, _ram: &mut RAM) {}
+    // PLIC source and the level of the interrupt line
+    fn interrupt_id(&self) -> Option<u32> {
+        None
+    }
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+    // node under /soc of the device tree, harts is the number of cpu nodes
+    fn fdt_node(&self, fdt: &mut FdtWriter, harts: usize);
+    // power on state for a reboot
+    fn reset(&mut self) {}
+    // poweroff or reboot asked for by the last write
+    fn power_request(&self) -> Option<Power> {
+        None
+    }
+}
+
+fn find_mut<T: MmioDevice>(devices: &mut [Box<dyn MmioDevice>]) -> Option<&mut T> {
+    devices
+        .iter_mut()
+        .find_map(|device| (device.as_mut() as &mut dyn Any).downcast_mut())
+}
+
+impl MemoryBus {
+    pub fn new(ram: RAM) -> Self {
+        MemoryBus {
+            ram,
+            plic: Plic::default(),
+            clint: Clint::default(),
+            htif: Htif::default(),
+            devices: Vec::new(),
+            sbi: None,
+            power: None,
+        }
+    }
+
+    // maps the device at its region, which must not overlap another device
+    pub fn attach(&mut self, device: Box<dyn MmioDevice>) -> Result<(), String> {
+        let (base, length) = device.region();
+        let end = base as u64 + length as u64;
+        if let Some(other) = self.devices().find(|other| {
+            let (other_base, other_length) = other.region();
+            (base as u64) < other_base as u64 + other_length as u64 && (other_base as u64) < end
+        }) {
+            return Err(format!(
+                "{} at 0x{:08x} overlaps the {}",
+                device.name(),
+                base,
+                other.name()
+            ));
+        }
+        let i = self
+            .devices
+            .partition_point(|other| other.region().0 < base);
+        self.devices.insert(i, device);
+        Ok(())
+    }
+
+    // all memory mapped devices, the interrupt controllers first
+    pub fn devices(&self) -> impl Iterator<Item = &dyn MmioDevice> {
+        [&self.clint as &dyn MmioDevice, &self.plic]
+            .into_iter()
+            .chain(self.devices.iter().map(|device| device.as_ref()))
+    }
+
+    // attached devices of a type in address order
+    pub fn devices_of<T: MmioDevice>(&self) -> impl Iterator<Item = &T> {
+        self.devices
+            .iter()
+            .filter_map(|device| (device.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    pub fn device<T: MmioDevice>(&self) -> Option<&T> {
+        self.devices_of().next()
+    }
+
+    pub fn device_mut<T: MmioDevice>(&mut self) -> Option<&mut T> {
+        find_mut(&mut self.devices)
+    }
+
+    fn device_at(&mut self, addr: u32) -> Option<&mut dyn MmioDevice> {
+        if self.clint.claim(addr) {
+            return Some(&mut self.clint);
+        }
+        if self.plic.claim(addr) {
+            return Some(&mut self.plic);
+        }
+        let i = self
+            .devices
+            .partition_point(|device| device.region().0 <= addr)
+            .checked_sub(1)?;
+        let device = self.devices[i].as_mut();
+        let (base, length) = device.region();
+        match addr - base < length {
+            true => Some(device),
+            false => None,
+        }
+    }
+
+    // an access of size bytes at addr reaches RAM or a device that implements the width
+    pub fn accepts(&mut self, addr: u32, size: u32) -> bool {
+        if self.ram.claim(addr) {
+            return true;
+        }
+        match self.device_at(addr) {
+            Some(device) => {
+                let access = device.access();
+                access.widths.contains(&size) || access.unsupported == Unsupported::Split
+            }
+            None => false,
+        }
+    }
+
+    // devices run before every quantum of a hart, their interrupt lines go through the PLIC
+    pub fn tick(&mut self, core: &mut Core) {
+        self.clint.tick(core);
+        for device in self.devices.iter_mut() {
+            device.tick(&mut self.ram);
+            if let Some(id) = device.interrupt_id() {
+                self.plic.set_active(id, device.interrupt_pending());
+            }
+        }
+        self.plic.tick(core);
+    }
+
+    // register accesses take effect on the interrupts of the hart immediately,
+    // e.g. a new mtimecmp or a claimed interrupt
+    pub fn update_mip(&mut self, core: &mut Core) {
+        self.clint.tick(core);
+        self.plic.update_mip(core);
+    }
+
+    // console of the monitor and the firmware interfaces, output is dropped without a UART
+    pub fn console_write(&mut self, bytes: &[u8]) {
+        if let Some(uart) = self.device_mut::<Uart>() {
+            uart.console_write(bytes);
+        }
+    }
+
+    pub fn console_read(&mut self) -> Option<u8> {
+        self.device_mut::<Uart>()?.console_read()
+    }
+
+    // power on state of the devices for a reboot, the console and the disk image stay attached
+    // htif keeps its configuration, the addresses are set again when the images are loaded
+    pub fn reset(&mut self) {
+        self.ram.reset();
+        self.plic = Plic::default();
+        self.clint = Clint::default();
+        self.power = None;
+        for device in self.devices.iter_mut() {
+            device.reset();
+        }
+    }
+}
+
+// Access widths in bytes a device implements. Accesses of other widths fault unless the device
+// splits them: a narrower access goes through the containing register (read-modify-write for
+// stores) and a wider one is made of several accesses of the widest implemented width.
+#[derive(Clone, Copy)]
+pub struct Access {
+    pub widths: &'static [u32],
+    pub unsupported: Unsupported,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Unsupported {
+    Fault,
+    Split,
+}
+
+// low `size` bytes
+fn mask(size: u32) -> u32 {
+    u32::MAX >> (32 - 8 * size)
+}
+
+// unmapped addresses and unsupported widths raise access faults
+fn mmio_load(bus: &mut MemoryBus, addr: u32, size: u32) -> Result<u32, exceptions::Exception> {
+    bus.device_at(addr)
+        .and_then(|device| device_load(device, addr, size))
+        .ok_or(exceptions::Exception::Load_access_fault)
+}
+
+fn mmio_store(
+    bus: &mut MemoryBus,
+    addr: u32,
+    size: u32,
+    data: u32,
+) -> Result<(), exceptions::Exception> {
+    let device = bus
+        .device_at(addr)
+        .ok_or(exceptions::Exception::StoreAMO_access_fault)?;
+    device_store(device, addr, size, data).ok_or(exceptions::Exception::StoreAMO_access_fault)?;
+    if let Some(power) = device.power_request() {
+        bus.power = Some(power);
+    }
+    Ok(())
+}
+
+fn device_load(device: &mut dyn MmioDevice, addr: u32, size: u32) -> Option<u32> {
+    let access = device.access();
+    if access.widths.contains(&size) {
+        return Some(device.read(addr, size));
+    }
+    if access.unsupported == Unsupported::Fault {
+        return None;
+    }
+    let width = access.widths.iter().copied().max().unwrap_or(size);
+    if size > width {
+        let mut val = 0;
+        for offset in (0..size).step_by(width as usize) {
+            val |= device_load(device, addr + offset, width)? << (8 * offset);
+        }
+        Some(val)
+    } else {
+        let reg = addr & !(width - 1);
+        Some((device_load(device, reg, width)? >> (8 * (addr - reg))) & mask(size))
+    }
+}
+
+fn device_store(device: &mut dyn MmioDevice, addr: u32, size: u32, data: u32) -> Option<()> {
+    let access = device.access();
+    if access.widths.contains(&size) {
+        device.write(addr, size, data);
+        return Some(());
+    }
+    if access.unsupported == Unsupported::Fault {
+        return None;
+    }
+    let width = access.widths.iter().copied().max().unwrap_or(size);
+    if size > width {
+        for offset in (0..size).step_by(width as usize) {
+            device_store(device, addr + offset, width, data >> (8 * offset))?;
+        }
+        Some(())
+    } else {
+        let reg = addr & !(width - 1);
+        let shift = 8 * (addr - reg);
+        let old = device_load(device, reg, width)?;
+        let bits = mask(size) << shift;
+        device_store(device, reg, width, (old & !bits) | ((data << shift) & bits))
+    }
 }
 
 pub fn load_word(bus: &mut MemoryBus, addr: u32) -> Result<u32, exceptions::Exception> {
     if bus.ram.claim(addr) {
         return Ok(bus.ram.load_word(addr));
-    } else if bus.plic.claim(addr) {
-        return Ok(bus.plic.read(addr));
-    } else if bus.blk.claim(addr) {
-        return Ok(bus.blk.read(addr));
     }
-    // NOTE: maybe some error ???
-    return Ok(0);
+    mmio_load(bus, addr, 4)
 }
 
 pub fn load_hword(bus: &mut MemoryBus, addr: u32) -> Result<u16, exceptions::Exception> {
     if bus.ram.claim(addr) {
         return Ok(bus.ram.load_hword(addr));
     }
-    // NOTE: maybe some error ???
-    return Ok(0);
+    mmio_load(bus, addr, 2).map(|x| x as u16)
 }
+
 pub fn load_byte(bus: &mut MemoryBus, addr: u32) -> Result<u8, exceptions::Exception> {
     if bus.ram.claim(addr) {
         return Ok(bus.ram.load_byte(addr));
-    } else if bus.uart.claim(addr) {
-        return Ok(bus.uart.read(addr));
     }
-    // NOTE: maybe some error ???
-    return Ok(0);
+    mmio_load(bus, addr, 1).map(|x| x as u8)
 }
 
 pub fn store_word(bus: &mut MemoryBus, addr: u32, data: u32) -> Result<(), exceptions::Exception> {
-    if bus.ram.claim(addr) {
+    if bus.htif.claim(addr) {
+        // tohost is in RAM
         bus.ram.store_word(addr, data);
-    } else if bus.plic.claim(addr) {
-        bus.plic.write(addr, data);
-    } else if bus.blk.claim(addr) {
-        bus.blk.write(addr, data);
+        let uart = find_mut::<Uart>(&mut bus.devices);
+        bus.htif.write(&mut bus.ram, uart);
+        return Ok(());
+    } else if bus.ram.claim(addr) {
+        bus.ram.store_word(addr, data);
+        return Ok(());
     }
-    Ok(())
+    mmio_store(bus, addr, 4, data)
 }
+
 pub fn store_hword(bus: &mut MemoryBus, addr: u32, data: u16) -> Result<(), exceptions::Exception> {
     if bus.ram.claim(addr) {
         bus.ram.store_hword(addr, data);
+        return Ok(());
     }
-    Ok(())
+    mmio_store(bus, addr, 2, data as u32)
 }
+
 pub fn store_byte(bus: &mut MemoryBus, addr: u32, data: u8) -> Result<(), exceptions::Exception> {
     if bus.ram.claim(addr) {
         bus.ram.store_byte(addr, data);
-    } else if bus.uart.claim(addr) {
-        bus.uart.write(addr, data);
+        return Ok(());
     }
-    Ok(())
+    mmio_store(bus, addr, 1, data as u32)
 }
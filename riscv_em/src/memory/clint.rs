@@ -1,4 +1,11 @@
-use crate::core::{Core, csr};
+use crate::core::{Core, MAX_HARTS, csr};
+use crate::fdt::{self, FdtWriter};
+use crate::memory::{Access, MmioDevice, Unsupported};
+
+// msip of hart n at 4 * n, mtimecmp of hart n at 0x4000 + 8 * n, one shared mtime
+const MSIP_OFF: usize = 0x0;
+const MTIMECMP_OFF: usize = 0x4000;
+const MTIME_OFF: usize = 0xbff8;
 
 pub struct Clint {
     base: usize,
@@ -7,8 +14,9 @@ pub struct Clint {
     pub mtime: u32,
     pub mtimeh: u32,
 
-    mtimecmp: u32,
-    mtimecmph: u32,
+    msip: [u32; MAX_HARTS],
+    mtimecmp: [u32; MAX_HARTS],
+    mtimecmph: [u32; MAX_HARTS],
 }
 
 impl Default for Clint {
@@ -18,8 +26,9 @@ impl Default for Clint {
             length: 0xc0000,
             mtime: 0,
             mtimeh: 0,
-            mtimecmp: 0,
-            mtimecmph: 0,
+            msip: [0; MAX_HARTS],
+            mtimecmp: [0; MAX_HARTS],
+            mtimecmph: [0; MAX_HARTS],
         }
     }
 }
@@ -33,8 +42,9 @@ impl Clint {
     }
 
     pub fn tick(&mut self, core: &mut Core) {
+        let hart_id = csr::read(csr::Csr::mhartid, core) as usize;
         let mtime = ((self.mtimeh as u64) << 32) + (self.mtime as u64);
-        let mtimecmp = ((self.mtimecmph as u64) << 32) + (self.mtimecmp as u64);
+        let mtimecmp = ((self.mtimecmph[hart_id] as u64) << 32) + (self.mtimecmp[hart_id] as u64);
         let mut mip = csr::read(csr::Csr::mip, core);
         if mtime > mtimecmp {
             mip |= 1 << 7;
@@ -42,29 +52,108 @@ impl Clint {
         } else {
             mip &= !(1 << 7);
         }
+        // software interrupt, sent by other harts
+        if self.msip[hart_id] & 0b1 != 0 {
+            mip |= 1 << 3;
+            core.wfi = false;
+        } else {
+            mip &= !(1 << 3);
+        }
         csr::write(csr::Csr::mip, mip, core);
     }
 
-    pub fn read(&mut self, addr: u32) -> u32 {
+    pub fn set_mtimecmp(&mut self, hart_id: usize, value: u64) {
+        self.mtimecmp[hart_id] = value as u32;
+        self.mtimecmph[hart_id] = (value >> 32) as u32;
+    }
+
+    // state dump for the monitor
+    pub fn info(&self, harts: usize) -> String {
+        let mut info = format!("mtime 0x{:08x}{:08x}\n", self.mtimeh, self.mtime);
+        for hart_id in 0..harts {
+            info += &format!(
+                "hart {}: msip {} mtimecmp 0x{:08x}{:08x}\n",
+                hart_id, self.msip[hart_id], self.mtimecmph[hart_id], self.mtimecmp[hart_id]
+            );
+        }
+        info
+    }
+}
+
+impl MmioDevice for Clint {
+    fn name(&self) -> &'static str {
+        "clint"
+    }
+
+    fn region(&self) -> (u32, u32) {
+        (self.base as u32, self.length as u32)
+    }
+
+    // 32-bit registers, 64-bit mtime and mtimecmp are accessed in halves
+    fn access(&self) -> Access {
+        Access {
+            widths: &[4],
+            unsupported: Unsupported::Fault,
+        }
+    }
+
+    fn read(&mut self, addr: u32, _size: u32) -> u32 {
         let addr = addr as usize - self.base;
 
         match addr {
+            MTIME_OFF => self.mtime,
             0xbffc => self.mtimeh,
-            0xbff8 => self.mtime,
-            0x4004 => self.mtimecmph,
-            0x4000 => self.mtimecmp,
+            MSIP_OFF..MTIMECMP_OFF => match self.msip.get((addr - MSIP_OFF) / 4) {
+                Some(x) => *x,
+                None => 0,
+            },
+            MTIMECMP_OFF..MTIME_OFF => {
+                let hart_id = (addr - MTIMECMP_OFF) / 8;
+                if hart_id >= MAX_HARTS {
+                    return 0;
+                }
+                match (addr - MTIMECMP_OFF) % 8 {
+                    0 => self.mtimecmp[hart_id],
+                    4 => self.mtimecmph[hart_id],
+                    _ => 0,
+                }
+            }
             _ => 0,
         }
     }
-    pub fn write(&mut self, addr: u32, data: u32) {
+
+    fn write(&mut self, addr: u32, _size: u32, data: u32) {
         let addr = addr as usize - self.base;
 
         match addr {
+            MTIME_OFF => self.mtime = data,
             0xbffc => self.mtimeh = data,
-            0xbff8 => self.mtime = data,
-            0x4004 => self.mtimecmph = data,
-            0x4000 => self.mtimecmp = data,
+            MSIP_OFF..MTIMECMP_OFF => {
+                if let Some(x) = self.msip.get_mut((addr - MSIP_OFF) / 4) {
+                    *x = data & 0b1;
+                }
+            }
+            MTIMECMP_OFF..MTIME_OFF => {
+                let hart_id = (addr - MTIMECMP_OFF) / 8;
+                if hart_id >= MAX_HARTS {
+                    return;
+                }
+                match (addr - MTIMECMP_OFF) % 8 {
+                    0 => self.mtimecmp[hart_id] = data,
+                    4 => self.mtimecmph[hart_id] = data,
+                    _ => {}
+                }
+            }
             _ => {}
         };
     }
+
+    fn fdt_node(&self, fdt: &mut FdtWriter, harts: usize) {
+        fdt.begin_node(&format!("clint@{:x}", self.base));
+        fdt.prop_str("compatible", "riscv,clint0");
+        let irqs = fdt::interrupts_extended(harts, &[fdt::IRQ_M_SOFT, fdt::IRQ_M_TIMER]);
+        fdt.prop_cells("interrupts-extended", &irqs);
+        fdt.prop_reg(self.region());
+        fdt.end_node();
+    }
 }
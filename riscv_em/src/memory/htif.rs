@@ -0,0 +1,124 @@
+use crate::memory::{ns16550::Uart, ram::RAM};
+
+// Host-target interface of spike, as used by riscv-tests and riscv-arch-test.
+// tohost and fromhost are 64-bit words in RAM, their addresses come from the ELF symbols.
+// The target writes a command to tohost: device << 56 | command << 48 | payload.
+// Writing the low word of tohost hands the command over, so RV32 targets that write the
+// high word first as well as riscv-tests (low word only, high stays zero) work.
+// The host clears tohost once the command is done and answers through fromhost.
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u32 = 93;
+const ENOSYS: u64 = 38;
+
+#[derive(Default)]
+pub struct Htif {
+    tohost: Option<u32>,
+    fromhost: Option<u32>,
+
+    // the target finished, 0 means success
+    pub exit_code: Option<u32>,
+    // an exit syscall (ecall with a7 = 93) ends the run as well, like under the proxy kernel
+    pub exit_on_ecall: bool,
+}
+
+impl Htif {
+    // symbols outside of RAM are ignored, both words are accessed as RAM
+    pub fn set_addresses(&mut self, ram: &RAM, tohost: Option<u32>, fromhost: Option<u32>) {
+        let in_ram =
+            |addr: &u32| ram.claim(*addr) && addr.checked_add(7).is_some_and(|x| ram.claim(x));
+        self.tohost = tohost.filter(in_ram);
+        self.fromhost = fromhost.filter(in_ram);
+    }
+
+    // Only word stores to the low word of tohost hand a command over: sw, sc.w, the AMOs and
+    // fsd, which writes the high word first. sb and sh only change RAM, a command built from
+    // them is handed over by the next word store to the low word.
+    pub fn claim(&self, addr: u32) -> bool {
+        self.tohost == Some(addr)
+    }
+
+    // the low word of tohost was just stored to RAM, console output goes to the UART if attached
+    pub fn write(&mut self, ram: &mut RAM, uart: Option<&mut Uart>) {
+        let Some(tohost) = self.tohost else {
+            return;
+        };
+        let cmd = load_dword(ram, tohost);
+        if cmd == 0 {
+            return;
+        }
+        let device = cmd >> 56;
+        let command = (cmd >> 48) & 0xff;
+        let payload = cmd & 0xffff_ffff_ffff;
+        let response = match (device, command) {
+            // syscall proxy
+            (0, 0) => {
+                if payload & 1 != 0 {
+                    self.exit_code = Some((payload >> 1) as u32);
+                    None
+                } else {
+                    self.syscall(payload as u32, ram, uart);
+                    Some(1)
+                }
+            }
+            // console, putchar
+            (1, 1) => {
+                if let Some(uart) = uart {
+                    uart.console_write(&[payload as u8]);
+                }
+                Some(1 << 56 | 1 << 48)
+            }
+            _ => None,
+        };
+        store_dword(ram, tohost, 0);
+        if let (Some(response), Some(fromhost)) = (response, self.fromhost) {
+            store_dword(ram, fromhost, response);
+        }
+    }
+
+    // ecall from the target, true when it was the exit syscall
+    pub fn ecall(&mut self, a7: u32, a0: u32) -> bool {
+        if !self.exit_on_ecall || a7 != SYS_EXIT {
+            return false;
+        }
+        self.exit_code = Some(a0);
+        true
+    }
+
+    // magic_mem holds the syscall number and its arguments, the return value replaces the number
+    fn syscall(&mut self, magic_mem: u32, ram: &mut RAM, uart: Option<&mut Uart>) {
+        let which = load_dword(ram, magic_mem);
+        let args: Vec<u64> = (1..4).map(|i| load_dword(ram, magic_mem + 8 * i)).collect();
+        let ret = match which {
+            // write(fd, buf, len) to stdout or stderr
+            SYS_WRITE if args[0] == 1 || args[0] == 2 => {
+                let bytes: Vec<u8> = (0..args[2] as u32)
+                    .map(|i| args[1] as u32 + i)
+                    .take_while(|addr| ram.claim(*addr))
+                    .map(|addr| ram.load_byte(addr))
+                    .collect();
+                if let Some(uart) = uart {
+                    uart.console_write(&bytes);
+                }
+                bytes.len() as u64
+            }
+            _ => ENOSYS.wrapping_neg(),
+        };
+        store_dword(ram, magic_mem, ret);
+    }
+}
+
+fn load_dword(ram: &RAM, addr: u32) -> u64 {
+    if !ram.claim(addr) || !ram.claim(addr + 7) {
+        return 0;
+    }
+    ram.load_word(addr) as u64 | (ram.load_word(addr + 4) as u64) << 32
+}
+
+fn store_dword(ram: &mut RAM, addr: u32, data: u64) {
+    if !ram.claim(addr) || !ram.claim(addr + 7) {
+        return;
+    }
+    ram.store_word(addr, data as u32);
+    ram.store_word(addr + 4, (data >> 32) as u32);
+}
@@ -1,12 +1,15 @@
 use std::io::{Bytes, Read, Write};
 use termion::async_stdin;
 
-use super::plic::Plic;
+use crate::fdt::{self, FdtWriter};
+use crate::memory::{Access, MmioDevice, Unsupported, ram::RAM};
+
+const UART_FREQ: u32 = 10000000;
 
 pub struct Uart {
     base: u32,
     length: u32,
-    interrupt_id: usize,
+    interrupt_id: u32,
 
     stdin: Bytes<termion::AsyncReader>,
     stdout: Box<dyn Write>,
@@ -24,6 +27,10 @@ pub struct Uart {
 
     thr_interrupt: bool,
     rhr_interrupt: bool,
+
+    escape: bool,     // last input byte was ctrl-a
+    held: Option<u8>, // byte of an unknown escape, it follows the ctrl-a
+    pub monitor_requested: bool,
 }
 
 impl Uart {
@@ -46,52 +53,46 @@ impl Uart {
             lsr: 0x60,
             thr_interrupt: false,
             rhr_interrupt: false,
+            escape: false,
+            held: None,
+            monitor_requested: false,
         }
     }
 
-    pub fn claim(&self, addr: u32) -> bool {
-        if addr >= self.base && addr < self.base + self.length {
-            return true;
+    // console input and output of the monitor, shared with the guest
+    pub fn console_read(&mut self) -> Option<u8> {
+        match self.stdin.next() {
+            Some(Ok(byte)) => Some(byte),
+            _ => None,
         }
-        return false;
     }
 
-    pub fn tick(&mut self, plic: &mut Plic) {
-        if self.bytes_to_read == 0 {
-            if let Some(Ok(byte)) = self.stdin.next() {
-                if byte == 1 {
-                    let mut next_byte: [u8; 1] = [0];
-                    std::io::stdin().read_exact(&mut next_byte).unwrap();
-                    if next_byte[0] == 3 {
-                        std::process::exit(1);
-                    }
-                }
-                self.rhr = byte;
-                self.bytes_to_read = 1;
-            }
-        }
+    // raw bytes, the guest may write any encoding
+    pub fn console_write(&mut self, bytes: &[u8]) {
+        self.stdout.write_all(bytes).unwrap();
+        self.stdout.flush().unwrap();
+    }
+}
 
-        if self.bytes_to_read > 0 {
-            self.rhr_interrupt = true;
-        }
+impl MmioDevice for Uart {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
 
-        if self.rhr_interrupt && (self.ier & 0b1 != 0) {
-            self.iir = 0b0100;
-        } else if self.thr_interrupt && (self.ier & 0b10 != 0) {
-            self.iir = 0b0010;
-        } else {
-            self.iir = 1;
-        }
+    fn region(&self) -> (u32, u32) {
+        (self.base, self.length)
+    }
 
-        if self.iir != 1 {
-            plic.intt_active |= 1 << self.interrupt_id;
-        } else {
-            plic.intt_active &= !(1 << self.interrupt_id);
+    // byte registers, reg-io-width is 1 in the device tree
+    fn access(&self) -> Access {
+        Access {
+            widths: &[1],
+            unsupported: Unsupported::Fault,
         }
     }
 
-    pub fn write(&mut self, addr: u32, data: u8) {
-        let addr = addr - self.base;
+    fn write(&mut self, addr: u32, _size: u32, data: u32) {
+        let (addr, data) = (addr - self.base, data as u8);
         match addr {
             // thr transmiter holding register
             0 => {
@@ -123,9 +124,9 @@ impl Uart {
         }
     }
 
-    pub fn read(&mut self, addr: u32) -> u8 {
+    fn read(&mut self, addr: u32, _size: u32) -> u32 {
         let addr = addr - self.base;
-        return match addr {
+        let val = match addr {
             // rhr register holding register
             0 => {
                 if self.lcr & (1 << 7) != 0 {
@@ -157,5 +158,89 @@ impl Uart {
             5 => self.lsr | self.bytes_to_read,
             _ => 0,
         };
+        val as u32
+    }
+
+    fn tick(&mut self, _ram: &mut RAM) {
+        if self.bytes_to_read == 0 {
+            if let Some(byte) = self.held.take() {
+                self.rhr = byte;
+                self.bytes_to_read = 1;
+            } else if let Some(Ok(byte)) = self.stdin.next() {
+                if self.escape {
+                    self.escape = false;
+                    match byte {
+                        // ctrl-a ctrl-c or ctrl-a x exits
+                        3 | b'x' => std::process::exit(1),
+                        // ctrl-a c opens the monitor
+                        b'c' => self.monitor_requested = true,
+                        // ctrl-a ctrl-a sends ctrl-a to the guest
+                        1 => {
+                            self.rhr = byte;
+                            self.bytes_to_read = 1;
+                        }
+                        // not an escape, the guest gets both bytes
+                        _ => {
+                            self.rhr = 1;
+                            self.bytes_to_read = 1;
+                            self.held = Some(byte);
+                        }
+                    }
+                } else if byte == 1 {
+                    self.escape = true;
+                } else {
+                    self.rhr = byte;
+                    self.bytes_to_read = 1;
+                }
+            }
+        }
+
+        if self.bytes_to_read > 0 {
+            self.rhr_interrupt = true;
+        }
+
+        if self.rhr_interrupt && (self.ier & 0b1 != 0) {
+            self.iir = 0b0100;
+        } else if self.thr_interrupt && (self.ier & 0b10 != 0) {
+            self.iir = 0b0010;
+        } else {
+            self.iir = 1;
+        }
+    }
+
+    fn interrupt_id(&self) -> Option<u32> {
+        Some(self.interrupt_id)
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.iir != 1
+    }
+
+    fn fdt_node(&self, fdt: &mut FdtWriter, _harts: usize) {
+        fdt.begin_node(&format!("serial@{:x}", self.base));
+        fdt.prop_str("compatible", "ns16550a");
+        fdt.prop_u32("clock-frequency", UART_FREQ);
+        fdt.prop_u32("interrupt-parent", fdt::PLIC_PHANDLE);
+        fdt.prop_u32("interrupts", self.interrupt_id);
+        fdt.prop_reg(self.region());
+        fdt.prop_u32("reg-shift", 0);
+        fdt.prop_u32("reg-io-width", 1);
+        fdt.end_node();
+    }
+
+    // registers to their power on values, the console stays attached
+    fn reset(&mut self) {
+        self.bytes_to_read = 0;
+        self.dll = 0;
+        self.dlh = 0;
+        self.rhr = 0;
+        self.thr = 0;
+        self.ier = 0;
+        self.iir = 0;
+        self.fcr = 0;
+        self.lcr = 0;
+        self.lsr = 0x60;
+        self.thr_interrupt = false;
+        self.rhr_interrupt = false;
     }
 }
@@ -1,26 +1,39 @@
-use crate::core::{Core, csr};
+use crate::core::{Core, MAX_HARTS, csr};
+use crate::fdt::{self, FdtWriter};
+use crate::memory::{Access, MmioDevice, Unsupported};
 
-// total of 31 interrupt sources, one context (no. 1), no priority
+// total of 31 interrupt sources (source 0 doesn't exist)
 // "hart context is a given privilege mode on a given hart"
-// NOTE: Only context 1 is implemented, so only supervisor interrupts work.
-// TODO: Implement contexts for all execution levels.
-const INT_PENDING_OFF: usize = 0x1000; // Interrupt Pending bit 0-31
-const INT_ENABLED_OFF: usize = 0x2080; // Enable bits for sources 0-31 on context 1
-const INT_THRESHOLD_OFF: usize = 0x201000; // Priority threshold for context 1
-const INT_CLAIM_OFF: usize = 0x201004; // Claim/complete for context 1
+// Every hart has two contexts: 2 * hartid for machine mode and 2 * hartid + 1 for supervisor
+// mode, same as qemu virt and spike.
+pub const NUM_SOURCES: u32 = 31;
+pub const MAX_PRIORITY: u32 = 7;
+const NUM_CONTEXTS: usize = 2 * MAX_HARTS;
+
+const PRIORITY_OFF: usize = 0x0; // Priority of source n at 4 * n
+const PENDING_OFF: usize = 0x1000; // Interrupt Pending bits 0-31
+const ENABLE_OFF: usize = 0x2000; // Enable bits 0-31, 0x80 per context
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFF: usize = 0x200000; // Threshold and claim/complete, 0x1000 per context
+const CONTEXT_STRIDE: usize = 0x1000;
+
+const MEIP: u32 = 1 << 11;
+const SEIP: u32 = 1 << 9;
 
 pub struct Plic {
     base: usize,
     length: usize,
 
-    pub intt_active: u32,
+    intt_active: u32,
     intt_pending: u32,
-    intt_enabled: u32,
     intt_masked: u32,
     //  Once plic records first interrupt from source it masks (ignores) all later interrupt signals
     //  until interrupt has been completed.
     //  Interrupt pending bit is cleared when interrupt is claimed by hart (but is still masked).
     //  Interrupt mask is cleared when interrupt is completed.
+    priority: [u32; NUM_SOURCES as usize + 1],
+    enabled: [u32; NUM_CONTEXTS],
+    threshold: [u32; NUM_CONTEXTS],
 }
 
 impl Default for Plic {
@@ -30,8 +43,10 @@ impl Default for Plic {
             length: 0x1000000,
             intt_active: 0,
             intt_pending: 0,
-            intt_enabled: 0,
             intt_masked: 0,
+            priority: [0; NUM_SOURCES as usize + 1],
+            enabled: [0; NUM_CONTEXTS],
+            threshold: [0; NUM_CONTEXTS],
         }
     }
 }
@@ -44,63 +59,195 @@ impl Plic {
         return false;
     }
 
+    // level of the interrupt line of a source
+    pub fn set_active(&mut self, id: u32, level: bool) {
+        match level {
+            true => self.intt_active |= 1 << id,
+            false => self.intt_active &= !(1 << id),
+        }
+    }
+
     pub fn tick(&mut self, core: &mut Core) {
         // Until interrupt is completed further signals are ignored.
         self.intt_pending |= self.intt_active & !self.intt_masked;
         self.intt_masked |= self.intt_active;
-        let mut mip = csr::read(csr::Csr::mip, core);
-        if self.intt_pending & self.intt_enabled != 0 {
-            mip |= 1 << 9;
-        } else {
-            // FIX: It works but is implemented wrong. Check specification of meip and siep.
-            mip &= !(1 << 9);
+        self.update_mip(core);
+    }
+
+    // drive MEIP and SEIP of the hart from its machine and supervisor contexts
+    pub fn update_mip(&self, core: &mut Core) {
+        let hart_id = csr::read(csr::Csr::mhartid, core) as usize;
+        let mut mip = csr::read(csr::Csr::mip, core) & !(MEIP | SEIP);
+        if self.best_candidate(2 * hart_id).is_some() {
+            mip |= MEIP;
+        }
+        if self.best_candidate(2 * hart_id + 1).is_some() {
+            mip |= SEIP;
+        }
+        if mip & csr::read(csr::Csr::mie, core) & (MEIP | SEIP) != 0 {
+            core.wfi = false;
         }
         csr::write(csr::Csr::mip, mip, core);
     }
 
-    pub fn read(&mut self, addr: u32) -> u32 {
+    // state dump for the monitor
+    pub fn info(&self) -> String {
+        let mut info = format!(
+            "active 0x{:08x} pending 0x{:08x} in service 0x{:08x}\n",
+            self.intt_active,
+            self.intt_pending,
+            self.intt_masked & !self.intt_pending
+        );
+        for id in 1..=NUM_SOURCES as usize {
+            if self.priority[id] != 0 {
+                info += &format!("source {:2}: priority {}\n", id, self.priority[id]);
+            }
+        }
+        for context in 0..NUM_CONTEXTS {
+            if self.enabled[context] != 0 || self.threshold[context] != 0 {
+                info += &format!(
+                    "context {:2} (hart {} {}-mode): enabled 0x{:08x} threshold {}\n",
+                    context,
+                    context / 2,
+                    if context % 2 == 0 { 'M' } else { 'S' },
+                    self.enabled[context],
+                    self.threshold[context]
+                );
+            }
+        }
+        info
+    }
+
+    // Pending and enabled source with the highest priority above the context threshold.
+    // On equal priority the lowest id wins.
+    fn best_candidate(&self, context: usize) -> Option<u32> {
+        if context >= NUM_CONTEXTS {
+            return None;
+        }
+        let candidates = self.intt_pending & self.enabled[context];
+        let mut best = None;
+        let mut best_priority = self.threshold[context];
+        for id in 1..=NUM_SOURCES {
+            if candidates & (1 << id) != 0 && self.priority[id as usize] > best_priority {
+                best = Some(id);
+                best_priority = self.priority[id as usize];
+            }
+        }
+        best
+    }
+}
+
+impl MmioDevice for Plic {
+    fn name(&self) -> &'static str {
+        "plic"
+    }
+
+    fn region(&self) -> (u32, u32) {
+        (self.base as u32, self.length as u32)
+    }
+
+    // 32-bit registers only
+    fn access(&self) -> Access {
+        Access {
+            widths: &[4],
+            unsupported: Unsupported::Fault,
+        }
+    }
+
+    fn read(&mut self, addr: u32, _size: u32) -> u32 {
         let addr = addr as usize - self.base;
-        let ret;
-        if addr > 0 && addr < 0x20 {
-            // no interrupt 0
-            ret = 1;
-        } else {
-            ret = match addr {
-                INT_PENDING_OFF => self.intt_pending,
-                INT_ENABLED_OFF => self.intt_enabled,
-                INT_THRESHOLD_OFF => 0, // no priority; threshold 0
-                INT_CLAIM_OFF => {
-                    // claim
-                    let candidates = self.intt_pending & self.intt_enabled;
-                    if candidates > 0 {
-                        let intt_num = (candidates as f64).log2() as u32; // return id of highest priority interrupt
-                        self.intt_pending &= !(1 << intt_num); // and set it's intt_pending bit to 0
-                        intt_num
-                    } else {
-                        0
+        match addr {
+            PRIORITY_OFF..PENDING_OFF => {
+                let id = (addr - PRIORITY_OFF) / 4;
+                if id >= 1 && id <= NUM_SOURCES as usize {
+                    self.priority[id]
+                } else {
+                    0 // no interrupt 0
+                }
+            }
+            PENDING_OFF => self.intt_pending,
+            ENABLE_OFF..CONTEXT_OFF => {
+                let context = (addr - ENABLE_OFF) / ENABLE_STRIDE;
+                // only the first word of every context, sources 0-31
+                if context < NUM_CONTEXTS && (addr - ENABLE_OFF).is_multiple_of(ENABLE_STRIDE) {
+                    self.enabled[context]
+                } else {
+                    0
+                }
+            }
+            CONTEXT_OFF.. => {
+                let context = (addr - CONTEXT_OFF) / CONTEXT_STRIDE;
+                if context >= NUM_CONTEXTS {
+                    return 0;
+                }
+                match (addr - CONTEXT_OFF) % CONTEXT_STRIDE {
+                    0 => self.threshold[context],
+                    4 => {
+                        // claim
+                        // return id of highest priority interrupt and set it's intt_pending bit to 0
+                        match self.best_candidate(context) {
+                            Some(id) => {
+                                self.intt_pending &= !(1 << id);
+                                id
+                            }
+                            None => 0,
+                        }
                     }
+                    _ => 0,
                 }
-                _ => 0,
-            };
+            }
+            _ => 0,
         }
-        return ret;
     }
 
-    pub fn write(&mut self, addr: u32, data: u32) {
+    fn write(&mut self, addr: u32, _size: u32, data: u32) {
         let addr = addr as usize - self.base;
-        if addr < 0x20 {
-        } else {
-            match addr {
-                INT_PENDING_OFF => {}
-                INT_ENABLED_OFF => self.intt_enabled = data & !(1), // interrupt 0 doesnt exist
-                INT_THRESHOLD_OFF => {}                             // hardwired to 0
-                INT_CLAIM_OFF => {
+        match addr {
+            PRIORITY_OFF..PENDING_OFF => {
+                let id = (addr - PRIORITY_OFF) / 4;
+                if id >= 1 && id <= NUM_SOURCES as usize {
+                    self.priority[id] = data.min(MAX_PRIORITY);
+                }
+            }
+            ENABLE_OFF..CONTEXT_OFF => {
+                let context = (addr - ENABLE_OFF) / ENABLE_STRIDE;
+                if context < NUM_CONTEXTS && (addr - ENABLE_OFF).is_multiple_of(ENABLE_STRIDE) {
+                    self.enabled[context] = data & !(1); // interrupt 0 doesnt exist
+                }
+            }
+            CONTEXT_OFF.. => {
+                let context = (addr - CONTEXT_OFF) / CONTEXT_STRIDE;
+                if context >= NUM_CONTEXTS {
+                    return;
+                }
+                match (addr - CONTEXT_OFF) % CONTEXT_STRIDE {
+                    0 => self.threshold[context] = data.min(MAX_PRIORITY),
                     // completion
-                    // reset corresponding bit from intt_mask
-                    self.intt_masked &= !(1 << data);
+                    // reset corresponding bit from intt_mask,
+                    // ignored for sources not enabled on this context
+                    4 if data <= NUM_SOURCES && self.enabled[context] & (1 << data) != 0 => {
+                        self.intt_masked &= !(1 << data);
+                    }
+                    _ => {}
                 }
-                _ => {}
-            };
+            }
+            _ => {}
         }
     }
+
+    fn fdt_node(&self, fdt: &mut FdtWriter, harts: usize) {
+        fdt.begin_node(&format!("plic@{:x}", self.base));
+        fdt.prop_str("compatible", "riscv,plic0");
+        // contexts 2n and 2n + 1 are machine and supervisor mode of hart n
+        let irqs = fdt::interrupts_extended(harts, &[fdt::IRQ_M_EXT, fdt::IRQ_S_EXT]);
+        fdt.prop_cells("interrupts-extended", &irqs);
+        fdt.prop_reg(self.region());
+        fdt.prop_u32("riscv,ndev", NUM_SOURCES);
+        fdt.prop_u32("riscv,max-priority", MAX_PRIORITY);
+        fdt.prop_u32("#address-cells", 0);
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_u32("phandle", fdt::PLIC_PHANDLE);
+        fdt.end_node();
+    }
 }
@@ -1,78 +1,157 @@
+use crate::core::MAX_HARTS;
 use crate::{RAM_OFFSET, RAM_SIZE};
 
+pub const DEFAULT_RESERVATION_GRANULE: u32 = 64;
+
+struct Bank {
+    base: u32,
+    data: Vec<u8>,
+}
+
 // #[derive(Debug)]
 pub struct RAM {
-    base: u32,
-    length: u32,
+    // the first bank is the main memory, images and the device tree are loaded there
+    banks: Vec<Bank>,
 
-    data: Vec<u8>,
+    // LR/SC reservation set of every hart, address aligned down to the granule.
+    // Any store to the reserved granule, from a hart or a device, invalidates it.
+    reservations: [Option<u32>; MAX_HARTS],
+    reservation_granule: u32,
 }
 
 impl Default for RAM {
     fn default() -> Self {
-        RAM {
-            base: RAM_OFFSET,
-            length: RAM_SIZE,
-            data: vec![0; RAM_SIZE as usize],
-        }
+        RAM::new(&[(RAM_OFFSET, RAM_SIZE)])
     }
 }
 
 impl RAM {
+    // banks are (base, length), they must not overlap
+    pub fn new(banks: &[(u32, u32)]) -> Self {
+        RAM {
+            banks: banks
+                .iter()
+                .map(|(base, length)| Bank {
+                    base: *base,
+                    data: vec![0; *length as usize],
+                })
+                .collect(),
+            reservations: [None; MAX_HARTS],
+            reservation_granule: DEFAULT_RESERVATION_GRANULE,
+        }
+    }
+
+    // main memory
+    pub fn region(&self) -> (u32, u32) {
+        self.regions()[0]
+    }
+
+    pub fn regions(&self) -> Vec<(u32, u32)> {
+        self.banks.iter().map(|bank| (bank.base, bank.data.len() as u32)).collect()
+    }
+
     pub fn claim(&self, addr: u32) -> bool {
-        if addr >= self.base && addr < self.base + self.length {
-            return true;
+        self.banks.iter().any(|bank| addr.wrapping_sub(bank.base) < bank.data.len() as u32)
+    }
+
+    // bank of a claimed address and the offset in it
+    fn locate(&self, addr: u32) -> (usize, usize) {
+        match self.banks.iter().position(|bank| addr.wrapping_sub(bank.base) < bank.data.len() as u32) {
+            Some(bank) => (bank, (addr - self.banks[bank].base) as usize),
+            None => panic!("0x{:08x} is not in RAM", addr),
         }
-        return false;
     }
 
-    pub fn load_word(&self, addr: u32) -> u32 {
-        let address = (addr - self.base) as usize;
-        if address > RAM_SIZE as usize {
-            println!("0x{:08x}", addr);
+    pub fn reset(&mut self) {
+        for bank in self.banks.iter_mut() {
+            bank.data.fill(0);
         }
-        let d = self.data[address] as u32;
-        let c = self.data[address + 1] as u32;
-        let b = self.data[address + 2] as u32;
-        let a = self.data[address + 3] as u32;
+        self.reservations = [None; MAX_HARTS];
+    }
+
+    // granule is a power of two, at least a word
+    pub fn set_reservation_granule(&mut self, granule: u32) {
+        self.reservation_granule = granule;
+    }
+
+    pub fn reserve(&mut self, hart_id: usize, addr: u32) {
+        self.reservations[hart_id] = Some(addr & !(self.reservation_granule - 1));
+    }
+
+    pub fn clear_reservation(&mut self, hart_id: usize) {
+        self.reservations[hart_id] = None;
+    }
+
+    // SC succeeds only while the reservation is valid, it's cleared either way
+    pub fn take_reservation(&mut self, hart_id: usize, addr: u32) -> bool {
+        let reserved = self.reservations[hart_id] == Some(addr & !(self.reservation_granule - 1));
+        self.reservations[hart_id] = None;
+        reserved
+    }
+
+    fn invalidate_reservations(&mut self, addr: u32, len: u32) {
+        let first = addr & !(self.reservation_granule - 1);
+        let last = (addr + len - 1) & !(self.reservation_granule - 1);
+        for reservation in self.reservations.iter_mut() {
+            if let Some(x) = *reservation
+                && (x == first || x == last)
+            {
+                *reservation = None;
+            }
+        }
+    }
+
+    pub fn load_word(&self, addr: u32) -> u32 {
+        let (bank, address) = self.locate(addr);
+        let data = &self.banks[bank].data;
+        let d = data[address] as u32;
+        let c = data[address + 1] as u32;
+        let b = data[address + 2] as u32;
+        let a = data[address + 3] as u32;
         (a << 24) + (b << 16) + (c << 8) + d
     }
 
     pub fn load_hword(&self, addr: u32) -> u16 {
-        let address = (addr - self.base) as usize;
-        let b = self.data[address] as u16;
-        let a = self.data[address + 1] as u16;
+        let (bank, address) = self.locate(addr);
+        let data = &self.banks[bank].data;
+        let b = data[address] as u16;
+        let a = data[address + 1] as u16;
         (a << 8) + b
     }
 
     pub fn load_byte(&self, addr: u32) -> u8 {
-        let address = (addr - self.base) as usize;
-        self.data[address]
+        let (bank, address) = self.locate(addr);
+        self.banks[bank].data[address]
     }
 
     pub fn store_word(&mut self, addr: u32, data: u32) {
-        let address = (addr - self.base) as usize;
+        self.invalidate_reservations(addr, 4);
+        let (bank, address) = self.locate(addr);
+        let bytes = &mut self.banks[bank].data;
         let mask: u32 = (1 << 8) - 1;
         let d: u8 = (data & mask) as u8;
         let c: u8 = ((data & mask << 8) >> 8) as u8;
         let b: u8 = ((data & mask << 16) >> 16) as u8;
         let a: u8 = ((data & mask << 24) >> 24) as u8;
-        self.data[address] = d;
-        self.data[address + 1] = c;
-        self.data[address + 2] = b;
-        self.data[address + 3] = a;
+        bytes[address] = d;
+        bytes[address + 1] = c;
+        bytes[address + 2] = b;
+        bytes[address + 3] = a;
     }
     pub fn store_hword(&mut self, addr: u32, data: u16) {
-        let address = (addr - self.base) as usize;
+        self.invalidate_reservations(addr, 2);
+        let (bank, address) = self.locate(addr);
+        let bytes = &mut self.banks[bank].data;
         let mask: u16 = (1 << 8) - 1;
         let d: u8 = (data & mask) as u8;
         let c: u8 = ((data & mask << 8) >> 8) as u8;
-        self.data[address] = d;
-        self.data[address + 1] = c;
+        bytes[address] = d;
+        bytes[address + 1] = c;
     }
 
     pub fn store_byte(&mut self, addr: u32, data: u8) {
-        let address = (addr - self.base) as usize;
-        self.data[address] = data;
+        self.invalidate_reservations(addr, 1);
+        let (bank, address) = self.locate(addr);
+        self.banks[bank].data[address] = data;
     }
 }
@@ -0,0 +1,88 @@
+use crate::fdt::FdtWriter;
+use crate::memory::{Access, MmioDevice, Power, Unsupported};
+
+// SiFive test finisher (sifive,test0) as in QEMU virt, ends the simulation.
+// The low half of the written word is the command, FINISHER_FAIL carries the exit code
+// in the high half.
+pub const FINISHER_FAIL: u32 = 0x3333;
+pub const FINISHER_PASS: u32 = 0x5555;
+pub const FINISHER_RESET: u32 = 0x7777;
+
+pub struct SifiveTest {
+    base: u32,
+    length: u32,
+
+    pub val: u32,
+}
+
+impl Default for SifiveTest {
+    fn default() -> Self {
+        SifiveTest {
+            base: 0x100000,
+            length: 0x1000,
+            val: 0,
+        }
+    }
+}
+
+impl SifiveTest {
+    pub fn command(&self) -> u32 {
+        self.val & 0xffff
+    }
+
+    // exit status of the emulator
+    pub fn exit_code(&self) -> u32 {
+        match self.command() {
+            FINISHER_FAIL => self.val >> 16,
+            _ => 0,
+        }
+    }
+}
+
+impl MmioDevice for SifiveTest {
+    fn name(&self) -> &'static str {
+        "test finisher"
+    }
+
+    fn region(&self) -> (u32, u32) {
+        (self.base, self.length)
+    }
+
+    // one 32-bit register
+    fn access(&self) -> Access {
+        Access {
+            widths: &[4],
+            unsupported: Unsupported::Fault,
+        }
+    }
+
+    // the register is write only
+    fn read(&mut self, _addr: u32, _size: u32) -> u32 {
+        0
+    }
+
+    fn write(&mut self, addr: u32, _size: u32, data: u32) {
+        if addr - self.base == 0 {
+            self.val = data;
+        }
+    }
+
+    fn fdt_node(&self, fdt: &mut FdtWriter, _harts: usize) {
+        fdt.begin_node(&format!("test@{:x}", self.base));
+        fdt.prop_strs("compatible", &["sifive,test1", "sifive,test0"]);
+        fdt.prop_reg(self.region());
+        fdt.end_node();
+    }
+
+    fn reset(&mut self) {
+        self.val = 0;
+    }
+
+    fn power_request(&self) -> Option<Power> {
+        match self.command() {
+            FINISHER_PASS | FINISHER_FAIL => Some(Power::Off),
+            FINISHER_RESET => Some(Power::Reboot),
+            _ => None,
+        }
+    }
+}
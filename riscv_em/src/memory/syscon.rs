@@ -1,3 +1,8 @@
+use crate::fdt::{self, FdtWriter};
+use crate::memory::{Access, MmioDevice, Power, Unsupported};
+
+// System controller for the syscon-poweroff and syscon-reboot drivers of Linux,
+// the guest writes SYSCON_POWEROFF or SYSCON_REBOOT to the register at offset 0.
 pub const SYSCON_POWEROFF: u32 = 1;
 pub const SYSCON_REBOOT: u32 = 2;
 
@@ -12,26 +17,60 @@ pub struct Syscon {
 impl Default for Syscon {
     fn default() -> Self {
         Syscon {
-            base: 0x1c00000 ,
+            base: 0x1c00000,
             length: 0x1000,
             val: 0,
         }
     }
 }
 
-impl Syscon {
-    pub fn claim(&self, addr: u32) -> bool {
-        if addr >= self.base && addr < self.base + self.length {
-            return true;
+impl MmioDevice for Syscon {
+    fn name(&self) -> &'static str {
+        "syscon"
+    }
+
+    fn region(&self) -> (u32, u32) {
+        (self.base, self.length)
+    }
+
+    // one 32-bit register, as the regmap of the syscon drivers
+    fn access(&self) -> Access {
+        Access {
+            widths: &[4],
+            unsupported: Unsupported::Fault,
+        }
+    }
+
+    fn read(&mut self, addr: u32, _size: u32) -> u32 {
+        match addr - self.base {
+            0 => self.val,
+            _ => 0,
         }
-        return false;
     }
 
-    pub fn store_word(&mut self, addr: u32, data: u32) {
-        println!("syscon store {data}");
-        let addr = addr - self.base;
-        if addr == 0 {
+    fn write(&mut self, addr: u32, _size: u32, data: u32) {
+        if addr - self.base == 0 {
             self.val = data;
         }
     }
+
+    fn fdt_node(&self, fdt: &mut FdtWriter, _harts: usize) {
+        fdt.begin_node(&format!("syscon@{:x}", self.base));
+        fdt.prop_str("compatible", "syscon");
+        fdt.prop_reg(self.region());
+        fdt.prop_u32("phandle", fdt::SYSCON_PHANDLE);
+        fdt.end_node();
+    }
+
+    fn reset(&mut self) {
+        self.val = 0;
+    }
+
+    fn power_request(&self) -> Option<Power> {
+        match self.val {
+            SYSCON_POWEROFF => Some(Power::Off),
+            SYSCON_REBOOT => Some(Power::Reboot),
+            _ => None,
+        }
+    }
 }
@@ -2,6 +2,7 @@
 pub mod registers;
 use registers::*;
 
+use crate::fdt::{self, FdtWriter};
 use crate::memory::*;
 
 #[derive(Debug, Default)]
@@ -38,6 +39,54 @@ pub struct VirtioQueue {
     pub last_avail: u16,
 }
 
+impl VirtioQueue {
+    // the driver made a chain available that the device has not taken yet
+    pub fn has_avail(&self, ram: &mut RAM) -> bool {
+        self.queue_ready != 0
+            && self.queue_size != 0
+            && ram.load_hword(self.queue_driver_low + 2) != self.last_avail
+    }
+
+    // head of the next available chain
+    pub fn pop_avail(&mut self, ram: &mut RAM) -> Option<u16> {
+        if !self.has_avail(ram) {
+            return None;
+        }
+        let avail_queue_idx = self.last_avail % self.queue_size; // ring index of last unread head
+        self.last_avail = self.last_avail.wrapping_add(1);
+        // 4 bytes in the available ring are for flags and idx
+        Some(ram.load_hword(self.queue_driver_low + 4 + 2 * avail_queue_idx as u32))
+    }
+
+    // returns a chain to the driver, len is the number of bytes the device wrote into it
+    pub fn push_used(&self, ram: &mut RAM, head_idx: u16, len: u32) {
+        let used_idx = ram.load_hword(self.queue_device_low + 2);
+        let used_queue_idx = used_idx % self.queue_size;
+        let used_ring_addr = self.queue_device_low + 4 + 8 * used_queue_idx as u32;
+        ram.store_word(used_ring_addr, head_idx as u32);
+        ram.store_word(used_ring_addr + 4, len);
+        // flags field of used ring needs to be 0
+        ram.store_hword(self.queue_device_low, 0);
+        ram.store_hword(self.queue_device_low + 2, used_idx.wrapping_add(1));
+    }
+
+    // descriptors of a chain in order, a chain longer than the queue is a loop
+    pub fn chain(&self, head_idx: u16, ram: &mut RAM) -> Result<Vec<Descriptor>, ()> {
+        let mut chain = Vec::new();
+        let mut idx = head_idx;
+        while chain.len() < self.queue_size as usize {
+            let desc = Descriptor::read(self.queue_desc_low + 16 * idx as u32, ram);
+            let next = desc.flags & VIRTQ_DESC_F_NEXT != 0;
+            idx = desc.next;
+            chain.push(desc);
+            if !next {
+                return Ok(chain);
+            }
+        }
+        Err(())
+    }
+}
+
 impl Default for VirtioQueue {
     fn default() -> Self {
         VirtioQueue {
@@ -61,8 +110,7 @@ pub struct VirtioMmio<const QCOUNT: usize> {
 
     pub queue_sel: usize,
     pub queues: [VirtioQueue; QCOUNT],
-    pub queue_notify: u32,
-    pub queue_notify_pending: bool,
+    pub queue_notify: u32, // queues notified since the last tick, one bit each
 
     pub interrupt_status: u32,
     // pub interrupt_ack: u32,
@@ -70,75 +118,162 @@ pub struct VirtioMmio<const QCOUNT: usize> {
     pub config_generation: u32,
 }
 
+// virtio-mmio transports, slot n is at VIRTIO_BASE + n * VIRTIO_STRIDE with PLIC source
+// VIRTIO_IRQ + n. Linux probes them in address order, the device in slot 0 is /dev/vda.
+pub const VIRTIO_SLOTS: u32 = 8;
+// most queues of a device, receive and transmit of virtio-net
+pub const VIRTIO_QUEUES: usize = 2;
+const VIRTIO_BASE: u32 = 0x4200000;
+const VIRTIO_STRIDE: u32 = 0x1000;
+const VIRTIO_IRQ: u32 = 3;
+
+// VIRTIO_F_VERSION_1 in the high word of the device features
+const FEATURES_HIGH: u32 = 1;
+
+impl<const QCOUNT: usize> VirtioMmio<QCOUNT> {
+    // queues from queue_count on do not exist, their maximum size is 0
+    fn new(device_features: u32, queue_count: usize) -> Self {
+        let mut queues = [VirtioQueue::default(); QCOUNT];
+        for queue in queues.iter_mut().skip(queue_count) {
+            queue.queue_size_max = 0;
+        }
+        VirtioMmio {
+            device_features: [device_features, FEATURES_HIGH],
+            device_features_sel: 0,
+            driver_features: [0; 2],
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            queue_notify: 0,
+            interrupt_status: 0,
+            // interrupt_ack: 0,
+            status: 0,
+            config_generation: 0,
+        }
+    }
+}
+
 pub struct VirtioDevice {
     base: u32,
     length: u32,
     interrupt_id: u32,
-    pub mmio: VirtioMmio<1>,
+    pub mmio: VirtioMmio<VIRTIO_QUEUES>,
     pub device: Box<dyn VirtioDev>,
 }
 
 impl VirtioDevice {
-    pub fn new(dev: Box<dyn VirtioDev>) -> Self {
+    pub fn new(slot: u32, dev: Box<dyn VirtioDev>) -> Self {
+        assert!(slot < VIRTIO_SLOTS);
         VirtioDevice {
-            base: 0x4200000,
+            base: VIRTIO_BASE + slot * VIRTIO_STRIDE,
             length: 0x200,
-            interrupt_id: 3,
-            mmio: VirtioMmio::<1> {
-                device_features: [0, 1],
-                device_features_sel: 0,
-                driver_features: [0; 2],
-                driver_features_sel: 0,
-                queue_sel: 0,
-                queues: [VirtioQueue::default(); 1],
-                queue_notify: 0,
-                queue_notify_pending: false,
-                interrupt_status: 0,
-                // interrupt_ack: 0,
-                status: 0,
-                config_generation: 0,
-            },
+            interrupt_id: VIRTIO_IRQ + slot,
+            mmio: VirtioMmio::new(dev.get_device_features(), dev.get_queue_count()),
             device: dev,
         }
     }
 
-    pub fn claim(&self, addr: u32) -> bool {
-        if self.device.get_conf_size() == 0 {
-            return false;
-        }
-        if addr >= self.base && addr < self.base + self.length {
-            return true;
+    // state dump for the monitor
+    pub fn info(&self, ram: &RAM) -> String {
+        let mut info = format!(
+            "device id {} status 0x{:02x} interrupt status 0x{:x} features 0x{:08x}{:08x}\n",
+            self.device.get_device_id(),
+            self.mmio.status,
+            self.mmio.interrupt_status,
+            self.mmio.driver_features[1],
+            self.mmio.driver_features[0]
+        );
+        let queues = self.mmio.queues.iter().take(self.device.get_queue_count());
+        for (i, queue) in queues.enumerate() {
+            info += &format!(
+                "queue {}: ready {} size {} desc 0x{:08x} avail 0x{:08x} used 0x{:08x}",
+                i,
+                queue.queue_ready,
+                queue.queue_size,
+                queue.queue_desc_low,
+                queue.queue_driver_low,
+                queue.queue_device_low
+            );
+            if queue.queue_ready != 0 && ram.claim(queue.queue_driver_low) && ram.claim(queue.queue_device_low) {
+                info += &format!(
+                    " avail idx {} used idx {} last avail {}",
+                    ram.load_hword(queue.queue_driver_low + 2),
+                    ram.load_hword(queue.queue_device_low + 2),
+                    queue.last_avail
+                );
+            }
+            info += "\n";
         }
-        return false;
+        info
     }
 
-    pub fn tick(&mut self, plic: &mut Plic, ram: &mut RAM) {
-        if self.device.get_conf_size() == 0 {
-            return;
+    fn set_fail(&mut self) {
+        self.mmio.status |= STATUS_NEEDS_RESET;
+        if self.mmio.status & STATUS_DRIVER_OK > 0 {
+            self.mmio.interrupt_status |= INT_ConfigurationChangeNotification;
         }
-        if self.mmio.interrupt_status > 0 {
-            plic.intt_active |= 1 << self.interrupt_id;
-        } else {
-            plic.intt_active &= !(1 << self.interrupt_id);
+    }
+
+    // None when the driver selected a queue the device does not have
+    fn selected_queue(&mut self) -> Option<&mut VirtioQueue> {
+        self.mmio.queues.get_mut(self.mmio.queue_sel)
+    }
+
+    fn handle_notify(&mut self, index: usize, ram: &mut RAM) -> Result<(), ()> {
+        let queue = &mut self.mmio.queues[index];
+        if self.device.queue_notify(index, queue, ram)? {
+            self.mmio.interrupt_status |= INT_UsedBufferNotification;
         }
+        Ok(())
+    }
+}
 
-        if self.mmio.status & STATUS_NEEDS_RESET > 0 {
-            self.reset();
-            return;
+impl MmioDevice for VirtioDevice {
+    fn name(&self) -> &'static str {
+        "virtio"
+    }
+
+    fn region(&self) -> (u32, u32) {
+        (self.base, self.length)
+    }
+
+    // drivers read the configuration space in the width of its fields
+    fn access(&self) -> Access {
+        Access {
+            widths: &[4],
+            unsupported: Unsupported::Split,
         }
+    }
 
-        if self.mmio.queue_notify_pending {
-            self.mmio.queue_notify_pending = false;
-            match self.handle_notify(ram) {
-                Ok(_) => {}
-                Err(_) => {
+    fn read(&mut self, addr: u32, _size: u32) -> u32 {
+        let addr = addr - self.base;
+        let val = match addr {
+            _MagicValue => 0x74726976,
+            _Version => 0x2,
+            _DeviceID => self.device.get_device_id(),
+            _VendorID => 0x0,
+            _DeviceFeatures => self.mmio.device_features[self.mmio.device_features_sel],
+            _QueueSizeMax => self.selected_queue().map_or(0, |x| x.queue_size_max as u32),
+            _QueueReady => self.selected_queue().map_or(0, |x| x.queue_ready),
+            _InterruptStatus => self.mmio.interrupt_status,
+            _Status => self.mmio.status,
+            _ConfigGeneration => self.mmio.config_generation,
+            _ => {
+                if addr >= _Config && addr < _Config + self.device.get_conf_size() {
+                    self.device
+                        .get_config()
+                        .read_word((addr - _Config) as usize)
+                } else {
+                    // Error
                     self.set_fail();
+                    0
                 }
             }
-        }
+        };
+        val
     }
 
-    pub fn write(&mut self, addr: u32, data: u32) {
+    fn write(&mut self, addr: u32, _size: u32, data: u32) {
         let addr = addr - self.base;
         match addr {
             _DeviceFeaturesSel => {
@@ -158,18 +293,24 @@ impl VirtioDevice {
                     self.mmio.driver_features_sel = data as usize;
                 }
             }
+            // the driver probes for queues, one past the last reads a maximum size of 0
             _QueueSel => {
                 self.mmio.queue_sel = data as usize;
             }
             _QueueSize => {
-                self.mmio.queues[self.mmio.queue_sel].queue_size = data as u16;
+                if let Some(queue) = self.selected_queue() {
+                    queue.queue_size = data as u16;
+                }
             }
             _QueueReady => {
-                self.mmio.queues[self.mmio.queue_sel].queue_ready = data;
+                if let Some(queue) = self.selected_queue() {
+                    queue.queue_ready = data;
+                }
             }
             _QueueNotify => {
-                self.mmio.queue_notify = data;
-                self.mmio.queue_notify_pending = true;
+                if (data as usize) < VIRTIO_QUEUES {
+                    self.mmio.queue_notify |= 1 << data;
+                }
             }
             _InterruptACK => {
                 // clear interrupt bits
@@ -183,19 +324,27 @@ impl VirtioDevice {
                 }
             }
             _QueueDescLow => {
-                self.mmio.queues[self.mmio.queue_sel].queue_desc_low = data;
+                if let Some(queue) = self.selected_queue() {
+                    queue.queue_desc_low = data;
+                }
             }
             _QueueDescHigh => {}
             _QueueDriverLow => {
-                self.mmio.queues[self.mmio.queue_sel].queue_driver_low = data;
+                if let Some(queue) = self.selected_queue() {
+                    queue.queue_driver_low = data;
+                }
             }
             _QueueDriverHigh => {}
             _QueueDeviceLow => {
-                self.mmio.queues[self.mmio.queue_sel].queue_device_low = data;
+                if let Some(queue) = self.selected_queue() {
+                    queue.queue_device_low = data;
+                }
             }
             _QueueDeviceHigh => {}
             _QueueReset => {
-                self.mmio.queues[self.mmio.queue_sel].queue_reset = data;
+                if let Some(queue) = self.selected_queue() {
+                    queue.queue_reset = data;
+                }
             }
             _ => {
                 if addr >= _Config && addr < _Config + self.device.get_conf_size() {
@@ -210,102 +359,44 @@ impl VirtioDevice {
         }
     }
 
-    pub fn read(&mut self, addr: u32) -> u32 {
-        let addr = addr - self.base;
-        let val = match addr {
-            _MagicValue => 0x74726976,
-            _Version => 0x2,
-            _DeviceID => self.device.get_device_id(),
-            _VendorID => 0x0,
-            _DeviceFeatures => self.mmio.device_features[self.mmio.device_features_sel],
-            _QueueSizeMax => self.mmio.queues[self.mmio.queue_sel].queue_size_max as u32,
-            _QueueReady => self.mmio.queues[self.mmio.queue_sel].queue_ready,
-            _InterruptStatus => self.mmio.interrupt_status,
-            _Status => self.mmio.status,
-            _ConfigGeneration => self.mmio.config_generation,
-            _ => {
-                if addr >= _Config && addr < _Config + self.device.get_conf_size() {
-                    self.device
-                        .get_config()
-                        .read_word((addr - _Config) as usize)
-                } else {
-                    // Error
-                    self.set_fail();
-                    0
-                }
-            }
-        };
-        val
-    }
+    fn tick(&mut self, ram: &mut RAM) {
+        if self.mmio.status & STATUS_NEEDS_RESET > 0 {
+            self.reset();
+            return;
+        }
 
-    fn set_fail(&mut self) {
-        self.mmio.status |= STATUS_NEEDS_RESET;
-        if self.mmio.status & STATUS_DRIVER_OK > 0 {
-            self.mmio.interrupt_status |= INT_ConfigurationChangeNotification;
+        let notified = std::mem::take(&mut self.mmio.queue_notify);
+        for index in 0..VIRTIO_QUEUES {
+            if notified & (1 << index) != 0 && self.handle_notify(index, ram).is_err() {
+                self.set_fail();
+                return;
+            }
         }
-    }
 
-    fn reset(&mut self) {
-        self.mmio = VirtioMmio::<1> {
-            device_features: [0, 1],
-            device_features_sel: 0,
-            driver_features: [0; 2],
-            driver_features_sel: 0,
-            queue_sel: 0,
-            queues: [VirtioQueue::default(); 1],
-            queue_notify: 0,
-            queue_notify_pending: false,
-            interrupt_status: 0,
-            // interrupt_ack: 0,
-            status: 0,
-            config_generation: 0,
+        if self.mmio.status & STATUS_DRIVER_OK != 0 && self.device.poll(&mut self.mmio.queues, ram) {
+            self.mmio.interrupt_status |= INT_UsedBufferNotification;
         }
     }
 
-    fn handle_notify(&mut self, ram: &mut RAM) -> Result<(), ()> {
-        // there is index to read in avaliable ring
-        let queue = &mut self.mmio.queues[self.mmio.queue_notify as usize];
-        let avail_idx = ram.load_hword(queue.queue_driver_low + 2); // one behind index of last written entry
-        let mut used_idx = ram.load_hword(queue.queue_device_low + 2);
-        while queue.last_avail != avail_idx {
-            // while not all descriptor chain heads had been read
-            let avail_queue_idx = queue.last_avail % queue.queue_size; // ring index of last unread head
-            // index of chain head in avail ring
-            let head_idx =
-                ram.load_hword(queue.queue_driver_low + 4 + (2 * avail_queue_idx as u32)); // 4 bytes in the available ring are for flags and idx
-
-            // TODO: process chain
-            let nbytes;
-            match self.device.process_chain(queue, head_idx, ram) {
-                Ok(len) => nbytes = len,
-                Err(_) => {
-                    self.set_fail();
-                    return Err(());
-                }
-            }
-
-            let used_queue_idx = used_idx % queue.queue_size; // ring index of last unread head
-            let used_ring_addr = queue.queue_device_low + 4 + (8 * used_queue_idx as u32);
-            ram.store_word(used_ring_addr, head_idx as u32);
-            ram.store_word(used_ring_addr + 4, nbytes);
+    fn interrupt_id(&self) -> Option<u32> {
+        Some(self.interrupt_id)
+    }
 
-            queue.last_avail += 1;
-            used_idx += 1;
-        }
+    fn interrupt_pending(&self) -> bool {
+        self.mmio.interrupt_status > 0
+    }
 
-        // flags field of used ring needs to be 0
-        ram.store_hword(queue.queue_device_low, 0);
-        // write new idx to used ring
-        ram.store_hword(queue.queue_device_low + 2, used_idx);
-
-        // INTERRUPT
-        let used_ring_flags = ram.load_hword(queue.queue_device_low);
-        if used_ring_flags != 1 {
-            // If flags is 1, the device SHOULD NOT send a notification
-            self.mmio.interrupt_status |= INT_UsedBufferNotification;
-        }
+    fn fdt_node(&self, fdt: &mut FdtWriter, _harts: usize) {
+        fdt.begin_node(&format!("virtio@{:x}", self.base));
+        fdt.prop_str("compatible", "virtio,mmio");
+        fdt.prop_reg(self.region());
+        fdt.prop_u32("interrupt-parent", fdt::PLIC_PHANDLE);
+        fdt.prop_u32("interrupts", self.interrupt_id);
+        fdt.end_node();
+    }
 
-        Ok(())
+    fn reset(&mut self) {
+        self.mmio = VirtioMmio::new(self.device.get_device_features(), self.device.get_queue_count());
     }
 }
 
@@ -313,6 +404,31 @@ pub trait VirtioDev {
     fn get_config(&mut self) -> &mut dyn VirtioConfig;
     fn get_conf_size(&self) -> u32;
     fn get_device_id(&self) -> u32;
+    // low word of the device features, device type specific bits
+    fn get_device_features(&self) -> u32 {
+        0
+    }
+    // number of queues, at most VIRTIO_QUEUES
+    fn get_queue_count(&self) -> usize {
+        1
+    }
+
+    // the driver made chains available on a queue, returns true when chains were used;
+    // devices that consume them right away process every chain
+    fn queue_notify(
+        &mut self,
+        _index: usize,
+        queue: &mut VirtioQueue,
+        ram: &mut RAM,
+    ) -> Result<bool, ()> {
+        process_queue(self, queue, ram)
+    }
+
+    // called every tick once the driver is ready, for devices that fill chains on their own
+    // like a network receive queue; returns true when chains were used
+    fn poll(&mut self, _queues: &mut [VirtioQueue], _ram: &mut RAM) -> bool {
+        false
+    }
 
     fn process_chain(
         &mut self,
@@ -322,6 +438,21 @@ pub trait VirtioDev {
     ) -> Result<u32, ()>;
 }
 
+// every available chain of the queue through process_chain
+pub fn process_queue<D: VirtioDev + ?Sized>(
+    dev: &mut D,
+    queue: &mut VirtioQueue,
+    ram: &mut RAM,
+) -> Result<bool, ()> {
+    let mut used = false;
+    while let Some(head_idx) = queue.pop_avail(ram) {
+        let nbytes = dev.process_chain(queue, head_idx, ram)?;
+        queue.push_used(ram, head_idx, nbytes);
+        used = true;
+    }
+    Ok(used)
+}
+
 pub trait VirtioConfig {
     fn read_word(&mut self, addr: usize) -> u32 {
         let base = self as *const _ as *const u8;
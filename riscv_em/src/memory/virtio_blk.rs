@@ -3,11 +3,9 @@
 use crate::memory::ram::RAM;
 
 use super::virtio::{registers::*, *};
-use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::os::linux::fs::MetadataExt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
 const VIRTIO_BLK_T_IN: u32 = 0;
 const VIRTIO_BLK_T_OUT: u32 = 1;
@@ -16,6 +14,9 @@ const VIRTIO_BLK_S_OK: u8 = 0;
 const VIRTIO_BLK_S_IOERR: u8 = 1;
 const VIRTIO_BLK_S_UNSUPP: u8 = 2;
 
+// device is read-only
+const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+
 const DISK_BLK_SIZE: u64 = 512;
 
 pub struct VirtioBlk {
@@ -23,22 +24,23 @@ pub struct VirtioBlk {
     pub config: virtio_blk_config,
     pub config_size: u32,
     pub drive: Option<File>,
+    pub read_only: bool,
 }
 
 impl VirtioBlk {
-    pub fn init(&mut self, drive: &str) {
-        let drive = match OpenOptions::new().read(true).write(true).open(drive) {
-            Ok(file) => file,
-            Err(err) => panic!("disk file error: {:?}", err),
-        };
-        let meta = match fs::metadata("disk_file") {
-            Ok(meta) => meta,
-            Err(err) => panic!("disk file error: {:?}", err),
-        };
+    // a read-only image is opened read-only and the driver is told so
+    pub fn init(&mut self, drive: &str, read_only: bool) -> io::Result<()> {
+        let drive = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(drive)?;
+        let len = drive.metadata()?.len();
         self.config = virtio_blk_config::default();
-        self.config.capacity = ((meta.st_size() - 1) / DISK_BLK_SIZE) + 1;
+        self.config.capacity = len.div_ceil(DISK_BLK_SIZE);
         self.config_size = size_of::<virtio_blk_config>() as u32;
         self.drive = Some(drive);
+        self.read_only = read_only;
+        Ok(())
     }
 }
 
@@ -51,6 +53,7 @@ impl Default for VirtioBlk {
             config,
             config_size: 0,
             drive: None,
+            read_only: false,
         }
     }
 }
@@ -67,6 +70,13 @@ impl VirtioDev for VirtioBlk {
         self.device_id
     }
 
+    fn get_device_features(&self) -> u32 {
+        match self.read_only {
+            true => VIRTIO_BLK_F_RO,
+            false => 0,
+        }
+    }
+
     fn process_chain(
         &mut self,
         queue: &mut VirtioQueue,
@@ -134,6 +144,10 @@ impl VirtioDev for VirtioBlk {
                     ram.store_byte(data_desc.addr as u32 + i as u32, buf[i]);
                 }
             }
+            VIRTIO_BLK_T_OUT if self.read_only => {
+                ram.store_byte(status_desc.addr as u32, VIRTIO_BLK_S_IOERR);
+                return Ok(1);
+            }
             VIRTIO_BLK_T_OUT => {
                 // write
                 let mut buf = vec![0u8; data_desc.len as usize];
@@ -158,6 +172,7 @@ impl VirtioDev for VirtioBlk {
             }
         }
 
+        ram.store_byte(status_desc.addr as u32, VIRTIO_BLK_S_OK);
         return Ok(data_desc.len);
     }
 }
@@ -0,0 +1,332 @@
+#![allow(non_camel_case_types)]
+
+use crate::memory::ram::RAM;
+
+use super::virtio::{registers::*, *};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+
+// device has given MAC address
+const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+
+// struct virtio_net_hdr in front of every frame, 12 bytes with VIRTIO_F_VERSION_1
+const NET_HDR_SIZE: usize = 12;
+// num_buffers of the header, always 1 without VIRTIO_NET_F_MRG_RXBUF
+const NET_HDR_NUM_BUFFERS: usize = 10;
+
+// largest datagram, a frame never gets near it without offloads
+const FRAME_SIZE: usize = 65536;
+
+// Where the frames of the guest go. Both tunnels carry one ethernet frame per datagram, like
+// the dgram netdev of QEMU, so two emulators talk to each other through a pair of sockets.
+#[derive(Clone, Debug)]
+pub enum Backend {
+    // frames from the guest are dropped, nothing is received
+    None,
+    Udp { bind: String, peer: String },
+    Unix { bind: PathBuf, peer: PathBuf },
+}
+
+// --net udp,bind=addr:port,peer=addr:port[,mac=..][,pcap=file]
+#[derive(Clone, Debug)]
+pub struct NetConfig {
+    pub backend: Backend,
+    pub mac: Option<[u8; 6]>,
+    pub pcap: Option<String>,
+}
+
+impl NetConfig {
+    // "udp,bind=..,peer=..", "unix,bind=..,peer=.." or "none", then the options
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let mut words = arg.split(',');
+        let kind = words.next().unwrap_or_default();
+        let (mut bind, mut peer, mut mac, mut pcap) = (None, None, None, None);
+        for word in words {
+            match word.split_once('=') {
+                Some(("bind", x)) => bind = Some(x.to_string()),
+                Some(("peer", x)) => peer = Some(x.to_string()),
+                Some(("mac", x)) => mac = Some(parse_mac(x)?),
+                Some(("pcap", x)) => pcap = Some(x.to_string()),
+                _ => return Err(format!("unknown net option {}", word)),
+            }
+        }
+        let backend = match (kind, bind, peer) {
+            ("none", None, None) => Backend::None,
+            ("udp", Some(bind), Some(peer)) => Backend::Udp { bind, peer },
+            ("unix", Some(bind), Some(peer)) => Backend::Unix {
+                bind: bind.into(),
+                peer: peer.into(),
+            },
+            ("udp" | "unix", _, _) => return Err(format!("{} needs bind= and peer=", kind)),
+            ("none", _, _) => return Err("none takes no bind= or peer=".to_string()),
+            _ => return Err(format!("unknown net backend {}", kind)),
+        };
+        Ok(NetConfig { backend, mac, pcap })
+    }
+
+    // device number n of this emulator, its default MAC address is 52:54:00 followed by the low
+    // bits of the process id and n, so the emulators on one host differ
+    pub fn open(&self, n: u32) -> io::Result<VirtioNet> {
+        let link = match &self.backend {
+            Backend::None => Link::None,
+            Backend::Udp { bind, peer } => {
+                let socket = UdpSocket::bind(bind)?;
+                socket.set_nonblocking(true)?;
+                let peer = peer
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or(io::ErrorKind::NotFound)?;
+                Link::Udp(socket, peer)
+            }
+            Backend::Unix { bind, peer } => {
+                // left behind by an earlier run
+                if fs::symlink_metadata(bind).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    fs::remove_file(bind)?;
+                }
+                let socket = UnixDatagram::bind(bind)?;
+                socket.set_nonblocking(true)?;
+                Link::Unix(socket, peer.clone())
+            }
+        };
+        let pcap = match &self.pcap {
+            Some(path) => Some(Pcap::create(path)?),
+            None => None,
+        };
+        let pid = std::process::id();
+        let mac = self
+            .mac
+            .unwrap_or([0x52, 0x54, 0x00, (pid >> 8) as u8, pid as u8, n as u8]);
+        Ok(VirtioNet::new(link, mac, pcap))
+    }
+}
+
+// "52:54:00:12:34:56"
+fn parse_mac(arg: &str) -> Result<[u8; 6], String> {
+    let bytes: Vec<u8> = arg
+        .split(':')
+        .map(|x| u8::from_str_radix(x, 16))
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid MAC address {}", arg))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("invalid MAC address {}", arg))
+}
+
+enum Link {
+    None,
+    Udp(UdpSocket, SocketAddr),
+    Unix(UnixDatagram, PathBuf),
+}
+
+impl Link {
+    // a peer that is not running yet loses the frame, like an unplugged cable
+    fn send(&self, frame: &[u8]) {
+        let _ = match self {
+            Link::None => Ok(0),
+            Link::Udp(socket, peer) => socket.send_to(frame, peer),
+            Link::Unix(socket, peer) => socket.send_to(frame, peer),
+        };
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Option<usize> {
+        match self {
+            Link::None => None,
+            Link::Udp(socket, _) => socket.recv(buf).ok(),
+            Link::Unix(socket, _) => socket.recv(buf).ok(),
+        }
+    }
+}
+
+// frames in both directions in the libpcap format of tcpdump and wireshark
+struct Pcap(File);
+
+impl Pcap {
+    fn create(path: &str) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        // version 2.4
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // time zone and accuracy of the time stamps
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&(FRAME_SIZE as u32).to_le_bytes());
+        // LINKTYPE_ETHERNET
+        header.extend_from_slice(&1u32.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Pcap(file))
+    }
+
+    // one write per record, the file is complete whenever the emulator exits
+    fn write(&mut self, frame: &[u8]) {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(frame);
+        let _ = self.0.write_all(&record);
+    }
+}
+
+pub struct VirtioNet {
+    pub device_id: u32,
+    pub config: virtio_net_config,
+    link: Link,
+    pcap: Option<Pcap>,
+    frame: Vec<u8>,
+}
+
+impl VirtioNet {
+    fn new(link: Link, mac: [u8; 6], pcap: Option<Pcap>) -> Self {
+        VirtioNet {
+            device_id: 1,
+            config: virtio_net_config {
+                mac,
+                ..Default::default()
+            },
+            link,
+            pcap,
+            frame: vec![0; FRAME_SIZE],
+        }
+    }
+
+    // copies header and frame into the writable descriptors of a receive chain, returns the
+    // number of bytes written or None when the frame does not fit or a buffer is outside RAM
+    fn receive(
+        &self,
+        queue: &VirtioQueue,
+        head_idx: u16,
+        len: usize,
+        ram: &mut RAM,
+    ) -> Option<u32> {
+        let mut chain = queue.chain(head_idx, ram).ok()?;
+        chain.retain(|desc| desc.flags & VIRTQ_DESC_F_WRITE != 0);
+        let capacity: usize = chain.iter().map(|desc| desc.len as usize).sum();
+        if NET_HDR_SIZE + len > capacity {
+            return None;
+        }
+
+        let mut header = [0u8; NET_HDR_SIZE];
+        header[NET_HDR_NUM_BUFFERS] = 1;
+        let mut bytes = header.iter().chain(&self.frame[..len]);
+        for desc in chain {
+            for (i, byte) in bytes.by_ref().take(desc.len as usize).enumerate() {
+                let addr = (desc.addr as u32).wrapping_add(i as u32);
+                if !ram.claim(addr) {
+                    return None;
+                }
+                ram.store_byte(addr, *byte);
+            }
+        }
+        Some((NET_HDR_SIZE + len) as u32)
+    }
+}
+
+impl VirtioDev for VirtioNet {
+    fn get_config(&mut self) -> &mut dyn VirtioConfig {
+        &mut self.config
+    }
+
+    fn get_conf_size(&self) -> u32 {
+        size_of::<virtio_net_config>() as u32
+    }
+
+    fn get_device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    fn get_device_features(&self) -> u32 {
+        VIRTIO_NET_F_MAC
+    }
+
+    fn get_queue_count(&self) -> usize {
+        2
+    }
+
+    // receive buffers wait for frames, see poll
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queue: &mut VirtioQueue,
+        ram: &mut RAM,
+    ) -> Result<bool, ()> {
+        match index {
+            TX_QUEUE => process_queue(self, queue, ram),
+            _ => Ok(false),
+        }
+    }
+
+    // frames stay in the socket until the driver has a buffer for them
+    fn poll(&mut self, queues: &mut [VirtioQueue], ram: &mut RAM) -> bool {
+        let queue = &mut queues[RX_QUEUE];
+        let mut used = false;
+        while queue.has_avail(ram) {
+            let Some(len) = self.link.recv(&mut self.frame) else {
+                break;
+            };
+            if let Some(pcap) = &mut self.pcap {
+                pcap.write(&self.frame[..len]);
+            }
+            let head_idx = queue.pop_avail(ram).unwrap();
+            // a frame the buffer can't take is dropped, the driver counts a length error
+            let nbytes = self.receive(queue, head_idx, len, ram).unwrap_or(0);
+            queue.push_used(ram, head_idx, nbytes);
+            used = true;
+        }
+        used
+    }
+
+    // transmit chain: header and frame in the readable descriptors, no larger than a datagram
+    fn process_chain(
+        &mut self,
+        queue: &mut VirtioQueue,
+        head_idx: u16,
+        ram: &mut RAM,
+    ) -> Result<u32, ()> {
+        let mut packet = Vec::new();
+        for desc in queue.chain(head_idx, ram)? {
+            if desc.flags & VIRTQ_DESC_F_WRITE != 0
+                || packet.len() + desc.len as usize > NET_HDR_SIZE + FRAME_SIZE
+            {
+                return Err(());
+            }
+            for i in 0..desc.len {
+                let addr = (desc.addr as u32).wrapping_add(i);
+                if !ram.claim(addr) {
+                    return Err(());
+                }
+                packet.push(ram.load_byte(addr));
+            }
+        }
+        let frame = packet.get(NET_HDR_SIZE..).ok_or(())?;
+        if let Some(pcap) = &mut self.pcap {
+            pcap.write(frame);
+        }
+        self.link.send(frame);
+        // nothing written into the chain
+        Ok(0)
+    }
+}
+
+#[derive(Default)]
+#[repr(C, packed)]
+pub struct virtio_net_config {
+    pub mac: [u8; 6],
+    status: u16,
+    max_virtqueue_pairs: u16,
+    mtu: u16,
+}
+
+impl VirtioConfig for virtio_net_config {}